@@ -1,25 +1,44 @@
 #![allow(non_snake_case)]
 
 use std::env;
+use std::fs;
 use std::fs::File;
-use std::io::{stderr, stdout, BufReader, Write};
-use std::path::Path;
+use std::io::{stderr, stdout, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use petgraph::graph::NodeIndex;
-use petgraph::{EdgeDirection, Graph};
+use path_absolutize::Absolutize;
+use regex::Regex;
 
+use octobuild::cache::Cache;
+use octobuild::checkpoint::Checkpoint;
 use octobuild::cluster::client::RemoteCompiler;
-use octobuild::compiler::{CommandArgs, Compiler, SharedState};
-use octobuild::config::Config;
+use octobuild::compiler::{CommandEnv, Compiler, SharedState, TaskOptions};
+use octobuild::config::{CacheMode, Config};
+use octobuild::dependencies::DependencyDump;
+use octobuild::history::TaskHistory;
+use octobuild::io::statistic::StatisticSnapshot;
+use octobuild::reproduce::Reproducer;
+use octobuild::signal;
 use octobuild::simple::supported_compilers;
+use octobuild::trace::Tracer;
 use octobuild::version;
-use octobuild::worker::execute_graph;
-use octobuild::worker::validate_graph;
-use octobuild::worker::{BuildAction, BuildGraph, BuildResult, BuildTask};
-use octobuild::xg;
-use octobuild::xg::parser::{XgGraph, XgNode};
+use octobuild::worker::OutputProducers;
+use octobuild::worker::{append_graph, execute_graph, execute_graphs, explain_graph};
+use octobuild::worker::{check_expect_cached, check_min_success, ExpectCached};
+use octobuild::worker::{check_output_collisions, check_output_dirs, remap_outputs};
+use octobuild::worker::{BuildAction, BuildGraph, BuildResult};
+use octobuild::xg::source::{GraphContext, GraphSources};
+
+// Exit code of build cancelled by --max-wall-time.
+const TIMEOUT_EXIT_CODE: i32 = 3;
+// Default size of console output buffers.
+const DEFAULT_OUTPUT_BUFFER: usize = 64 * 1024;
+// Larger buffers are never filled by output of a single task.
+const MAX_OUTPUT_BUFFER: usize = 16 * 1024 * 1024;
 
 pub fn main() -> octobuild::Result<()> {
     env_logger::init();
@@ -39,6 +58,10 @@ pub fn main() -> octobuild::Result<()> {
 
     process::exit(match execute(&config, &args[1..]) {
         Ok(_) => 0,
+        Err(e @ octobuild::Error::BuildTimedOut { .. }) => {
+            writeln!(stderr(), "ERROR: {e}")?;
+            TIMEOUT_EXIT_CODE
+        }
         Err(e) => {
             writeln!(stderr(), "ERROR: {e}")?;
             1
@@ -47,8 +70,7 @@ pub fn main() -> octobuild::Result<()> {
 }
 
 fn execute(config: &Config, args: &[String]) -> octobuild::Result<()> {
-    let state = SharedState::new(config)?;
-    let compiler = RemoteCompiler::new(&config.coordinator, supported_compilers());
+    let mut state = SharedState::new(config)?;
 
     match args.first() {
         None => Err(octobuild::Error::NoTaskFiles),
@@ -62,93 +84,514 @@ fn execute(config: &Config, args: &[String]) -> octobuild::Result<()> {
                 _ = std::fs::remove_dir_all(&config.cache);
                 writeln!(stdout(), "Done!")?;
                 Ok(())
+            } else if arg.eq_ignore_ascii_case("/verifycache") {
+                let delete = args[1..].iter().any(|v| v.eq_ignore_ascii_case("/delete"));
+                writeln!(
+                    stdout(),
+                    "Verifying cache directory: {}...",
+                    config.cache.display()
+                )?;
+                let statistic = state.cache.verify(config.process_limit, delete)?;
+                writeln!(stdout(), "{statistic}")?;
+                Ok(())
+            } else if let Some(key) = option_value(arg, "cacheshow") {
+                write!(stdout(), "{}", state.cache.inspect(key, None)?)?;
+                Ok(())
+            } else if let Some(key) = option_value(arg, "cacheextract") {
+                let dir = args[1..]
+                    .iter()
+                    .find_map(|v| option_value(v, "to"))
+                    .ok_or_else(|| {
+                        octobuild::Error::Generic("Target directory is not set: --to=DIR".into())
+                    })?;
+                write!(
+                    stdout(),
+                    "{}",
+                    state.cache.inspect(key, Some(Path::new(dir)))?
+                )?;
+                writeln!(stdout(), "Outputs extracted to: {dir}")?;
+                Ok(())
             } else {
-                let mut graph = Graph::new();
-                let file = File::open(Path::new(&args[0]))?;
-                xg::parser::parse(&mut graph, BufReader::new(file))?;
-                let build_graph = prepare_graph(&compiler, validate_graph(graph)?, config)?;
-
-                let result =
-                    execute_graph(&state, build_graph, config.process_limit, print_task_result);
-                drop(state.cache.cleanup());
+                // Leading arguments are task files
+                let files = 1 + args[1..].iter().take_while(|arg| !is_option(arg)).count();
+                let (files, options) = (&args[..files], BuildOptions::parse(&args[files..])?);
+                state.preprocess_only.clone_from(&options.preprocess_only);
+                state.reproduce = options.reproduce.clone().map(Reproducer::new);
+                state.trace = options.trace.clone().map(Tracer::new);
+                state.verify_outputs = options.verify_outputs;
+                state.verify_objects = options.verify_objects;
+                state.keep_going = options.keep_going || options.min_success.is_some();
+                state.max_wall_time = options.max_wall_time;
+                if options.warm {
+                    state.cache = Cache::new(&Config {
+                        cache_mode: CacheMode::WriteOnly,
+                        ..config.clone()
+                    });
+                }
+                let compiler = RemoteCompiler::new(&config.coordinator, supported_compilers())
+                    .with_toolchain(options.toolchain.clone())
+                    .with_capacity_aware(options.builder_capacity_aware)
+                    .with_remote_preprocess(options.remote_preprocess);
+                let sources = &state.graph_sources;
+                let load = |path: &String| load_graph(sources, &compiler, config, &options, path);
+                // Several task files are run while following ones are loaded, unless
+                // the whole build graph is needed beforehand.
+                let streaming =
+                    files.len() > 1 && options.explain.is_none() && options.resume.is_none();
+                let mut build_graph = BuildGraph::new();
+                if !streaming {
+                    for path in files {
+                        append_graph(&mut build_graph, &load(path)?);
+                    }
+                    check_output_collisions(&build_graph)?;
+                }
+                if let Some(path) = &options.explain {
+                    let file = File::create(path)?;
+                    serde_json::to_writer_pretty(file, &explain_graph(&build_graph)?)?;
+                    writeln!(stdout(), "Execution plan written to {}", path.display())?;
+                    return Ok(());
+                }
+
+                // Preprocessed-only run doesn't complete tasks
+                if let (Some(path), None) = (&options.resume, &state.preprocess_only) {
+                    let checkpoint = Checkpoint::open(path.clone(), &build_graph)?;
+                    if !checkpoint.is_empty() {
+                        writeln!(
+                            stdout(),
+                            "Resuming build, {} tasks completed earlier",
+                            checkpoint.len()
+                        )?;
+                    }
+                    state.checkpoint = Some(checkpoint);
+                }
+
+                if let Some(path) = &options.report_flaky {
+                    state.history = Some(TaskHistory::open(path.clone())?);
+                }
+                if let Some(path) = &options.dump_dependencies {
+                    state.dependencies = Some(DependencyDump::new(path.clone()));
+                }
+
+                // Fail early on unreadable baseline instead of after the build
+                let baseline: Option<StatisticSnapshot> = match &options.compare_stats {
+                    Some(path) => Some(serde_json::from_reader(BufReader::new(File::open(path)?))?),
+                    None => None,
+                };
+                if let Some(baseline) = &baseline {
+                    // Older files have only slowest tasks
+                    state.task_durations = (baseline.slowest.iter())
+                        .map(|task| (&task.title, task.duration))
+                        .chain(baseline.durations.iter().map(|(title, v)| (title, *v)))
+                        .filter_map(|(title, duration)| {
+                            Some((title.clone(), Duration::try_from_secs_f64(duration).ok()?))
+                        })
+                        .collect();
+                }
+
+                let start = Instant::now();
+                let filter = &options.progress;
+                let console = Mutex::new(Console::new(stdout(), stderr(), options.output_buffer));
+                let progress = |result: &BuildResult| {
+                    print_task_result(result, filter, &mut console.lock().unwrap())
+                };
+                let run = || {
+                    if streaming {
+                        let mut producers = OutputProducers::default();
+                        let graphs = files.iter().map(|path| {
+                            let graph = load(path)?;
+                            producers.add(&graph)?;
+                            Ok(graph)
+                        });
+                        execute_graphs(&state, graphs, config.worker_limit(), progress)
+                    } else {
+                        execute_graph(&state, build_graph, config.worker_limit(), progress)
+                    }
+                };
+                // Termination request cancels the build like timeout, completed tasks are
+                // still recorded.
+                let result = signal::interruptible(state.cancel_flag(), || {
+                    if options.print_stats_on_signal {
+                        signal::install()?;
+                        signal::watch_statistic(&state.statistic, &mut stderr(), run)
+                    } else {
+                        run()
+                    }
+                });
+                let elapsed = start.elapsed();
+                if let Some(reproducer) = &state.reproduce {
+                    let count = reproducer.finish()?;
+                    if count > 0 {
+                        writeln!(
+                            stdout(),
+                            "Failed compilations ({count}) bundled to {}",
+                            reproducer.path().display()
+                        )?;
+                    }
+                }
+                if result.is_ok() {
+                    if let Some(checkpoint) = state.checkpoint.take() {
+                        checkpoint.remove()?;
+                    }
+                }
+                let result = match options.min_success {
+                    Some(min_success) => check_min_success(result, min_success, &mut stdout()),
+                    None => result,
+                };
+                let result = match (&options.expect_cached, result) {
+                    (Some(expected), Ok(())) => {
+                        check_expect_cached(&state.statistic, expected, &mut stdout())
+                    }
+                    (_, result) => result,
+                };
+                if let Some(history) = &state.history {
+                    history.print_flaky(&mut stdout())?;
+                }
+                if let Some(dump) = &state.dependencies {
+                    let count = dump.finish()?;
+                    writeln!(
+                        stdout(),
+                        "Dependencies of {count} objects written to {}",
+                        dump.path().display()
+                    )?;
+                    let unknown = dump.unknown();
+                    if !unknown.is_empty() {
+                        writeln!(
+                            stdout(),
+                            "Omitted tasks executed without preprocessing ({}):",
+                            unknown.len()
+                        )?;
+                        for title in unknown {
+                            writeln!(stdout(), "  {title}")?;
+                        }
+                    }
+                }
+                if let Some(tracer) = &state.trace {
+                    let count = tracer.finish()?;
+                    writeln!(
+                        stdout(),
+                        "Trace of {count} tasks written to {}",
+                        tracer.path().display()
+                    )?;
+                }
+                let abort = AtomicBool::new(false);
+                signal::interruptible(&abort, || {
+                    drop(state.cache.cleanup(config.process_limit, &abort));
+                });
                 writeln!(stdout(), "{}", state.statistic)?;
+                state.statistic.print_workers(&mut stdout())?;
+                state.statistic.print_compilers(&mut stdout())?;
+                let snapshot = state.statistic.snapshot(elapsed);
+                if let Some(path) = &options.save_stats {
+                    serde_json::to_writer_pretty(File::create(path)?, &snapshot)?;
+                }
+                if let Some(baseline) = &baseline {
+                    snapshot.print_diff(baseline, &mut stdout())?;
+                }
                 result
             }
         }
     }
 }
 
-fn env_resolver(name: &str) -> Option<String> {
-    env::var(name).ok()
+#[derive(Default)]
+struct BuildOptions {
+    print_stats_on_signal: bool,
+    // Print classification of each task argument and how they are split between phases.
+    explain_args: bool,
+    // Write preprocessed sources to directory instead of compiling.
+    preprocess_only: Option<PathBuf>,
+    // Write execution plan to file instead of building.
+    explain: Option<PathBuf>,
+    // Task file format name, selected by file extension if not set.
+    format: Option<String>,
+    // Object output prefixes replaced with other roots.
+    output_remap: Vec<(PathBuf, PathBuf)>,
+    // Bundle failed compilations to archive.
+    reproduce: Option<PathBuf>,
+    // Fail compilations which didn't write declared outputs.
+    verify_outputs: bool,
+    // Fail compilations which produced malformed object file.
+    verify_objects: bool,
+    // Run independent tasks after failure.
+    keep_going: bool,
+    // Succeed in keep going mode if at least this percent of tasks succeeded.
+    min_success: Option<f64>,
+    // Fail build if these compilation tasks weren't restored from cache.
+    expect_cached: Option<ExpectCached>,
+    // Compile every task ignoring cached results and store them to cache.
+    warm: bool,
+    // Skip tasks completed by interrupted build, tracked in this file.
+    resume: Option<PathBuf>,
+    // Cancel the build when it runs longer.
+    max_wall_time: Option<Duration>,
+    // Record task outcomes to history file and report tasks with mixed recent outcomes.
+    report_flaky: Option<PathBuf>,
+    // Write Chrome trace of task execution to file.
+    trace: Option<PathBuf>,
+    // Write header dependencies of compiled objects to JSON file.
+    dump_dependencies: Option<PathBuf>,
+    // Write build statistic to file.
+    save_stats: Option<PathBuf>,
+    // Compare build statistic with previously saved one.
+    compare_stats: Option<PathBuf>,
+    // Don't inherit process environment except essential and allowed variables.
+    isolate_env: bool,
+    // Toolchain required on remote builders.
+    toolchain: Option<String>,
+    // Send tasks to remote builders with most free capacity.
+    builder_capacity_aware: bool,
+    // Let remote builders preprocess sources.
+    remote_preprocess: bool,
+    // Fail tasks which would run directly instead of through compiler backend.
+    require_backend: bool,
+    // Which completed tasks are printed.
+    progress: ProgressFilter,
+    // Size of console output buffers, flushed after every task.
+    output_buffer: usize,
+}
+
+impl BuildOptions {
+    fn parse(args: &[String]) -> octobuild::Result<Self> {
+        let mut options = BuildOptions {
+            output_buffer: DEFAULT_OUTPUT_BUFFER,
+            ..BuildOptions::default()
+        };
+        for arg in args {
+            let (name, value) = split_option(arg)
+                .ok_or_else(|| octobuild::Error::Generic(format!("Unexpected argument: {arg}")))?;
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("print-stats-on-signal", None) => options.print_stats_on_signal = true,
+                // `dump-args` printed argument classification before it became part of
+                // `explain-args`.
+                ("explain-args" | "dump-args", None) => options.explain_args = true,
+                ("preprocess-only", Some(value)) => {
+                    options.preprocess_only = Some(PathBuf::from(value));
+                }
+                ("explain", Some(value)) => options.explain = Some(PathBuf::from(value)),
+                ("format", Some(value)) => options.format = Some(value.to_string()),
+                ("output-remap", Some(value)) => {
+                    let (from, to) = value.split_once('=').ok_or_else(|| {
+                        octobuild::Error::Generic(format!("Invalid output remap: {value}"))
+                    })?;
+                    options.output_remap.push((
+                        Path::new(from).absolutize()?.to_path_buf(),
+                        Path::new(to).absolutize()?.to_path_buf(),
+                    ));
+                }
+                ("reproduce", Some(value)) => options.reproduce = Some(PathBuf::from(value)),
+                ("verify-outputs", None) => options.verify_outputs = true,
+                ("verify-objects", None) => options.verify_objects = true,
+                ("keep-going", None) => options.keep_going = true,
+                ("warm", None) => options.warm = true,
+                ("min-success", Some(value)) => {
+                    let percent = value.strip_suffix('%').unwrap_or(value);
+                    options.min_success = Some(
+                        percent
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|v| (0.0..=100.0).contains(v))
+                            .ok_or_else(|| {
+                                octobuild::Error::Generic(format!("Invalid percent: {value}"))
+                            })?,
+                    );
+                }
+                ("expect-cached", Some(value)) => {
+                    options.expect_cached = Some(if value.eq_ignore_ascii_case("all") {
+                        ExpectCached::All
+                    } else {
+                        ExpectCached::Titles(
+                            std::fs::read_to_string(value)?
+                                .lines()
+                                .map(str::trim)
+                                .filter(|line| !line.is_empty())
+                                .map(ToString::to_string)
+                                .collect(),
+                        )
+                    });
+                }
+                ("max-wall-time", Some(value)) => {
+                    options.max_wall_time =
+                        Some(Duration::from_secs(value.parse().map_err(|_| {
+                            octobuild::Error::Generic(format!("Invalid duration: {value}"))
+                        })?));
+                }
+                ("resume", Some(value)) => options.resume = Some(PathBuf::from(value)),
+                ("trace", Some(value)) => options.trace = Some(PathBuf::from(value)),
+                ("dump-dependencies", Some(value)) => {
+                    options.dump_dependencies = Some(PathBuf::from(value));
+                }
+                ("report-flaky", Some(value)) => {
+                    options.report_flaky = Some(PathBuf::from(value));
+                }
+                ("isolate-env", None) => options.isolate_env = true,
+                ("save-stats", Some(value)) => options.save_stats = Some(PathBuf::from(value)),
+                ("compare-stats", Some(value)) => {
+                    options.compare_stats = Some(PathBuf::from(value));
+                }
+                ("toolchain", Some(value)) => options.toolchain = Some(value.to_string()),
+                ("builder-capacity-aware", None) => options.builder_capacity_aware = true,
+                ("remote-preprocess", None) => options.remote_preprocess = true,
+                ("require-backend", None) => options.require_backend = true,
+                ("show-tasks", Some(value)) => {
+                    options.progress.title = Some(Regex::new(value).map_err(|e| {
+                        octobuild::Error::Generic(format!("Invalid task filter {value}: {e}"))
+                    })?);
+                }
+                ("output-buffer", Some(value)) => {
+                    options.output_buffer = value
+                        .parse()
+                        .ok()
+                        .filter(|v| *v <= MAX_OUTPUT_BUFFER)
+                        .ok_or_else(|| {
+                            octobuild::Error::Generic(format!(
+                                "Invalid buffer size: {value} (expected 0 to {MAX_OUTPUT_BUFFER})"
+                            ))
+                        })?;
+                }
+                ("show-min-duration", Some(value)) => {
+                    options.progress.min_duration = value
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(|v| Duration::try_from_secs_f64(v).ok())
+                        .ok_or_else(|| {
+                            octobuild::Error::Generic(format!("Invalid duration: {value}"))
+                        })?;
+                }
+                _ => {
+                    return Err(octobuild::Error::Generic(format!("Unknown option: {arg}")));
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+// Filter for completed tasks in progress output. Failed tasks are always shown.
+#[derive(Default)]
+struct ProgressFilter {
+    title: Option<Regex>,
+    min_duration: Duration,
+}
+
+impl ProgressFilter {
+    fn matches(&self, result: &BuildResult) -> bool {
+        if !matches!(&result.result.output, Ok(output) if output.success()) {
+            return true;
+        }
+        result.result.duration >= self.min_duration
+            && self
+                .title
+                .as_ref()
+                .is_none_or(|re| re.is_match(&result.task.title))
+    }
+}
+
+// Buffered stdout and stderr of build. Buffers are flushed after output of every task, so
+// output of task is never split and nothing is left unwritten when build stops. Task output
+// flushes one stream before writing to another, so their order is kept.
+struct Console<O: Write, E: Write> {
+    out: BufWriter<O>,
+    err: BufWriter<E>,
 }
 
-fn prepare_graph<C: Compiler>(
-    compiler: &C,
-    graph: XgGraph,
+impl<O: Write, E: Write> Console<O, E> {
+    fn new(out: O, err: E, capacity: usize) -> Self {
+        Console {
+            out: BufWriter::with_capacity(capacity, out),
+            err: BufWriter::with_capacity(capacity, err),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()?;
+        self.err.flush()
+    }
+}
+
+// Split `--name[=value]` or `/name[=value]` option to name and value.
+fn split_option(arg: &str) -> Option<(&str, Option<&str>)> {
+    let option = arg.strip_prefix("--").or_else(|| arg.strip_prefix('/'))?;
+    Some(match option.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (option, None),
+    })
+}
+
+// Option names are words, unlike absolute Unix paths starting with `/` too.
+fn is_option(arg: &str) -> bool {
+    split_option(arg).is_some_and(|(name, _)| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+// Value of option with given name, case-insensitive.
+fn option_value<'a>(arg: &'a str, name: &str) -> Option<&'a str> {
+    match split_option(arg)? {
+        (option, Some(value)) if option.eq_ignore_ascii_case(name) => Some(value),
+        _ => None,
+    }
+}
+
+// Load task file and check its tasks before running them.
+fn load_graph(
+    sources: &GraphSources,
+    compiler: &dyn Compiler,
     config: &Config,
+    options: &BuildOptions,
+    path: &str,
 ) -> octobuild::Result<BuildGraph> {
-    let mut remap: Vec<NodeIndex> = Vec::with_capacity(graph.node_count());
-    let mut depends: Vec<NodeIndex> = Vec::with_capacity(graph.node_count());
-
-    let mut result: BuildGraph = Graph::new();
-    for raw_node in graph.raw_nodes() {
-        let node: &XgNode = &raw_node.weight;
-        let raw_args: String = expand_arg(&node.raw_args, &env_resolver);
-        let command = node.command.clone();
-
-        let actions = BuildAction::create_tasks(
-            compiler,
-            command.clone(),
-            CommandArgs::Raw(raw_args),
-            &node.title,
-            config.run_second_cpp,
-        );
-        let node_index = NodeIndex::new(remap.len());
-        if actions.len() == 1 {
-            depends.push(node_index);
-            remap.push(result.add_node(Arc::new(BuildTask {
-                title: node.title.clone(),
-                action: actions.into_iter().next().unwrap(),
-            })));
-        } else {
-            // Add group node for tracking end of all task actions
-            let group_node = result.add_node(Arc::new(BuildTask {
-                title: node.title.clone(),
-                action: BuildAction::Empty,
-            }));
-            depends.push(NodeIndex::end());
-            // Add task actions
-            let mut index = 1;
-            let total = actions.len();
-            for action in actions {
-                let action_node = result.add_node(Arc::new(BuildTask {
-                    title: format!("{} ({index}/{total})", node.title),
-                    action,
-                }));
-                depends.push(node_index);
-                result.add_edge(group_node, action_node, ());
-                index += 1;
-            }
-            remap.push(group_node);
+    let inherited = if options.isolate_env {
+        CommandEnv::isolated(&config.env_passthrough)
+    } else {
+        env::vars().collect()
+    };
+    let task_options = TaskOptions {
+        require_backend: options.require_backend,
+        ..TaskOptions::new(config)
+    };
+    let context = GraphContext {
+        inherited: &inherited,
+        compiler,
+        options: &task_options,
+    };
+    let mut build_graph = sources.load(Path::new(path), options.format.as_deref(), &context)?;
+    let remapped_dirs = remap_outputs(&mut build_graph, &options.output_remap)?;
+    if options.explain_args {
+        print_args_reports(&build_graph, &mut stdout())?;
+    }
+    // Explaining build doesn't touch filesystem
+    if options.explain.is_none() {
+        for dir in remapped_dirs {
+            fs::create_dir_all(dir)?;
         }
+        check_output_dirs(&build_graph)?;
     }
+    Ok(build_graph)
+}
 
-    assert_eq!(remap.len(), graph.node_count());
-    assert_eq!(depends.len(), result.node_count());
-    for i in 0..depends.len() {
-        let node_a = NodeIndex::new(i);
-        for neighbor in graph.neighbors_directed(*depends.get(i).unwrap(), EdgeDirection::Outgoing)
-        {
-            let node_b = remap.get(neighbor.index()).unwrap();
-            result.add_edge(node_a, *node_b, ());
+fn print_args_reports(graph: &BuildGraph, out: &mut impl Write) -> octobuild::Result<()> {
+    for node in graph.raw_nodes() {
+        if let BuildAction::Compilation(toolchain, task) = &node.weight.action {
+            writeln!(out, "{}:", node.weight.title)?;
+            write!(out, "{}", task.args_report(toolchain.as_ref())?)?;
         }
     }
-    validate_graph(result)
+    Ok(())
 }
 
-fn print_task_result(result: &BuildResult) -> octobuild::Result<()> {
+fn print_task_result<O: Write, E: Write>(
+    result: &BuildResult,
+    filter: &ProgressFilter,
+    console: &mut Console<O, E>,
+) -> octobuild::Result<()> {
+    if !filter.matches(result) {
+        return Ok(());
+    }
     writeln!(
-        stdout(),
+        console.out,
         "#{} {}/{}: {} @ {}s",
         result.worker,
         result.completed,
@@ -156,59 +599,390 @@ fn print_task_result(result: &BuildResult) -> octobuild::Result<()> {
         result.task.title,
         result.result.duration.as_secs(),
     )?;
-    result.result.print_output()?;
+    result
+        .result
+        .write_output(&mut console.out, &mut console.err)?;
+    console.flush()?;
     Ok(())
 }
 
-fn expand_arg<F: Fn(&str) -> Option<String>>(arg: &str, resolver: &F) -> String {
-    let mut result = String::new();
-    let mut suffix = arg;
-    loop {
-        match suffix.find("$(") {
-            Some(begin) => match suffix[begin..].find(')') {
-                Some(end) => {
-                    let name = &suffix[begin + 2..begin + end];
-                    match resolver(name) {
-                        Some(ref value) => {
-                            result += &suffix[..begin];
-                            result += value;
-                        }
-                        None => {
-                            result += &suffix[..=begin + end];
-                        }
-                    }
-                    suffix = &suffix[begin + end + 1..];
-                }
-                None => {
-                    result += suffix;
-                    break;
-                }
-            },
-            None => {
-                result += suffix;
-                break;
-            }
-        }
+#[cfg(test)]
+fn parse(args: &[&str]) -> octobuild::Result<BuildOptions> {
+    BuildOptions::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+}
+
+#[test]
+fn test_parse_print_stats_on_signal() {
+    assert!(
+        parse(&["--print-stats-on-signal"])
+            .unwrap()
+            .print_stats_on_signal
+    );
+    assert!(
+        parse(&["/Print-Stats-On-Signal"])
+            .unwrap()
+            .print_stats_on_signal
+    );
+}
+
+#[test]
+fn test_parse_toolchain() {
+    let options = parse(&["/toolchain=cl.exe 14.38"]).unwrap();
+    assert_eq!(options.toolchain.as_deref(), Some("cl.exe 14.38"));
+}
+
+#[test]
+fn test_parse_explain_args() {
+    assert!(parse(&["--explain-args"]).unwrap().explain_args);
+    assert!(parse(&["/Dump-Args"]).unwrap().explain_args);
+}
+
+#[test]
+fn test_parse_preprocess_only() {
+    let options = parse(&["/preprocess-only=out"]).unwrap();
+    assert_eq!(options.preprocess_only, Some(PathBuf::from("out")));
+}
+
+#[test]
+fn test_parse_explain() {
+    let options = parse(&["/Explain=plan.json"]).unwrap();
+    assert_eq!(options.explain, Some(PathBuf::from("plan.json")));
+}
+
+#[test]
+fn test_parse_output_remap() {
+    let options = parse(&["--output-remap=obj=ci/obj", "/Output-Remap=lib=ci/lib"]).unwrap();
+    let dir = env::current_dir().unwrap();
+    assert_eq!(
+        options.output_remap,
+        [
+            (dir.join("obj"), dir.join("ci").join("obj")),
+            (dir.join("lib"), dir.join("ci").join("lib")),
+        ]
+    );
+    assert!(parse(&["--output-remap=obj"]).is_err());
+}
+
+#[test]
+fn test_parse_reproduce() {
+    let options = parse(&["--reproduce=bug.tar"]).unwrap();
+    assert_eq!(options.reproduce, Some(PathBuf::from("bug.tar")));
+}
+
+#[test]
+fn test_parse_keep_going() {
+    assert!(parse(&["--keep-going"]).unwrap().keep_going);
+}
+
+#[test]
+fn test_parse_warm() {
+    assert!(parse(&["/Warm"]).unwrap().warm);
+}
+
+#[test]
+fn test_parse_verify() {
+    assert!(parse(&["/Verify-Outputs"]).unwrap().verify_outputs);
+    assert!(parse(&["--verify-objects"]).unwrap().verify_objects);
+}
+
+#[test]
+fn test_parse_min_success() {
+    let options = parse(&["/Min-Success=80%"]).unwrap();
+    assert_eq!(options.min_success, Some(80.0));
+    assert!(parse(&["--min-success=120%"]).is_err());
+}
+
+#[test]
+fn test_parse_expect_cached() {
+    assert!(matches!(
+        parse(&["--expect-cached=ALL"]).unwrap().expect_cached,
+        Some(ExpectCached::All)
+    ));
+    assert!(parse(&["--expect-cached=missing.txt"]).is_err());
+}
+
+#[test]
+fn test_parse_format() {
+    let options = parse(&["/Format=xge"]).unwrap();
+    assert_eq!(options.format.as_deref(), Some("xge"));
+}
+
+#[test]
+fn test_parse_max_wall_time() {
+    let options = parse(&["/Max-Wall-Time=3600"]).unwrap();
+    assert_eq!(options.max_wall_time, Some(Duration::from_secs(3600)));
+    assert!(parse(&["--max-wall-time=1h"]).is_err());
+}
+
+#[test]
+fn test_parse_resume() {
+    let options = parse(&["--resume=build.done"]).unwrap();
+    assert_eq!(options.resume, Some(PathBuf::from("build.done")));
+}
+
+#[test]
+fn test_parse_report_flaky() {
+    let options = parse(&["--report-flaky=history.txt"]).unwrap();
+    assert_eq!(options.report_flaky, Some(PathBuf::from("history.txt")));
+}
+
+#[test]
+fn test_parse_trace() {
+    let options = parse(&["/Trace=build.json"]).unwrap();
+    assert_eq!(options.trace, Some(PathBuf::from("build.json")));
+}
+
+#[test]
+fn test_parse_dump_dependencies() {
+    let options = parse(&["/Dump-Dependencies=deps.json"]).unwrap();
+    assert_eq!(options.dump_dependencies, Some(PathBuf::from("deps.json")));
+}
+
+#[test]
+fn test_parse_stats() {
+    let options = parse(&["--save-stats=new.json", "/Compare-Stats=old.json"]).unwrap();
+    assert_eq!(options.save_stats, Some(PathBuf::from("new.json")));
+    assert_eq!(options.compare_stats, Some(PathBuf::from("old.json")));
+}
+
+#[test]
+fn test_parse_builder_capacity_aware() {
+    assert!(
+        parse(&["--builder-capacity-aware"])
+            .unwrap()
+            .builder_capacity_aware
+    );
+}
+
+#[test]
+fn test_parse_remote_preprocess() {
+    assert!(parse(&["/Remote-Preprocess"]).unwrap().remote_preprocess);
+}
+
+#[test]
+fn test_parse_require_backend() {
+    assert!(parse(&["/Require-Backend"]).unwrap().require_backend);
+}
+
+#[test]
+fn test_parse_isolate_env() {
+    assert!(parse(&["/Isolate-Env"]).unwrap().isolate_env);
+}
+
+#[test]
+fn test_parse_progress_filter() {
+    let options = parse(&["--show-tasks=\\.cpp$", "/Show-Min-Duration=1.5"]).unwrap();
+    assert!(options.progress.title.unwrap().is_match("Compile a.cpp"));
+    assert_eq!(options.progress.min_duration, Duration::from_millis(1500));
+    assert!(parse(&["--show-tasks=("]).is_err());
+    assert!(parse(&["--show-min-duration=-1"]).is_err());
+}
+
+#[test]
+fn test_parse_output_buffer() {
+    assert_eq!(parse(&[]).unwrap().output_buffer, DEFAULT_OUTPUT_BUFFER);
+    let options = parse(&["/Output-Buffer=0"]).unwrap();
+    assert_eq!(options.output_buffer, 0);
+    assert!(parse(&["--output-buffer=1M"]).is_err());
+    assert!(parse(&["--output-buffer=33554432"]).is_err());
+}
+
+#[test]
+fn test_parse_invalid() {
+    assert!(parse(&["--unknown"]).is_err());
+    assert!(parse(&["file.xml"]).is_err());
+}
+
+#[test]
+fn test_progress_filter() {
+    use octobuild::worker::BuildTask;
+    use std::sync::Arc;
+
+    let state = SharedState::new(&Config::default()).unwrap();
+    let mut graph = BuildGraph::new();
+    for title in ["Compile a.cpp", "Link a.exe", "Compile b.cpp"] {
+        graph.add_node(Arc::new(BuildTask {
+            title: title.to_string(),
+            action: BuildAction::Empty,
+        }));
     }
-    result
+    let filter = ProgressFilter {
+        title: Some(Regex::new(r"\.cpp$").unwrap()),
+        ..ProgressFilter::default()
+    };
+    let console = Mutex::new(Console::new(Vec::new(), Vec::new(), 0));
+    execute_graph(&state, graph, 1, |result| {
+        print_task_result(result, &filter, &mut console.lock().unwrap())
+    })
+    .unwrap();
+    let console = console.into_inner().unwrap();
+    let out = String::from_utf8(console.out.into_inner().unwrap()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().all(|line| line.contains(".cpp @")));
+    assert!(lines[1].starts_with("#0 3/3: "));
+    let tasks: usize = state
+        .statistic
+        .snapshot(Duration::ZERO)
+        .workers
+        .iter()
+        .map(|w| w.tasks)
+        .sum();
+    assert_eq!(tasks, 3);
 }
 
+#[cfg(unix)]
 #[test]
-fn test_parse_vars() {
+fn test_console_flushed() {
+    use octobuild::compiler::{CommandArgs, CommandInfo};
+    use octobuild::worker::BuildTask;
+    use std::sync::Arc;
+
+    let state = SharedState::new(&Config::default()).unwrap();
+    let task = |title: &str, script: &str| {
+        Arc::new(BuildTask {
+            title: title.to_string(),
+            action: BuildAction::Exec(
+                CommandInfo::simple(PathBuf::from("sh")),
+                CommandArgs::Regular(vec!["-c".to_string(), script.to_string()]),
+            ),
+        })
+    };
+    let mut graph = BuildGraph::new();
+    let first = graph.add_node(task("first", "echo out-1; echo err-1 >&2"));
+    let second = graph.add_node(task("second", "echo out-2; echo err-2 >&2; exit 1"));
+    graph.add_edge(second, first, ());
+
+    // Both streams go to the same terminal
+    #[derive(Clone, Default)]
+    struct Terminal(Arc<Mutex<Vec<u8>>>);
+    impl Write for Terminal {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let terminal = Terminal::default();
+    // Buffers are large enough to keep everything, but each task output is flushed
+    let console = Mutex::new(Console::new(terminal.clone(), terminal.clone(), 1 << 20));
+    let result = execute_graph(&state, graph, 1, |result| {
+        print_task_result(
+            result,
+            &ProgressFilter::default(),
+            &mut console.lock().unwrap(),
+        )
+    });
+    assert!(result.is_err());
+    // Every task output is flushed without waiting for buffers to fill
+    let text = String::from_utf8(terminal.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 7, "{text}");
+    assert!(lines[0].starts_with("#0 1/2: first @ "), "{text}");
+    assert_eq!(lines[1..3], ["out-1", "err-1"]);
+    assert!(lines[3].starts_with("#0 2/2: second @ "), "{text}");
     assert_eq!(
-        expand_arg(
-            "A$(test)$(inner)$(none)B",
-            &|name: &str| -> Option<String> {
-                match name {
-                    "test" => Some("foo".to_string()),
-                    "inner" => Some("$(bar)".to_string()),
-                    "none" => None,
-                    _ => {
-                        unreachable!("Unexpected value: {}", name);
-                    }
+        lines[4..],
+        ["ERROR: Task failed with exit code: 1", "out-2", "err-2"]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_custom_graph_source() {
+    use octobuild::compiler::{CommandArgs, CommandInfo};
+    use octobuild::worker::BuildTask;
+    use octobuild::xg::source::GraphSource;
+    use std::sync::Arc;
+
+    // Every line is `title: program args`, tasks run one after another.
+    struct LineSource;
+
+    impl GraphSource for LineSource {
+        fn name(&self) -> &'static str {
+            "lines"
+        }
+
+        fn extensions(&self) -> &[&'static str] {
+            &["tasks"]
+        }
+
+        fn parse(
+            &self,
+            reader: &mut dyn std::io::Read,
+            context: &GraphContext,
+        ) -> octobuild::Result<BuildGraph> {
+            let mut data = String::new();
+            reader.read_to_string(&mut data)?;
+            let mut graph = BuildGraph::new();
+            let mut previous = None;
+            for line in data.lines() {
+                let (title, command) = line.split_once(": ").unwrap();
+                let (program, args) = command.split_once(' ').unwrap();
+                let action = BuildAction::create_tasks(
+                    context.compiler,
+                    CommandInfo::simple(PathBuf::from(program)),
+                    CommandArgs::Raw(args.to_string()),
+                    title,
+                    context.options,
+                )
+                .remove(0);
+                let node = graph.add_node(Arc::new(BuildTask {
+                    title: title.to_string(),
+                    action,
+                }));
+                if let Some(previous) = previous {
+                    graph.add_edge(node, previous, ());
                 }
-            },
+                previous = Some(node);
+            }
+            Ok(graph)
+        }
+    }
+
+    let temp = tempfile::tempdir().unwrap();
+    let path = temp.path().join("build.tasks");
+    std::fs::write(
+        &path,
+        format!(
+            "first: touch {0}/a\nsecond: touch {0}/b\n",
+            temp.path().display()
         ),
-        "Afoo$(bar)$(none)B"
-    );
+    )
+    .unwrap();
+    let config = Config::default();
+    let mut state = SharedState::new(&config).unwrap();
+    state.graph_sources = GraphSources::new().register(LineSource);
+    let graph = load_graph(
+        &state.graph_sources,
+        &supported_compilers(),
+        &config,
+        &BuildOptions::default(),
+        path.to_str().unwrap(),
+    )
+    .unwrap();
+    let titles = std::sync::Mutex::new(Vec::new());
+    execute_graph(&state, graph, 2, |result| {
+        titles.lock().unwrap().push(result.task.title.clone());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(titles.into_inner().unwrap(), ["first", "second"]);
+    assert!(temp.path().join("a").exists());
+    assert!(temp.path().join("b").exists());
+}
+
+#[test]
+fn test_is_option() {
+    assert!(is_option("--explain=plan.json"));
+    assert!(is_option("/Resume=/tmp/checkpoint"));
+    assert!(is_option("--print-stats-on-signal"));
+    // Task files, even missing ones
+    assert!(!is_option("/tmp/missing/build.xml"));
+    assert!(!is_option("/build.xml"));
+    assert!(!is_option("build.xml"));
+    assert!(!is_option("C:\\build.xml"));
 }
@@ -1,5 +1,4 @@
 use std::collections::HashMap;
-use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
 use std::net::SocketAddr;
@@ -20,14 +19,14 @@ use rouille::{router, try_or_400, Request, Response, Server};
 use sha2::digest::DynDigest;
 use sha2::{Digest, Sha256};
 
-use octobuild::cluster::builder::{CompileRequest, CompileResponse};
-use octobuild::cluster::common::{
-    BuilderInfo, BuilderInfoUpdate, RPC_BUILDER_TASK, RPC_BUILDER_UPDATE, RPC_BUILDER_UPLOAD,
+use octobuild::cluster::builder::{
+    BuilderToolchains, CompileRequest, CompileResponse, RemoteInput, REPROBE_INTERVAL,
 };
-use octobuild::compiler::CompileInput::Preprocessed;
-use octobuild::compiler::{
-    CompileStep, Compiler, CompilerOutput, PCHArgs, PCHUsage, SharedState, Toolchain,
+use octobuild::cluster::common::{
+    BuilderInfo, BuilderInfoUpdate, RPC_BUILDER_HEALTH, RPC_BUILDER_TASK, RPC_BUILDER_UPDATE,
+    RPC_BUILDER_UPLOAD,
 };
+use octobuild::compiler::{Compiler, PCHArgs, PCHUsage, SharedState};
 use octobuild::config::Config;
 use octobuild::io::tempfile::TempFile;
 use octobuild::simple::supported_compilers;
@@ -41,9 +40,13 @@ struct BuilderService {
 
 struct BuilderState {
     name: String,
+    // Count of concurrently compiled tasks.
+    capacity: usize,
+    // Accept sources and preprocess them.
+    preprocess: bool,
     shared: SharedState,
     precompiled_dir: PathBuf,
-    toolchains: HashMap<String, Arc<dyn Toolchain>>,
+    toolchains: BuilderToolchains,
     precompiled: Mutex<HashMap<String, Arc<PrecompiledFile>>>,
 }
 
@@ -60,8 +63,13 @@ impl BuilderService {
 
         let state = Arc::new(BuilderState {
             name: hostname::get()?.into_string().unwrap(),
+            capacity: config.compile_limit,
+            preprocess: config.builder_preprocess,
             shared: SharedState::new(&config)?,
-            toolchains: BuilderService::discover_toolchains(),
+            toolchains: BuilderToolchains::new(
+                || supported_compilers().discover_toolchains(),
+                REPROBE_INTERVAL,
+            ),
             precompiled_dir: config.cache,
             precompiled: Mutex::new(HashMap::new()),
         });
@@ -78,6 +86,10 @@ impl BuilderService {
                 (POST) [RPC_BUILDER_TASK] => {
                     try_or_400!(handle_task(worker_state.clone(), request))
                 },
+                (GET) [RPC_BUILDER_HEALTH] => {
+                    let health = worker_state.toolchains.health();
+                    Response::json(&health).with_status_code(health.http_status())
+                },
                 _ => Response::empty_404(),
             )
         })
@@ -85,11 +97,6 @@ impl BuilderService {
 
         info!("Helper local address: {}", server.server_addr());
 
-        info!("Found toolchains:");
-        for toolchain in &state.toolchain_names() {
-            info!("- {}", toolchain);
-        }
-
         let done = Arc::new(AtomicBool::new(false));
         Ok(BuilderService {
             announcer: Some(BuilderService::thread_announcer(
@@ -110,15 +117,29 @@ impl BuilderService {
         endpoint: SocketAddr,
     ) -> JoinHandle<()> {
         thread::spawn(move || {
-            let info = BuilderInfoUpdate::new(BuilderInfo {
-                name: state.name.clone(),
-                version: version::VERSION.to_owned(),
-                endpoint: endpoint.to_string(),
-                toolchains: state.toolchain_names(),
-            });
+            // Health endpoint reports builder as initializing until toolchains are probed.
+            state.toolchains.refresh();
+            info!("Found toolchains:");
+            for toolchain in &state.toolchains.names() {
+                info!("- {}", toolchain);
+            }
+            let health = state.toolchains.health();
+            if health.failed_probes > 0 {
+                info!("Failed to probe {} toolchain(s)", health.failed_probes);
+            }
 
             let client = reqwest::blocking::Client::new();
             while !done.load(Ordering::Relaxed) {
+                // Toolchains which failed to probe are probed again periodically.
+                state.toolchains.refresh();
+                let info = BuilderInfoUpdate::new(BuilderInfo {
+                    name: state.name.clone(),
+                    version: version::VERSION.to_owned(),
+                    endpoint: endpoint.to_string(),
+                    toolchains: state.toolchains.names(),
+                    capacity: state.capacity,
+                    preprocess: state.preprocess,
+                });
                 match client
                     .post(coordinator.join(RPC_BUILDER_UPDATE).unwrap())
                     .body(bincode::serialize(&info).unwrap())
@@ -133,21 +154,14 @@ impl BuilderService {
             }
         })
     }
-
-    #[must_use]
-    fn discover_toolchains() -> HashMap<String, Arc<dyn Toolchain>> {
-        let compiler = supported_compilers();
-        compiler
-            .discover_toolchains()
-            .into_iter()
-            .filter_map(|toolchain| toolchain.identifier().map(|name| (name, toolchain)))
-            .collect()
-    }
 }
 
 fn handle_task(state: Arc<BuilderState>, request: &Request) -> octobuild::Result<Response> {
     // Receive compilation request.
     info!("Received task from: {}", &request.remote_addr());
+    if !state.toolchains.is_probed() {
+        return Ok(Response::text("Builder is initializing").with_status_code(503));
+    }
     let request: CompileRequest = bincode::deserialize_from(request.data().unwrap())?;
     let pch_usage: PCHUsage = match request.precompiled_hash {
         Some(ref hash) => {
@@ -174,16 +188,14 @@ fn handle_task(state: Arc<BuilderState>, request: &Request) -> octobuild::Result
         }
         None => PCHUsage::None,
     };
-    let compile_step = CompileStep {
-        output_object: None,
-        pch_usage,
-        args: request.args.iter().map(OsString::from).collect(),
-        input: Preprocessed(CompilerOutput::Vec(request.preprocessed_data)),
-        run_second_cpp: false,
+    let response = if matches!(request.input, RemoteInput::Source(_)) && !state.preprocess {
+        CompileResponse::Unavailable("Builder doesn't preprocess sources".to_string())
+    } else {
+        let toolchain = state.toolchains.get(&request.toolchain).ok_or_else(|| {
+            octobuild::Error::Generic(format!("Unknown toolchain: {}", request.toolchain))
+        })?;
+        request.compile(&state.shared, toolchain.as_ref(), pch_usage)
     };
-
-    let toolchain: Arc<dyn Toolchain> = state.toolchains.get(&request.toolchain).unwrap().clone();
-    let response = CompileResponse::from(toolchain.run_compile(&state.shared, compile_step));
     let payload = bincode::serialize(&response)?;
     Ok(Response::from_data("application/octet-stream", payload))
 }
@@ -267,12 +279,6 @@ fn is_valid_sha256(hash: &str) -> bool {
 }
 
 impl BuilderState {
-    fn toolchain_names(&self) -> Vec<String> {
-        let mut names: Vec<String> = self.toolchains.keys().cloned().collect();
-        names.sort();
-        names
-    }
-
     fn get_precompiled(&self, hash: &str) -> Arc<PrecompiledFile> {
         self.precompiled
             .lock()
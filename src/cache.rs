@@ -1,6 +1,6 @@
 use crate::compiler::OutputInfo;
 use crate::config::Config;
-use crate::io::filecache::FileCache;
+use crate::io::filecache::{EntryInfo, EntryMetadata, FileCache, OutputFile, VerifyStatistic};
 use crate::io::memcache::MemCache;
 use crate::io::statistic::Statistic;
 use crate::utils::hash_stream;
@@ -8,6 +8,7 @@ use std::fs;
 use std::fs::File;
 use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::time::SystemTime;
 
 #[derive(Clone)]
@@ -44,14 +45,40 @@ impl Cache {
         &self,
         statistic: &Statistic,
         hash: &str,
-        outputs: Vec<PathBuf>,
+        outputs: Vec<OutputFile>,
         worker: F,
     ) -> crate::Result<OutputInfo> {
         self.file_cache.run_cached(statistic, hash, outputs, worker)
     }
 
-    pub fn cleanup(&self) -> crate::Result<()> {
-        self.file_cache.cleanup()
+    pub fn write_metadata(&self, hash: &str, metadata: &EntryMetadata) {
+        self.file_cache.write_metadata(hash, metadata);
+    }
+
+    pub fn set_pinned(&self, hash: &str, pinned: bool) {
+        self.file_cache.set_pinned(hash, pinned);
+    }
+
+    // Whether entry of task with given source and output files should be pinned.
+    pub fn is_pinned<'a>(&self, paths: impl IntoIterator<Item = &'a Path>) -> bool {
+        self.file_cache.is_pinned(paths)
+    }
+
+    #[must_use]
+    pub fn read_metadata(&self, hash: &str) -> EntryMetadata {
+        self.file_cache.read_metadata(hash)
+    }
+
+    pub fn inspect(&self, hash: &str, dir: Option<&Path>) -> crate::Result<EntryInfo> {
+        self.file_cache.inspect(hash, dir)
+    }
+
+    pub fn cleanup(&self, threads: usize, abort: &AtomicBool) -> crate::Result<()> {
+        self.file_cache.cleanup(threads, abort)
+    }
+
+    pub fn verify(&self, threads: usize, delete: bool) -> crate::Result<VerifyStatistic> {
+        self.file_cache.verify(threads, delete)
     }
 }
 
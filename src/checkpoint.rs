@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha256};
+
+use crate::compiler::Hasher;
+use crate::worker::{BuildGraph, BuildTask};
+
+// Header of checkpoint file, followed by graph hash.
+const HEADER: &str = "octobuild-checkpoint-v2";
+
+// Persistent list of completed tasks, lets restarted build skip them.
+// Valid only for the same build graph: any change of tasks or dependencies starts from scratch.
+// Only tasks declaring outputs are tracked, other commands may have side effects which can't be
+// checked.
+pub struct Checkpoint {
+    path: PathBuf,
+    // Signatures of tasks completed by previous runs: fingerprint and input hash.
+    completed: HashSet<String>,
+    file: Mutex<File>,
+}
+
+impl Checkpoint {
+    // Load checkpoint for graph or start new one.
+    pub fn open(path: PathBuf, graph: &BuildGraph) -> crate::Result<Self> {
+        let header = format!("{HEADER} {}", graph_hash(graph));
+        let mut completed = HashSet::new();
+        if let Ok(file) = File::open(&path) {
+            let mut lines = BufReader::new(file).lines();
+            if lines.next().transpose()?.as_deref() == Some(header.as_str()) {
+                // Last line may be truncated by crash, it just won't match any task.
+                for line in lines {
+                    completed.insert(line?);
+                }
+            }
+        }
+        let file = if completed.is_empty() {
+            let mut file = File::create(&path)?;
+            writeln!(file, "{header}")?;
+            file
+        } else {
+            OpenOptions::new().append(true).open(&path)?
+        };
+        Ok(Checkpoint {
+            path,
+            completed,
+            file: Mutex::new(file),
+        })
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Number of tasks completed by previous runs.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.completed.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.completed.is_empty()
+    }
+
+    // Task was completed by previous run with the same inputs and its outputs still exist.
+    #[must_use]
+    pub fn is_completed(&self, task: &BuildTask, inputs: &str) -> bool {
+        let outputs = task.action.outputs();
+        !self.completed.is_empty()
+            && !outputs.is_empty()
+            && self.completed.contains(&signature(task, inputs))
+            && outputs.iter().all(|output| output.is_file())
+    }
+
+    // Mark task completed with given input hash.
+    pub fn add(&self, task: &BuildTask, inputs: &str) -> crate::Result<()> {
+        if task.action.outputs().is_empty() {
+            return Ok(());
+        }
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", signature(task, inputs))?;
+        file.flush()?;
+        Ok(())
+    }
+
+    // Remove checkpoint after the build is completed.
+    pub fn remove(self) -> crate::Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+fn signature(task: &BuildTask, inputs: &str) -> String {
+    format!("{} {inputs}", task.fingerprint())
+}
+
+fn graph_hash(graph: &BuildGraph) -> String {
+    let mut hasher = Sha256::new();
+    hasher.hash_u64(graph.node_count() as u64);
+    for node in graph.raw_nodes() {
+        hasher.hash_str(&node.weight.fingerprint());
+    }
+    hasher.hash_u64(graph.edge_count() as u64);
+    for edge in graph.raw_edges() {
+        hasher.hash_u64(edge.source().index() as u64);
+        hasher.hash_u64(edge.target().index() as u64);
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use crate::checkpoint::Checkpoint;
+    use crate::clang::compiler::ClangCompiler;
+    use crate::compiler::{CommandArgs, CommandInfo, Compiler, SharedState, TaskOptions};
+    use crate::config::{CacheMode, Config};
+    use crate::test_utils::fake_clang;
+    use crate::worker::{execute_graph, BuildAction, BuildGraph, BuildTask};
+
+    fn shell_task(dir: &Path, title: &str, script: &str) -> Arc<BuildTask> {
+        let mut command = CommandInfo::simple(PathBuf::from("sh"));
+        command.current_dir = Some(dir.to_path_buf());
+        Arc::new(BuildTask {
+            title: title.to_string(),
+            action: BuildAction::Exec(
+                command,
+                CommandArgs::Regular(vec!["-c".to_string(), script.to_string()]),
+            ),
+        })
+    }
+
+    fn compile_task(dir: &Path, clang: &Path, source: &str, config: &Config) -> Arc<BuildTask> {
+        let command = CommandInfo {
+            current_dir: Some(dir.to_path_buf()),
+            ..CommandInfo::simple(clang.to_path_buf())
+        };
+        let output = Path::new(source).with_extension("o");
+        let args = ["-c", source, "-o", output.to_str().unwrap()].map(String::from);
+        let task = ClangCompiler::default()
+            .create_tasks(
+                command,
+                CommandArgs::Regular(args.to_vec()),
+                &TaskOptions::new(config),
+            )
+            .unwrap()
+            .remove(0);
+        Arc::new(BuildTask {
+            title: source.to_string(),
+            action: BuildAction::Compilation(task.toolchain, task.task),
+        })
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.join("checkpoint");
+
+        // Fake compiler: preprocessing copies source, compilation logs and writes object.
+        let clang = fake_clang(
+            dir,
+            r#"if [ -n "$preprocess" ]; then cat "$in"; exit 0; fi
+basename "$out" >> log
+echo object > "$out"
+"#,
+        );
+        std::fs::write(dir.join("a.cpp"), "int a;\n").unwrap();
+        std::fs::write(dir.join("b.cpp"), "int b;\n").unwrap();
+        let config = Config {
+            cache_mode: CacheMode::None,
+            ..Config::default()
+        };
+
+        // Link fails until `ready` file appears.
+        let mut graph = BuildGraph::new();
+        let a = graph.add_node(compile_task(dir, &clang, "a.cpp", &config));
+        let b = graph.add_node(compile_task(dir, &clang, "b.cpp", &config));
+        let stamp = graph.add_node(shell_task(dir, "stamp", "echo stamp >> log"));
+        let link = graph.add_node(shell_task(dir, "link", "test -f ready && echo link >> log"));
+        for dep in [a, b, stamp] {
+            graph.add_edge(link, dep, ());
+        }
+
+        let mut state = SharedState::new(&config).unwrap();
+        state.checkpoint = Some(Checkpoint::open(path.clone(), &graph).unwrap());
+        assert!(execute_graph(&state, graph.clone(), 2, |_| Ok(())).is_err());
+
+        // Edited source is compiled again, command without outputs is always run.
+        std::fs::write(dir.join("ready"), "").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(dir.join("b.cpp"), "int b2;\n").unwrap();
+        let mut state = SharedState::new(&config).unwrap();
+        state.checkpoint = Some(Checkpoint::open(path.clone(), &graph).unwrap());
+        assert_eq!(state.checkpoint.as_ref().unwrap().len(), 2);
+        execute_graph(&state, graph.clone(), 2, |_| Ok(())).unwrap();
+        let log = std::fs::read_to_string(dir.join("log")).unwrap();
+        let mut log: Vec<&str> = log.lines().collect();
+        log.sort_unstable();
+        assert_eq!(log, ["a.o", "b.o", "b.o", "link", "stamp", "stamp"]);
+        state.checkpoint.take().unwrap().remove().unwrap();
+        assert!(!path.exists());
+
+        // Checkpoint of another graph is discarded.
+        std::fs::write(&path, "octobuild-checkpoint-v2 other\nabc\n").unwrap();
+        assert!(Checkpoint::open(path, &graph).unwrap().is_empty());
+    }
+}
@@ -1,4 +1,4 @@
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
@@ -11,9 +11,12 @@ use regex::Regex;
 use crate::compiler::CompileInput::{Preprocessed, Source};
 use crate::compiler::{
     Arg, CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OsCommandArgs,
-    OutputInfo, ParamForm, PreprocessResult, Scope, SharedState, Toolchain, ToolchainHolder,
+    OutputInfo, ParamForm, PreprocessResult, Scope, SharedState, SourceInput, TaskOptions,
+    Toolchain, ToolchainHolder,
 };
+use crate::io::tempfile::TempFile;
 use crate::lazy::Lazy;
+use crate::utils::retry_spawn;
 use os_str_bytes::OsStrBytes;
 
 fn re_clang() -> &'static regex::bytes::Regex {
@@ -29,7 +32,7 @@ pub struct ClangCompiler {
     toolchains: ToolchainHolder,
 }
 
-struct ClangToolchain {
+pub(crate) struct ClangToolchain {
     path: PathBuf,
     identifier: Lazy<Option<String>>,
 }
@@ -122,13 +125,17 @@ impl Toolchain for ClangToolchain {
         self.identifier.get(|| clang_identifier(&self.path))
     }
 
+    fn compiler(&self) -> &'static str {
+        "ClangCompiler"
+    }
+
     fn create_tasks(
         &self,
         command: CommandInfo,
         args: &[String],
-        run_second_cpp: bool,
+        options: &TaskOptions,
     ) -> crate::Result<Vec<CompilationTask>> {
-        super::prepare::create_tasks(command, args, run_second_cpp)
+        super::prepare::create_tasks(command, args, options)
     }
 
     fn run_preprocess(
@@ -145,19 +152,13 @@ impl Toolchain for ClangToolchain {
             OsString::from("-o"),
             OsString::from("-"),
         ];
-        collect_args(
-            &task.shared.args,
-            Scope::Preprocessor,
-            false,
-            false,
-            &mut args,
-        )?;
+        args.extend(self.phase_args(task, Scope::Preprocessor)?);
 
-        let output = state.wrap_slow(|| -> crate::Result<Output> {
+        let output = state.wrap_preprocess(|| -> crate::Result<Output> {
             let mut command = task.shared.command.to_command();
             let response_file =
                 state.do_response_file(OsCommandArgs::Regular(args), &mut command)?;
-            let output = command.output()?;
+            let output = state.output(&mut command)?;
             drop(response_file);
 
             if output.status.success() {
@@ -195,23 +196,71 @@ impl Toolchain for ClangToolchain {
         preprocessed: CompilerOutput,
     ) -> crate::Result<CompileStep> {
         let mut args = vec![OsString::from("-x"), OsString::from(&task.language)];
+        args.extend(self.phase_args(task, Scope::Compiler)?);
+
+        let mut step = CompileStep::new(task, preprocessed, args);
+        if task.shared.color_diagnostics {
+            step.extra_args.push(OsString::from("-fcolor-diagnostics"));
+        }
+        if let Some(module) = task.module_file()? {
+            let mut arg = OsString::from("-fmodule-output=");
+            arg.push(module);
+            step.extra_args.push(arg);
+        }
+        Ok(step)
+    }
+
+    fn phase_args(&self, task: &CompilationTask, phase: Scope) -> crate::Result<Vec<OsString>> {
+        let (run_second_cpp, output_precompiled) = match phase {
+            Scope::Preprocessor => (false, false),
+            _ => (task.shared.run_second_cpp, task.shared.pch_usage.is_some()),
+        };
+        let mut args = Vec::new();
         collect_args(
             &task.shared.args,
-            Scope::Compiler,
-            task.shared.run_second_cpp,
-            task.shared.pch_usage.is_some(),
+            phase,
+            run_second_cpp,
+            output_precompiled,
             &mut args,
         )?;
-
-        Ok(CompileStep::new(task, preprocessed, args))
+        Ok(args)
     }
 
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
+        // Write preprocessed source to temporary file if stdin feeding is disabled.
+        let temp_input = match &task.input {
+            Preprocessed(preprocessed) if !state.clang_stdin => {
+                let input_temp = TempFile::new_in(state.temp_dir.path(), ".i");
+                preprocessed.copy(&mut File::create(input_temp.path())?)?;
+                Some(input_temp)
+            }
+            _ => None,
+        };
+
         let mut args = task.args.clone();
+        if let Source(SourceInput {
+            origin: Some(origin),
+            ..
+        }) = &task.input
+        {
+            // Copy of source resolves quoted includes and names debug info like original one.
+            if let Some(dir) = origin.parent() {
+                args.insert(0, OsString::from("-iquote"));
+                args.insert(1, OsString::from(dir));
+            }
+            if let Some(name) = origin.file_name() {
+                for arg in [OsStr::new("-main-file-name"), name] {
+                    args.push(OsString::from("-Xclang"));
+                    args.push(OsString::from(arg));
+                }
+            }
+        }
+        args.extend(task.extra_args.iter().cloned());
         args.push(OsString::from("-c"));
-        match &task.input {
-            Preprocessed(_) => args.push(OsString::from("-")),
-            Source(source) => args.push(OsString::from(&source.path)),
+        match (&task.input, &temp_input) {
+            (Preprocessed(_), Some(temp)) => args.push(OsString::from(temp.path())),
+            (Preprocessed(_), None) => args.push(OsString::from("-")),
+            (Source(source), _) => args.push(OsString::from(&source.path)),
         };
 
         args.push(OsString::from("-o"));
@@ -236,23 +285,26 @@ impl Toolchain for ClangToolchain {
                 }
             }
 
+            let use_stdin = matches!(task.input, Preprocessed(_)) && temp_input.is_none();
             command
-                .stdin(match &task.input {
-                    Preprocessed(_) => Stdio::piped(),
-                    Source(_) => Stdio::null(),
+                .stdin(if use_stdin {
+                    Stdio::piped()
+                } else {
+                    Stdio::null()
                 })
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped());
 
             let response_file =
                 state.do_response_file(OsCommandArgs::Regular(args), &mut command)?;
-            let mut child = command.spawn()?;
+            let mut child = retry_spawn(|| command.spawn())?;
 
-            if let Preprocessed(preprocessed) = task.input {
+            if let (Preprocessed(preprocessed), true) = (task.input, use_stdin) {
                 preprocessed.copy(child.stdin.as_mut().unwrap())?;
             }
 
-            let output = child.wait_with_output()?;
+            let output = state.wait_with_output(child)?;
+            drop(temp_input);
             drop(response_file);
             Ok(OutputInfo::new(output))
         })
@@ -290,6 +342,312 @@ fn clang_identifier(clang: &Path) -> Option<String> {
 
 #[cfg(test)]
 mod test {
+    #[cfg(unix)]
+    #[test]
+    fn test_compile_stdin_same_as_file() {
+        use crate::compiler::CompileInput::Preprocessed;
+        use crate::compiler::{CompileStep, CompilerOutput, PCHUsage, Toolchain};
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: copies input source (file or stdin) to output object.
+        let fixture = Fixture::clang(
+            r#"if [ "$in" = "-" ]; then cat > "$out"; else echo "$in" >&2; cat "$in" > "$out"; fi
+"#,
+        );
+        let toolchain = super::ClangToolchain::new(fixture.compiler.clone());
+
+        let compile = |clang_stdin: bool| -> (Vec<u8>, Vec<u8>) {
+            let mut state = fixture.state();
+            state.clang_stdin = clang_stdin;
+            let output = fixture.path().join(format!("sample-{clang_stdin}.o"));
+            let step = CompileStep {
+                args: vec!["-x".into(), "c++".into()],
+                output_object: Some(output.clone()),
+                pch_usage: PCHUsage::None,
+                input: Preprocessed(CompilerOutput::Vec(b"int main() {}\n".to_vec())),
+                language: "c++".to_string(),
+                run_second_cpp: false,
+                extra_args: Vec::new(),
+            };
+            let result = toolchain.run_compile(&state, step).unwrap();
+            assert!(result.success());
+            (std::fs::read(output).unwrap(), result.stderr)
+        };
+
+        let (stdin_object, stdin_stderr) = compile(true);
+        let (file_object, file_stderr) = compile(false);
+        assert_eq!(stdin_object, b"int main() {}\n");
+        assert_eq!(stdin_object, file_object);
+        assert!(stdin_stderr.is_empty());
+        assert!(file_stderr.ends_with(b".i\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compile_preprocessed() {
+        use crate::compiler::CompileInput::Preprocessed;
+        use crate::compiler::{CompileStep, CompilerOutput, PCHUsage, Toolchain};
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: "compiles" input to upper case.
+        let fixture = Fixture::clang(
+            r#"echo "compiling" >&2
+tr a-z A-Z < "$in" > "$out"
+"#,
+        );
+        let toolchain = super::ClangToolchain::new(fixture.compiler.clone());
+
+        let mut state = fixture.state();
+        state.clang_stdin = false;
+        let step = CompileStep {
+            args: vec!["-x".into(), "c++".into()],
+            output_object: None,
+            pch_usage: PCHUsage::None,
+            input: Preprocessed(CompilerOutput::Vec(b"int main() {}\n".to_vec())),
+            language: "c++".to_string(),
+            run_second_cpp: false,
+            extra_args: Vec::new(),
+        };
+        let (output, object) = toolchain.compile_preprocessed(&state, step).unwrap();
+        assert!(output.success());
+        assert_eq!(object, b"INT MAIN() {}\n");
+        assert!(output.stdout.is_empty());
+        assert_eq!(output.stderr, b"compiling\n");
+        // Temporary object file is removed
+        assert_eq!(std::fs::read_dir(state.temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_color_diagnostics_not_cached() {
+        use crate::compiler::{CompilerOutput, TaskOptions, Toolchain};
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: emits colored warning only when asked to.
+        let fixture = Fixture::new(
+            "clang",
+            r#"color=
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -o) out="$2"; shift ;;
+        -fcolor-diagnostics) color=1 ;;
+    esac
+    shift
+done
+if [ -n "$color" ]; then printf '\033[35mwarning:\033[0m unused\n' >&2; else echo "warning: unused" >&2; fi
+cat > "$out"
+"#,
+        );
+        let toolchain = super::ClangToolchain::new(fixture.compiler.clone());
+
+        let state = fixture.state();
+        let compile = |source: &str, color_output: bool| -> Vec<u8> {
+            let options = TaskOptions {
+                color_output,
+                ..TaskOptions::new(&fixture.config)
+            };
+            let args = [
+                "-c",
+                "-fdiagnostics-color=auto",
+                source,
+                "-o",
+                &format!("{source}.o"),
+            ]
+            .map(String::from);
+            let tasks = toolchain
+                .create_tasks(fixture.command(), &args, &options)
+                .unwrap();
+            let preprocessed = CompilerOutput::Vec(source.as_bytes().to_vec());
+            let output = toolchain
+                .run_compile_cached(&state, &tasks[0], preprocessed)
+                .unwrap();
+            assert!(output.success());
+            output.stderr
+        };
+
+        // Terminal output keeps color, but cached output doesn't
+        assert_eq!(compile("a.cpp", true), b"\x1B[35mwarning:\x1B[0m unused\n");
+        assert_eq!(compile("a.cpp", true), b"warning: unused\n");
+        assert!(state.statistic.to_string().contains("hit 1 of 2"));
+        // Redirected output is not colored
+        assert_eq!(compile("b.cpp", false), b"warning: unused\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_verify_outputs() {
+        use crate::compiler::{CompilerOutput, TaskOptions, Toolchain};
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: reports success without writing object file.
+        let fixture = Fixture::new("clang", "cat > /dev/null\n");
+        let toolchain = super::ClangToolchain::new(fixture.compiler.clone());
+
+        let mut state = fixture.state();
+        state.verify_outputs = true;
+        let args = ["-c", "a.cpp", "-o", "a.o"].map(String::from);
+        let tasks = toolchain
+            .create_tasks(fixture.command(), &args, &TaskOptions::new(&fixture.config))
+            .unwrap();
+        for _ in 0..2 {
+            let preprocessed = CompilerOutput::Vec(b"int a;\n".to_vec());
+            match toolchain.run_compile_cached(&state, &tasks[0], preprocessed) {
+                Err(crate::Error::MissingOutput { input, output, .. }) => {
+                    assert_eq!(input, std::path::PathBuf::from("a.cpp"));
+                    assert_eq!(output, fixture.path().join("a.o"));
+                }
+                _ => panic!("missing output is not reported"),
+            }
+        }
+        // Nothing is cached
+        assert_eq!(
+            state
+                .statistic
+                .hit_count
+                .load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_verify_objects() {
+        use crate::compiler::{CompilerOutput, TaskOptions, Toolchain};
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: copies prepared object file content to output.
+        let fixture = Fixture::new(
+            "clang",
+            r#"cat > /dev/null
+cat object > a.o
+"#,
+        );
+        let toolchain = super::ClangToolchain::new(fixture.compiler.clone());
+
+        let mut state = fixture.state();
+        state.verify_objects = true;
+        let args = ["-c", "a.cpp", "-o", "a.o"].map(String::from);
+        let tasks = toolchain
+            .create_tasks(fixture.command(), &args, &TaskOptions::new(&fixture.config))
+            .unwrap();
+        let compile = |object: &[u8]| {
+            std::fs::write(fixture.path().join("object"), object).unwrap();
+            let preprocessed = CompilerOutput::Vec(object.to_vec());
+            toolchain.run_compile_cached(&state, &tasks[0], preprocessed)
+        };
+        for (object, reason) in [
+            (&b""[..], "file is empty"),
+            (b"garbage", "unknown object file signature"),
+        ] {
+            match compile(object) {
+                Err(crate::Error::MalformedObject { path, reason: r }) => {
+                    assert_eq!(path, fixture.path().join("a.o"));
+                    assert_eq!(r, reason);
+                }
+                _ => panic!("malformed object is not reported"),
+            }
+        }
+        assert!(compile(b"\x7fELF\x02\x01").unwrap().success());
+        assert!(compile(b"\x64\x86\x03\x00").unwrap().success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cached_warnings_as_errors() {
+        use crate::compiler::{CompilerOutput, TaskOptions, Toolchain};
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: warns, and fails on the warning under `-Werror` unless it is excluded.
+        let fixture = Fixture::clang(
+            r#"echo "a.cpp:1:5: warning: unused variable 'a'" >&2
+case " $* " in
+    *" -Wno-error=unused-variable "*) ;;
+    *" -Werror "*) exit 1 ;;
+esac
+cat > "$out"
+"#,
+        );
+        let toolchain = super::ClangToolchain::new(fixture.compiler.clone());
+
+        let state = fixture.state();
+        let compile = |flags: &[&str]| -> (Option<i32>, Vec<u8>) {
+            let mut args: Vec<String> = ["-c", "a.cpp", "-o", "a.o"].map(String::from).to_vec();
+            args.extend(flags.iter().map(|x| x.to_string()));
+            let tasks = toolchain
+                .create_tasks(fixture.command(), &args, &TaskOptions::default())
+                .unwrap();
+            let preprocessed = CompilerOutput::Vec(b"int a;\n".to_vec());
+            let output = toolchain
+                .run_compile_cached(&state, &tasks[0], preprocessed)
+                .unwrap();
+            (output.status, output.stderr)
+        };
+
+        // Cache miss and following cache hit must end with the same status and diagnostics
+        for (flags, status) in [
+            (&["-Werror"][..], Some(1)),
+            (&["-Werror", "-Wno-error=unused-variable"][..], Some(0)),
+            (&[][..], Some(0)),
+        ] {
+            let miss = compile(flags);
+            assert_eq!(miss.0, status);
+            assert_eq!(compile(flags), miss);
+        }
+        let stat = state.statistic.snapshot(std::time::Duration::ZERO);
+        assert_eq!(stat.hit_count, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_args_file() {
+        use crate::compiler::{CompilerOutput, TaskOptions, Toolchain};
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: writes its arguments to object file.
+        let mut fixture = Fixture::new(
+            "clang",
+            r#"echo "$@" > a.o
+cat > /dev/null
+"#,
+        );
+        let toolchain = super::ClangToolchain::new(fixture.compiler.clone());
+
+        let args_file = fixture.path().join("clang.args");
+        fixture.config.clang_args_file = Some(args_file.clone());
+        let state = fixture.state();
+        let compile = || {
+            let args = ["-c", "a.cpp", "-o", "a.o", "-O2"].map(String::from);
+            let tasks = toolchain
+                .create_tasks(fixture.command(), &args, &TaskOptions::new(&fixture.config))
+                .unwrap();
+            let preprocessed = CompilerOutput::Vec(b"int a;\n".to_vec());
+            toolchain
+                .run_compile_cached(&state, &tasks[0], preprocessed)
+                .unwrap();
+            let hits = state
+                .statistic
+                .hit_count
+                .load(std::sync::atomic::Ordering::Relaxed);
+            (
+                std::fs::read_to_string(fixture.path().join("a.o")).unwrap(),
+                hits,
+            )
+        };
+
+        // Injected arguments go before command line ones
+        std::fs::write(&args_file, "-O0\n-DFROM_FILE\n").unwrap();
+        let (object, hits) = compile();
+        assert!(object.contains("-O0 -D FROM_FILE -O2 "), "{object}");
+        assert_eq!(hits, 0);
+        assert_eq!(compile().1, 1);
+
+        // Edited file changes cache key
+        std::fs::write(&args_file, "-O0 -DFROM_EDITED_FILE").unwrap();
+        let (object, hits) = compile();
+        assert!(object.contains("-D FROM_EDITED_FILE"), "{object}");
+        assert_eq!(hits, 1);
+    }
+
     #[test]
     fn test_ubuntu_14_04_clang_3_5() {
         assert_eq!(
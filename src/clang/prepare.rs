@@ -4,16 +4,17 @@ use std::sync::Arc;
 
 use crate::compiler::{
     Arg, CommandInfo, CompilationArgs, CompilationTask, InputKind, OutputKind, PCHUsage, ParamForm,
-    Scope,
+    Scope, TaskOptions,
 };
 use crate::utils::{expand_response_files, find_param, ParamValue};
 
 pub fn create_tasks(
     command: CommandInfo,
     args: &[String],
-    run_second_cpp: bool,
+    options: &TaskOptions,
 ) -> crate::Result<Vec<CompilationTask>> {
-    let expanded_args = expand_response_files(&command.current_dir, args)?;
+    let args = TaskOptions::with_args_file(options.clang_args_file.as_deref(), args);
+    let expanded_args = expand_response_files(&command.current_dir, &args)?;
 
     if expanded_args.iter().any(|v| v == "--analyze") {
         // Support only compilation steps
@@ -25,7 +26,18 @@ pub fn create_tasks(
         return Ok(Vec::new());
     }
 
-    let parsed_args = parse_arguments(&expanded_args)?;
+    let mut parsed_args = parse_arguments(&expanded_args)?;
+    if parsed_args.iter().any(implicit_modules) {
+        // Implicitly built modules are not tracked
+        return Ok(Vec::new());
+    }
+    // Explicit language makes every input a source file.
+    if !parsed_args
+        .iter()
+        .any(|arg| matches!(arg, Arg::Param { name, .. } if name == "x"))
+    {
+        options.classify_inputs(&mut parsed_args);
+    }
     // Source file name.
     let input_sources: Vec<PathBuf> = parsed_args
         .iter()
@@ -39,6 +51,41 @@ pub fn create_tasks(
     if input_sources.is_empty() {
         return Err(crate::Error::from("Can't find source file path."));
     }
+    if input_sources.len() > 1
+        && parsed_args.iter().any(|arg| {
+            matches!(
+                arg,
+                Arg::Output {
+                    kind: OutputKind::Module,
+                    ..
+                }
+            )
+        })
+    {
+        // Module interfaces of several sources can't share output path
+        return Ok(Vec::new());
+    }
+    // Consumed module interfaces change compilation result like headers do.
+    let mut referenced_files = Vec::new();
+    for arg in &mut parsed_args {
+        if let Arg::Param { name, value, .. } = arg {
+            if let Some(module) = value.strip_prefix("module-file=").filter(|_| name == "f") {
+                let (module_name, path) = match module.split_once('=') {
+                    Some((module_name, path)) => (Some(module_name), path),
+                    None => (None, module),
+                };
+                // Compiler runs outside of working directory.
+                let path = command.absolutize(Path::new(path))?;
+                *value = match module_name {
+                    Some(module_name) => {
+                        format!("module-file={module_name}={}", path.display())
+                    }
+                    None => format!("module-file={}", path.display()),
+                };
+                referenced_files.push(path);
+            }
+        }
+    }
     /*
     // Precompiled header file name.
     let pch_in = match find_param(&parsed_args, |arg: &Arg| -> Option<PathBuf> {
@@ -115,7 +162,7 @@ pub fn create_tasks(
         ParamValue::None => None,
         ParamValue::Single(v) => {
             match &v[..] {
-                "c" | "c++" | "objective-c++" => Some(v.to_string()),
+                "c" | "c++" | "c++-module" | "objective-c++" => Some(v.to_string()),
                 "c-header" | "c++-header" | "objective-c++-header" => {
                     // Precompiled headers must build locally
                     return Ok(Vec::new());
@@ -133,13 +180,16 @@ pub fn create_tasks(
             )));
         }
     };
+    let color_diagnostics = color_diagnostics(&parsed_args, options.color_output);
     let shared = Arc::new(CompilationArgs {
         command,
         args: parsed_args,
         // No PCH support for clang for now
         pch_usage: PCHUsage::None,
         deps_file,
-        run_second_cpp,
+        referenced_files,
+        run_second_cpp: options.run_second_cpp,
+        color_diagnostics,
     });
     input_sources
         .into_iter()
@@ -153,6 +203,7 @@ pub fn create_tasks(
                             let lang = match source.extension()?.to_str() {
                                 Some(e) if e.eq_ignore_ascii_case("cpp") => Some("c++"),
                                 Some(e) if e.eq_ignore_ascii_case("c") => Some("c"),
+                                Some(e) if e.eq_ignore_ascii_case("cppm") => Some("c++-module"),
                                 Some(e) if e.eq_ignore_ascii_case("hpp") => Some("c++-header"),
                                 Some(e) if e.eq_ignore_ascii_case("h") => Some("c-header"),
                                 _ => None,
@@ -167,15 +218,52 @@ pub fn create_tasks(
                             source.as_os_str().to_string_lossy()
                         )
                     })?,
-                output_object: output_object
-                    .as_ref()
-                    .map_or_else(|| source.with_extension("o"), |path| path.clone()),
+                // Without `-o` object is written to working directory.
+                output_object: output_object.as_ref().map_or_else(
+                    || shared.command.default_output(&source, "o"),
+                    |path| Ok(path.clone()),
+                )?,
                 input_source: source,
             })
         })
         .collect()
 }
 
+// Modules built and looked up by compiler itself, their files are unknown before compilation.
+fn implicit_modules(arg: &Arg) -> bool {
+    match arg {
+        Arg::Param { name, value, .. } if name == "f" => {
+            let option = value.split_once('=').map_or(value.as_str(), |(v, _)| v);
+            matches!(
+                option,
+                "modules"
+                    | "implicit-module-maps"
+                    | "modules-cache-path"
+                    | "prebuilt-module-path"
+                    | "module-map-file"
+            )
+        }
+        _ => false,
+    }
+}
+
+// Colored diagnostics are requested explicitly or on auto mode with terminal output. Last flag wins.
+fn color_diagnostics(args: &[Arg], color_output: bool) -> bool {
+    args.iter().fold(false, |color, arg| match arg {
+        Arg::Flag { name, .. } => match name.as_str() {
+            "fcolor-diagnostics" | "fdiagnostics-color" => true,
+            "fno-color-diagnostics" | "fno-diagnostics-color" => false,
+            _ => color,
+        },
+        Arg::Param { name, value, .. } if name == "fdiagnostics-color" => match value.as_str() {
+            "always" => true,
+            "auto" => color_output,
+            _ => false,
+        },
+        _ => color,
+    })
+}
+
 fn parse_arguments(args: &[String]) -> Result<Vec<Arg>, String> {
     let mut result: Vec<Arg> = Vec::new();
     let mut errors: Vec<String> = Vec::new();
@@ -193,9 +281,50 @@ fn parse_arguments(args: &[String]) -> Result<Vec<Arg>, String> {
     if !errors.is_empty() {
         return Err(format!("Found unknown command line arguments: {errors:?}"));
     }
+    // Value of `-Xclang -include -Xclang <file>` goes to the same phase as its option.
+    for i in 1..result.len() {
+        if let [Arg::Param {
+            name: option,
+            value: payload,
+            scope: option_scope,
+            ..
+        }, Arg::Param {
+            name: value, scope, ..
+        }] = &mut result[i - 1..=i]
+        {
+            if option == "Xclang" && value == "Xclang" && frontend_option(payload).1 {
+                *scope = *option_scope;
+            }
+        }
+    }
     Ok(result)
 }
 
+// Scope of `-Xclang` payload is scope of the same option given to driver.
+// Also returns whether payload is option which value is passed by next `-Xclang`.
+fn frontend_option(payload: &str) -> (Scope, bool) {
+    let Some(key) = payload.strip_prefix('-') else {
+        return (Scope::Shared, false);
+    };
+    for param in DASH_PARAMS {
+        for value_type in param.value_type {
+            let matched = match value_type {
+                ArgValueType::None | ArgValueType::Separate => key == param.name,
+                ArgValueType::Combined => key
+                    .strip_prefix(param.name)
+                    .is_some_and(|v| v.starts_with('=')),
+                ArgValueType::StartsWith => {
+                    key.len() > param.name.len() && key.starts_with(param.name)
+                }
+            };
+            if matched {
+                return (param.scope, *value_type == ArgValueType::Separate);
+            }
+        }
+    }
+    (Scope::Shared, false)
+}
+
 struct CompilerArgument {
     scope: Scope,
     name: &'static str,
@@ -215,6 +344,7 @@ const NONE: &[ArgValueType] = &[ArgValueType::None];
 const COMBINED: &[ArgValueType] = &[ArgValueType::Combined];
 const PSYCHEDELIC: &[ArgValueType] = &[ArgValueType::Separate, ArgValueType::StartsWith];
 const STARTS_WITH: &[ArgValueType] = &[ArgValueType::StartsWith];
+const OPTIONAL_COMBINED: &[ArgValueType] = &[ArgValueType::None, ArgValueType::Combined];
 const OPTIONAL_STARTS_WITH: &[ArgValueType] = &[ArgValueType::None, ArgValueType::StartsWith];
 const SEPARATE: &[ArgValueType] = &[ArgValueType::Separate];
 
@@ -242,6 +372,27 @@ static DASH_DASH_PARAMS: &[CompilerArgument] = &[
 ];
 
 static DASH_PARAMS: &[CompilerArgument] = &[
+    // Diagnostics color: passed to compiler only when output goes to terminal (must precede "f")
+    CompilerArgument {
+        scope: Scope::Ignore,
+        name: "fcolor-diagnostics",
+        value_type: NONE,
+    },
+    CompilerArgument {
+        scope: Scope::Ignore,
+        name: "fno-color-diagnostics",
+        value_type: NONE,
+    },
+    CompilerArgument {
+        scope: Scope::Ignore,
+        name: "fdiagnostics-color",
+        value_type: OPTIONAL_COMBINED,
+    },
+    CompilerArgument {
+        scope: Scope::Ignore,
+        name: "fno-diagnostics-color",
+        value_type: NONE,
+    },
     // Shared
     CompilerArgument {
         scope: Scope::Shared,
@@ -263,6 +414,11 @@ static DASH_PARAMS: &[CompilerArgument] = &[
         name: "g",
         value_type: OPTIONAL_STARTS_WITH,
     },
+    CompilerArgument {
+        scope: Scope::Compiler,
+        name: "mllvm",
+        value_type: SEPARATE,
+    },
     CompilerArgument {
         scope: Scope::Shared,
         name: "m",
@@ -313,6 +469,27 @@ static DASH_PARAMS: &[CompilerArgument] = &[
         name: "target",
         value_type: SEPARATE,
     },
+    // Two-token passthroughs, value is never an input file
+    CompilerArgument {
+        scope: Scope::Shared,
+        name: "Xclang",
+        value_type: SEPARATE,
+    },
+    CompilerArgument {
+        scope: Scope::Compiler,
+        name: "Xassembler",
+        value_type: SEPARATE,
+    },
+    CompilerArgument {
+        scope: Scope::Preprocessor,
+        name: "Xpreprocessor",
+        value_type: SEPARATE,
+    },
+    CompilerArgument {
+        scope: Scope::Ignore,
+        name: "Xlinker",
+        value_type: SEPARATE,
+    },
     // Preprocessor
     CompilerArgument {
         scope: Scope::Preprocessor,
@@ -453,6 +630,14 @@ fn parse_argument(iter: &mut Iter<String>) -> Option<Result<Arg, String>> {
                         if flag == "o" {
                             // Minor hack
                             Ok(Arg::output(OutputKind::Object, flag, value))
+                        } else if flag == "f" && value == "module-output" {
+                            Ok(Arg::output(OutputKind::Module, "-fmodule-output", ""))
+                        } else if let Some(path) =
+                            value.strip_prefix("module-output=").filter(|_| flag == "f")
+                        {
+                            Ok(Arg::output(OutputKind::Module, "-fmodule-output=", path))
+                        } else if flag == "Xclang" {
+                            Ok(Arg::param(frontend_option(value).0, "-", flag, value))
                         } else {
                             Ok(v)
                         }
@@ -601,3 +786,102 @@ fn test_parse_argument_compile() {
         ]
     )
 }
+
+#[test]
+fn test_parse_argument_passthrough() {
+    let args: Vec<String> = "-c -Xclang -ffoo -Xclang -include -Xclang pch.h -Xclang -Wno-bar \
+         -mllvm -inline-threshold=100 -Xpreprocessor -DX -Xlinker -lfoo -Wl,--as-needed \
+         -o a.o a.cpp"
+        .split_whitespace()
+        .map(|x| x.to_string())
+        .collect();
+    assert_eq!(
+        parse_arguments(&args).unwrap(),
+        [
+            Arg::flag(Scope::Ignore, "-", "c"),
+            Arg::param(Scope::Shared, "-", "Xclang", "-ffoo"),
+            Arg::param(Scope::Preprocessor, "-", "Xclang", "-include"),
+            Arg::param(Scope::Preprocessor, "-", "Xclang", "pch.h"),
+            Arg::param(Scope::Compiler, "-", "Xclang", "-Wno-bar"),
+            Arg::param(Scope::Compiler, "-", "mllvm", "-inline-threshold=100"),
+            Arg::param(Scope::Preprocessor, "-", "Xpreprocessor", "-DX"),
+            Arg::param(Scope::Ignore, "-", "Xlinker", "-lfoo"),
+            Arg::param_ext(
+                Scope::Compiler,
+                "-",
+                "W",
+                "l,--as-needed",
+                ParamForm::Smushed
+            ),
+            Arg::output(OutputKind::Object, "o", "a.o"),
+            Arg::input(InputKind::Source, "a.cpp")
+        ]
+    );
+
+    let args = ["-c", "-Xclang", "-ffoo", "a.cpp", "-o", "a.o"].map(String::from);
+    let tasks = create_tasks(
+        CommandInfo::simple(PathBuf::from("clang")),
+        &args,
+        &TaskOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].input_source, PathBuf::from("a.cpp"));
+    let toolchain = super::compiler::ClangToolchain::new(PathBuf::from("clang"));
+    let report = tasks[0].args_report(&toolchain).unwrap();
+    assert!(report.compile.join(" ").contains("-Xclang -ffoo"));
+}
+
+#[test]
+fn test_module_output() {
+    let dir = std::env::temp_dir();
+    let command = || CommandInfo {
+        current_dir: Some(dir.clone()),
+        ..CommandInfo::simple(PathBuf::from("clang"))
+    };
+    let args = |line: &str| -> Vec<String> { line.split(' ').map(String::from).collect() };
+
+    let tasks = create_tasks(
+        command(),
+        &args("-c -std=c++20 -fmodule-output=a.pcm -fmodule-file=b=b.pcm a.cppm -o a.o"),
+        &TaskOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(tasks.len(), 1);
+    let task = &tasks[0];
+    assert_eq!(task.language, "c++-module");
+    assert!(task.shared.args.contains(&Arg::output(
+        OutputKind::Module,
+        "-fmodule-output=",
+        "a.pcm"
+    )));
+    assert_eq!(task.module_file().unwrap(), Some(dir.join("a.pcm")));
+    assert_eq!(task.shared.referenced_files, [dir.join("b.pcm")]);
+    let toolchain = super::compiler::ClangToolchain::new(PathBuf::from("clang"));
+    let report = task.args_report(&toolchain).unwrap();
+    assert!(report
+        .compile
+        .contains(&format!("-fmodule-file=b={}", dir.join("b.pcm").display())));
+
+    // Without path interface is written next to object
+    let tasks = create_tasks(
+        command(),
+        &args("-c -fmodule-output -x c++-module a.ixx -o out/a.o"),
+        &TaskOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(tasks[0].module_file().unwrap(), Some(dir.join("out/a.pcm")));
+
+    // Implicit modules and several interfaces run directly
+    for line in [
+        "-c -fmodules a.cpp -o a.o",
+        "-c -fprebuilt-module-path=mods a.cpp -o a.o",
+        "-c -fmodule-output a.cppm b.cppm",
+    ] {
+        assert!(
+            create_tasks(command(), &args(line), &TaskOptions::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -1,26 +1,392 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
 use serde::{Deserialize, Serialize};
 
-use crate::compiler::OutputInfo;
+use crate::compiler::CompileInput::{Preprocessed, Source};
+use crate::compiler::{
+    CompileStep, CompilerOutput, OutputInfo, PCHUsage, SharedState, SourceInput, Toolchain,
+};
+use crate::io::tempfile::TempFile;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CompileRequest {
     pub toolchain: String,
     pub args: Vec<String>,
-    pub preprocessed_data: Vec<u8>,
+    pub language: String,
+    pub input: RemoteInput,
     pub precompiled_hash: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RemoteInput {
+    Preprocessed(Vec<u8>),
+    // Source preprocessed by builder, requires builder preprocessing capability
+    Source(RemoteSource),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteSource {
+    // Original source path
+    pub path: PathBuf,
+    pub content: Vec<u8>,
+    pub current_dir: Option<PathBuf>,
+    // Include directories (source directory first), which builder must have at the same paths
+    pub include_dirs: Vec<PathBuf>,
+}
+
+impl RemoteSource {
+    // Write source copy to `scratch` directory, file is removed with returned guard. Copy starts
+    // with `#line` naming original source, so `__FILE__`, diagnostics and debug info are the same
+    // as on client. Builder must have every directory source needs.
+    pub fn materialize(&self, scratch: &Path) -> crate::Result<(SourceInput, TempFile)> {
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        for required in [dir]
+            .into_iter()
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .chain(self.current_dir.as_deref())
+        {
+            if !required.is_dir() {
+                return Err(crate::Error::Generic(format!(
+                    "Directory is not available on builder: {}",
+                    required.display()
+                )));
+            }
+        }
+        let name = self.path.file_name().ok_or_else(|| {
+            crate::Error::Generic(format!(
+                "Source path does not contain file name: {}",
+                self.path.display()
+            ))
+        })?;
+        let file = TempFile::new_in(scratch, &format!("-{}", name.to_string_lossy()));
+        let origin = self
+            .path
+            .to_string_lossy()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        let mut content = format!("#line 1 \"{origin}\"\n").into_bytes();
+        content.extend_from_slice(&self.content);
+        fs::write(file.path(), content)?;
+        let input = SourceInput {
+            path: file.path().to_path_buf(),
+            origin: Some(self.path.clone()),
+            current_dir: self.current_dir.clone(),
+            include_dirs: self.include_dirs.clone(),
+        };
+        Ok((input, file))
+    }
+}
+
+impl CompileRequest {
+    // Compile request on builder. Source which can't be materialized is reported as unavailable.
+    pub fn compile(
+        self,
+        state: &SharedState,
+        toolchain: &dyn Toolchain,
+        pch_usage: PCHUsage,
+    ) -> CompileResponse {
+        let mut _source_file = None;
+        let (input, run_second_cpp) = match self.input {
+            RemoteInput::Preprocessed(data) => (Preprocessed(CompilerOutput::Vec(data)), false),
+            RemoteInput::Source(source) => match source.materialize(state.temp_dir.path()) {
+                Ok((input, file)) => {
+                    _source_file = Some(file);
+                    (Source(input), true)
+                }
+                Err(e) => return CompileResponse::Unavailable(e.to_string()),
+            },
+        };
+        let step = CompileStep {
+            output_object: None,
+            pch_usage,
+            args: self.args.iter().map(OsString::from).collect(),
+            input,
+            language: self.language,
+            run_second_cpp,
+            extra_args: Vec::new(),
+        };
+        toolchain.compile_preprocessed(state, step).into()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CompileResponse {
-    Success(OutputInfo),
+    // Compiler output and object file content
+    Success(OutputInfo, Vec<u8>),
     Err(String),
+    // Builder can't compile request (missing capability or directory), client compiles locally
+    Unavailable(String),
 }
 
-impl From<crate::Result<OutputInfo>> for CompileResponse {
-    fn from(result: crate::Result<OutputInfo>) -> Self {
+impl From<crate::Result<(OutputInfo, Vec<u8>)>> for CompileResponse {
+    fn from(result: crate::Result<(OutputInfo, Vec<u8>)>) -> Self {
         match result {
-            Ok(output) => CompileResponse::Success(output),
+            Ok((output, object)) => CompileResponse::Success(output, object),
             Err(v) => CompileResponse::Err(v.to_string()),
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuilderStatus {
+    // Toolchains are not probed yet
+    Initializing,
+    Ready,
+    // Some toolchains failed to report their identity
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BuilderHealth {
+    pub status: BuilderStatus,
+    // Identities of ready toolchains
+    pub toolchains: Vec<String>,
+    // Number of toolchains that failed to probe
+    pub failed_probes: usize,
+}
+
+impl BuilderHealth {
+    #[must_use]
+    pub fn initializing() -> Self {
+        BuilderHealth {
+            status: BuilderStatus::Initializing,
+            toolchains: Vec::new(),
+            failed_probes: 0,
+        }
+    }
+
+    #[must_use]
+    pub fn probed(toolchains: Vec<String>, failed_probes: usize) -> Self {
+        BuilderHealth {
+            status: if failed_probes == 0 {
+                BuilderStatus::Ready
+            } else {
+                BuilderStatus::Failed
+            },
+            toolchains,
+            failed_probes,
+        }
+    }
+
+    // HTTP status for health probe: only ready builder should receive tasks.
+    #[must_use]
+    pub fn http_status(&self) -> u16 {
+        match self.status {
+            BuilderStatus::Ready => 200,
+            BuilderStatus::Initializing | BuilderStatus::Failed => 503,
+        }
+    }
+}
+
+// Delay before toolchains are probed again after probe with failures.
+pub const REPROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Toolchains available on builder. Probe with failed toolchains is repeated once
+// `reprobe_interval` passes, so transient failure doesn't make builder unhealthy until restart.
+pub struct BuilderToolchains {
+    discover: Box<dyn Fn() -> Vec<Arc<dyn Toolchain>> + Send + Sync>,
+    reprobe_interval: Duration,
+    // Not set until toolchains are probed.
+    probed: RwLock<Option<ProbedToolchains>>,
+    // Held by running probe.
+    probing: Mutex<()>,
+}
+
+struct ProbedToolchains {
+    ready: HashMap<String, Arc<dyn Toolchain>>,
+    // Toolchains that failed to report their identity.
+    failed: usize,
+    time: Instant,
+}
+
+impl BuilderToolchains {
+    pub fn new<F>(discover: F, reprobe_interval: Duration) -> Self
+    where
+        F: Fn() -> Vec<Arc<dyn Toolchain>> + Send + Sync + 'static,
+    {
+        BuilderToolchains {
+            discover: Box::new(discover),
+            reprobe_interval,
+            probed: RwLock::new(None),
+            probing: Mutex::new(()),
+        }
+    }
+
+    // Probe toolchains unless they are probed without failures or recently, waiting for
+    // running probe.
+    pub fn refresh(&self) {
+        let _probing = self.probing.lock().unwrap();
+        if self.is_stale() {
+            self.probe();
+        }
+    }
+
+    fn probe(&self) {
+        let mut ready = HashMap::new();
+        let mut failed = 0;
+        for toolchain in (self.discover)() {
+            match toolchain.identifier() {
+                Some(name) => {
+                    ready.insert(name, toolchain);
+                }
+                None => failed += 1,
+            }
+        }
+        *self.probed.write().unwrap() = Some(ProbedToolchains {
+            ready,
+            failed,
+            time: Instant::now(),
+        });
+    }
+
+    fn is_stale(&self) -> bool {
+        self.probed.read().unwrap().as_ref().is_none_or(|probed| {
+            probed.failed > 0 && probed.time.elapsed() >= self.reprobe_interval
+        })
+    }
+
+    #[must_use]
+    pub fn is_probed(&self) -> bool {
+        self.probed.read().unwrap().is_some()
+    }
+
+    // Identities of ready toolchains, sorted.
+    #[must_use]
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = (self.probed.read().unwrap().as_ref())
+            .map_or(Vec::new(), |probed| probed.ready.keys().cloned().collect());
+        names.sort();
+        names
+    }
+
+    #[must_use]
+    pub fn get(&self, identifier: &str) -> Option<Arc<dyn Toolchain>> {
+        (self.probed.read().unwrap().as_ref())
+            .and_then(|probed| probed.ready.get(identifier).cloned())
+    }
+
+    // Health of builder. Old failed probe is repeated first, unless another probe is running.
+    #[must_use]
+    pub fn health(&self) -> BuilderHealth {
+        if self.is_probed() && self.is_stale() {
+            if let Ok(_probing) = self.probing.try_lock() {
+                if self.is_stale() {
+                    self.probe();
+                }
+            }
+        }
+        let failed = match self.probed.read().unwrap().as_ref() {
+            Some(probed) => probed.failed,
+            None => return BuilderHealth::initializing(),
+        };
+        BuilderHealth::probed(self.names(), failed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cluster::builder::BuilderHealth;
+
+    #[test]
+    fn test_health() {
+        let health = BuilderHealth::initializing();
+        assert_eq!(health.http_status(), 503);
+        assert_eq!(
+            serde_json::to_string(&health).unwrap(),
+            r#"{"status":"initializing","toolchains":[],"failed_probes":0}"#
+        );
+
+        let health = BuilderHealth::probed(vec!["clang 17.0.6 x86_64-pc-linux-gnu".to_string()], 0);
+        assert_eq!(health.http_status(), 200);
+        assert_eq!(
+            serde_json::to_string(&health).unwrap(),
+            r#"{"status":"ready","toolchains":["clang 17.0.6 x86_64-pc-linux-gnu"],"failed_probes":0}"#
+        );
+
+        let health = BuilderHealth::probed(Vec::new(), 1);
+        assert_eq!(health.http_status(), 503);
+        assert_eq!(
+            serde_json::to_string(&health).unwrap(),
+            r#"{"status":"failed","toolchains":[],"failed_probes":1}"#
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_health_reprobe() {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use crate::clang::compiler::ClangCompiler;
+        use crate::cluster::builder::BuilderToolchains;
+        use crate::cluster::common::RPC_BUILDER_HEALTH;
+        use crate::compiler::{CommandInfo, Compiler};
+        use crate::test_utils::{fake_compiler, serve_http_status};
+
+        // Fake compiler reports version only once `ready` file exists.
+        let temp = tempfile::tempdir().unwrap();
+        let ready = temp.path().join("ready");
+        let clang = fake_compiler(
+            temp.path(),
+            "clang",
+            &format!(
+                "[ -f '{}' ] || exit 1\nprintf 'clang version 15.0.0 (health)\\nTarget: x86_64-pc-linux-gnu\\n'\n",
+                ready.display()
+            ),
+        );
+        let command = CommandInfo::simple(clang);
+        let toolchains = Arc::new(BuilderToolchains::new(
+            move || {
+                (ClangCompiler::default().resolve_toolchain(&command))
+                    .into_iter()
+                    .collect()
+            },
+            Duration::ZERO,
+        ));
+        let handler = toolchains.clone();
+        let endpoint = serve_http_status(move |path, _| {
+            assert_eq!(path, RPC_BUILDER_HEALTH);
+            let health = handler.health();
+            (health.http_status(), serde_json::to_vec(&health).unwrap())
+        });
+        let check = || {
+            let response =
+                reqwest::blocking::get(format!("http://{endpoint}{RPC_BUILDER_HEALTH}")).unwrap();
+            (response.status().as_u16(), response.text().unwrap())
+        };
+
+        assert_eq!(
+            check(),
+            (
+                503,
+                r#"{"status":"initializing","toolchains":[],"failed_probes":0}"#.to_string()
+            )
+        );
+        toolchains.refresh();
+        assert_eq!(
+            check(),
+            (
+                503,
+                r#"{"status":"failed","toolchains":[],"failed_probes":1}"#.to_string()
+            )
+        );
+
+        // Failed probe is repeated on the next request
+        std::fs::write(&ready, b"").unwrap();
+        assert_eq!(
+            check(),
+            (
+                200,
+                r#"{"status":"ready","toolchains":["clang health x86_64-pc-linux-gnu"],"failed_probes":0}"#
+                    .to_string()
+            )
+        );
+        assert_eq!(toolchains.names().len(), 1);
+    }
+}
@@ -1,10 +1,12 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
 use std::io::{Error, ErrorKind, Write};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
 use log::{trace, warn};
@@ -13,12 +15,14 @@ use reqwest::blocking::Client;
 use reqwest::StatusCode;
 
 use crate::cache::FileHasher;
-use crate::cluster::builder::{CompileRequest, CompileResponse};
-use crate::cluster::common::{BuilderInfo, RPC_BUILDER_LIST, RPC_BUILDER_TASK, RPC_BUILDER_UPLOAD};
-use crate::compiler::CompileInput::Preprocessed;
+use crate::cluster::builder::{CompileRequest, CompileResponse, RemoteInput, RemoteSource};
+use crate::cluster::common::{
+    BuilderInfo, RPC_BUILDER_HEALTH, RPC_BUILDER_LIST, RPC_BUILDER_TASK, RPC_BUILDER_UPLOAD,
+};
+use crate::compiler::CompileInput::{Preprocessed, Source};
 use crate::compiler::{
     CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OutputInfo,
-    PreprocessResult, SharedState, Toolchain,
+    PreprocessResult, Scope, SharedState, TaskOptions, Toolchain,
 };
 
 pub struct RemoteCompiler<C: Compiler> {
@@ -36,6 +40,12 @@ struct RemoteShared {
     mutable: RwLock<RemoteSharedMut>,
     base_url: Option<reqwest::Url>,
     client: Client,
+    // Toolchain required on builders instead of local one.
+    toolchain: Option<String>,
+    // Tasks in progress per builder endpoint, tracked by capacity-aware scheduler only.
+    outstanding: Option<Mutex<HashMap<String, usize>>>,
+    // Send sources to builders which preprocess them.
+    preprocess: bool,
 }
 
 struct RemoteToolchain {
@@ -53,10 +63,57 @@ impl<C: Compiler> RemoteCompiler<C> {
                 }),
                 base_url: base_url.as_ref().cloned(),
                 client: Client::new(),
+                toolchain: None,
+                outstanding: None,
+                preprocess: false,
             }),
             local: compiler,
         }
     }
+
+    // Require builders with specific toolchain.
+    #[must_use]
+    pub fn with_toolchain(mut self, toolchain: Option<String>) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("remote compiler is not shared yet")
+            .toolchain = toolchain;
+        self
+    }
+
+    // Send tasks to builders with most free slots instead of random ones.
+    #[must_use]
+    pub fn with_capacity_aware(mut self, enabled: bool) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("remote compiler is not shared yet")
+            .outstanding = enabled.then(|| Mutex::new(HashMap::new()));
+        self
+    }
+
+    // Let capable builders preprocess sources instead of preprocessing them locally.
+    #[must_use]
+    pub fn with_remote_preprocess(mut self, enabled: bool) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("remote compiler is not shared yet")
+            .preprocess = enabled;
+        self
+    }
+}
+
+// Tracks task sent to builder until response is received.
+struct OutstandingGuard<'a> {
+    outstanding: Option<&'a Mutex<HashMap<String, usize>>>,
+    endpoint: String,
+}
+
+impl Drop for OutstandingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(outstanding) = self.outstanding {
+            let mut outstanding = outstanding.lock().unwrap();
+            if let Some(count) = outstanding.get_mut(&self.endpoint) {
+                *count -= 1;
+            }
+        }
+    }
 }
 
 impl RemoteSharedMut {
@@ -99,14 +156,19 @@ impl RemoteToolchain {
         &self,
         state: &SharedState,
         task: &CompileStep,
-    ) -> Result<CompileResponse, Error> {
+        input: RemoteInput,
+    ) -> Result<Result<OutputInfo, String>, Error> {
         let name = self
-            .identifier()
+            .builder_toolchain()
             .ok_or_else(|| Error::new(ErrorKind::Other, "Can't get toolchain name"))?;
 
-        let addr = self
-            .remote_endpoint(&name)
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Can't find helper for toolchain"))?;
+        let preprocess = matches!(input, RemoteInput::Source(_));
+        let (addr, _guard) = self.remote_endpoint(&name, preprocess).ok_or_else(|| {
+            Error::new(
+                ErrorKind::Other,
+                format!("Can't find helper for toolchain: {name}"),
+            )
+        })?;
         if task.pch_usage.is_some() {
             return Err(Error::new(
                 ErrorKind::Other,
@@ -116,12 +178,6 @@ impl RemoteToolchain {
 
         let base_url = get_base_url(&addr);
 
-        let preprocessed = if let Preprocessed(preprocessed) = &task.input {
-            preprocessed
-        } else {
-            unimplemented!()
-        };
-
         // Send compilation request.
         let request = CompileRequest {
             toolchain: name,
@@ -130,31 +186,27 @@ impl RemoteToolchain {
                 .iter()
                 .map(|s| s.to_str().unwrap().to_string())
                 .collect(),
-            preprocessed_data: preprocessed.to_vec(),
+            language: task.language.clone(),
+            input,
             precompiled_hash: self.upload_precompiled(
                 state,
                 &task.pch_usage.get_in_abs(),
                 &base_url,
             )?,
         };
-        let request_payload = bincode::serialize(&request).unwrap();
-        let mut resp: reqwest::blocking::Response = self
-            .shared
-            .client
-            .post(base_url.join(RPC_BUILDER_TASK).unwrap())
-            .body(request_payload)
-            .send()
-            .map_err(|e| Error::new(ErrorKind::Other, e))?;
-        // Receive compilation result.
-        let result: CompileResponse = bincode::deserialize_from(&mut resp)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
-        if let CompileResponse::Success(ref output) = result {
-            write_output(
-                &task.output_object,
-                output.success(),
-                output.stdout.as_slice(),
-            )?;
-        }
+        let result = match send_task(&self.shared.client, &base_url, &request)? {
+            CompileResponse::Success(output, object) => {
+                write_output(&task.output_object, output.success(), &object)?;
+                Ok(output)
+            }
+            CompileResponse::Err(err) => Err(err),
+            CompileResponse::Unavailable(reason) => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("Builder can't compile task: {reason}"),
+                ));
+            }
+        };
         state.statistic.inc_remote();
         Ok(result)
     }
@@ -241,31 +293,91 @@ impl RemoteToolchain {
             holder.builders.clone()
         }
     }
-    // Resolve toolchain for command execution.
-    fn remote_endpoint(&self, toolchain_name: &str) -> Option<SocketAddr> {
-        let name = toolchain_name.to_string();
+    // Resolve toolchain for command execution. Returned guard keeps task counted as outstanding.
+    fn remote_endpoint(
+        &self,
+        toolchain_name: &str,
+        preprocess: bool,
+    ) -> Option<(SocketAddr, OutstandingGuard<'_>)> {
         let all_builders = self.builders();
-        let builder = all_builders
-            .iter()
-            .filter(|b| b.toolchains.contains(&name))
-            .choose(&mut rand::rng())?;
-        SocketAddr::from_str(&builder.endpoint).ok()
+        let outstanding = self.shared.outstanding.as_ref();
+        let mut counts = outstanding.map(|v| v.lock().unwrap());
+        let builder = select_builder(&all_builders, toolchain_name, preprocess, counts.as_deref())?;
+        let addr = SocketAddr::from_str(&builder.endpoint).ok()?;
+        if let Some(counts) = &mut counts {
+            *counts.entry(builder.endpoint.clone()).or_default() += 1;
+        }
+        Some((
+            addr,
+            OutstandingGuard {
+                outstanding,
+                endpoint: builder.endpoint.clone(),
+            },
+        ))
+    }
+
+    // Toolchain required on builders: forced one or local.
+    fn builder_toolchain(&self) -> Option<String> {
+        self.shared
+            .toolchain
+            .clone()
+            .or_else(|| self.local.identifier())
+    }
+
+    // Whether compilation can fall back to local toolchain.
+    fn allow_local(&self) -> bool {
+        match &self.shared.toolchain {
+            Some(toolchain) => self.local.identifier().as_ref() == Some(toolchain),
+            None => true,
+        }
     }
 }
 
+// Choose builder advertising exactly the given toolchain (and preprocessing, if required). When
+// tasks in progress per builder endpoint are known, builder with most free capacity is chosen,
+// otherwise random one. Capacity is relative, so tasks are spread proportionally to capacity
+// even when all slots are busy.
+fn select_builder<'a>(
+    builders: &'a [BuilderInfo],
+    toolchain: &str,
+    preprocess: bool,
+    outstanding: Option<&HashMap<String, usize>>,
+) -> Option<&'a BuilderInfo> {
+    let candidates = builders
+        .iter()
+        .filter(|b| b.toolchains.iter().any(|t| t == toolchain))
+        .filter(|b| b.preprocess || !preprocess);
+    let Some(outstanding) = outstanding else {
+        return candidates.choose(&mut rand::rng());
+    };
+    // Load of builder after sending one more task to it.
+    let load = |builder: &BuilderInfo| {
+        let busy = outstanding.get(&builder.endpoint).copied().unwrap_or(0);
+        (busy + 1) as f64 / builder.capacity.max(1) as f64
+    };
+    let lowest = candidates.clone().map(load).min_by(f64::total_cmp)?;
+    candidates
+        .filter(|b| load(b) == lowest)
+        .choose(&mut rand::rng())
+}
+
 impl Toolchain for RemoteToolchain {
     fn identifier(&self) -> Option<String> {
         self.local.identifier()
     }
 
+    fn compiler(&self) -> &'static str {
+        self.local.compiler()
+    }
+
     // Parse compiler arguments.
     fn create_tasks(
         &self,
         command: CommandInfo,
         args: &[String],
-        run_second_cpp: bool,
+        options: &TaskOptions,
     ) -> crate::Result<Vec<CompilationTask>> {
-        self.local.create_tasks(command, args, run_second_cpp)
+        self.local.create_tasks(command, args, options)
     }
 
     // Preprocessing source file.
@@ -286,18 +398,261 @@ impl Toolchain for RemoteToolchain {
         self.local.create_compile_step(task, preprocessed)
     }
 
+    fn phase_args(&self, task: &CompilationTask, phase: Scope) -> crate::Result<Vec<OsString>> {
+        self.local.phase_args(task, phase)
+    }
+
+    // Compilation is sent to builders with local fallback when coordinator is configured.
+    fn backend(&self) -> &'static str {
+        if self.shared.base_url.is_some() {
+            "remote"
+        } else {
+            self.local.backend()
+        }
+    }
+
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
-        match self.compile_remote(state, &task) {
-            Ok(response) => match response {
-                CompileResponse::Success(output) => Ok(output),
-                CompileResponse::Err(err) => Err(err.into()),
-            },
+        let input = match &task.input {
+            Preprocessed(preprocessed) => Ok(RemoteInput::Preprocessed(preprocessed.to_vec()?)),
+            Source(_) => Err(Error::new(
+                ErrorKind::Other,
+                "Source is compiled remotely only with remote preprocessing",
+            )),
+        };
+        match input.and_then(|input| self.compile_remote(state, &task, input)) {
+            Ok(result) => result.map_err(Into::into),
+            Err(e) if !self.allow_local() => Err(e.into()),
             Err(e) => {
                 trace!("Fallback to local build: {}", e);
                 self.local.run_compile(state, task)
             }
         }
     }
+
+    // Builder preprocesses source, fallback is regular compilation with local preprocessing.
+    fn compile_source(
+        &self,
+        state: &SharedState,
+        task: &CompilationTask,
+    ) -> Option<crate::Result<OutputInfo>> {
+        if !self.shared.preprocess
+            || task.shared.pch_usage.is_some()
+            || task.shared.deps_file.is_some()
+        {
+            return None;
+        }
+        let result = source_step(self.local.as_ref(), task)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))
+            .and_then(|(step, source)| {
+                self.compile_remote(state, &step, RemoteInput::Source(source))
+            });
+        match result {
+            Ok(result) => Some(result.map_err(Into::into)),
+            Err(e) => {
+                trace!("Fallback to local preprocessing: {}", e);
+                None
+            }
+        }
+    }
+}
+
+// Compilation step from source with its content. Builder compiles copy of source placed next to
+// original one, so includes are resolved the same way.
+fn source_step(
+    local: &dyn Toolchain,
+    task: &CompilationTask,
+) -> crate::Result<(CompileStep, RemoteSource)> {
+    let mut shared = (*task.shared).clone();
+    shared.run_second_cpp = true;
+    let path = shared.command.absolutize(&task.input_source)?;
+    let task = CompilationTask {
+        shared: Arc::new(shared),
+        ..task.clone()
+    };
+    let step = local.create_compile_step(&task, CompilerOutput::Vec(Vec::new()))?;
+    let Source(input) = &step.input else {
+        return Err(crate::Error::Generic(
+            "Toolchain doesn't compile sources".to_string(),
+        ));
+    };
+    let source = RemoteSource {
+        content: fs::read(&path)?,
+        path,
+        current_dir: input.current_dir.clone(),
+        include_dirs: input.include_dirs.clone(),
+    };
+    Ok((step, source))
+}
+
+// Send compilation request to builder and receive compilation result.
+fn send_task(
+    client: &Client,
+    base_url: &reqwest::Url,
+    request: &CompileRequest,
+) -> Result<CompileResponse, Error> {
+    let request_payload = bincode::serialize(request).unwrap();
+    let mut resp: reqwest::blocking::Response = client
+        .post(base_url.join(RPC_BUILDER_TASK).unwrap())
+        .body(request_payload)
+        .send()
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    bincode::deserialize_from(&mut resp).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+// Outcome of builder check by network probe.
+#[derive(Debug)]
+pub struct BuilderProbe {
+    pub name: String,
+    pub endpoint: String,
+    // Time of health check and trivial compilation together.
+    pub latency: Duration,
+    pub result: Result<(), String>,
+}
+
+// Source compiled on every builder by network probe.
+const PROBE_SOURCE: &str = "int octobuild_probe(int value) { return value + 1; }\n";
+
+// Receive builders from coordinator, then check every builder: health check followed by
+// compilation of trivial source, preprocessed locally by toolchain of `command`.
+pub fn probe_network(
+    state: &SharedState,
+    coordinator: &reqwest::Url,
+    command: &CommandInfo,
+    toolchain: &dyn Toolchain,
+) -> crate::Result<Vec<BuilderProbe>> {
+    let builders = RemoteSharedMut::receive_builders(&Some(coordinator.clone()))?;
+    let temp = tempfile::tempdir_in(state.temp_dir.path())?;
+    fs::write(temp.path().join("probe.cpp"), PROBE_SOURCE)?;
+    let command = CommandInfo {
+        current_dir: Some(temp.path().to_path_buf()),
+        ..command.clone()
+    };
+    let client = Client::new();
+    Ok(builders
+        .iter()
+        .map(|builder| {
+            let start = Instant::now();
+            let result = probe_builder(state, &client, builder, &command, toolchain)
+                .map_err(|e| e.to_string());
+            BuilderProbe {
+                name: builder.name.clone(),
+                endpoint: builder.endpoint.clone(),
+                latency: start.elapsed(),
+                result,
+            }
+        })
+        .collect())
+}
+
+fn probe_builder(
+    state: &SharedState,
+    client: &Client,
+    builder: &BuilderInfo,
+    command: &CommandInfo,
+    toolchain: &dyn Toolchain,
+) -> crate::Result<()> {
+    let addr = SocketAddr::from_str(&builder.endpoint).map_err(|e| {
+        crate::Error::Generic(format!("Invalid endpoint {}: {e}", builder.endpoint))
+    })?;
+    let base_url = get_base_url(&addr);
+    let status = client
+        .get(base_url.join(RPC_BUILDER_HEALTH).unwrap())
+        .send()?
+        .status();
+    if !status.is_success() {
+        return Err(crate::Error::Generic(format!(
+            "Builder is not ready: {status}"
+        )));
+    }
+
+    let name = toolchain
+        .identifier()
+        .ok_or_else(|| crate::Error::Generic("Can't get toolchain name".to_string()))?;
+    if !builder.toolchains.contains(&name) {
+        return Err(crate::Error::Generic(format!(
+            "Builder doesn't have toolchain: {name}"
+        )));
+    }
+    let args = ["-c", "probe.cpp"].map(String::from);
+    // Builder compiles preprocessed source.
+    let options = TaskOptions {
+        run_second_cpp: false,
+        ..TaskOptions::default()
+    };
+    let task = toolchain
+        .create_tasks(command.clone(), &args, &options)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| crate::Error::Generic("Can't create probe task".to_string()))?;
+    let preprocessed = match toolchain.run_preprocess(state, &task)? {
+        PreprocessResult::Success(preprocessed) => preprocessed,
+        PreprocessResult::Failed(output) => {
+            return Err(crate::Error::Generic(format!(
+                "Can't preprocess probe source: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    };
+    let step = toolchain.create_compile_step(&task, preprocessed)?;
+    let Preprocessed(preprocessed) = &step.input else {
+        return Err(crate::Error::Generic(
+            "Toolchain doesn't compile preprocessed sources".to_string(),
+        ));
+    };
+    let request = CompileRequest {
+        toolchain: name,
+        args: step
+            .args
+            .iter()
+            .map(|s| s.to_string_lossy().into_owned())
+            .collect(),
+        language: step.language.clone(),
+        input: RemoteInput::Preprocessed(preprocessed.to_vec()?),
+        precompiled_hash: None,
+    };
+    match send_task(client, &base_url, &request)? {
+        CompileResponse::Success(output, _) if output.success() => Ok(()),
+        CompileResponse::Success(output, _) => Err(crate::Error::Generic(format!(
+            "Compilation failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+        CompileResponse::Err(e) | CompileResponse::Unavailable(e) => Err(crate::Error::Generic(e)),
+    }
+}
+
+pub fn print_probes(probes: &[BuilderProbe], out: &mut impl Write) -> std::io::Result<()> {
+    if probes.is_empty() {
+        return writeln!(out, "No builders registered on coordinator");
+    }
+    let width = |header: &str, value: fn(&BuilderProbe) -> &str| {
+        probes
+            .iter()
+            .map(|probe| value(probe).len())
+            .chain([header.len()])
+            .max()
+            .unwrap_or(0)
+    };
+    let name = width("Builder", |probe| &probe.name);
+    let endpoint = width("Endpoint", |probe| &probe.endpoint);
+    writeln!(
+        out,
+        "{:name$}  {:endpoint$}  {:>9}  Result",
+        "Builder", "Endpoint", "Latency"
+    )?;
+    for probe in probes {
+        let result = match &probe.result {
+            Ok(()) => "ok",
+            Err(e) => e,
+        };
+        writeln!(
+            out,
+            "{:name$}  {:endpoint$}  {:>6} ms  {result}",
+            probe.name,
+            probe.endpoint,
+            probe.latency.as_millis()
+        )?;
+    }
+    Ok(())
 }
 
 fn get_base_url(addr: &SocketAddr) -> reqwest::Url {
@@ -323,3 +678,390 @@ fn write_output(path: &Option<PathBuf>, success: bool, output: &[u8]) -> Result<
         None => Ok(()),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::cluster::client::select_builder;
+    use crate::cluster::common::BuilderInfo;
+
+    fn builder(name: &str, toolchains: &[&str]) -> BuilderInfo {
+        BuilderInfo {
+            name: name.to_string(),
+            endpoint: format!("{name}:3000"),
+            version: String::new(),
+            toolchains: toolchains.iter().map(ToString::to_string).collect(),
+            capacity: 1,
+            preprocess: false,
+        }
+    }
+
+    #[test]
+    fn test_select_builder_toolchain() {
+        let builders = vec![
+            builder("old", &["cl.exe 14.29", "clang 15"]),
+            builder("new", &["cl.exe 14.38"]),
+            builder("both", &["cl.exe 14.29", "cl.exe 14.38"]),
+        ];
+        for _ in 0..20 {
+            let selected = select_builder(&builders, "cl.exe 14.38", false, None).unwrap();
+            assert!(selected.name == "new" || selected.name == "both");
+            let selected = select_builder(&builders, "clang 15", false, None).unwrap();
+            assert_eq!(selected.name, "old");
+        }
+        // Exact match only
+        assert!(select_builder(&builders, "cl.exe 14", false, None).is_none());
+        assert!(select_builder(&builders, "cl.exe 14.10", false, None).is_none());
+        // Preprocessing capability
+        assert!(select_builder(&builders, "clang 15", true, None).is_none());
+        let builders = vec![
+            builder("plain", &["clang 15"]),
+            BuilderInfo {
+                preprocess: true,
+                ..builder("preprocessing", &["clang 15"])
+            },
+        ];
+        for _ in 0..20 {
+            let selected = select_builder(&builders, "clang 15", true, None).unwrap();
+            assert_eq!(selected.name, "preprocessing");
+        }
+    }
+
+    #[test]
+    fn test_select_builder_capacity() {
+        let builders: Vec<BuilderInfo> = [("small", 2), ("medium", 4), ("large", 8)]
+            .into_iter()
+            .map(|(name, capacity)| BuilderInfo {
+                capacity,
+                ..builder(name, &["clang 15"])
+            })
+            .collect();
+
+        // Fill all slots
+        let mut outstanding: HashMap<String, usize> = HashMap::new();
+        for _ in 0..14 {
+            let selected =
+                select_builder(&builders, "clang 15", false, Some(&outstanding)).unwrap();
+            *outstanding.entry(selected.endpoint.clone()).or_default() += 1;
+        }
+        assert_eq!(outstanding["small:3000"], 2);
+        assert_eq!(outstanding["medium:3000"], 4);
+        assert_eq!(outstanding["large:3000"], 8);
+
+        // Overload builders
+        let mut assigned: HashMap<String, usize> = HashMap::new();
+        for _ in 0..700 {
+            let selected =
+                select_builder(&builders, "clang 15", false, Some(&outstanding)).unwrap();
+            *outstanding.entry(selected.endpoint.clone()).or_default() += 1;
+            *assigned.entry(selected.name.clone()).or_default() += 1;
+        }
+        for (name, capacity) in [("small", 2), ("medium", 4), ("large", 8)] {
+            let expected = 700 * capacity / 14;
+            let actual = assigned[name];
+            assert!(
+                actual.abs_diff(expected) <= expected / 5,
+                "{name}: {actual} tasks, expected about {expected}"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_preprocess() {
+        use crate::clang::compiler::ClangCompiler;
+        use std::path::Path;
+
+        use crate::cluster::builder::{CompileRequest, CompileResponse, RemoteInput};
+        use crate::cluster::client::{source_step, write_output};
+        use crate::compiler::{CommandArgs, Compiler, PCHUsage, TaskOptions};
+        use crate::config::CacheMode;
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: object file lists arguments and compiled source.
+        let mut fixture = Fixture::clang(
+            r#"printf '%s\n' "$@" > "$out"
+cat "$in" >> "$out"
+"#,
+        );
+        std::fs::create_dir_all(fixture.path().join("src")).unwrap();
+        std::fs::create_dir_all(fixture.path().join("include")).unwrap();
+        std::fs::write(
+            fixture.path().join("src/sample.cpp"),
+            "#include \"local.h\"\n",
+        )
+        .unwrap();
+        let object = fixture.path().join("sample.o");
+
+        fixture.config.cache_mode = CacheMode::None;
+        let state = fixture.state();
+        let args = ["-c", "src/sample.cpp", "-Iinclude", "-o"]
+            .map(String::from)
+            .into_iter()
+            .chain([object.to_string_lossy().into_owned()]);
+        let tasks = ClangCompiler::default()
+            .create_tasks(
+                fixture.command(),
+                CommandArgs::Regular(args.collect()),
+                &TaskOptions::default(),
+            )
+            .unwrap();
+        let toolchain = tasks[0].toolchain.as_ref();
+
+        // Client ships source with include manifest, local source changes don't affect it.
+        let (step, source) = source_step(toolchain, &tasks[0].task).unwrap();
+        assert_eq!(
+            source.include_dirs,
+            [fixture.path().join("src"), fixture.path().join("include")]
+        );
+        std::fs::write(fixture.path().join("src/sample.cpp"), "broken").unwrap();
+        let request = CompileRequest {
+            toolchain: "clang".to_string(),
+            args: step
+                .args
+                .iter()
+                .map(|s| s.to_str().unwrap().to_string())
+                .collect(),
+            language: step.language.clone(),
+            input: RemoteInput::Source(source),
+            precompiled_hash: None,
+        };
+        let payload = bincode::serialize(&request).unwrap();
+
+        // Builder compiles copy of source in its temporary directory, named after original one.
+        let request: CompileRequest = bincode::deserialize(&payload).unwrap();
+        let response = request.compile(&state, toolchain, PCHUsage::None);
+        let CompileResponse::Success(output, content) = response else {
+            panic!("remote compilation failed");
+        };
+        assert!(output.success());
+        write_output(&step.output_object, true, &content).unwrap();
+        let object = std::fs::read_to_string(&object).unwrap();
+        let source = fixture.path().join("src/sample.cpp");
+        assert!(object.starts_with(&format!(
+            "-iquote\n{}\n",
+            fixture.path().join("src").display()
+        )));
+        assert!(object.contains("-Xclang\n-main-file-name\n-Xclang\nsample.cpp\n"));
+        let copy = object
+            .lines()
+            .find(|line| line.ends_with("-sample.cpp"))
+            .unwrap();
+        assert_eq!(Path::new(copy).parent(), Some(state.temp_dir.path()));
+        assert!(!Path::new(copy).exists());
+        assert!(object.ends_with(&format!(
+            "#line 1 \"{}\"\n#include \"local.h\"\n",
+            source.display()
+        )));
+
+        // Builder without include tree can't compile source.
+        std::fs::remove_dir(fixture.path().join("include")).unwrap();
+        let request: CompileRequest = bincode::deserialize(&payload).unwrap();
+        let CompileResponse::Unavailable(reason) =
+            request.compile(&state, toolchain, PCHUsage::None)
+        else {
+            panic!("source without include directory is compiled");
+        };
+        assert!(reason.contains("not available on builder"), "{reason}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_probe_network() {
+        use crate::clang::compiler::ClangCompiler;
+        use crate::cluster::builder::{CompileRequest, CompileResponse};
+        use crate::cluster::client::{print_probes, probe_network};
+        use crate::cluster::common::{RPC_BUILDER_HEALTH, RPC_BUILDER_LIST, RPC_BUILDER_TASK};
+        use crate::compiler::{CommandInfo, Compiler, OutputInfo, SharedState};
+        use crate::config::{CacheMode, Config};
+        use crate::test_utils::{fake_compiler, serve_http};
+
+        // Fake compiler: reports version and preprocesses source as is.
+        let temp = tempfile::tempdir().unwrap();
+        let clang = fake_compiler(
+            temp.path(),
+            "clang",
+            r#"case "$1" in
+    --version) printf 'clang version 15.0.0 (probe)\nTarget: x86_64-pc-linux-gnu\n' ;;
+    -E) cat "$5" ;;
+    *) exit 1 ;;
+esac
+"#,
+        );
+        let command = CommandInfo::simple(clang);
+        let toolchain = ClangCompiler::default()
+            .resolve_toolchain(&command)
+            .unwrap();
+        let identifier = toolchain.identifier().unwrap();
+
+        // Stub builders: one compiles probe source, another one fails.
+        let start_builder = |error: Option<&'static str>| {
+            serve_http(move |path, body| {
+                if path == RPC_BUILDER_HEALTH {
+                    return b"{}".to_vec();
+                }
+                assert_eq!(path, RPC_BUILDER_TASK);
+                let request: CompileRequest = bincode::deserialize(body).unwrap();
+                assert!(request.args.iter().any(|arg| arg == "c++"));
+                let response = match error {
+                    Some(e) => CompileResponse::Err(e.to_string()),
+                    None => CompileResponse::Success(
+                        OutputInfo {
+                            status: Some(0),
+                            stdout: Vec::new(),
+                            stderr: Vec::new(),
+                        },
+                        b"object".to_vec(),
+                    ),
+                };
+                bincode::serialize(&response).unwrap()
+            })
+        };
+        let good = start_builder(None);
+        let bad = start_builder(Some("compiler crashed"));
+        let builders: Vec<BuilderInfo> = [("good", &good), ("bad", &bad)]
+            .into_iter()
+            .map(|(name, endpoint)| BuilderInfo {
+                endpoint: endpoint.clone(),
+                ..builder(name, &[&identifier])
+            })
+            .collect();
+        let payload = bincode::serialize(&builders).unwrap();
+        let coordinator = serve_http(move |path, _| {
+            assert_eq!(path, RPC_BUILDER_LIST);
+            payload.clone()
+        });
+
+        let config = Config {
+            cache_mode: CacheMode::None,
+            ..Config::default()
+        };
+        let state = SharedState::new(&config).unwrap();
+        let coordinator = reqwest::Url::parse(&format!("http://{coordinator}")).unwrap();
+        let probes = probe_network(&state, &coordinator, &command, toolchain.as_ref()).unwrap();
+        assert_eq!(probes.len(), 2);
+        assert_eq!(probes[0].result, Ok(()));
+        assert_eq!(probes[1].result, Err("Error: compiler crashed".to_string()));
+
+        let mut out = Vec::new();
+        print_probes(&probes, &mut out).unwrap();
+        let table = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3, "{table}");
+        assert!(lines[0].starts_with("Builder  Endpoint"), "{table}");
+        assert!(lines[1].starts_with(&format!("good     {good}")), "{table}");
+        assert!(lines[1].ends_with(" ms  ok"), "{table}");
+        assert!(lines[2].starts_with(&format!("bad      {bad}")), "{table}");
+        assert!(
+            lines[2].ends_with(" ms  Error: compiler crashed"),
+            "{table}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_preprocess_unavailable() {
+        use std::sync::mpsc;
+
+        use crate::clang::compiler::ClangCompiler;
+        use crate::cluster::builder::{CompileRequest, CompileResponse};
+        use crate::cluster::client::RemoteCompiler;
+        use crate::cluster::common::{RPC_BUILDER_LIST, RPC_BUILDER_TASK};
+        use crate::compiler::{Compiler, PCHUsage, SharedState, TaskOptions};
+        use crate::config::CacheMode;
+        use crate::test_utils::{serve_http, Fixture};
+
+        // Fake compiler: reports version, compilation writes object.
+        let mut fixture = Fixture::clang(
+            r#"if [ "$1" = "--version" ]; then
+    printf 'clang version 15.0.0 (probe)\nTarget: x86_64-pc-linux-gnu\n'
+    exit 0
+fi
+echo object > "$out"
+"#,
+        );
+        std::fs::create_dir_all(fixture.path().join("src")).unwrap();
+        std::fs::write(fixture.path().join("src/sample.cpp"), "int a;\n").unwrap();
+        fixture.config.cache_mode = CacheMode::None;
+        let state = fixture.state();
+        let command = fixture.command();
+        let identifier = ClangCompiler::default()
+            .resolve_toolchain(&command)
+            .unwrap()
+            .identifier()
+            .unwrap();
+
+        // Builder compiles requests for real, include directory is missing on both sides.
+        let (sender, responses) = mpsc::channel();
+        let builder_config = fixture.config.clone();
+        let builder_command = command.clone();
+        let endpoint = serve_http(move |path, body| {
+            assert_eq!(path, RPC_BUILDER_TASK);
+            let state = SharedState::new(&builder_config).unwrap();
+            let toolchain = ClangCompiler::default()
+                .resolve_toolchain(&builder_command)
+                .unwrap();
+            let request: CompileRequest = bincode::deserialize(body).unwrap();
+            let response = request.compile(&state, toolchain.as_ref(), PCHUsage::None);
+            sender
+                .send(matches!(response, CompileResponse::Unavailable(_)))
+                .unwrap();
+            bincode::serialize(&response).unwrap()
+        });
+        let builders = vec![BuilderInfo {
+            endpoint,
+            preprocess: true,
+            ..builder("builder", &[&identifier])
+        }];
+        let payload = bincode::serialize(&builders).unwrap();
+        let coordinator = serve_http(move |path, _| {
+            assert_eq!(path, RPC_BUILDER_LIST);
+            payload.clone()
+        });
+
+        let coordinator = reqwest::Url::parse(&format!("http://{coordinator}")).unwrap();
+        let compiler = RemoteCompiler::new(&Some(coordinator), ClangCompiler::default())
+            .with_remote_preprocess(true);
+        let toolchain = compiler.resolve_toolchain(&command).unwrap();
+        let args = ["-c", "src/sample.cpp", "-Imissing", "-o", "sample.o"].map(String::from);
+        let tasks = toolchain
+            .create_tasks(command, &args, &TaskOptions::default())
+            .unwrap();
+
+        // Unavailable source falls back to local preprocessing.
+        assert!(toolchain.compile_source(&state, &tasks[0]).is_none());
+        assert_eq!(responses.try_recv(), Ok(true));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_forced_toolchain_identifier() {
+        use crate::clang::compiler::ClangCompiler;
+        use crate::cluster::client::{RemoteCompiler, RemoteToolchain};
+        use crate::compiler::{CommandInfo, Compiler, Toolchain};
+        use crate::test_utils::fake_compiler;
+
+        let temp = tempfile::tempdir().unwrap();
+        let clang = fake_compiler(
+            temp.path(),
+            "clang",
+            "printf 'clang version 15.0.0 (local)\\nTarget: x86_64-pc-linux-gnu\\n'\n",
+        );
+        let command = CommandInfo::simple(clang);
+        let local = ClangCompiler::default()
+            .resolve_toolchain(&command)
+            .unwrap();
+
+        // Forced builder toolchain doesn't change local identity used by cache key.
+        let compiler = RemoteCompiler::new(&None, ClangCompiler::default())
+            .with_toolchain(Some("clang 16".to_string()));
+        let toolchain = RemoteToolchain {
+            shared: compiler.shared.clone(),
+            local: local.clone(),
+        };
+        assert_eq!(toolchain.identifier(), local.identifier());
+        assert_eq!(toolchain.builder_toolchain().as_deref(), Some("clang 16"));
+        assert!(!toolchain.allow_local());
+    }
+}
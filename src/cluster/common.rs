@@ -1,11 +1,12 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-pub const RPC_BUILDER_UPDATE: &str = "/rpc/v1/builder/update";
-pub const RPC_BUILDER_LIST: &str = "/rpc/v1/builder/list";
+pub const RPC_BUILDER_UPDATE: &str = "/rpc/v2/builder/update";
+pub const RPC_BUILDER_LIST: &str = "/rpc/v2/builder/list";
 
-pub const RPC_BUILDER_TASK: &str = "/rpc/v1/builder/task";
-pub const RPC_BUILDER_UPLOAD: &str = "/rpc/v1/builder/upload";
+pub const RPC_BUILDER_TASK: &str = "/rpc/v2/builder/task";
+pub const RPC_BUILDER_UPLOAD: &str = "/rpc/v2/builder/upload";
+pub const RPC_BUILDER_HEALTH: &str = "/rpc/v2/builder/health";
 
 #[derive(Serialize, Deserialize)]
 pub struct BuilderInfo {
@@ -17,6 +18,10 @@ pub struct BuilderInfo {
     pub version: String,
     // Agent toolchain list
     pub toolchains: Vec<String>,
+    // Count of tasks agent compiles concurrently
+    pub capacity: usize,
+    // Agent accepts sources and preprocesses them with its own include tree
+    pub preprocess: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -1,4 +1,6 @@
+use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
+use std::path::Path;
 
 // Parsing command line arguments from singe line.
 pub fn parse(cmd: &str) -> crate::Result<Vec<String>> {
@@ -11,6 +13,12 @@ pub fn quote(arg: impl AsRef<OsStr>) -> crate::Result<OsString> {
     Ok(OsString::from(quoted.as_ref()))
 }
 
+// Paths need no special prefix outside Windows.
+#[must_use]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
 #[test]
 fn test_parse_1() {
     assert_eq!(parse(r#""abc" d e"#).unwrap(), ["abc", "d", "e"]);
@@ -1,9 +1,14 @@
+use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
 use std::iter;
 use std::iter::Peekable;
 use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf, Prefix};
 use std::str::Chars;
 
+// Longest path that works without extended-length prefix (MAX_PATH minus room for 8.3 file name).
+const MAX_SHORT_PATH: usize = 248;
+
 trait CharsExt {
     fn advance_while<P: FnMut(char) -> bool>(&mut self, predicate: P) -> usize;
 }
@@ -138,6 +143,46 @@ pub fn quote(arg: impl AsRef<OsStr>) -> crate::Result<OsString> {
     Ok(OsStringExt::from_wide(&result))
 }
 
+// Add extended-length `\\?\` prefix to absolute path that is too long for legacy Windows API.
+// Extended-length paths are not normalized by Windows, so `.` and `..` are resolved here.
+#[must_use]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    if path.as_os_str().encode_wide().count() < MAX_SHORT_PATH || !path.is_absolute() {
+        return Cow::Borrowed(path);
+    }
+    let mut components = path.components();
+    let mut result = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(disk) => OsString::from(format!("\\\\?\\{}:", char::from(disk))),
+            Prefix::UNC(server, share) => {
+                let mut result = OsString::from("\\\\?\\UNC\\");
+                result.push(server);
+                result.push("\\");
+                result.push(share);
+                result
+            }
+            // Already verbatim or device path
+            _ => return Cow::Borrowed(path),
+        },
+        _ => return Cow::Borrowed(path),
+    };
+    let mut names: Vec<&OsStr> = Vec::new();
+    for component in components {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::ParentDir => {
+                names.pop();
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    for name in names {
+        result.push("\\");
+        result.push(name);
+    }
+    Cow::Owned(PathBuf::from(result))
+}
+
 #[test]
 fn test_parse_1() {
     assert_eq!(parse("\"abc\" d e").unwrap(), ["abc", "d", "e"]);
@@ -200,3 +245,23 @@ fn test_parse_9() {
         ["/FpDebug\\HelloWorld.pch", "/FoDebug\\", "/Gd"]
     );
 }
+
+#[test]
+fn test_long_path() {
+    let short = Path::new("C:\\Work\\sample.obj");
+    assert_eq!(long_path(short), short);
+    let relative = "dir\\".repeat(100);
+    assert_eq!(long_path(Path::new(&relative)), Path::new(&relative));
+
+    let deep = "\\very-long-directory-name".repeat(20);
+    assert_eq!(
+        long_path(Path::new(&format!("C:{deep}/./Debug\\..\\sample.obj"))),
+        Path::new(&format!("\\\\?\\C:{deep}\\sample.obj"))
+    );
+    assert_eq!(
+        long_path(Path::new(&format!("\\\\server\\share{deep}\\sample.obj"))),
+        Path::new(&format!("\\\\?\\UNC\\server\\share{deep}\\sample.obj"))
+    );
+    let verbatim = format!("\\\\?\\C:{deep}\\sample.obj");
+    assert_eq!(long_path(Path::new(&verbatim)), Path::new(&verbatim));
+}
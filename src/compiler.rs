@@ -1,30 +1,43 @@
+use std::borrow::Cow;
 use std::cmp::max;
 use std::collections::hash_map;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::io::{stderr, stdout, Write};
+use std::fmt;
+use std::fs;
+use std::io::{stderr, stdout, BufRead, BufReader, IsTerminal, Read, Write};
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use ipc::Semaphore;
 use os_str_bytes::OsStrBytes;
 use path_absolutize::Absolutize;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tempfile::{NamedTempFile, TempDir};
 use thiserror::Error;
 
 use crate::cache::{Cache, FileHasher};
+use crate::checkpoint::Checkpoint;
 use crate::cmd;
 use crate::compiler::CompileInput::{Preprocessed, Source};
-use crate::config::Config;
+use crate::config::{Config, IncrementalPolicy, SchedulePolicy, UnclaimedPolicy, WrapperPolicy};
+use crate::dependencies::{DependencyDump, LineMarkers};
+use crate::history::TaskHistory;
+use crate::io::filecache::{EntryMetadata, OutputFile};
 use crate::io::memstream::MemStream;
 use crate::io::statistic::Statistic;
-use crate::utils::OsStrExt;
+use crate::io::tempfile::TempFile;
+use crate::reproduce::Reproducer;
+use crate::trace::Tracer;
+use crate::utils::{retry_spawn, wait_cancellable, Limiter, OsStrExt};
+use crate::xg::source::GraphSources;
 
 #[derive(Error, Debug)]
 pub enum CompilerError {
@@ -70,6 +83,8 @@ impl Scope {
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum InputKind {
     Source,
+    // Non-source input (object, library, resource), passed through without preprocessing.
+    Passthrough,
     Marker,
     Precompiled,
 }
@@ -78,16 +93,20 @@ pub enum InputKind {
 pub enum OutputKind {
     Object,
     Marker,
+    // Map file (`/Fm`)
+    Map,
+    // Clang module interface (`-fmodule-output`)
+    Module,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ParamForm {
     Separate,
     Combined,
     Smushed,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Arg {
     Flag {
         scope: Scope,
@@ -165,9 +184,87 @@ impl Arg {
             file: file.into(),
         }
     }
+
+    // Argument type and its scope or file kind.
+    #[must_use]
+    pub fn classification(&self) -> (&'static str, &'static str) {
+        let scope = |scope: &Scope| match scope {
+            Scope::Preprocessor => "preprocessor",
+            Scope::Compiler => "compiler",
+            Scope::Shared => "shared",
+            Scope::Ignore => "ignore",
+        };
+        match self {
+            Arg::Flag { scope: s, .. } => ("flag", scope(s)),
+            Arg::Param { scope: s, .. } => ("param", scope(s)),
+            Arg::Input { kind, .. } => (
+                "input",
+                match kind {
+                    InputKind::Source => "source",
+                    InputKind::Passthrough => "passthrough",
+                    InputKind::Marker => "marker",
+                    InputKind::Precompiled => "precompiled",
+                },
+            ),
+            Arg::Output { kind, .. } => (
+                "output",
+                match kind {
+                    OutputKind::Object => "object",
+                    OutputKind::Marker => "marker",
+                    OutputKind::Map => "map",
+                    OutputKind::Module => "module",
+                },
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arg::Flag { prefix, name, .. } => write!(f, "{prefix}{name}"),
+            Arg::Param {
+                prefix,
+                name,
+                value,
+                form,
+                ..
+            } => match form {
+                ParamForm::Separate => write!(f, "{prefix}{name} {value}"),
+                ParamForm::Combined => write!(f, "{prefix}{name}={value}"),
+                ParamForm::Smushed => write!(f, "{prefix}{name}{value}"),
+            },
+            Arg::Input { file, .. } => write!(f, "{file}"),
+            Arg::Output { name, file, .. } => write!(f, "{name}{file}"),
+        }
+    }
+}
+
+// How task arguments are classified and distributed between preprocessing and compilation.
+pub struct ArgsReport {
+    // Every argument with its type and scope or file kind.
+    pub classified: Vec<String>,
+    pub preprocess: Vec<String>,
+    pub compile: Vec<String>,
+    // Arguments not passed to compiler as is, with reason.
+    pub dropped: Vec<(String, &'static str)>,
+}
+
+impl fmt::Display for ArgsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for arg in &self.classified {
+            writeln!(f, "  {arg}")?;
+        }
+        writeln!(f, "  preprocess: {}", self.preprocess.join(" "))?;
+        writeln!(f, "  compile:    {}", self.compile.join(" "))?;
+        for (arg, reason) in &self.dropped {
+            writeln!(f, "  dropped:    {arg} ({reason})")?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct CommandEnv {
     map: HashMap<String, String>,
 }
@@ -181,31 +278,146 @@ pub struct CommandInfo {
 
 pub struct SharedState {
     pub semaphore: Semaphore,
+    preprocess_limit: Limiter,
+    compile_limit: Limiter,
     pub cache: Cache,
     pub statistic: Statistic,
     pub temp_dir: TempDir,
+    cache_partition: CachePartition,
+    // Feed preprocessed source to clang via stdin instead of temporary file.
+    pub clang_stdin: bool,
+    // Keep preprocessed sources compressed in memory until compilation.
+    pub compress_preprocessed: bool,
+    // Normalize line endings of preprocessed cl.exe output.
+    pub normalize_newlines: bool,
+    // Write preprocessed sources to this directory instead of compiling them.
+    pub preprocess_only: Option<PathBuf>,
+    // Bundle failed compilations for replaying elsewhere.
+    pub reproduce: Option<Reproducer>,
+    // Fail compilations which succeeded without writing declared outputs.
+    pub verify_outputs: bool,
+    // Fail compilations which produced malformed object file.
+    pub verify_objects: bool,
+    // Run independent tasks after failure instead of stopping the build.
+    pub keep_going: bool,
+    // Record task execution intervals for visualization.
+    pub trace: Option<Tracer>,
+    // Completed tasks of interrupted build to skip.
+    pub checkpoint: Option<Checkpoint>,
+    // Outcomes of tasks in recent builds for flakiness detection.
+    pub history: Option<TaskHistory>,
+    // Header dependencies of compiled objects.
+    pub dependencies: Option<DependencyDump>,
+    pub schedule_policy: SchedulePolicy,
+    // Task durations of previous build for scheduling.
+    pub task_durations: HashMap<String, Duration>,
+    // Task file formats, applications using octobuild as library may register their own.
+    pub graph_sources: GraphSources,
+    // Cancel the build when it runs longer.
+    pub max_wall_time: Option<Duration>,
+    // Set once the build is cancelled by timeout or termination request: running processes are
+    // killed, queued tasks are skipped.
+    cancelled: AtomicBool,
     use_response_files: bool,
 }
 
+// Which cache entries are reachable by task.
+enum CachePartition {
+    // Entries of all projects
+    Shared,
+    // Entries of tasks with the same working directory
+    WorkingDir,
+    // Entries of tasks with the same configured project identifier
+    Project(String),
+}
+
 #[derive(Default)]
 pub struct CompilerGroup(Vec<Box<dyn Compiler>>);
 
 impl SharedState {
     pub fn new(config: &Config) -> std::io::Result<Self> {
         let semaphore = Semaphore::new("octobuild-worker", max(config.process_limit, 1_usize))?;
+        let mut temp_dir = tempfile::Builder::new();
+        temp_dir.prefix("octobuild");
         Ok(SharedState {
             semaphore,
+            preprocess_limit: Limiter::new(config.preprocess_limit),
+            compile_limit: Limiter::new(config.compile_limit),
             cache: Cache::new(config),
             statistic: Statistic::new(),
-            temp_dir: tempfile::Builder::new().prefix("octobuild").tempdir()?,
+            temp_dir: match &config.temp_dir {
+                Some(dir) => temp_dir.tempdir_in(dir)?,
+                None => temp_dir.tempdir()?,
+            },
+            cache_partition: match (config.cache_isolation, &config.cache_project) {
+                (false, _) => CachePartition::Shared,
+                (true, None) => CachePartition::WorkingDir,
+                (true, Some(project)) => CachePartition::Project(project.clone()),
+            },
+            clang_stdin: config.clang_stdin,
+            compress_preprocessed: config.compress_preprocessed,
+            normalize_newlines: config.normalize_newlines,
+            preprocess_only: None,
+            reproduce: None,
+            keep_going: false,
+            verify_outputs: false,
+            verify_objects: false,
+            trace: None,
+            checkpoint: None,
+            history: None,
+            dependencies: None,
+            schedule_policy: config.schedule_policy,
+            task_durations: HashMap::new(),
+            graph_sources: GraphSources::new(),
+            max_wall_time: None,
+            cancelled: AtomicBool::new(false),
             use_response_files: config.use_response_files,
         })
     }
 
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    // Abort flag of the build, set by `cancel`.
+    #[must_use]
+    pub fn cancel_flag(&self) -> &AtomicBool {
+        &self.cancelled
+    }
+
+    // Run process collecting its output like `Command::output`, but kill it on build cancellation.
+    pub fn output(&self, command: &mut Command) -> std::io::Result<Output> {
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        self.wait_with_output(retry_spawn(|| command.spawn())?)
+    }
+
+    // Same as `Child::wait_with_output`, but kill process on build cancellation.
+    pub fn wait_with_output(&self, child: Child) -> std::io::Result<Output> {
+        wait_cancellable(child, &self.cancelled)
+    }
+
+    // Run compilation or other CPU-bound process.
     pub fn wrap_slow<T, F: FnOnce() -> T>(&self, func: F) -> T {
+        let limit = self.compile_limit.access();
         let guard = self.semaphore.access();
         let result = func();
         drop(guard);
+        drop(limit);
+        result
+    }
+
+    // Run preprocessing process. It is mostly I/O-bound, so only preprocess limit applies.
+    pub fn wrap_preprocess<T, F: FnOnce() -> T>(&self, func: F) -> T {
+        let limit = self.preprocess_limit.access();
+        let result = func();
+        drop(limit);
         result
     }
 
@@ -229,12 +441,40 @@ impl SharedState {
     }
 }
 
+// Inherited variables that are required to run any process.
+#[cfg(windows)]
+const ESSENTIAL_ENV: &[&str] = &["SystemDrive", "SystemRoot", "TEMP", "TMP"];
+#[cfg(not(windows))]
+const ESSENTIAL_ENV: &[&str] = &["TMPDIR"];
+
 impl CommandEnv {
     #[must_use]
     pub fn new() -> Self {
         CommandEnv::default()
     }
 
+    // Current process environment reduced to essential and explicitly allowed variables.
+    #[must_use]
+    pub fn isolated(passthrough: &[String]) -> Self {
+        env::vars().collect::<CommandEnv>().isolate(passthrough)
+    }
+
+    // This environment reduced to essential and explicitly allowed variables.
+    #[must_use]
+    pub fn isolate(&self, passthrough: &[String]) -> Self {
+        let mut result = CommandEnv::new();
+        for name in ESSENTIAL_ENV
+            .iter()
+            .copied()
+            .chain(passthrough.iter().map(String::as_str))
+        {
+            if let Some(value) = self.get(name) {
+                result.insert(name, value);
+            }
+        }
+        result
+    }
+
     pub fn get<K: Into<String>>(&self, key: K) -> Option<&str> {
         let value = self.map.get(&CommandEnv::normalize_key(key.into()))?;
         Some(value.as_str())
@@ -245,6 +485,10 @@ impl CommandEnv {
             .insert(CommandEnv::normalize_key(key.into()), value.into())
     }
 
+    pub fn remove<K: Into<String>>(&mut self, key: K) -> Option<String> {
+        self.map.remove(&CommandEnv::normalize_key(key.into()))
+    }
+
     #[must_use]
     pub fn iter(&self) -> hash_map::Iter<String, String> {
         self.map.iter()
@@ -289,6 +533,17 @@ impl CommandInfo {
         .to_path_buf())
     }
 
+    // Output file that compiler writes to working directory when output path is not given.
+    pub fn default_output(&self, source: &Path, extension: &str) -> crate::Result<PathBuf> {
+        let name = source.file_name().ok_or_else(|| {
+            crate::Error::Generic(format!(
+                "Input file path does not contain file name: {}",
+                source.display()
+            ))
+        })?;
+        self.absolutize(&Path::new(name).with_extension(extension))
+    }
+
     #[must_use]
     pub fn to_command(&self) -> Command {
         let mut command = Command::new(&self.program);
@@ -361,22 +616,32 @@ pub struct BuildTaskResult {
 
 impl BuildTaskResult {
     pub fn print_output(&self) -> crate::Result<()> {
+        self.write_output(&mut stdout(), &mut stderr())
+    }
+
+    // Write task output. Buffered stream is flushed before writing to another one, so
+    // streams sharing the same console keep their order.
+    pub fn write_output(&self, out: &mut impl Write, err: &mut impl Write) -> crate::Result<()> {
         match &self.output {
             Ok(output) => {
                 if !output.success() {
+                    out.flush()?;
                     writeln!(
-                        stderr(),
+                        err,
                         "ERROR: Task failed with exit code: {}",
                         output
                             .status
                             .map_or_else(|| "unknown".to_string(), |v| v.to_string())
                     )?;
+                    err.flush()?;
                 }
-                stdout().write_all(&output.stdout)?;
-                stderr().write_all(&output.stderr)?;
+                out.write_all(&output.stdout)?;
+                out.flush()?;
+                err.write_all(&output.stderr)?;
             }
             Err(e) => {
-                writeln!(stderr(), "ERROR: {e}")?;
+                out.flush()?;
+                writeln!(err, "ERROR: {e}")?;
             }
         }
         Ok(())
@@ -453,7 +718,99 @@ impl PCHUsage {
     }
 }
 
-#[derive(Debug)]
+// Options affecting compilation task creation.
+#[derive(Clone, Debug)]
+pub struct TaskOptions {
+    pub run_second_cpp: bool,
+    // Convert compiler search path environment variables to explicit arguments.
+    pub explicit_env_paths: bool,
+    // Extensions of compilable source files (lowercase, without dot).
+    pub source_extensions: Vec<String>,
+    pub unclaimed_policy: UnclaimedPolicy,
+    // Fail tasks which would run directly instead of through compiler backend.
+    pub require_backend: bool,
+    // Toolset versions pinned by source path pattern.
+    pub toolsets: Vec<(Regex, String)>,
+    // Compiler output is forwarded to terminal.
+    pub color_output: bool,
+    pub wrapper_policy: WrapperPolicy,
+    pub incremental_policy: IncrementalPolicy,
+    // Absolute paths of files with arguments injected into commands of each backend.
+    pub vs_args_file: Option<PathBuf>,
+    pub clang_args_file: Option<PathBuf>,
+}
+
+impl Default for TaskOptions {
+    fn default() -> Self {
+        TaskOptions::new(&Config::default())
+    }
+}
+
+impl TaskOptions {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        TaskOptions {
+            run_second_cpp: config.run_second_cpp,
+            explicit_env_paths: config.explicit_env_paths,
+            source_extensions: config.source_extensions.clone(),
+            unclaimed_policy: config.unclaimed_policy,
+            require_backend: false,
+            toolsets: config
+                .vs_toolsets
+                .iter()
+                .filter_map(|rule| Some((Regex::new(&rule.source).ok()?, rule.version.clone())))
+                .collect(),
+            color_output: stderr().is_terminal(),
+            wrapper_policy: config.wrapper_policy,
+            incremental_policy: config.incremental_policy,
+            vs_args_file: absolute_path(config.vs_args_file.as_deref()),
+            clang_args_file: absolute_path(config.clang_args_file.as_deref()),
+        }
+    }
+
+    // Command line arguments with args file prepended as response file, so command line
+    // arguments override injected ones. File is read for every task and its content becomes
+    // part of task arguments, so editing it invalidates cached results.
+    #[must_use]
+    pub fn with_args_file(file: Option<&Path>, args: &[String]) -> Vec<String> {
+        file.map(|path| format!("@{}", path.display()))
+            .into_iter()
+            .chain(args.iter().cloned())
+            .collect()
+    }
+
+    // Toolset version required for source file, if any.
+    #[must_use]
+    pub fn pinned_toolset(&self, source: &Path) -> Option<&str> {
+        let source = source.to_string_lossy();
+        self.toolsets
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(&source))
+            .map(|(_, version)| version.as_str())
+    }
+
+    // Mark inputs without recognized source extension as passthrough.
+    pub fn classify_inputs(&self, args: &mut [Arg]) {
+        for arg in args {
+            if let Arg::Input { kind, file } = arg {
+                if *kind == InputKind::Source && !self.is_source(Path::new(file)) {
+                    *kind = InputKind::Passthrough;
+                }
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn is_source(&self, path: &Path) -> bool {
+        path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+            self.source_extensions
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(ext))
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct CompilationArgs {
     // Original compiler executable.
     pub command: CommandInfo,
@@ -461,7 +818,11 @@ pub struct CompilationArgs {
     pub args: Vec<Arg>,
     pub pch_usage: PCHUsage,
     pub deps_file: Option<PathBuf>,
+    // Files that affect compilation result besides preprocessed source (absolute paths).
+    pub referenced_files: Vec<PathBuf>,
     pub run_second_cpp: bool,
+    // Ask compiler for colored diagnostics.
+    pub color_diagnostics: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -476,9 +837,125 @@ pub struct CompilationTask {
     pub output_object: PathBuf,
 }
 
+impl CompilationTask {
+    // Describe how arguments are classified and which of them toolchain passes to each phase.
+    pub fn args_report(&self, toolchain: &dyn Toolchain) -> crate::Result<ArgsReport> {
+        let phase_args = |phase| -> crate::Result<Vec<String>> {
+            Ok(toolchain
+                .phase_args(self, phase)?
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect())
+        };
+        let mut report = ArgsReport {
+            classified: Vec::new(),
+            preprocess: phase_args(Scope::Preprocessor)?,
+            compile: phase_args(Scope::Compiler)?,
+            dropped: Vec::new(),
+        };
+        for arg in &self.shared.args {
+            let (kind, class) = arg.classification();
+            report
+                .classified
+                .push(format!("{kind:<6} {class:<12} {arg}"));
+            let reason = match arg {
+                Arg::Flag { scope, .. } | Arg::Param { scope, .. } => {
+                    if *scope != Scope::Ignore {
+                        continue;
+                    }
+                    "set by octobuild for each phase"
+                }
+                Arg::Input { kind, .. } => match kind {
+                    InputKind::Source => "source file is passed by octobuild",
+                    InputKind::Passthrough => "not a source file",
+                    InputKind::Marker | InputKind::Precompiled => {
+                        "precompiled header is passed by octobuild"
+                    }
+                },
+                Arg::Output { .. } => "output path is set by octobuild",
+            };
+            report.dropped.push((arg.to_string(), reason));
+        }
+        Ok(report)
+    }
+
+    // Map file path (absolute). Without file name (`/Fm`, `/Fm<dir>\`) it is named after source.
+    pub fn map_file(&self) -> crate::Result<Option<PathBuf>> {
+        let Some(file) = self.shared.args.iter().rev().find_map(|arg| match arg {
+            Arg::Output {
+                kind: OutputKind::Map,
+                file,
+                ..
+            } => Some(file),
+            _ => None,
+        }) else {
+            return Ok(None);
+        };
+        let command = &self.shared.command;
+        if file.is_empty() {
+            return command.default_output(&self.input_source, "map").map(Some);
+        }
+        let path = command.absolutize(Path::new(file))?;
+        if !file.ends_with(['/', '\\']) && !path.is_dir() {
+            return Ok(Some(path));
+        }
+        let name = command.default_output(&self.input_source, "map")?;
+        Ok(Some(path.join(name.file_name().unwrap_or_default())))
+    }
+
+    // Module interface file path (absolute). Without file name it is placed next to object.
+    pub fn module_file(&self) -> crate::Result<Option<PathBuf>> {
+        let Some(file) = self.shared.args.iter().rev().find_map(|arg| match arg {
+            Arg::Output {
+                kind: OutputKind::Module,
+                file,
+                ..
+            } => Some(file),
+            _ => None,
+        }) else {
+            return Ok(None);
+        };
+        if file.is_empty() {
+            return Ok(Some(self.output_object.with_extension("pcm")));
+        }
+        self.shared.command.absolutize(Path::new(file)).map(Some)
+    }
+
+    // Directories searched for includes: source directory and explicit include paths (absolute).
+    #[must_use]
+    pub fn include_dirs(&self) -> Vec<PathBuf> {
+        let command = &self.shared.command;
+        let explicit = self.shared.args.iter().filter_map(|arg| match arg {
+            Arg::Param {
+                scope: Scope::Preprocessor,
+                name,
+                value,
+                ..
+            } if INCLUDE_DIR_PARAMS.contains(&name.as_str()) => Some(Path::new(value)),
+            _ => None,
+        });
+        let mut dirs: Vec<PathBuf> = Vec::new();
+        for dir in self.input_source.parent().into_iter().chain(explicit) {
+            if let Ok(dir) = command.absolutize(dir) {
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+        dirs
+    }
+}
+
+// Parameters adding include directory for cl.exe and clang.
+const INCLUDE_DIR_PARAMS: &[&str] = &["I", "external:I", "isystem", "iquote", "idirafter"];
+
 pub struct SourceInput {
     pub path: PathBuf,
+    // Original source when `path` is its copy, quoted includes are searched in its directory.
+    pub origin: Option<PathBuf>,
     pub current_dir: Option<PathBuf>,
+    // Directories searched for includes, required on builder compiling source.
+    pub include_dirs: Vec<PathBuf>,
 }
 
 pub enum CompileInput {
@@ -493,7 +970,11 @@ pub struct CompileStep {
     pub output_object: Option<PathBuf>,
     pub pch_usage: PCHUsage,
     pub input: CompileInput,
+    // Source language as detected by toolchain.
+    pub language: String,
     pub run_second_cpp: bool,
+    // Arguments that don't affect compilation result and are excluded from cache key.
+    pub extra_args: Vec<OsString>,
 }
 
 impl CompileStep {
@@ -506,12 +987,16 @@ impl CompileStep {
             input: if task.shared.run_second_cpp {
                 Source(SourceInput {
                     path: task.input_source.clone(),
+                    origin: None,
                     current_dir: task.shared.command.current_dir.clone(),
+                    include_dirs: task.include_dirs(),
                 })
             } else {
                 Preprocessed(preprocessed)
             },
+            language: task.language.clone(),
             run_second_cpp: task.shared.run_second_cpp,
+            extra_args: Vec::new(),
         }
     }
 }
@@ -519,6 +1004,18 @@ impl CompileStep {
 pub enum CompilerOutput {
     MemSteam(MemStream),
     Vec(Vec<u8>),
+    Compressed(CompressedOutput),
+}
+
+// LZ4 compressed content, decompressed on every read. Cache key part and triviality are computed
+// from original content on compression, so only compilation needs to decompress it.
+pub struct CompressedOutput {
+    pub(crate) data: Vec<u8>,
+    len: usize,
+    // Hasher fed with content length and content.
+    hasher: Sha256,
+    trivial: bool,
+    ascii: bool,
 }
 
 impl CompilerOutput {
@@ -529,15 +1026,46 @@ impl CompilerOutput {
                 writer.write_all(v)?;
                 Ok(v.len())
             }
+            CompilerOutput::Compressed(v) => {
+                let written = std::io::copy(&mut lz4::Decoder::new(v.data.as_slice())?, writer)?;
+                Ok(written as usize)
+            }
         }
     }
 
+    // Keep content compressed in memory, trading CPU time for memory while task waits for compilation.
+    pub fn compress(self) -> std::io::Result<CompilerOutput> {
+        if let CompilerOutput::Compressed(..) = self {
+            return Ok(self);
+        }
+        let hasher = self.content_hasher()?;
+        let mut stream = lz4::EncoderBuilder::new().level(1).build(Vec::new())?;
+        self.copy(&mut stream)?;
+        let (data, result) = stream.finish();
+        result?;
+        Ok(CompilerOutput::Compressed(CompressedOutput {
+            data,
+            len: self.len(),
+            hasher,
+            trivial: self.is_trivial(),
+            ascii: self.is_ascii(),
+        }))
+    }
+
+    // Hasher fed with content length and content.
+    fn content_hasher(&self) -> std::io::Result<Sha256> {
+        if let CompilerOutput::Compressed(v) = self {
+            return Ok(v.hasher.clone());
+        }
+        let mut hasher = Sha256::new();
+        hasher.hash_u64(self.len() as u64);
+        self.copy(&mut hasher)?;
+        Ok(hasher)
+    }
+
     #[must_use]
     pub fn is_empty(&self) -> bool {
-        match self {
-            CompilerOutput::MemSteam(v) => v.is_empty(),
-            CompilerOutput::Vec(v) => v.is_empty(),
-        }
+        self.len() == 0
     }
 
     #[must_use]
@@ -545,15 +1073,47 @@ impl CompilerOutput {
         match self {
             CompilerOutput::MemSteam(v) => v.len(),
             CompilerOutput::Vec(v) => v.len(),
+            CompilerOutput::Compressed(v) => v.len,
         }
     }
 
-    pub fn to_vec(&self) -> Vec<u8> {
-        match self {
+    pub fn to_vec(&self) -> crate::Result<Vec<u8>> {
+        Ok(match self {
             CompilerOutput::MemSteam(v) => From::from(v),
             CompilerOutput::Vec(v) => v.clone(),
+            CompilerOutput::Compressed(v) => {
+                let mut result = Vec::with_capacity(v.len);
+                self.copy(&mut result)?;
+                result
+            }
+        })
+    }
+
+    // Check that content has only ASCII characters, which are read the same in any code page.
+    #[must_use]
+    pub fn is_ascii(&self) -> bool {
+        match self {
+            CompilerOutput::MemSteam(v) => v.iter().all(<[u8]>::is_ascii),
+            CompilerOutput::Vec(v) => v.is_ascii(),
+            CompilerOutput::Compressed(v) => v.ascii,
         }
     }
+
+    // Check that preprocessed source has no code, only blank lines and directives (`#line`, `#pragma`).
+    #[must_use]
+    pub fn is_trivial(&self) -> bool {
+        let reader: Box<dyn BufRead + '_> = match self {
+            CompilerOutput::MemSteam(v) => Box::new(BufReader::new(v.reader())),
+            CompilerOutput::Vec(v) => Box::new(v.as_slice()),
+            CompilerOutput::Compressed(v) => return v.trivial,
+        };
+        reader.split(b'\n').all(|line| {
+            line.is_ok_and(|line| match line.trim_ascii_start().first() {
+                None | Some(b'#') => true,
+                Some(_) => false,
+            })
+        })
+    }
 }
 
 pub enum PreprocessResult {
@@ -564,13 +1124,17 @@ pub enum PreprocessResult {
 pub trait Toolchain: Send + Sync {
     // Get toolchain identificator.
     fn identifier(&self) -> Option<String>;
+    // Compiler family name for build summary.
+    fn compiler(&self) -> &'static str {
+        "other"
+    }
 
     // Parse compiler arguments.
     fn create_tasks(
         &self,
         command: CommandInfo,
         args: &[String],
-        run_second_cpp: bool,
+        options: &TaskOptions,
     ) -> crate::Result<Vec<CompilationTask>>;
     // Preprocessing source file.
     fn run_preprocess(
@@ -583,19 +1147,77 @@ pub trait Toolchain: Send + Sync {
         task: &CompilationTask,
         preprocessed: CompilerOutput,
     ) -> crate::Result<CompileStep>;
+    // Task arguments passed to compiler in given phase (`Scope::Preprocessor` or `Scope::Compiler`).
+    fn phase_args(&self, task: &CompilationTask, phase: Scope) -> crate::Result<Vec<OsString>>;
 
     // Compile preprocessed file.
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo>;
 
+    // Compile task from source without local preprocessing, result is not cached.
+    // Returns None when toolchain doesn't support it for the task.
+    fn compile_source(
+        &self,
+        _state: &SharedState,
+        _task: &CompilationTask,
+    ) -> Option<crate::Result<OutputInfo>> {
+        None
+    }
+
+    // Compile preprocessed source. Object file content is returned when step has no output path,
+    // so compilation doesn't depend on local preprocessing or output location.
+    fn compile_preprocessed(
+        &self,
+        state: &SharedState,
+        mut step: CompileStep,
+    ) -> crate::Result<(OutputInfo, Vec<u8>)> {
+        if step.output_object.is_some() {
+            return Ok((self.run_compile(state, step)?, Vec::new()));
+        }
+        let temp = TempFile::new_in(state.temp_dir.path(), ".o");
+        step.output_object = Some(temp.path().to_path_buf());
+        let output = self.run_compile(state, step)?;
+        let object = if output.success() {
+            fs::read(temp.path())?
+        } else {
+            Vec::new()
+        };
+        Ok((output, object))
+    }
+
+    // Where compilation runs.
+    fn backend(&self) -> &'static str {
+        "local"
+    }
+
     fn compile_task(
         &self,
         state: &SharedState,
         task: &CompilationTask,
     ) -> crate::Result<OutputInfo> {
+        if state.preprocess_only.is_none() && state.reproduce.is_none() {
+            if let Some(output) = self.compile_source(state, task) {
+                return output;
+            }
+        }
         let preprocessed = self.run_preprocess(state, task)?;
         match preprocessed {
-            PreprocessResult::Success(preprocessed) => {
-                self.run_compile_cached(state, task, preprocessed)
+            PreprocessResult::Success(mut preprocessed) => {
+                if let Some(dump) = &state.dependencies {
+                    let mut markers = LineMarkers::default();
+                    preprocessed.copy(&mut markers)?;
+                    dump.add(task, markers);
+                }
+                if state.compress_preprocessed {
+                    // Preprocessed source may wait long for compilation slot
+                    preprocessed = preprocessed.compress()?;
+                }
+                match (&state.preprocess_only, &state.reproduce) {
+                    (Some(dir), _) => self.write_preprocessed(state, task, preprocessed, dir),
+                    (None, Some(reproducer)) => {
+                        self.compile_reproducible(state, task, preprocessed, reproducer)
+                    }
+                    (None, None) => self.run_compile_cached(state, task, preprocessed),
+                }
             }
             PreprocessResult::Failed(output) => Ok(OutputInfo {
                 status: output.status,
@@ -607,21 +1229,154 @@ pub trait Toolchain: Send + Sync {
         }
     }
 
+    // Write preprocessed source to `<cache key>.i` file in directory instead of compiling it.
+    fn write_preprocessed(
+        &self,
+        state: &SharedState,
+        task: &CompilationTask,
+        preprocessed: CompilerOutput,
+        dir: &Path,
+    ) -> crate::Result<OutputInfo> {
+        fs::create_dir_all(dir)?;
+        let mut temp = NamedTempFile::new_in(dir)?;
+        preprocessed.copy(&mut temp)?;
+        let (hash, _) = self.prepare_compile_step(state, task, preprocessed)?;
+        let path = dir.join(hash + ".i");
+        temp.persist(&path).map_err(|e| e.error)?;
+        Ok(OutputInfo {
+            status: Some(0),
+            stdout: format!("Preprocessed to {}\n", path.display()).into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+
+    // Compile and bundle everything needed to replay compilation if it fails.
+    fn compile_reproducible(
+        &self,
+        state: &SharedState,
+        task: &CompilationTask,
+        preprocessed: CompilerOutput,
+        reproducer: &Reproducer,
+    ) -> crate::Result<OutputInfo> {
+        let mut source = Vec::with_capacity(preprocessed.len());
+        preprocessed.copy(&mut source)?;
+        let output = self.run_compile_cached(state, task, preprocessed)?;
+        if !output.success() {
+            let step = self.create_compile_step(task, CompilerOutput::Vec(Vec::new()))?;
+            reproducer.add(self.identifier(), task, &step.args, &source, &output)?;
+        }
+        Ok(output)
+    }
+
     fn run_compile_cached(
         &self,
         state: &SharedState,
         task: &CompilationTask,
         preprocessed: CompilerOutput,
     ) -> crate::Result<OutputInfo> {
-        let mut hasher = Sha256::new();
+        let (hash, step) = self.prepare_compile_step(state, task, preprocessed)?;
+
+        // Output files list
+        let mut outputs: Vec<OutputFile> = Vec::new();
+        if let Some(path) = &step.output_object {
+            assert!(path.is_absolute());
+            outputs.push(OutputFile::new("object", path.clone()));
+        }
+        if let Some(path) = step.pch_usage.get_out_abs() {
+            assert!(path.is_absolute());
+            outputs.push(OutputFile::new("pch", path.clone()));
+        }
+        if let Some(path) = task.map_file()? {
+            outputs.push(OutputFile::new("map", path));
+        }
+        if let Some(path) = task.module_file()? {
+            outputs.push(OutputFile::new("module", path));
+        }
+        let source = task.shared.command.absolutize(&task.input_source)?;
+        let pinned = state.cache.is_pinned(
+            std::iter::once(source.as_path()).chain(outputs.iter().map(|v| v.path.as_path())),
+        );
+
+        let verify = if state.verify_outputs {
+            outputs.clone()
+        } else {
+            Vec::new()
+        };
+
+        let object = step.output_object.clone();
+
+        // Try to get files from cache or run
+        let mut compiled = false;
+        let output = state.cache.run_file_cached(
+            &state.statistic,
+            &hash,
+            outputs,
+            || -> crate::Result<OutputInfo> {
+                compiled = true;
+                let output = self.compile_preprocessed(state, step)?.0;
+                // Checked before storing, nonexistent output can't be cached
+                if let Some(missing) = verify.iter().find(|v| !v.path.is_file()) {
+                    if output.success() {
+                        return Err(crate::Error::MissingOutput {
+                            input: task.input_source.clone(),
+                            role: missing.role,
+                            output: missing.path.clone(),
+                        });
+                    }
+                }
+                if state.verify_objects && output.success() {
+                    if let Some(path) = &object {
+                        check_object_file(path)?;
+                    }
+                }
+                Ok(output)
+            },
+        )?;
+        if output.success() {
+            if compiled {
+                state.cache.write_metadata(
+                    &hash,
+                    &EntryMetadata::new(&task.input_source, self.identifier().as_deref()),
+                );
+            }
+            // Cache hit updates pin too, pin globs may change since entry was stored.
+            state.cache.set_pinned(&hash, pinned);
+        }
+
+        Ok(output)
+    }
+
+    // Create compile step and calculate its cache key.
+    fn prepare_compile_step(
+        &self,
+        state: &SharedState,
+        task: &CompilationTask,
+        preprocessed: CompilerOutput,
+    ) -> crate::Result<(String, CompileStep)> {
         // Get hash from preprocessed data
-        hasher.hash_u64(preprocessed.len() as u64);
-        preprocessed.copy(&mut hasher)?;
+        let mut hasher = preprocessed.content_hasher()?;
 
         if let Some(identifier) = self.identifier() {
             hasher.hash_str(&identifier);
         }
 
+        // Sources without code (for example, `#if 0` around whole file) are indistinguishable
+        // after preprocessing, keep their entries apart.
+        if preprocessed.is_trivial() {
+            let source = task.shared.command.absolutize(&task.input_source)?;
+            hasher.hash_os_string(source.as_os_str());
+        }
+
+        // Hash cache partition of project
+        match &state.cache_partition {
+            CachePartition::Shared => {}
+            CachePartition::WorkingDir => {
+                let dir = task.shared.command.current_dir.as_deref();
+                hasher.hash_os_string(dir.map_or(OsStr::new(""), Path::as_os_str));
+            }
+            CachePartition::Project(project) => hasher.hash_str(project),
+        }
+
         let step = self.create_compile_step(task, preprocessed)?;
 
         // Hash arguments
@@ -641,25 +1396,17 @@ pub trait Toolchain: Send + Sync {
         }
         // Store output precompiled flag
         hasher.hash_u8(u8::from(step.pch_usage.is_out()));
-
-        // Output files list
-        let mut outputs: Vec<PathBuf> = Vec::new();
-        if let Some(path) = &step.output_object {
-            assert!(path.is_absolute());
-            outputs.push(path.clone());
+        // Entries with module interface output have more files.
+        if task.module_file()?.is_some() {
+            hasher.hash_str("module");
         }
-        if let Some(path) = step.pch_usage.get_out_abs() {
+        // Hash referenced files
+        hasher.hash_u64(task.shared.referenced_files.len() as u64);
+        for path in &task.shared.referenced_files {
             assert!(path.is_absolute());
-            outputs.push(path.clone());
+            hasher.hash_str(&state.cache.file_hash(path)?.hash);
         }
-
-        // Try to get files from cache or run
-        state.cache.run_file_cached(
-            &state.statistic,
-            &hex::encode(hasher.finalize()),
-            outputs,
-            || -> crate::Result<OutputInfo> { self.run_compile(state, step) },
-        )
+        Ok((hex::encode(hasher.finalize()), step))
     }
 }
 
@@ -687,9 +1434,12 @@ impl Compiler for CompilerGroup {
             .flat_map(|c| c.discover_toolchains())
             .collect()
     }
+    fn find_toolset(&self, command: &CommandInfo, version: &str) -> Option<PathBuf> {
+        self.0.iter().find_map(|c| c.find_toolset(command, version))
+    }
 }
 
-trait Hasher: Digest {
+pub(crate) trait Hasher: Digest {
     fn hash_u64(&mut self, number: u64) {
         let mut n = number;
         let mut buf: [u8; 8] = [0; 8];
@@ -793,32 +1543,142 @@ pub trait Compiler: Send + Sync {
     fn resolve_toolchain(&self, command: &CommandInfo) -> Option<Arc<dyn Toolchain>>;
     // Discover local toolchains.
     fn discover_toolchains(&self) -> Vec<Arc<dyn Toolchain>>;
+    // Find executable of specific toolset version that can replace command program.
+    fn find_toolset(&self, _command: &CommandInfo, _version: &str) -> Option<PathBuf> {
+        None
+    }
 
     fn create_tasks(
         &self,
         command: CommandInfo,
         args: CommandArgs,
-        run_second_cpp: bool,
+        options: &TaskOptions,
     ) -> crate::Result<Vec<ToolchainCompilationTask>> {
-        let toolchain = self
-            .resolve_toolchain(&command)
-            .ok_or_else(|| crate::Error::ToolchainNotFound(command.program.clone()))?;
-
         let argv = match args {
             CommandArgs::Raw(v) => cmd::native::parse(&v)?,
             CommandArgs::Regular(v) => v,
         };
+        let (command, argv) = match wrapped_compiler(&command, &argv) {
+            None => (command, argv),
+            // Run as is, wrapper caches by itself.
+            Some(_) if options.wrapper_policy == WrapperPolicy::Bypass => return Ok(Vec::new()),
+            Some(program) => (CommandInfo { program, ..command }, argv[1..].to_vec()),
+        };
 
-        let tasks = toolchain.create_tasks(command, &argv, run_second_cpp)?;
+        let toolchain = self
+            .resolve_toolchain(&command)
+            .ok_or_else(|| crate::Error::ToolchainNotFound(command.program.clone()))?;
 
-        Ok(tasks
-            .into_iter()
-            .map(|task| ToolchainCompilationTask {
-                toolchain: toolchain.clone(),
-                task,
-            })
-            .collect())
+        let tasks = toolchain.create_tasks(command.clone(), &argv, options)?;
+
+        // Tasks for sources with pinned toolset are recreated with its executable.
+        // Toolset identifier differs between versions, so it also changes cache key.
+        let mut pinned = HashMap::<&str, (Arc<dyn Toolchain>, Vec<CompilationTask>)>::new();
+        let mut result = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            let Some(version) = options.pinned_toolset(&task.input_source) else {
+                result.push(ToolchainCompilationTask {
+                    toolchain: toolchain.clone(),
+                    task,
+                });
+                continue;
+            };
+            let not_found = || crate::Error::ToolsetNotFound {
+                version: version.to_string(),
+                path: task.input_source.clone(),
+            };
+            let (pinned_toolchain, pinned_tasks) = match pinned.entry(version) {
+                hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                hash_map::Entry::Vacant(entry) => {
+                    let program = self.find_toolset(&command, version).ok_or_else(not_found)?;
+                    let pinned_command = CommandInfo {
+                        program,
+                        ..command.clone()
+                    };
+                    let pinned_toolchain = self
+                        .resolve_toolchain(&pinned_command)
+                        .ok_or_else(not_found)?;
+                    let pinned_tasks =
+                        pinned_toolchain.create_tasks(pinned_command, &argv, options)?;
+                    entry.insert((pinned_toolchain, pinned_tasks))
+                }
+            };
+            let task = pinned_tasks
+                .iter()
+                .find(|pinned| pinned.input_source == task.input_source)
+                .ok_or_else(not_found)?;
+            result.push(ToolchainCompilationTask {
+                toolchain: pinned_toolchain.clone(),
+                task: task.clone(),
+            });
+        }
+        Ok(result)
+    }
+}
+
+fn absolute_path(path: Option<&Path>) -> Option<PathBuf> {
+    path.map(|path| {
+        path.absolutize()
+            .map_or_else(|_| path.to_path_buf(), Cow::into_owned)
+    })
+}
+
+// Compiler wrappers taking wrapped compiler as first argument.
+const COMPILER_WRAPPERS: &[&str] = &["ccache", "sccache", "distcc"];
+
+// Compiler of `<wrapper> <compiler> <args>` command.
+fn wrapped_compiler(command: &CommandInfo, argv: &[String]) -> Option<PathBuf> {
+    let is_wrapper = command
+        .program
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| {
+            COMPILER_WRAPPERS
+                .iter()
+                .any(|wrapper| name.eq_ignore_ascii_case(wrapper))
+        });
+    // Without explicit compiler wrapper runs default one.
+    match argv.first() {
+        Some(program) if is_wrapper && !program.starts_with('-') => Some(PathBuf::from(program)),
+        _ => None,
+    }
+}
+
+// Known object file signatures: ELF, Mach-O, LLVM bitcode (LTO) and anonymous COFF header (/GL, /bigobj).
+const OBJECT_MAGICS: &[&[u8]] = &[
+    b"\x7fELF",
+    b"\xfe\xed\xfa\xce",
+    b"\xfe\xed\xfa\xcf",
+    b"\xce\xfa\xed\xfe",
+    b"\xcf\xfa\xed\xfe",
+    b"BC\xc0\xde",
+    b"\xde\xc0\x17\x0b",
+    b"\x00\x00\xff\xff",
+];
+
+// COFF machine types: i386, AMD64, ARM, ARMv7 Thumb-2 and ARM64.
+const COFF_MACHINES: &[u16] = &[0x014c, 0x8664, 0x01c0, 0x01c4, 0xaa64];
+
+// Check that object file is not empty and starts with known signature.
+pub fn check_object_file(path: &Path) -> crate::Result<()> {
+    let malformed = |reason: &'static str| crate::Error::MalformedObject {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let mut header = [0u8; 4];
+    let size = fs::File::open(path)
+        .and_then(|mut file| file.read(&mut header))
+        .map_err(|_| malformed("file can't be read"))?;
+    if size == 0 {
+        return Err(malformed("file is empty"));
+    }
+    if size == header.len() && OBJECT_MAGICS.contains(&header.as_slice()) {
+        return Ok(());
+    }
+    if size >= 2 && COFF_MACHINES.contains(&u16::from_le_bytes([header[0], header[1]])) {
+        return Ok(());
     }
+    Err(malformed("unknown object file signature"))
 }
 
 #[derive(Default)]
@@ -1,30 +1,128 @@
+use std::cmp::max;
+use std::env;
 use std::io::Write;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 
-use figment::providers::{Env, Format, Serialized, Yaml};
+use figment::providers::{Env, Format, Serialized, Toml, Yaml};
 use figment::Figment;
 
+use crate::utils::glob_to_regex;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum CacheMode {
     None,
     ReadOnly,
     ReadWrite,
+    // Don't read existing entries, but store every result (cache warming)
+    WriteOnly,
+}
+
+impl CacheMode {
+    #[must_use]
+    pub fn can_read(self) -> bool {
+        matches!(self, CacheMode::ReadOnly | CacheMode::ReadWrite)
+    }
+
+    #[must_use]
+    pub fn can_write(self) -> bool {
+        matches!(self, CacheMode::ReadWrite | CacheMode::WriteOnly)
+    }
+}
+
+// What to do with commands that no supported compiler recognizes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum UnclaimedPolicy {
+    // Run command directly
+    Passthrough,
+    // Run command directly and log warning
+    Warn,
+    // Fail task
+    Error,
+}
+
+// What to do with commands run through compiler wrapper (ccache, sccache, distcc).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum WrapperPolicy {
+    // Drop wrapper and cache wrapped compiler command
+    Strip,
+    // Run command directly and let wrapper do caching
+    Bypass,
+}
+
+// What to do with compiler-maintained incremental build state (`/Gm`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum IncrementalPolicy {
+    // Drop flag and cache command as usual
+    Strip,
+    // Run command directly without caching
+    Bypass,
+}
+
+// Order in which ready tasks are run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum SchedulePolicy {
+    // In order of discovery
+    Discovery,
+    // Tasks with most dependent tasks first
+    Descendants,
+    // Tasks slowest in previous build first
+    Duration,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+// Visual Studio toolset version required for sources matching regular expression.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ToolsetRule {
+    pub source: String,
+    pub version: String,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Config {
+    // Builder accepts sources and preprocesses them with its own include tree.
+    pub builder_preprocess: bool,
     pub cache: PathBuf,
     pub cache_mode: CacheMode,
     pub cache_limit_mb: u64,
+    // Globs of source or output paths which cache entries are kept on cleanup.
+    pub cache_pin: Vec<String>,
     pub cache_compression_level: u32,
+    pub cache_dedup: bool,
+    // Separate cache entries of different projects.
+    pub cache_isolation: bool,
+    // Project identifier for cache isolation, task working directory is used if not set.
+    pub cache_project: Option<String>,
+    pub cache_reflink: bool,
+    // Retries of cache filesystem operations failed with transient error.
+    pub cache_retries: u32,
+    pub cache_strip_color: bool,
+    // File with arguments added to every clang command.
+    pub clang_args_file: Option<PathBuf>,
+    pub clang_stdin: bool,
+    // Keep preprocessed sources compressed in memory until compilation.
+    pub compress_preprocessed: bool,
     pub coordinator: Option<url::Url>,
     pub coordinator_bind: SocketAddr,
+    pub env_passthrough: Vec<String>,
+    pub explicit_env_paths: bool,
     pub helper_bind: SocketAddr,
+    pub incremental_policy: IncrementalPolicy,
+    // Hash preprocessed cl.exe output with LF line endings and without BOM.
+    pub normalize_newlines: bool,
     pub process_limit: usize,
+    pub preprocess_limit: usize,
+    pub compile_limit: usize,
     pub run_second_cpp: bool,
+    pub schedule_policy: SchedulePolicy,
+    pub source_extensions: Vec<String>,
+    pub temp_dir: Option<PathBuf>,
+    pub unclaimed_policy: UnclaimedPolicy,
     pub use_response_files: bool,
+    pub vs_toolsets: Vec<ToolsetRule>,
+    // File with arguments added to every cl.exe command.
+    pub vs_args_file: Option<PathBuf>,
+    pub wrapper_policy: WrapperPolicy,
 }
 
 #[must_use]
@@ -42,21 +140,58 @@ const DEFAULT_USE_RESPONSE_FILES: bool = false;
 impl Default for Config {
     fn default() -> Self {
         Self {
+            builder_preprocess: false,
             cache: project_dirs().cache_dir().into(),
             cache_mode: CacheMode::ReadWrite,
             cache_limit_mb: 64 * 1024,
+            cache_pin: Vec::new(),
             cache_compression_level: 1,
+            cache_dedup: false,
+            cache_isolation: false,
+            cache_project: None,
+            cache_reflink: false,
+            cache_retries: 3,
+            cache_strip_color: true,
+            clang_args_file: None,
+            clang_stdin: true,
+            compress_preprocessed: false,
             coordinator: None,
             coordinator_bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 3000)),
+            env_passthrough: Vec::new(),
+            explicit_env_paths: false,
             helper_bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
+            incremental_policy: IncrementalPolicy::Strip,
+            normalize_newlines: false,
             process_limit: num_cpus::get(),
+            preprocess_limit: num_cpus::get() * 2,
+            compile_limit: num_cpus::get(),
             run_second_cpp: true,
+            schedule_policy: SchedulePolicy::Discovery,
+            source_extensions: ["c", "cc", "cpp", "cxx", "c++", "cppm", "h", "hpp"]
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            temp_dir: None,
+            unclaimed_policy: UnclaimedPolicy::Passthrough,
             use_response_files: DEFAULT_USE_RESPONSE_FILES,
+            vs_toolsets: Vec::new(),
+            vs_args_file: None,
+            wrapper_policy: WrapperPolicy::Strip,
         }
     }
 }
 
+// Environment variable with explicit path to TOML configuration file.
+const CONFIG_ENV: &str = "OCTOBUILD_CONFIG";
+
 impl Config {
+    // Number of build workers, enough to reach both preprocess and compile limits.
+    #[must_use]
+    pub fn worker_limit(&self) -> usize {
+        max(self.preprocess_limit, self.compile_limit)
+    }
+
+    // Precedence (highest first): environment variables, TOML file, YAML files, built-in defaults.
     pub fn load() -> crate::Result<Config> {
         let mut figment = Figment::from(Serialized::defaults(Config::default()));
 
@@ -67,15 +202,165 @@ impl Config {
             figment = figment.merge(Yaml::file(path));
         }
 
-        Ok(figment.merge(Env::prefixed("OCTOBUILD_")).extract()?)
+        if let Some(path) = toml_config_path()? {
+            figment = figment.merge(Toml::file(path));
+        }
+
+        Config::extract(figment)
+    }
+
+    // Precedence (highest first): environment variables, TOML file, built-in defaults.
+    pub fn from_file(path: &Path) -> crate::Result<Config> {
+        Config::extract(
+            Figment::from(Serialized::defaults(Config::default())).merge(Toml::file(path)),
+        )
+    }
+
+    fn extract(figment: Figment) -> crate::Result<Config> {
+        let config: Config = figment
+            .merge(Env::prefixed("OCTOBUILD_").ignore(&["config"]))
+            .extract()?;
+        if config.preprocess_limit == 0 || config.compile_limit == 0 {
+            return Err(crate::Error::Generic(
+                "preprocess_limit and compile_limit must be positive".to_string(),
+            ));
+        }
+        for glob in &config.cache_pin {
+            glob_to_regex(glob)
+                .map_err(|e| crate::Error::Generic(format!("invalid cache_pin glob: {e}")))?;
+        }
+        for rule in &config.vs_toolsets {
+            regex::Regex::new(&rule.source).map_err(|e| {
+                crate::Error::Generic(format!("invalid vs_toolsets source pattern: {e}"))
+            })?;
+        }
+        Ok(config)
     }
 
     pub fn print_help(&self, executable: &str, out: &mut impl Write) -> crate::Result<()> {
         writeln!(out)?;
         writeln!(out, "Usage:")?;
-        writeln!(out, "  {} <file>", executable)?;
+        writeln!(out, "  {} <file>... [options]", executable)?;
         writeln!(out, "  {} /reset", executable)?;
-        writeln!(out,)?;
+        writeln!(out, "  {} /VerifyCache [/Delete]", executable)?;
+        writeln!(out, "  {} /CacheShow=KEY", executable)?;
+        writeln!(out, "  {} /CacheExtract=KEY --to=DIR", executable)?;
+        writeln!(out)?;
+        writeln!(out, "Options:")?;
+        writeln!(
+            out,
+            "  --print-stats-on-signal  print cache statistic on SIGUSR1 (Ctrl+Break on Windows)"
+        )?;
+        writeln!(
+            out,
+            "  --toolchain=ID           compile remotely only on builders with given toolchain"
+        )?;
+        writeln!(
+            out,
+            "  --builder-capacity-aware send tasks to remote builders with most free capacity"
+        )?;
+        writeln!(
+            out,
+            "  --remote-preprocess      let capable remote builders preprocess sources"
+        )?;
+        writeln!(
+            out,
+            "  --require-backend        fail compiler tasks which backend can't handle instead of running them directly"
+        )?;
+        writeln!(
+            out,
+            "  --explain-args           print type and scope of task arguments and how they are split between phases"
+        )?;
+        writeln!(out, "  --dump-args              same as --explain-args")?;
+        writeln!(
+            out,
+            "  --preprocess-only=DIR    write preprocessed sources to DIR instead of compiling"
+        )?;
+        writeln!(
+            out,
+            "  --explain=FILE           write execution plan to JSON FILE instead of building"
+        )?;
+        writeln!(
+            out,
+            "  --format=NAME            task file format, selected by file extension by default"
+        )?;
+        writeln!(
+            out,
+            "  --output-remap=FROM=TO   write objects under FROM directory to TO (repeatable)"
+        )?;
+        writeln!(
+            out,
+            "  --warm                   compile all tasks ignoring cached results to fill cache"
+        )?;
+        writeln!(
+            out,
+            "  --reproduce=FILE         bundle failed compilations to tar FILE for replaying"
+        )?;
+        writeln!(
+            out,
+            "  --save-stats=FILE        write build statistic to JSON FILE"
+        )?;
+        writeln!(
+            out,
+            "  --verify-outputs         fail compilations which didn't write declared outputs"
+        )?;
+        writeln!(
+            out,
+            "  --verify-objects         fail compilations which produced malformed object files"
+        )?;
+        writeln!(
+            out,
+            "  --keep-going             run independent tasks after failure"
+        )?;
+        writeln!(
+            out,
+            "  --min-success=N%         keep going and succeed if at least N% of tasks succeeded"
+        )?;
+        writeln!(
+            out,
+            "  --expect-cached=FILE     fail if compilation tasks with titles from FILE (or all) missed cache"
+        )?;
+        writeln!(
+            out,
+            "  --max-wall-time=SECONDS  kill running tasks and fail the build when it runs longer"
+        )?;
+        writeln!(
+            out,
+            "  --resume=FILE            skip tasks completed by interrupted build, tracked in FILE"
+        )?;
+        writeln!(
+            out,
+            "  --report-flaky=FILE      record task outcomes to FILE and report tasks with mixed outcomes"
+        )?;
+        writeln!(
+            out,
+            "  --trace=FILE             write Chrome trace of task execution to JSON FILE"
+        )?;
+        writeln!(
+            out,
+            "  --dump-dependencies=FILE write header dependencies of compiled objects to JSON FILE"
+        )?;
+        writeln!(
+            out,
+            "  --compare-stats=FILE     compare build statistic with JSON FILE saved earlier"
+        )?;
+        writeln!(
+            out,
+            "  --isolate-env            run tasks without inherited environment except env_passthrough"
+        )?;
+        writeln!(
+            out,
+            "  --show-tasks=REGEX       print only completed tasks with title matching REGEX"
+        )?;
+        writeln!(
+            out,
+            "  --show-min-duration=SEC  print only completed tasks running at least SEC seconds"
+        )?;
+        writeln!(
+            out,
+            "  --output-buffer=BYTES    size of console output buffers (default: 65536, 0 to disable)"
+        )?;
+        writeln!(out)?;
         writeln!(out, "Octobuild configuration:")?;
         writeln!(
             out,
@@ -91,6 +376,13 @@ impl Config {
                 .and_then(|v| Some(v.to_str()?.to_string()))
                 .unwrap_or_else(|| "none".to_string())
         )?;
+        writeln!(
+            out,
+            "  toml config path:   {}",
+            toml_config_path()?
+                .and_then(|v| Some(v.to_str()?.to_string()))
+                .unwrap_or_else(|| "none".to_string())
+        )?;
         writeln!(out)?;
         writeln!(out, "Current configuration:")?;
         self.show(out)?;
@@ -108,10 +400,25 @@ fn local_config_path() -> Option<PathBuf> {
     Some(project_dirs().config_dir().join("octobuild.conf"))
 }
 
+// Default file is optional, but explicitly configured one must exist.
+fn toml_config_path() -> crate::Result<Option<PathBuf>> {
+    match env::var_os(CONFIG_ENV) {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            if !path.is_file() {
+                return Err(crate::Error::Generic(format!(
+                    "{CONFIG_ENV} points to missing configuration file: {}",
+                    path.display()
+                )));
+            }
+            Ok(Some(path))
+        }
+        None => Ok(Some(project_dirs().config_dir().join("octobuild.toml"))),
+    }
+}
+
 #[cfg(windows)]
 fn global_config_path() -> Option<PathBuf> {
-    use std::env;
-
     Some(
         PathBuf::from(env::var("ProgramData").ok()?)
             .join("octobuild")
@@ -127,3 +434,93 @@ fn global_config_path() -> Option<PathBuf> {
             .join("octobuild.conf"),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use crate::config::{toml_config_path, Config};
+
+    #[test]
+    fn test_from_file_precedence() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                "octobuild.toml",
+                r#"
+                cache_limit_mb = 100
+                process_limit = 3
+                "#,
+            )?;
+            jail.set_env("OCTOBUILD_PROCESS_LIMIT", 5);
+
+            let config = Config::from_file(Path::new("octobuild.toml")).unwrap();
+            // Environment overrides file
+            assert_eq!(config.process_limit, 5);
+            // File overrides defaults
+            assert_eq!(config.cache_limit_mb, 100);
+            // Defaults are kept for everything else
+            assert_eq!(
+                config.cache_compression_level,
+                Config::default().cache_compression_level
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_stage_limits_positive() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file("octobuild.toml", "preprocess_limit = 0")?;
+            assert!(Config::from_file(Path::new("octobuild.toml")).is_err());
+            jail.create_file("octobuild.toml", "preprocess_limit = 1")?;
+            jail.set_env("OCTOBUILD_COMPILE_LIMIT", 0);
+            assert!(Config::from_file(Path::new("octobuild.toml")).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_vs_toolsets() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                "octobuild.toml",
+                r#"
+                [[vs_toolsets]]
+                source = "ThirdParty"
+                version = "14.29"
+                "#,
+            )?;
+            let config = Config::from_file(Path::new("octobuild.toml")).unwrap();
+            assert_eq!(config.vs_toolsets.len(), 1);
+            assert_eq!(config.vs_toolsets[0].version, "14.29");
+
+            jail.create_file(
+                "octobuild.toml",
+                r#"
+                [[vs_toolsets]]
+                source = "("
+                version = "14.29"
+                "#,
+            )?;
+            assert!(Config::from_file(Path::new("octobuild.toml")).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_toml_config_path_env() {
+        figment::Jail::expect_with(|jail| {
+            jail.set_env("OCTOBUILD_CONFIG", "custom.toml");
+            let error = toml_config_path().unwrap_err().to_string();
+            assert!(error.contains("custom.toml"), "{error}");
+
+            jail.create_file("custom.toml", "preprocess_limit = 3")?;
+            assert_eq!(
+                toml_config_path().unwrap(),
+                Some(PathBuf::from("custom.toml"))
+            );
+            assert_eq!(Config::load().unwrap().preprocess_limit, 3);
+            Ok(())
+        });
+    }
+}
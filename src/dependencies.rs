@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::compiler::CompilationTask;
+
+// Header dependencies of every compiled object, collected from line markers of preprocessed
+// sources and written as JSON object mapping object path to sorted header paths.
+pub struct DependencyDump {
+    path: PathBuf,
+    inner: Mutex<DumpState>,
+}
+
+#[derive(Default)]
+struct DumpState {
+    objects: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+    // Titles of tasks completed without preprocessing.
+    unknown: Vec<String>,
+}
+
+impl DependencyDump {
+    #[must_use]
+    pub fn new(path: PathBuf) -> Self {
+        DependencyDump {
+            path,
+            inner: Mutex::new(DumpState::default()),
+        }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Record files included by preprocessed source of task, except source itself.
+    pub(crate) fn add(&self, task: &CompilationTask, markers: LineMarkers) {
+        let command = &task.shared.command;
+        let absolutize = |path: &Path| command.absolutize(path).unwrap_or(path.to_path_buf());
+        let source = absolutize(&task.input_source);
+        let headers = markers
+            .finish()
+            .iter()
+            .map(|file| absolutize(Path::new(file)))
+            .filter(|path| *path != source)
+            .collect();
+        let object = absolutize(&task.output_object);
+        self.inner.lock().unwrap().objects.insert(object, headers);
+    }
+
+    // Record task completed without preprocessing, so its dependencies are unknown.
+    pub fn add_unknown(&self, title: &str) {
+        self.inner.lock().unwrap().unknown.push(title.to_string());
+    }
+
+    #[must_use]
+    pub(crate) fn contains(&self, task: &CompilationTask) -> bool {
+        let object = (task.shared.command)
+            .absolutize(&task.output_object)
+            .unwrap_or(task.output_object.clone());
+        self.inner.lock().unwrap().objects.contains_key(&object)
+    }
+
+    // Titles of tasks without dependency information, in completion order.
+    #[must_use]
+    pub fn unknown(&self) -> Vec<String> {
+        self.inner.lock().unwrap().unknown.clone()
+    }
+
+    // Write dependencies to file, returns count of objects.
+    pub fn finish(&self) -> crate::Result<usize> {
+        let inner = self.inner.lock().unwrap();
+        let file = BufWriter::new(File::create(&self.path)?);
+        serde_json::to_writer_pretty(file, &inner.objects)?;
+        Ok(inner.objects.len())
+    }
+}
+
+// Files named by line markers (`#line 12 "file"` of cl.exe, `# 12 "file"` of clang) of
+// preprocessed source written to it. Pseudo-files like `<built-in>` are skipped. Only lines
+// starting with `#` are buffered, so source is scanned while it is copied.
+#[derive(Default)]
+pub(crate) struct LineMarkers {
+    files: BTreeSet<String>,
+    // Start of current line.
+    line: Vec<u8>,
+    // Current line is not a line marker.
+    skip: bool,
+}
+
+impl LineMarkers {
+    fn extend_line(&mut self, data: &[u8]) {
+        if self.skip {
+            return;
+        }
+        self.line.extend_from_slice(data);
+        if let Some(c) = self.line.iter().find(|c| !c.is_ascii_whitespace()) {
+            if *c != b'#' {
+                self.skip = true;
+                self.line.clear();
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        if !self.skip {
+            if let Some(file) = marker_file(&self.line) {
+                self.files.insert(file);
+            }
+        }
+        self.line.clear();
+        self.skip = false;
+    }
+
+    pub(crate) fn finish(mut self) -> BTreeSet<String> {
+        self.end_line();
+        self.files
+    }
+}
+
+impl Write for LineMarkers {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut lines = buf.split(|c| *c == b'\n');
+        if let Some(last) = lines.next_back() {
+            for line in lines {
+                self.extend_line(line);
+                self.end_line();
+            }
+            self.extend_line(last);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn marker_file(line: &[u8]) -> Option<String> {
+    let line = line
+        .trim_ascii_start()
+        .strip_prefix(b"#")?
+        .trim_ascii_start();
+    let line = line
+        .strip_prefix(b"line")
+        .unwrap_or(line)
+        .trim_ascii_start();
+    let digits = line.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    let quoted = line[digits..].trim_ascii_start().strip_prefix(b"\"")?;
+    let mut name = Vec::new();
+    let mut iter = quoted.iter();
+    while let Some(c) = iter.next() {
+        match c {
+            b'"' => break,
+            b'\\' => name.extend(iter.next()),
+            c => name.push(*c),
+        }
+    }
+    (!name.is_empty() && !name.starts_with(b"<"))
+        .then(|| String::from_utf8_lossy(&name).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use std::io::Write;
+
+    use crate::dependencies::LineMarkers;
+
+    fn included_files(preprocessed: &[u8]) -> BTreeSet<String> {
+        let mut markers = LineMarkers::default();
+        markers.write_all(preprocessed).unwrap();
+        markers.finish()
+    }
+
+    #[test]
+    fn test_included_files() {
+        let preprocessed = b"#line 1 \"c:\\\\src\\\\a.cpp\"\n\
+            #line 1 \"c:\\\\src\\\\a.h\"\n\
+            int a;\n\
+            # 1 \"<built-in>\" 1\n\
+            # 3 \"include/b.h\" 2\n\
+            #pragma once\n\
+            #  line 7 \"c:\\\\src\\\\a.cpp\"\n";
+        assert_eq!(
+            included_files(preprocessed),
+            BTreeSet::from(
+                ["c:\\src\\a.cpp", "c:\\src\\a.h", "include/b.h"].map(ToString::to_string)
+            )
+        );
+    }
+
+    #[test]
+    fn test_included_files_split() {
+        let preprocessed = b"int a;\n# 1 \"a.h\" 1\n#pragma once\n  # 2 \"b.h\"";
+        // Markers and other lines split between writes at every position
+        for split in 0..preprocessed.len() {
+            let mut markers = LineMarkers::default();
+            markers.write_all(&preprocessed[..split]).unwrap();
+            markers.write_all(&preprocessed[split..]).unwrap();
+            assert_eq!(
+                markers.finish(),
+                BTreeSet::from(["a.h", "b.h"].map(ToString::to_string)),
+                "split at {split}"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dump_dependencies() {
+        use crate::clang::compiler::ClangCompiler;
+        use crate::compiler::{CommandArgs, Compiler, TaskOptions};
+        use crate::config::CacheMode;
+        use crate::dependencies::DependencyDump;
+        use crate::test_utils::Fixture;
+
+        // Fake compiler: preprocessing emits line markers of included headers.
+        let mut fixture = Fixture::clang(
+            r#"if [ -n "$preprocess" ]; then
+    printf '# 1 "%s"\n# 1 "<built-in>" 1\n# 1 "include/a.h" 1\n# 1 "b.h" 1\n' "$in"
+    exit 0
+fi
+echo object > "$out"
+"#,
+        );
+        std::fs::write(fixture.path().join("sample.cpp"), "int main() {}\n").unwrap();
+
+        fixture.config.cache_mode = CacheMode::None;
+        let mut state = fixture.state();
+        let path = fixture.path().join("deps.json");
+        state.dependencies = Some(DependencyDump::new(path.clone()));
+
+        let args = ["-c", "sample.cpp", "-o", "sample.o"].map(String::from);
+        let tasks = ClangCompiler::default()
+            .create_tasks(
+                fixture.command(),
+                CommandArgs::Regular(args.to_vec()),
+                &TaskOptions::default(),
+            )
+            .unwrap();
+        let output = tasks[0]
+            .toolchain
+            .compile_task(&state, &tasks[0].task)
+            .unwrap();
+        assert!(output.success());
+
+        let dump = state.dependencies.as_ref().unwrap();
+        assert!(dump.contains(&tasks[0].task));
+        assert_eq!(dump.finish().unwrap(), 1);
+        let json: serde_json::Value =
+            serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                fixture.path().join("sample.o").to_str().unwrap(): [
+                    fixture.path().join("b.h").to_str().unwrap(),
+                    fixture.path().join("include/a.h").to_str().unwrap(),
+                ]
+            })
+        );
+    }
+}
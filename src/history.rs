@@ -0,0 +1,335 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::worker::BuildTask;
+
+// How many recent outcomes of every task are kept.
+const HISTORY_DEPTH: usize = 10;
+
+// Persistent log of recent task outcomes keyed by task fingerprint, lets find tasks that
+// sometimes fail with the same command and inputs. Every line is
+// `fingerprint timestamp pass|fail inputs title`, where `inputs` is hash of task inputs.
+pub struct TaskHistory {
+    path: PathBuf,
+    inner: Mutex<HistoryState>,
+}
+
+struct HistoryState {
+    tasks: HashMap<String, TaskRuns>,
+    file: File,
+}
+
+struct TaskRuns {
+    title: String,
+    // Recent runs, oldest first.
+    outcomes: VecDeque<Outcome>,
+}
+
+struct Outcome {
+    // Seconds since Unix epoch.
+    timestamp: u64,
+    success: bool,
+    inputs: String,
+}
+
+impl TaskRuns {
+    fn add(&mut self, outcome: Outcome) {
+        if self.outcomes.len() == HISTORY_DEPTH {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(outcome);
+    }
+
+    // Passed and failed runs having the same inputs as some run with another outcome. Outcome
+    // change after inputs change (like fixed compilation error) is not a flakiness.
+    fn mixed(&self) -> (usize, usize) {
+        let mut inputs: HashMap<&str, (usize, usize)> = HashMap::new();
+        for outcome in &self.outcomes {
+            let counts = inputs.entry(&outcome.inputs).or_default();
+            if outcome.success {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+        inputs
+            .into_values()
+            .filter(|(passed, failed)| *passed > 0 && *failed > 0)
+            .fold((0, 0), |(p, f), (passed, failed)| (p + passed, f + failed))
+    }
+}
+
+// Task with both succeeded and failed runs with the same inputs among recent ones.
+#[derive(Debug, Eq, PartialEq)]
+pub struct FlakyTask {
+    pub fingerprint: String,
+    pub title: String,
+    pub passed: usize,
+    pub failed: usize,
+}
+
+impl TaskHistory {
+    // Load history, dropping outcomes beyond recent ones.
+    pub fn open(path: PathBuf) -> crate::Result<Self> {
+        let mut tasks: HashMap<String, TaskRuns> = HashMap::new();
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                // Malformed lines, like one truncated by crash, are skipped.
+                let Ok(line) = line else {
+                    continue;
+                };
+                let mut parts = line.splitn(5, ' ');
+                let (Some(fingerprint), Some(timestamp), Some(status), Some(inputs), Some(title)) = (
+                    parts.next(),
+                    parts.next(),
+                    parts.next(),
+                    parts.next(),
+                    parts.next(),
+                ) else {
+                    continue;
+                };
+                let (Ok(timestamp), Some(success)) = (
+                    timestamp.parse::<u64>(),
+                    match status {
+                        "pass" => Some(true),
+                        "fail" => Some(false),
+                        _ => None,
+                    },
+                ) else {
+                    continue;
+                };
+                let runs = tasks
+                    .entry(fingerprint.to_string())
+                    .or_insert_with(|| TaskRuns {
+                        title: String::new(),
+                        outcomes: VecDeque::new(),
+                    });
+                runs.title = title.to_string();
+                runs.add(Outcome {
+                    timestamp,
+                    success,
+                    inputs: inputs.to_string(),
+                });
+            }
+        }
+        let mut file = File::create(&path)?;
+        for (fingerprint, runs) in &tasks {
+            for outcome in &runs.outcomes {
+                write_outcome(&mut file, fingerprint, outcome, &runs.title)?;
+            }
+        }
+        drop(file);
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(TaskHistory {
+            path,
+            inner: Mutex::new(HistoryState { tasks, file }),
+        })
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Record outcome of task run with given input hash.
+    pub fn add(&self, task: &BuildTask, inputs: &str, success: bool) -> crate::Result<()> {
+        let fingerprint = task.fingerprint();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.as_secs());
+        let outcome = Outcome {
+            timestamp,
+            success,
+            inputs: inputs.to_string(),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        write_outcome(&mut inner.file, &fingerprint, &outcome, &task.title)?;
+        inner.file.flush()?;
+        let runs = inner.tasks.entry(fingerprint).or_insert_with(|| TaskRuns {
+            title: String::new(),
+            outcomes: VecDeque::new(),
+        });
+        runs.title.clone_from(&task.title);
+        runs.add(outcome);
+        Ok(())
+    }
+
+    // Tasks with mixed outcomes of the same inputs among recent runs, ordered by title.
+    #[must_use]
+    pub fn flaky(&self) -> Vec<FlakyTask> {
+        let inner = self.inner.lock().unwrap();
+        let mut result: Vec<FlakyTask> = inner
+            .tasks
+            .iter()
+            .filter_map(|(fingerprint, runs)| {
+                let (passed, failed) = runs.mixed();
+                (passed > 0).then(|| FlakyTask {
+                    fingerprint: fingerprint.clone(),
+                    title: runs.title.clone(),
+                    passed,
+                    failed,
+                })
+            })
+            .collect();
+        result.sort_by(|a, b| (&a.title, &a.fingerprint).cmp(&(&b.title, &b.fingerprint)));
+        result
+    }
+
+    pub fn print_flaky(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let flaky = self.flaky();
+        if flaky.is_empty() {
+            return writeln!(out, "No flaky tasks in recent builds");
+        }
+        writeln!(out, "Flaky tasks ({}):", flaky.len())?;
+        for task in flaky {
+            writeln!(
+                out,
+                "  {} [{}]: {} passed, {} failed with the same inputs",
+                task.title,
+                &task.fingerprint[..12],
+                task.passed,
+                task.failed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn write_outcome(
+    out: &mut impl Write,
+    fingerprint: &str,
+    outcome: &Outcome,
+    title: &str,
+) -> std::io::Result<()> {
+    // Title is the last field, line breaks would split the record.
+    let title = title.replace(['\r', '\n'], " ");
+    let status = if outcome.success { "pass" } else { "fail" };
+    writeln!(
+        out,
+        "{fingerprint} {} {status} {} {title}",
+        outcome.timestamp, outcome.inputs
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::history::{FlakyTask, TaskHistory, HISTORY_DEPTH};
+    use crate::worker::{BuildAction, BuildTask};
+
+    #[test]
+    fn test_flaky_tasks() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("history");
+        let task = |title: &str| {
+            Arc::new(BuildTask {
+                title: title.to_string(),
+                action: BuildAction::Empty,
+            })
+        };
+        let (flaky, stable) = (task("flaky"), task("stable"));
+
+        // Every build reopens history, like separate runs
+        for run in 0..4 {
+            let history = TaskHistory::open(path.clone()).unwrap();
+            history.add(&flaky, "inputs", run % 2 == 0).unwrap();
+            history.add(&stable, "inputs", true).unwrap();
+        }
+        let history = TaskHistory::open(path.clone()).unwrap();
+        assert_eq!(
+            history.flaky(),
+            [FlakyTask {
+                fingerprint: flaky.fingerprint(),
+                title: "flaky".to_string(),
+                passed: 2,
+                failed: 2,
+            }]
+        );
+        let mut out = Vec::new();
+        history.print_flaky(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "Flaky tasks (1):\n  flaky [{}]: 2 passed, 2 failed with the same inputs\n",
+                &flaky.fingerprint()[..12]
+            )
+        );
+
+        // Old failures are forgotten
+        for _ in 0..HISTORY_DEPTH {
+            history.add(&flaky, "inputs", true).unwrap();
+        }
+        assert!(history.flaky().is_empty());
+        drop(history);
+        let history = TaskHistory::open(path.clone()).unwrap();
+        assert!(history.flaky().is_empty());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().lines().count(),
+            HISTORY_DEPTH + 4
+        );
+    }
+
+    #[test]
+    fn test_fixed_task_not_flaky() {
+        let temp = tempfile::tempdir().unwrap();
+        let task = Arc::new(BuildTask {
+            title: "fixed".to_string(),
+            action: BuildAction::Empty,
+        });
+
+        // Failure is fixed by editing source: fail, edit, pass
+        let history = TaskHistory::open(temp.path().join("history")).unwrap();
+        history.add(&task, "broken", false).unwrap();
+        history.add(&task, "broken", false).unwrap();
+        history.add(&task, "fixed", true).unwrap();
+        assert!(history.flaky().is_empty());
+
+        // The same inputs fail again
+        history.add(&task, "fixed", false).unwrap();
+        assert_eq!(
+            history.flaky(),
+            [FlakyTask {
+                fingerprint: task.fingerprint(),
+                title: "fixed".to_string(),
+                passed: 1,
+                failed: 1,
+            }]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_edited_task_not_flaky() {
+        use std::path::PathBuf;
+
+        use crate::compiler::{CommandArgs, CommandInfo, SharedState};
+        use crate::config::Config;
+        use crate::worker::{execute_graph, BuildGraph};
+
+        let temp = tempfile::tempdir().unwrap();
+        let mut command = CommandInfo::simple(PathBuf::from("sh"));
+        command.current_dir = Some(temp.path().to_path_buf());
+        let mut graph = BuildGraph::new();
+        graph.add_node(Arc::new(BuildTask {
+            title: "script".to_string(),
+            action: BuildAction::Exec(command, CommandArgs::Regular(vec!["script.sh".to_string()])),
+        }));
+
+        let mut state = SharedState::new(&Config::default()).unwrap();
+        state.history = Some(TaskHistory::open(temp.path().join("history")).unwrap());
+        let run = |script: &str| {
+            std::fs::write(temp.path().join("script.sh"), script).unwrap();
+            // Modification time granularity must not hide edit from file hash cache.
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            execute_graph(&state, graph.clone(), 1, |_| Ok(())).is_ok()
+        };
+        assert!(!run("exit 1\n"));
+        assert!(run("exit 0\n"));
+        assert!(state.history.as_ref().unwrap().flaky().is_empty());
+    }
+}
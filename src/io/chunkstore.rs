@@ -0,0 +1,177 @@
+use std::cmp::min;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{Seek, Write};
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+const SUFFIX: &str = ".chunk";
+
+// Chunk size limits. Average chunk size is defined by mask bit count (2^13 = 8KB).
+const MIN_SIZE: usize = 2 * 1024;
+const MAX_SIZE: usize = 64 * 1024;
+const MASK: u64 = ((1 << 13) - 1) << (64 - 13);
+
+// Random values for gear rolling hash (generated by splitmix64).
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+static GEAR: [u64; 256] = gear_table();
+
+// Content-addressed storage for deduplicated blob chunks.
+pub struct ChunkStore {
+    dir: PathBuf,
+    compression_level: u32,
+}
+
+impl ChunkStore {
+    #[must_use]
+    pub fn new(dir: PathBuf, compression_level: u32) -> Self {
+        ChunkStore {
+            dir,
+            compression_level,
+        }
+    }
+
+    // Store blob and return list of its chunk hashes.
+    pub fn store(&self, data: &[u8]) -> crate::Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        for chunk in split(data) {
+            let hash = hex::encode(Sha256::digest(chunk));
+            let path = self.chunk_path(&hash);
+            if !path.exists() {
+                let parent = path.parent().unwrap();
+                fs::create_dir_all(parent)?;
+                let temp = tempfile::NamedTempFile::new_in(parent)?;
+                let mut stream = lz4::EncoderBuilder::new()
+                    .level(self.compression_level)
+                    .build(temp.as_file())?;
+                stream.write_all(chunk)?;
+                let (_, result) = stream.finish();
+                result?;
+                // Other process may store the same chunk concurrently.
+                if let Err(e) = temp.persist(&path) {
+                    if !path.exists() {
+                        return Err(e.error.into());
+                    }
+                }
+            }
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    // Write blob assembled from chunks.
+    pub fn restore(&self, hashes: &[String], writer: &mut impl Write) -> crate::Result<u64> {
+        let mut total: u64 = 0;
+        for hash in hashes {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(self.chunk_path(hash))?;
+            // Touch file to keep it from eviction.
+            file.write_all(&[4])?;
+            file.rewind()?;
+            total += std::io::copy(&mut lz4::Decoder::new(file)?, writer)?;
+        }
+        Ok(total)
+    }
+
+    #[must_use]
+    pub fn contains(&self, hash: &str) -> bool {
+        self.chunk_path(hash).is_file()
+    }
+
+    #[must_use]
+    pub fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.dir
+            .join(&hash[0..2])
+            .join(hash[2..].to_string() + SUFFIX)
+    }
+}
+
+// Split data to content-defined chunks using gear rolling hash.
+#[must_use]
+pub fn split(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = min(start + MAX_SIZE, data.len());
+        let mut cut = end;
+        let mut hash: u64 = 0;
+        for (i, byte) in data.iter().enumerate().take(end).skip(start) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if i + 1 - start >= MIN_SIZE && hash & MASK == 0 {
+                cut = i + 1;
+                break;
+            }
+        }
+        chunks.push(&data[start..cut]);
+        start = cut;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use crate::io::chunkstore::{split, ChunkStore, MAX_SIZE};
+
+    fn count_files(dir: &std::path::Path) -> usize {
+        let mut count = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                count += count_files(&path);
+            } else {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn test_split_limits() {
+        let data: Vec<u8> = (0..MAX_SIZE * 4).map(|_| rand::random::<u8>()).collect();
+        let chunks = split(&data);
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks.iter().all(|c| c.len() <= MAX_SIZE));
+    }
+
+    #[test]
+    fn test_store_dedup() {
+        let temp = tempfile::tempdir().unwrap();
+        let store = ChunkStore::new(temp.path().to_path_buf(), 1);
+
+        let first: Vec<u8> = (0..512 * 1024).map(|_| rand::random::<u8>()).collect();
+        let mut second = first.clone();
+        // Change a few bytes in the middle of blob
+        second[256 * 1024..256 * 1024 + 16].copy_from_slice(&[0; 16]);
+
+        let first_hashes = store.store(&first).unwrap();
+        let second_hashes = store.store(&second).unwrap();
+
+        let unique: HashSet<&String> = first_hashes.iter().chain(second_hashes.iter()).collect();
+        assert!(unique.len() < first_hashes.len() + second_hashes.len());
+        assert_eq!(count_files(temp.path()), unique.len());
+
+        let mut restored = Vec::new();
+        store.restore(&second_hashes, &mut restored).unwrap();
+        assert_eq!(restored, second);
+    }
+}
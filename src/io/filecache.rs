@@ -1,22 +1,51 @@
-use std::cmp::Ordering;
-use std::collections::BTreeSet;
+use std::cmp::{max, Ordering};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::OsString;
+use std::fmt;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use crate::compiler::OutputInfo;
 use crate::config::{CacheMode, Config};
 use crate::io::binary::{read_exact, read_u64, read_usize, write_u64, write_usize};
+use crate::io::chunkstore::ChunkStore;
 use crate::io::counter::Counter;
+use crate::io::filestore::FileStore;
 use crate::io::statistic::Statistic;
+use crate::utils::{glob_to_regex, hash_stream, is_transient_io_error, strip_ansi};
+use crate::version;
 use thiserror::Error;
 
-const HEADER: &[u8] = b"OBCF\x00\x03";
+const HEADER: &[u8] = b"OBCF\x00\x04";
+// Header for entries with output files stored in chunk store.
+const HEADER_CHUNKED: &[u8] = b"OBCC\x00\x02";
+// Header for entries with output files stored uncompressed in file store.
+const HEADER_REFLINK: &[u8] = b"OBCR\x00\x01";
 const FOOTER: &[u8] = b"END\x00";
 const SUFFIX: &str = ".lz4";
+// Extension of entry metadata sidecar file.
+const METADATA_EXTENSION: &str = "meta";
+// Extension of empty sidecar file marking pinned entry, found by cleanup without reading metadata.
+const PIN_EXTENSION: &str = "pin";
+// Placeholder for metadata fields that can't be read.
+const UNKNOWN: &str = "unknown";
+// How often cleanup workers check for new directories and abort request.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Delay before first retry of failed cache operation, doubled on each next one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+// Unreferenced chunks and stored files younger than this are kept on cleanup: entry referencing
+// them may be written by another process right now.
+const STORED_GRACE: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Error, Debug)]
 pub enum CacheError {
@@ -26,15 +55,152 @@ pub enum CacheError {
     InvalidFooter(PathBuf),
     #[error("unexpected count of packed cached files: {0}")]
     PackedFilesMismatch(PathBuf),
+    #[error("cache chunk not found: {0}")]
+    ChunkNotFound(String),
+    #[error("cache blob not found: {0}")]
+    BlobNotFound(String),
+    #[error("cache entry not found: {0}")]
+    EntryNotFound(String),
     #[error("mutex error: {0}")]
     MutexError(String),
 }
 
+// Where output files of cache entry are stored.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Storage {
+    // Inside entry itself
+    Inline,
+    // In chunk store
+    Chunked,
+    // In file store
+    Reflink,
+}
+
+// Task output file with its role (object, precompiled header, etc).
+#[derive(Clone, Debug)]
+pub struct OutputFile {
+    pub role: &'static str,
+    pub path: PathBuf,
+}
+
+impl OutputFile {
+    #[must_use]
+    pub fn new(role: &'static str, path: PathBuf) -> Self {
+        OutputFile { role, path }
+    }
+}
+
+// Audit information stored next to cache entry.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct EntryMetadata {
+    pub source: String,
+    // Creation time in seconds since Unix epoch.
+    pub created: u64,
+    pub toolchain: String,
+    pub version: String,
+}
+
+impl EntryMetadata {
+    #[must_use]
+    pub fn new(source: &Path, toolchain: Option<&str>) -> Self {
+        EntryMetadata {
+            source: source.display().to_string(),
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |v| v.as_secs()),
+            toolchain: toolchain.unwrap_or(UNKNOWN).to_string(),
+            version: version::full(),
+        }
+    }
+
+    // Metadata of entry without readable sidecar file.
+    #[must_use]
+    pub fn unknown() -> Self {
+        EntryMetadata {
+            source: UNKNOWN.to_string(),
+            created: 0,
+            toolchain: UNKNOWN.to_string(),
+            version: UNKNOWN.to_string(),
+        }
+    }
+}
+
+// Cache entry description for inspection.
+pub struct EntryInfo {
+    pub path: PathBuf,
+    pub metadata: EntryMetadata,
+    // Entry is kept on cleanup while cache limit can be met by evicting other entries.
+    pub pinned: bool,
+    // Output file roles with their sizes.
+    pub outputs: Vec<(String, u64)>,
+    // Compiler output stored with entry.
+    pub output: OutputInfo,
+    // Entry file size, access and modification times.
+    pub size: u64,
+    pub accessed: SystemTime,
+    pub modified: SystemTime,
+    // SHA-256 of entry file.
+    pub checksum: String,
+}
+
+impl fmt::Display for EntryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let seconds = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |v| v.as_secs())
+        };
+        writeln!(f, "Cache entry: {}", self.path.display())?;
+        writeln!(f, "  source:    {}", self.metadata.source)?;
+        writeln!(f, "  toolchain: {}", self.metadata.toolchain)?;
+        writeln!(f, "  version:   {}", self.metadata.version)?;
+        writeln!(f, "  pinned:    {}", self.pinned)?;
+        // Times are in seconds since Unix epoch
+        writeln!(f, "  created:   {}", self.metadata.created)?;
+        writeln!(f, "  modified:  {}", seconds(self.modified))?;
+        writeln!(f, "  accessed:  {}", seconds(self.accessed))?;
+        writeln!(f, "  size:      {} bytes", self.size)?;
+        writeln!(f, "  checksum:  {}", self.checksum)?;
+        writeln!(f, "  stdout:    {} bytes", self.output.stdout.len())?;
+        writeln!(f, "  stderr:    {} bytes", self.output.stderr.len())?;
+        writeln!(f, "  outputs:")?;
+        for (role, size) in &self.outputs {
+            writeln!(f, "    {role}: {size} bytes")?;
+        }
+        Ok(())
+    }
+}
+
 pub struct FileCache {
     cache_mode: CacheMode,
     cache_dir: PathBuf,
     cache_limit: u64,
+    cache_pin: Vec<Regex>,
     cache_compression_level: u32,
+    cache_dedup: bool,
+    cache_reflink: bool,
+    cache_retries: u32,
+    cache_strip_color: bool,
+    chunks: ChunkStore,
+    files: FileStore,
+}
+
+#[derive(Default)]
+pub struct VerifyStatistic {
+    pub good: AtomicUsize,
+    pub corrupt: AtomicUsize,
+    pub removed: AtomicUsize,
+}
+
+impl fmt::Display for VerifyStatistic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Cache verification: good {}, corrupt {}, removed {}",
+            self.good.load(AtomicOrdering::Relaxed),
+            self.corrupt.load(AtomicOrdering::Relaxed),
+            self.removed.load(AtomicOrdering::Relaxed),
+        )
+    }
 }
 
 struct CacheFile {
@@ -81,7 +247,17 @@ impl FileCache {
             cache_mode: config.cache_mode,
             cache_dir: config.cache.clone(),
             cache_limit: config.cache_limit_mb * 1024 * 1024,
+            // Globs are validated on configuration load.
+            cache_pin: (config.cache_pin.iter())
+                .filter_map(|glob| glob_to_regex(glob).ok())
+                .collect(),
             cache_compression_level: config.cache_compression_level,
+            cache_dedup: config.cache_dedup,
+            cache_reflink: config.cache_reflink,
+            cache_retries: config.cache_retries,
+            cache_strip_color: config.cache_strip_color,
+            chunks: ChunkStore::new(config.cache.join("chunks"), config.cache_compression_level),
+            files: FileStore::new(config.cache.join("files")),
         }
     }
 
@@ -89,88 +265,465 @@ impl FileCache {
         &self,
         statistic: &Statistic,
         hash: &str,
-        outputs: Vec<PathBuf>,
+        outputs: Vec<OutputFile>,
         worker: F,
     ) -> crate::Result<OutputInfo> {
-        let path = self
-            .cache_dir
-            .join(&hash[0..2])
-            .join(hash[2..].to_string() + SUFFIX);
+        let path = self.entry_path(hash);
 
-        if self.cache_mode != CacheMode::None {
+        // Cache warming replaces existing entries.
+        let mut replace = !self.cache_mode.can_read();
+        if self.cache_mode.can_read() {
             // Try to read data from cache.
-            if let Ok(output) = self.read_cache(statistic, &path, &outputs) {
-                return Ok(output);
+            match self.retry(statistic, || self.read_cache(statistic, &path, &outputs)) {
+                Ok(output) => return Ok(output),
+                // Corrupt or truncated entry, or one with evicted chunks, would stay forever.
+                Err(_) if path.is_file() => replace = true,
+                Err(_) => {}
             }
         }
 
         let output = worker()?;
 
-        if self.cache_mode == CacheMode::ReadWrite {
-            self.write_cache(statistic, &path, outputs, &output)?;
+        if self.cache_mode.can_write() {
+            // Build result is already produced, failing to cache it is not fatal.
+            if let Err(e) = self.retry(statistic, || {
+                self.write_cache(statistic, &path, &outputs, &output, replace)
+            }) {
+                warn!("Can't write cache entry {}: {e}", path.display());
+            }
         }
 
         Ok(output)
     }
 
-    pub fn cleanup(&self) -> crate::Result<()> {
-        if self.cache_mode != CacheMode::ReadWrite {
+    // Write metadata next to existing cache entry. Metadata is informational, so errors are only logged.
+    pub fn write_metadata(&self, hash: &str, metadata: &EntryMetadata) {
+        let path = self.entry_path(hash);
+        if !self.cache_mode.can_write() || !path.is_file() {
+            return;
+        }
+        let path = path.with_extension(METADATA_EXTENSION);
+        if let Err(e) = serde_json::to_vec(metadata)
+            .map_err(crate::Error::from)
+            .and_then(|data| Ok(fs::write(&path, data)?))
+        {
+            warn!("Can't write cache metadata {}: {e}", path.display());
+        }
+    }
+
+    // Mark existing cache entry as pinned or unpinned. Called on every use of entry, so changed
+    // pin globs apply to entries stored before.
+    pub fn set_pinned(&self, hash: &str, pinned: bool) {
+        let path = self.entry_path(hash);
+        if !self.cache_mode.can_write() || !path.is_file() {
+            return;
+        }
+        let path = path.with_extension(PIN_EXTENSION);
+        let result = match (pinned, path.is_file()) {
+            (true, false) => fs::write(&path, b""),
+            (false, true) => fs::remove_file(&path),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Can't update cache pin {}: {e}", path.display());
+        }
+    }
+
+    // Read metadata of cache entry, missing or corrupt metadata is reported as unknown.
+    #[must_use]
+    pub fn read_metadata(&self, hash: &str) -> EntryMetadata {
+        fs::read(self.entry_path(hash).with_extension(METADATA_EXTENSION))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_else(EntryMetadata::unknown)
+    }
+
+    // Describe cache entry without touching it. Output files are restored to `dir` and named
+    // by their role, if it is given.
+    pub fn inspect(&self, hash: &str, dir: Option<&Path>) -> crate::Result<EntryInfo> {
+        let not_found = || CacheError::EntryNotFound(hash.to_string()).into();
+        if hash.len() < 3 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(not_found());
+        }
+        let hash = &hash.to_ascii_lowercase();
+        let path = self.entry_path(hash);
+        let stat = match fs::metadata(&path) {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(not_found()),
+            Err(e) => return Err(e.into()),
+        };
+        let checksum = hash_stream(&mut File::open(&path)?)?;
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut stream = lz4::Decoder::new(File::open(&path)?)?;
+        let storage = read_header(&mut stream, &path)?;
+        let mut outputs = Vec::new();
+        for _ in 0..read_usize(&mut stream)? {
+            let role = String::from_utf8(read_blob(&mut stream)?)?;
+            let size = match dir {
+                Some(dir) => {
+                    let target = dir.join(&role);
+                    match storage {
+                        Storage::Inline => read_cached_file(&mut stream, &target)?,
+                        Storage::Chunked => read_chunked_file(&mut stream, &target, &self.chunks)?,
+                        Storage::Reflink => read_reflink_file(&mut stream, &target, &self.files)?,
+                    }
+                    fs::metadata(&target)?.len()
+                }
+                None => match storage {
+                    Storage::Inline => {
+                        let size = read_u64(&mut stream)?;
+                        std::io::copy(&mut (&mut stream).take(size), &mut std::io::sink())?
+                    }
+                    Storage::Chunked => self
+                        .chunks
+                        .restore(&read_chunk_list(&mut stream)?, &mut std::io::sink())?,
+                    Storage::Reflink => self
+                        .files
+                        .size(&String::from_utf8(read_blob(&mut stream)?)?)?,
+                },
+            };
+            outputs.push((role, size));
+        }
+        let output = read_output(&mut stream)?;
+        if read_exact(&mut stream, FOOTER.len())? != FOOTER {
+            return Err(CacheError::InvalidFooter(path).into());
+        }
+        Ok(EntryInfo {
+            metadata: self.read_metadata(hash),
+            pinned: path.with_extension(PIN_EXTENSION).is_file(),
+            outputs,
+            output,
+            size: stat.len(),
+            accessed: stat.accessed()?,
+            modified: stat.modified()?,
+            checksum,
+            path,
+        })
+    }
+
+    // Whether entry of task with given source and output files should be pinned.
+    pub fn is_pinned<'a>(&self, paths: impl IntoIterator<Item = &'a Path>) -> bool {
+        paths.into_iter().any(|path| {
+            let path = path.to_string_lossy();
+            self.cache_pin.iter().any(|glob| glob.is_match(&path))
+        })
+    }
+
+    // Pinned entries with their sidecars and stored files they reference. Without pin globs nothing
+    // is pinned, so entries are not read at all.
+    fn pinned_files<'a>(&self, files: impl Iterator<Item = &'a Path>) -> HashSet<PathBuf> {
+        let mut pinned = HashSet::new();
+        if self.cache_pin.is_empty() {
+            return pinned;
+        }
+        for pin in files.filter(|path| path.extension().is_some_and(|ext| ext == PIN_EXTENSION)) {
+            let path = pin.with_extension(&SUFFIX[1..]);
+            match self.referenced_files(&path) {
+                Ok(referenced) => pinned.extend(referenced),
+                // Entry is already removed, leave orphaned pin to cleanup.
+                Err(crate::Error::IO(e)) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => warn!("Can't read pinned cache entry {}: {e}", path.display()),
+            }
+            pinned.insert(path.with_extension(METADATA_EXTENSION));
+            pinned.insert(path);
+            pinned.insert(pin.to_path_buf());
+        }
+        pinned
+    }
+
+    // Chunk and file store files with output files of entry.
+    fn referenced_files(&self, path: &Path) -> crate::Result<Vec<PathBuf>> {
+        let mut stream = lz4::Decoder::new(File::open(path)?)?;
+        let storage = read_header(&mut stream, path)?;
+        let mut result = Vec::new();
+        for _ in 0..read_usize(&mut stream)? {
+            // Output file role
+            read_blob(&mut stream)?;
+            match storage {
+                Storage::Inline => {
+                    let size = read_u64(&mut stream)?;
+                    std::io::copy(&mut (&mut stream).take(size), &mut std::io::sink())?;
+                }
+                Storage::Chunked => result.extend(
+                    (read_chunk_list(&mut stream)?.iter())
+                        .filter(|hash| hash.len() > 2)
+                        .map(|hash| self.chunks.chunk_path(hash)),
+                ),
+                Storage::Reflink => {
+                    let hash = String::from_utf8(read_blob(&mut stream)?)?;
+                    if hash.len() > 2 {
+                        result.push(self.files.blob_path(&hash));
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.cache_dir
+            .join(&hash[0..2])
+            .join(hash[2..].to_string() + SUFFIX)
+    }
+
+    // Run cache operation, retrying with exponential backoff on transient filesystem errors.
+    fn retry<T>(
+        &self,
+        statistic: &Statistic,
+        mut operation: impl FnMut() -> crate::Result<T>,
+    ) -> crate::Result<T> {
+        let mut delay = RETRY_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match operation() {
+                Err(e) if attempt < self.cache_retries && is_transient_error(&e) => {
+                    warn!("Cache operation failed ({e}), retrying in {delay:?}");
+                    statistic.inc_retry();
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Remove least recently used files above cache limit using `threads` parallel workers.
+    // Pinned entries are kept even if cache limit can't be met. Stops early when `abort` is set.
+    pub fn cleanup(&self, threads: usize, abort: &AtomicBool) -> crate::Result<()> {
+        if !self.cache_mode.can_write() || !self.cache_dir.is_dir() {
             return Ok(());
         }
+        let threads = max(threads, 1);
 
-        let mut files = BTreeSet::<CacheFile>::new();
+        let files = Mutex::new(BTreeSet::<CacheFile>::new());
+        let (tx_dir, rx_dir) = crossbeam_channel::unbounded::<PathBuf>();
+        // Count of directories queued or being scanned.
+        let pending = AtomicUsize::new(1);
+        tx_dir
+            .send(self.cache_dir.clone())
+            .map_err(crate::Error::send_error)?;
+        run_workers(threads, || {
+            let mut found = Vec::new();
+            while pending.load(AtomicOrdering::Acquire) > 0 && !abort.load(AtomicOrdering::Relaxed)
+            {
+                let Ok(dir) = rx_dir.recv_timeout(POLL_INTERVAL) else {
+                    continue;
+                };
+                let scanned = (|| -> crate::Result<()> {
+                    for entry in fs::read_dir(&dir)? {
+                        let path = entry?.path();
+                        let metadata = fs::metadata(&path)?;
+                        if metadata.is_dir() {
+                            pending.fetch_add(1, AtomicOrdering::AcqRel);
+                            tx_dir.send(path).map_err(crate::Error::send_error)?;
+                        } else {
+                            found.push(CacheFile {
+                                path,
+                                size: metadata.len(),
+                                accessed: metadata.accessed()?,
+                                modified: metadata.modified()?,
+                            });
+                        }
+                    }
+                    Ok(())
+                })();
+                pending.fetch_sub(1, AtomicOrdering::AcqRel);
+                scanned?;
+            }
+            files.lock().unwrap().extend(found);
+            Ok(())
+        })?;
+        if abort.load(AtomicOrdering::Relaxed) {
+            return Ok(());
+        }
 
-        foreach_cache_file(
-            &self.cache_dir,
-            &mut (|path: PathBuf, metadata: fs::Metadata| -> crate::Result<()> {
-                files.insert(CacheFile {
-                    path,
-                    size: metadata.len(),
-                    accessed: metadata.accessed()?,
-                    modified: metadata.modified()?,
-                });
-                Ok(())
-            }),
-        )?;
+        let (tx_path, rx_path) = crossbeam_channel::unbounded::<PathBuf>();
+        for path in self.select_evicted(&files.into_inner().unwrap()) {
+            // Every path is queued exactly once, so it is removed by single worker.
+            tx_path.send(path).map_err(crate::Error::send_error)?;
+        }
+        drop(tx_path);
+        run_workers(threads, || {
+            for path in &rx_path {
+                if abort.load(AtomicOrdering::Relaxed) {
+                    break;
+                }
+                match fs::remove_file(&path) {
+                    // Other process may clean up cache concurrently.
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+    }
 
-        let mut cache_size: u64 = 0;
+    // Least recently used entries above cache limit with their sidecars, orphaned sidecars and
+    // chunks and stored files that no kept entry references.
+    fn select_evicted(&self, files: &BTreeSet<CacheFile>) -> Vec<PathBuf> {
+        let chunks_dir = self.cache_dir.join("chunks");
+        let files_dir = self.cache_dir.join("files");
+        let is_stored = |path: &Path| path.starts_with(&chunks_dir) || path.starts_with(&files_dir);
+        let is_sidecar = |path: &Path| {
+            !is_stored(path)
+                && path
+                    .extension()
+                    .is_some_and(|ext| ext == METADATA_EXTENSION || ext == PIN_EXTENSION)
+        };
+        let sizes: HashMap<&Path, u64> = (files.iter())
+            .map(|item| (item.path.as_path(), item.size))
+            .collect();
+        // Entries are read for references only if anything is stored outside of them.
+        let has_stored = files.iter().any(|item| is_stored(&item.path));
 
+        let pinned = self.pinned_files(files.iter().map(|item| item.path.as_path()));
+        // Pinned files are counted first, so only unpinned ones are evicted.
+        let mut cache_size: u64 = (pinned.iter())
+            .filter_map(|path| sizes.get(path.as_path()))
+            .sum();
+        if cache_size > self.cache_limit {
+            warn!(
+                "Cache limit of {} bytes can't be met without evicting pinned entries ({} bytes)",
+                self.cache_limit, cache_size
+            );
+        }
+        // Chunks and stored files referenced by kept entries.
+        let mut referenced: HashSet<PathBuf> = (pinned.iter())
+            .filter(|path| is_stored(path))
+            .cloned()
+            .collect();
+        let mut evicted = Vec::new();
         // Attention, reverse order. We want to keep newer files
         for item in files.iter().rev() {
-            cache_size += item.size;
+            let path = &item.path;
+            if pinned.contains(path) || is_stored(path) || is_sidecar(path) {
+                continue;
+            }
+            let mut group = vec![path.clone()];
+            let mut size = item.size;
+            let mut references = Vec::new();
+            if path.to_string_lossy().ends_with(SUFFIX) {
+                for sidecar in
+                    [METADATA_EXTENSION, PIN_EXTENSION].map(|ext| path.with_extension(ext))
+                {
+                    if let Some(sidecar_size) = sizes.get(sidecar.as_path()) {
+                        size += sidecar_size;
+                        group.push(sidecar);
+                    }
+                }
+                if has_stored {
+                    match self.referenced_files(path) {
+                        Ok(files) => references = files,
+                        Err(e) => warn!("Can't read cache entry {}: {e}", path.display()),
+                    }
+                }
+                let new: HashSet<&PathBuf> = (references.iter())
+                    .filter(|file| !referenced.contains(*file))
+                    .collect();
+                size += (new.into_iter())
+                    .filter_map(|file| sizes.get(file.as_path()))
+                    .sum::<u64>();
+            }
+            cache_size += size;
             if cache_size > self.cache_limit {
-                fs::remove_file(&item.path)?;
+                evicted.extend(group);
+            } else {
+                referenced.extend(references);
             }
         }
-        Ok(())
+        for item in files {
+            let path = &item.path;
+            let orphaned = is_sidecar(path)
+                && !pinned.contains(path)
+                && !sizes.contains_key(path.with_extension(&SUFFIX[1..]).as_path());
+            let unreferenced = is_stored(path)
+                && !referenced.contains(path)
+                && item.modified + STORED_GRACE < SystemTime::now();
+            if orphaned || unreferenced {
+                evicted.push(path.clone());
+            }
+        }
+        evicted
     }
 
+    // Check integrity of every cache entry using `threads` parallel workers.
+    pub fn verify(&self, threads: usize, delete: bool) -> crate::Result<VerifyStatistic> {
+        let statistic = VerifyStatistic::default();
+        if !self.cache_dir.is_dir() {
+            return Ok(statistic);
+        }
+
+        let (tx_path, rx_path) = crossbeam_channel::unbounded::<PathBuf>();
+        foreach_cache_file(
+            &self.cache_dir,
+            &mut (|path: PathBuf, _: fs::Metadata| -> crate::Result<()> {
+                if path.to_string_lossy().ends_with(SUFFIX) {
+                    tx_path.send(path).map_err(crate::Error::send_error)?;
+                }
+                Ok(())
+            }),
+        )?;
+        drop(tx_path);
+
+        std::thread::scope(|scope| {
+            for _ in 0..max(threads, 1) {
+                let rx_path = rx_path.clone();
+                let statistic = &statistic;
+                scope.spawn(move || {
+                    for path in rx_path {
+                        match self.verify_cache_file(&path) {
+                            Ok(_) => {
+                                statistic.good.fetch_add(1, AtomicOrdering::Relaxed);
+                            }
+                            Err(e) => {
+                                warn!("Corrupt cache file {}: {e}", path.display());
+                                statistic.corrupt.fetch_add(1, AtomicOrdering::Relaxed);
+                                if delete && fs::remove_file(&path).is_ok() {
+                                    statistic.removed.fetch_add(1, AtomicOrdering::Relaxed);
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        Ok(statistic)
+    }
+
+    // Restore requested outputs from cache entry, skipping other cached files.
     fn read_cache(
         &self,
         statistic: &Statistic,
         path: &PathBuf,
-        paths: &[PathBuf],
+        outputs: &[OutputFile],
     ) -> crate::Result<OutputInfo> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(PathBuf::from(path))?;
-        file.write_all(&[4])?;
-        file.rewind()?;
+        let file = File::open(path)?;
         let mut stream = lz4::Decoder::new(Counter::reader(file))?;
-        if read_exact(&mut stream, HEADER.len())? != HEADER {
-            return Err(CacheError::InvalidHeader(path.clone()).into());
-        }
-        if read_usize(&mut stream)? != paths.len() {
-            return Err(CacheError::PackedFilesMismatch(path.clone()).into());
-        }
-        for path in paths {
+        let storage = read_header(&mut stream, path)?;
+        let mut restored = 0;
+        for _ in 0..read_usize(&mut stream)? {
+            let role = String::from_utf8(read_blob(&mut stream)?)?;
+            let Some(output) = outputs.iter().find(|output| output.role == role) else {
+                self.skip_file(&mut stream, storage, false)?;
+                continue;
+            };
+            restored += 1;
+            let path = &output.path;
             assert!(path.is_absolute());
             let mut temp_name = OsString::from("~tmp~");
             temp_name.push(path.file_name().unwrap());
             let temp = path.with_file_name(temp_name);
             drop(fs::remove_file(path));
-            match read_cached_file(&mut stream, &temp).and_then(|_| Ok(fs::rename(&temp, path)?)) {
+            let restored = match storage {
+                Storage::Inline => read_cached_file(&mut stream, &temp),
+                Storage::Chunked => read_chunked_file(&mut stream, &temp, &self.chunks),
+                Storage::Reflink => read_reflink_file(&mut stream, &temp, &self.files),
+            };
+            match restored.and_then(|_| Ok(fs::rename(&temp, path)?)) {
                 Ok(_) => {}
                 Err(e) => {
                     drop(fs::remove_file(&temp));
@@ -178,6 +731,9 @@ impl FileCache {
                 }
             };
         }
+        if restored != outputs.len() {
+            return Err(CacheError::PackedFilesMismatch(path.clone()).into());
+        }
         let output = read_output(&mut stream)?;
         if read_exact(&mut stream, FOOTER.len())? != FOOTER {
             return Err(CacheError::InvalidFooter(path.clone()).into());
@@ -186,37 +742,163 @@ impl FileCache {
         if stream.read(&mut eof)? != 0 {
             return Err(CacheError::InvalidFooter(path.clone()).into());
         }
+        // Update modification time of valid entry only, so broken ones are evicted first.
+        OpenOptions::new().write(true).open(path)?.write_all(&[4])?;
         statistic.add_hit(stream.finish().0.len());
         Ok(output)
     }
 
+    // Store entry. Existing entry is kept unless `replace` is set.
     fn write_cache(
         &self,
         statistic: &Statistic,
         path: &Path,
-        paths: Vec<PathBuf>,
+        outputs: &[OutputFile],
         output: &OutputInfo,
+        replace: bool,
     ) -> crate::Result<()> {
         if !output.success() {
             return Ok(());
         }
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent)?;
+        // Entry is moved in place only when complete, so concurrent writers of the same entry
+        // can't mix their data and readers never see partially written entry.
+        let temp = tempfile::Builder::new()
+            .prefix("~tmp~")
+            .tempfile_in(parent)?;
         let mut stream = lz4::EncoderBuilder::new()
             .level(self.cache_compression_level)
-            .build(Counter::writer(File::create(path)?))?;
-        stream.write_all(HEADER)?;
-        write_usize(&mut stream, paths.len())?;
-        for path in paths {
-            assert!(path.is_absolute());
-            write_cached_file(&mut stream, path)?;
+            .build(Counter::writer(temp.as_file()))?;
+        let storage = if self.cache_reflink {
+            Storage::Reflink
+        } else if self.cache_dedup {
+            Storage::Chunked
+        } else {
+            Storage::Inline
+        };
+        stream.write_all(match storage {
+            Storage::Inline => HEADER,
+            Storage::Chunked => HEADER_CHUNKED,
+            Storage::Reflink => HEADER_REFLINK,
+        })?;
+        write_usize(&mut stream, outputs.len())?;
+        for output in outputs {
+            assert!(output.path.is_absolute());
+            write_blob(&mut stream, output.role.as_bytes())?;
+            match storage {
+                Storage::Inline => write_cached_file(&mut stream, output.path.clone())?,
+                Storage::Chunked => {
+                    write_chunked_file(&mut stream, output.path.clone(), &self.chunks)?;
+                }
+                Storage::Reflink => {
+                    write_reflink_file(&mut stream, output.path.clone(), &self.files)?;
+                }
+            }
+        }
+        if self.cache_strip_color {
+            write_output(
+                &mut stream,
+                &OutputInfo {
+                    status: output.status,
+                    stdout: strip_ansi(&output.stdout),
+                    stderr: strip_ansi(&output.stderr),
+                },
+            )?;
+        } else {
+            write_output(&mut stream, output)?;
         }
-        write_output(&mut stream, output)?;
         stream.write_all(FOOTER)?;
         let (writer, result) = stream.finish();
+        result?;
         statistic.add_miss(writer.len());
-        Ok(result?)
+        if replace {
+            temp.persist(path).map_err(|e| e.error)?;
+            return Ok(());
+        }
+        match temp.persist_noclobber(path) {
+            Ok(_) => Ok(()),
+            // Other build stored the same entry first, its content is equivalent.
+            Err(e) if e.error.kind() == ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(e.error.into()),
+        }
+    }
+
+    fn verify_cache_file(&self, path: &Path) -> crate::Result<()> {
+        let mut stream = lz4::Decoder::new(File::open(path)?)?;
+        let storage = read_header(&mut stream, path)?;
+        for _ in 0..read_usize(&mut stream)? {
+            read_blob(&mut stream)?;
+            self.skip_file(&mut stream, storage, true)?;
+        }
+        read_output(&mut stream)?;
+        if read_exact(&mut stream, FOOTER.len())? != FOOTER {
+            return Err(CacheError::InvalidFooter(path.to_path_buf()).into());
+        }
+        let mut eof = [0];
+        if stream.read(&mut eof)? != 0 {
+            return Err(CacheError::InvalidFooter(path.to_path_buf()).into());
+        }
+        Ok(())
+    }
+
+    // Skip cached file content. Presence of externally stored data is checked if requested.
+    fn skip_file(
+        &self,
+        stream: &mut impl Read,
+        storage: Storage,
+        check: bool,
+    ) -> crate::Result<()> {
+        match storage {
+            Storage::Inline => {
+                let size = read_u64(stream)?;
+                let copied = std::io::copy(&mut stream.take(size), &mut std::io::sink())?;
+                if copied != size {
+                    return Err(crate::Error::Generic("Expected end of stream".to_string()));
+                }
+            }
+            Storage::Chunked => {
+                for hash in read_chunk_list(stream)? {
+                    if check && !self.chunks.contains(&hash) {
+                        return Err(CacheError::ChunkNotFound(hash).into());
+                    }
+                }
+            }
+            Storage::Reflink => {
+                let hash = String::from_utf8(read_blob(stream)?)?;
+                if check && !self.files.contains(&hash) {
+                    return Err(CacheError::BlobNotFound(hash).into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Run function in `threads` parallel workers and return first error.
+fn run_workers<F>(threads: usize, func: F) -> crate::Result<()>
+where
+    F: Fn() -> crate::Result<()> + Sync,
+{
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(&func)).collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })
+}
+
+// Read entry header and return where output files are stored.
+fn read_header(stream: &mut impl Read, path: &Path) -> crate::Result<Storage> {
+    let header = read_exact(stream, HEADER.len())?;
+    if header == HEADER {
+        Ok(Storage::Inline)
+    } else if header == HEADER_CHUNKED {
+        Ok(Storage::Chunked)
+    } else if header == HEADER_REFLINK {
+        Ok(Storage::Reflink)
+    } else {
+        Err(CacheError::InvalidHeader(path.to_path_buf()).into())
     }
 }
 
@@ -244,6 +926,14 @@ where
     Ok(())
 }
 
+fn is_transient_error(e: &crate::Error) -> bool {
+    match e {
+        crate::Error::IO(e) => is_transient_io_error(e),
+        crate::Error::FileOpen { error, .. } => is_transient_error(error),
+        _ => false,
+    }
+}
+
 fn write_cached_file<W: Write>(stream: &mut W, path: PathBuf) -> crate::Result<()> {
     assert!(path.is_absolute());
     let mut file = File::open(&path).map_err(|e| crate::Error::FileOpen {
@@ -271,6 +961,62 @@ fn read_cached_file(stream: &mut impl Read, path: &Path) -> crate::Result<()> {
     Ok(())
 }
 
+fn write_chunked_file<W: Write>(
+    stream: &mut W,
+    path: PathBuf,
+    chunks: &ChunkStore,
+) -> crate::Result<()> {
+    assert!(path.is_absolute());
+    let data = fs::read(&path).map_err(|e| crate::Error::FileOpen {
+        path,
+        error: Box::new(e.into()),
+    })?;
+    let hashes = chunks.store(&data)?;
+    write_usize(stream, hashes.len())?;
+    for hash in hashes {
+        write_blob(stream, hash.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_chunk_list(stream: &mut impl Read) -> crate::Result<Vec<String>> {
+    let count = read_usize(stream)?;
+    let mut hashes = Vec::with_capacity(count);
+    for _ in 0..count {
+        hashes.push(String::from_utf8(read_blob(stream)?)?);
+    }
+    Ok(hashes)
+}
+
+fn read_chunked_file(
+    stream: &mut impl Read,
+    path: &Path,
+    chunks: &ChunkStore,
+) -> crate::Result<()> {
+    let hashes = read_chunk_list(stream)?;
+    chunks.restore(&hashes, &mut File::create(path)?)?;
+    Ok(())
+}
+
+fn write_reflink_file<W: Write>(
+    stream: &mut W,
+    path: PathBuf,
+    files: &FileStore,
+) -> crate::Result<()> {
+    assert!(path.is_absolute());
+    let hash = files.store(&path).map_err(|e| crate::Error::FileOpen {
+        path,
+        error: Box::new(e),
+    })?;
+    write_blob(stream, hash.as_bytes())
+}
+
+fn read_reflink_file(stream: &mut impl Read, path: &Path, files: &FileStore) -> crate::Result<()> {
+    let hash = String::from_utf8(read_blob(stream)?)?;
+    files.restore(&hash, path)?;
+    Ok(())
+}
+
 fn write_blob(stream: &mut impl Write, blob: &[u8]) -> crate::Result<()> {
     write_usize(stream, blob.len())?;
     stream.write_all(blob)?;
@@ -297,3 +1043,732 @@ fn read_output(stream: &mut impl Read) -> crate::Result<OutputInfo> {
         stderr,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::fs::{File, FileTimes};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, SystemTime};
+
+    use crate::compiler::OutputInfo;
+    use crate::config::{CacheMode, Config};
+    use crate::io::filecache::{CacheError, EntryMetadata, FileCache, OutputFile};
+    use crate::io::statistic::Statistic;
+
+    // Successful task output without messages.
+    fn success() -> OutputInfo {
+        OutputInfo {
+            status: Some(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    // Run cached task writing `content` to object file `output`, returns whether task was run.
+    fn compile(
+        cache: &FileCache,
+        statistic: &Statistic,
+        hash: &str,
+        output: &Path,
+        content: &[u8],
+    ) -> bool {
+        let mut compiled = false;
+        let outputs = vec![OutputFile::new("object", output.to_path_buf())];
+        cache
+            .run_cached(statistic, hash, outputs, || {
+                compiled = true;
+                fs::write(output, content)?;
+                Ok(success())
+            })
+            .unwrap();
+        compiled
+    }
+
+    #[test]
+    fn test_entry_metadata() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let hash = "0123456789abcdef";
+        let metadata = EntryMetadata::new(Path::new("sample.cpp"), Some("clang 17"));
+
+        // No entry, no metadata
+        cache.write_metadata(hash, &metadata);
+        assert_eq!(cache.read_metadata(hash), EntryMetadata::unknown());
+
+        let output = temp.path().join("sample.obj");
+        compile(&cache, &Statistic::new(), hash, &output, b"object");
+        cache.write_metadata(hash, &metadata);
+        let stored = cache.read_metadata(hash);
+        assert_eq!(stored, metadata);
+        assert_eq!(stored.source, "sample.cpp");
+        assert_eq!(stored.toolchain, "clang 17");
+        assert!(stored.created > 0);
+
+        let sidecar = config.cache.join("01").join("23456789abcdef.meta");
+        fs::write(&sidecar, b"{\"source\": 42").unwrap();
+        assert_eq!(cache.read_metadata(hash), EntryMetadata::unknown());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_retry_transient_read() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let statistic = Statistic::new();
+
+        let output = temp.path().join("sample.obj");
+        let outputs = vec![OutputFile::new("object", output.clone())];
+        compile(&cache, &statistic, "0123456789abcdef", &output, b"object");
+        fs::remove_file(&output).unwrap();
+
+        // Stale network file handle on first attempt
+        let path = cache.entry_path("0123456789abcdef");
+        let mut attempts = 0;
+        cache
+            .retry(&statistic, || {
+                attempts += 1;
+                if attempts == 1 {
+                    return Err(std::io::Error::from_raw_os_error(libc::ESTALE).into());
+                }
+                cache.read_cache(&statistic, &path, &outputs)
+            })
+            .unwrap();
+        assert_eq!(attempts, 2);
+        assert_eq!(fs::read(&output).unwrap(), b"object");
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.retry_count.load(Ordering::Relaxed), 1);
+
+        // Permanent errors are not retried
+        let mut attempts = 0;
+        let missing = cache.entry_path("fedcba9876543210");
+        assert!(cache
+            .retry(&statistic, || {
+                attempts += 1;
+                cache.read_cache(&statistic, &missing, &outputs)
+            })
+            .is_err());
+        assert_eq!(attempts, 1);
+
+        // Writing missing output is a failure, not retried either
+        fs::remove_file(&output).unwrap();
+        let output_info = success();
+        let mut attempts = 0;
+        assert!(cache
+            .retry(&statistic, || {
+                attempts += 1;
+                cache.write_cache(&statistic, &missing, &outputs, &output_info, false)
+            })
+            .is_err());
+        assert_eq!(attempts, 1);
+        assert!(!missing.exists());
+        assert_eq!(statistic.retry_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_write_only_warm() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let output = temp.path().join("sample.obj");
+        let run = |cache: &FileCache, statistic: &Statistic, hash: &str, content: &str| {
+            compile(cache, statistic, hash, &output, content.as_bytes())
+        };
+        let statistic = Statistic::new();
+        assert!(run(
+            &FileCache::new(&config),
+            &statistic,
+            "0123456789abcdef",
+            "old"
+        ));
+
+        // Existing entry is recompiled and replaced, missing entry is added
+        let warm = FileCache::new(&Config {
+            cache_mode: CacheMode::WriteOnly,
+            ..config.clone()
+        });
+        let statistic = Statistic::new();
+        assert!(run(&warm, &statistic, "0123456789abcdef", "new"));
+        assert!(run(&warm, &statistic, "fedcba9876543210", "other"));
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 0);
+        assert_eq!(statistic.miss_count.load(Ordering::Relaxed), 2);
+
+        let cache = FileCache::new(&config);
+        for (hash, content) in [("0123456789abcdef", "new"), ("fedcba9876543210", "other")] {
+            assert!(!run(&cache, &statistic, hash, "unused"));
+            assert_eq!(fs::read_to_string(&output).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_write() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let statistic = Statistic::new();
+        let barrier = std::sync::Barrier::new(2);
+        let store = |cache: &FileCache, name: &str| {
+            let output = temp.path().join(name);
+            cache
+                .run_cached(
+                    &statistic,
+                    "0123456789abcdef",
+                    vec![OutputFile::new("object", output.clone())],
+                    || {
+                        fs::write(&output, name.repeat(100_000))?;
+                        // Both builds miss and store the same entry at once
+                        barrier.wait();
+                        Ok(success())
+                    },
+                )
+                .unwrap();
+        };
+        std::thread::scope(|scope| {
+            for name in ["first.obj", "second.obj"] {
+                let cache = FileCache::new(&config);
+                scope.spawn(move || store(&cache, name));
+            }
+        });
+        assert_eq!(statistic.miss_count.load(Ordering::Relaxed), 2);
+
+        let files: Vec<PathBuf> = fs::read_dir(config.cache.join("01"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(
+            files,
+            [FileCache::new(&config).entry_path("0123456789abcdef")]
+        );
+
+        let output = temp.path().join("restored.obj");
+        FileCache::new(&config)
+            .run_cached(
+                &statistic,
+                "0123456789abcdef",
+                vec![OutputFile::new("object", output.clone())],
+                || unreachable!(),
+            )
+            .unwrap();
+        let content = fs::read_to_string(&output).unwrap();
+        assert!(content == "first.obj".repeat(100_000) || content == "second.obj".repeat(100_000));
+    }
+
+    #[test]
+    fn test_write_error_not_fatal() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let statistic = Statistic::new();
+
+        // Declared output is not produced, so it can't be stored
+        let output = temp.path().join("missing.obj");
+        let result = cache
+            .run_cached(
+                &statistic,
+                "0123456789abcdef",
+                vec![OutputFile::new("object", output)],
+                || {
+                    Ok(OutputInfo {
+                        stdout: b"done".to_vec(),
+                        ..success()
+                    })
+                },
+            )
+            .unwrap();
+        assert_eq!(result.stdout, b"done");
+        assert!(!cache.entry_path("0123456789abcdef").exists());
+    }
+
+    #[test]
+    fn test_dedup_roundtrip() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            cache_dedup: true,
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let statistic = Statistic::new();
+
+        let output = temp.path().join("sample.obj");
+        compile(&cache, &statistic, "0123456789abcdef", &output, b"object");
+        fs::remove_file(&output).unwrap();
+        cache
+            .run_cached(
+                &statistic,
+                "0123456789abcdef",
+                vec![OutputFile::new("object", output.clone())],
+                || unreachable!(),
+            )
+            .unwrap();
+
+        assert_eq!(fs::read(&output).unwrap(), b"object");
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            cache.verify(1, false).unwrap().good.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_broken_entry_rewritten() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            cache_dedup: true,
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let statistic = Statistic::new();
+
+        let output = temp.path().join("sample.obj");
+        let run = || compile(&cache, &statistic, "0123456789abcdef", &output, b"object");
+        let path = cache.entry_path("0123456789abcdef");
+        assert!(run());
+
+        // Truncated entry
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() / 2]).unwrap();
+        assert!(run());
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        // Entry with evicted chunks
+        fs::remove_dir_all(temp.path().join("cache").join("chunks")).unwrap();
+        assert!(run());
+        assert!(!run());
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            cache.verify(1, false).unwrap().good.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_reflink_roundtrip() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            cache_reflink: true,
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let statistic = Statistic::new();
+
+        let output = temp.path().join("sample.obj");
+        let outputs = vec![OutputFile::new("object", output.clone())];
+        compile(&cache, &statistic, "0123456789abcdef", &output, b"object");
+        // Rewriting output must not affect cached copy
+        fs::write(&output, b"garbage").unwrap();
+        cache
+            .run_cached(&statistic, "0123456789abcdef", outputs, || unreachable!())
+            .unwrap();
+
+        assert_eq!(fs::read(&output).unwrap(), b"object");
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            cache.verify(1, false).unwrap().good.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_inspect_entry() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let object = temp.path().join("sample.obj");
+        let pch = temp.path().join("sample.pch");
+        let hash = "0123456789abcdef";
+        cache
+            .run_cached(
+                &Statistic::new(),
+                hash,
+                vec![
+                    OutputFile::new("object", object.clone()),
+                    OutputFile::new("pch", pch.clone()),
+                ],
+                || {
+                    fs::write(&object, b"object")?;
+                    fs::write(&pch, b"pch")?;
+                    Ok(OutputInfo {
+                        stdout: b"sample.cpp\n".to_vec(),
+                        ..success()
+                    })
+                },
+            )
+            .unwrap();
+        let metadata = EntryMetadata::new(Path::new("sample.cpp"), Some("clang 17"));
+        cache.write_metadata(hash, &metadata);
+
+        let info = cache.inspect("0123456789ABCDEF", None).unwrap();
+        let entry = config.cache.join("01").join("23456789abcdef.lz4");
+        assert_eq!(info.path, entry);
+        assert_eq!(info.metadata, metadata);
+        assert_eq!(
+            info.outputs,
+            [("object".to_string(), 6), ("pch".to_string(), 3)]
+        );
+        assert_eq!(info.output.stdout, b"sample.cpp\n");
+        assert_eq!(info.size, fs::metadata(&entry).unwrap().len());
+        assert_eq!(
+            info.checksum,
+            crate::utils::hash_stream(&mut File::open(&entry).unwrap()).unwrap()
+        );
+        let text = info.to_string();
+        assert!(text.contains("  toolchain: clang 17\n"), "{text}");
+        assert!(text.contains("    object: 6 bytes\n"), "{text}");
+
+        let dir = temp.path().join("extracted");
+        let extracted = cache.inspect(hash, Some(&dir)).unwrap();
+        assert_eq!(extracted.outputs, info.outputs);
+        assert_eq!(fs::read(dir.join("object")).unwrap(), b"object");
+        assert_eq!(fs::read(dir.join("pch")).unwrap(), b"pch");
+
+        for missing in ["fedcba9876543210", "01", "../secret"] {
+            match cache.inspect(missing, None) {
+                Err(crate::Error::Cache(CacheError::EntryNotFound(key))) => {
+                    assert_eq!(key, missing);
+                }
+                _ => panic!("missing entry {missing} is not reported"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_restore_subset() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let statistic = Statistic::new();
+
+        let object = temp.path().join("sample.obj");
+        let pch = temp.path().join("sample.pch");
+        let hash = "0123456789abcdef";
+        cache
+            .run_cached(
+                &statistic,
+                hash,
+                vec![
+                    OutputFile::new("object", object.clone()),
+                    OutputFile::new("pch", pch.clone()),
+                ],
+                || {
+                    fs::write(&object, b"object")?;
+                    fs::write(&pch, b"pch")?;
+                    Ok(success())
+                },
+            )
+            .unwrap();
+        fs::remove_file(&object).unwrap();
+        fs::remove_file(&pch).unwrap();
+
+        // Only declared output is restored
+        let restored = temp.path().join("restored.obj");
+        cache
+            .run_cached(
+                &statistic,
+                hash,
+                vec![OutputFile::new("object", restored.clone())],
+                || unreachable!(),
+            )
+            .unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), b"object");
+        assert!(!pch.exists());
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+
+        // Output missing from entry is a cache miss
+        let map = temp.path().join("sample.map");
+        let output = cache
+            .run_cached(
+                &statistic,
+                hash,
+                vec![
+                    OutputFile::new("object", restored.clone()),
+                    OutputFile::new("map", map.clone()),
+                ],
+                || {
+                    fs::write(&map, b"map")?;
+                    Ok(OutputInfo {
+                        status: Some(1),
+                        ..success()
+                    })
+                },
+            )
+            .unwrap();
+        assert_eq!(output.status, Some(1));
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_strip_color() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+
+        let output = temp.path().join("sample.obj");
+        let hash = "0123456789abcdef";
+        let outputs = vec![OutputFile::new("object", output.clone())];
+        let stderr = b"\x1B[1msample.c:1:1: \x1B[35mwarning:\x1B[0m unused\n";
+        let result = cache
+            .run_cached(&Statistic::new(), hash, outputs.clone(), || {
+                fs::write(&output, b"object")?;
+                Ok(OutputInfo {
+                    stderr: stderr.to_vec(),
+                    ..success()
+                })
+            })
+            .unwrap();
+        // Fresh output is kept as is
+        assert_eq!(result.stderr, stderr);
+
+        let result = cache
+            .run_cached(&Statistic::new(), hash, outputs, || unreachable!())
+            .unwrap();
+        assert_eq!(result.stderr, b"sample.c:1:1: warning: unused\n");
+        assert!(!result.stderr.contains(&0x1B));
+    }
+
+    fn populate_cache(dir: &Path) {
+        let now = SystemTime::now();
+        for i in 0..200u64 {
+            let path = dir.join(format!("{:02x}", i % 16)).join(format!("{i}.lz4"));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, vec![0; 1024]).unwrap();
+            let time = now - Duration::from_secs(i * 60);
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_times(FileTimes::new().set_accessed(time).set_modified(time))
+                .unwrap();
+        }
+    }
+
+    fn list_files(dir: &Path) -> BTreeSet<PathBuf> {
+        let mut files = BTreeSet::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = PathBuf::from(path.file_name().unwrap());
+            if path.is_dir() {
+                files.extend(list_files(&path).into_iter().map(|file| name.join(file)));
+            } else {
+                files.insert(name);
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_cleanup_parallel() {
+        let temp = tempfile::tempdir().unwrap();
+        let cleanup = |name: &str, threads: usize| {
+            let config = Config {
+                cache: temp.path().join(name),
+                cache_limit_mb: 0,
+                ..Config::default()
+            };
+            populate_cache(&config.cache);
+            let mut cache = FileCache::new(&config);
+            // 50 files of 1KB each
+            cache.cache_limit = 50 * 1024;
+            cache.cleanup(threads, &AtomicBool::new(false)).unwrap();
+            list_files(&config.cache)
+        };
+
+        let serial = cleanup("serial", 1);
+        let parallel = cleanup("parallel", 8);
+        assert_eq!(serial.len(), 50);
+        assert_eq!(serial, parallel);
+        // Newest files are kept
+        assert!(serial.contains(&PathBuf::from("00").join("0.lz4")));
+        assert!(!serial.contains(&PathBuf::from("07").join("199.lz4")));
+    }
+
+    #[test]
+    fn test_cleanup_pinned() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            cache_dedup: true,
+            cache_limit_mb: 0,
+            cache_pin: vec!["**/keep/*.obj".to_string()],
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let store = |name: &str, hash: &str| {
+            let output = temp.path().join(name);
+            fs::create_dir_all(output.parent().unwrap()).unwrap();
+            let content = name.repeat(1000);
+            compile(&cache, &Statistic::new(), hash, &output, content.as_bytes());
+            cache.set_pinned(hash, cache.is_pinned([output.as_path()]));
+            output
+        };
+        let kept = store("keep/a.obj", "aa01");
+        for i in 0..5 {
+            store(&format!("drop/{i}.obj"), &format!("bb0{i}"));
+        }
+        assert!(cache.inspect("aa01", None).unwrap().pinned);
+        assert!(!cache.inspect("bb00", None).unwrap().pinned);
+
+        // Nothing fits into the limit, only unpinned entries are evicted
+        cache.cleanup(2, &AtomicBool::new(false)).unwrap();
+        let files = list_files(&config.cache);
+        assert!(files.contains(&PathBuf::from("aa").join("01.lz4")));
+        assert!(files.contains(&PathBuf::from("aa").join("01.pin")));
+        assert!(!files.iter().any(|v| v.starts_with("bb")), "{files:?}");
+        // Referenced chunks are kept too
+        fs::remove_file(&kept).unwrap();
+        cache
+            .run_cached(
+                &Statistic::new(),
+                "aa01",
+                vec![OutputFile::new("object", kept.clone())],
+                || panic!("pinned entry is evicted"),
+            )
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&kept).unwrap(),
+            "keep/a.obj".repeat(1000)
+        );
+
+        // Entry no longer matching pin globs is unpinned on use
+        cache.set_pinned("aa01", false);
+        assert!(!cache.inspect("aa01", None).unwrap().pinned);
+        cache.cleanup(2, &AtomicBool::new(false)).unwrap();
+        assert!(!config.cache.join("aa").join("01.lz4").exists());
+    }
+
+    #[test]
+    fn test_cleanup_sidecars_and_chunks() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            cache_dedup: true,
+            ..Config::default()
+        };
+        let mut cache = FileCache::new(&config);
+        let store = |cache: &FileCache, name: &str, hash: &str| {
+            let output = temp.path().join(name);
+            let content = name.repeat(1000);
+            compile(cache, &Statistic::new(), hash, &output, content.as_bytes());
+            cache.write_metadata(hash, &EntryMetadata::new(Path::new(name), None));
+            output
+        };
+        let set_time = |path: &Path, time: SystemTime| {
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_times(FileTimes::new().set_accessed(time).set_modified(time))
+                .unwrap();
+        };
+        let kept = store(&cache, "new.obj", "dd01");
+        let kept_files = list_files(&config.cache);
+        let kept_size: u64 = (kept_files.iter())
+            .map(|path| fs::metadata(config.cache.join(path)).unwrap().len())
+            .sum();
+        store(&cache, "old.obj", "cc01");
+        let old = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        set_time(&config.cache.join("cc").join("01.lz4"), old);
+        let orphans = [
+            PathBuf::from("ee").join("01.meta"),
+            PathBuf::from("chunks").join("ff").join("orphan.chunk"),
+        ];
+        for orphan in &orphans {
+            let path = config.cache.join(orphan);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, b"orphan").unwrap();
+        }
+        for path in list_files(&config.cache) {
+            if path.starts_with("chunks") {
+                set_time(&config.cache.join(path), old);
+            }
+        }
+
+        // Old entry is evicted with its metadata and chunks, orphans are removed
+        cache.cache_limit = kept_size;
+        cache.cleanup(2, &AtomicBool::new(false)).unwrap();
+        assert_eq!(list_files(&config.cache), kept_files);
+        fs::remove_file(&kept).unwrap();
+        cache
+            .run_cached(
+                &Statistic::new(),
+                "dd01",
+                vec![OutputFile::new("object", kept.clone())],
+                || panic!("kept entry is evicted"),
+            )
+            .unwrap();
+        assert_eq!(fs::read_to_string(&kept).unwrap(), "new.obj".repeat(1000));
+    }
+
+    #[test]
+    fn test_cleanup_abort() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            cache_limit_mb: 0,
+            ..Config::default()
+        };
+        populate_cache(&config.cache);
+        let cache = FileCache::new(&config);
+        cache.cleanup(4, &AtomicBool::new(true)).unwrap();
+        assert_eq!(list_files(&config.cache).len(), 200);
+    }
+
+    #[test]
+    fn test_verify_corrupt() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+
+        let output = temp.path().join("sample.obj");
+        let hash = "0123456789abcdef";
+        compile(&cache, &Statistic::new(), hash, &output, b"object");
+
+        let corrupt = config.cache.join("fe").join("dcba.lz4");
+        fs::create_dir_all(corrupt.parent().unwrap()).unwrap();
+        fs::write(&corrupt, b"garbage").unwrap();
+
+        let statistic = cache.verify(2, false).unwrap();
+        assert_eq!(statistic.good.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.corrupt.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.removed.load(Ordering::Relaxed), 0);
+        assert!(corrupt.exists());
+
+        let statistic = cache.verify(2, true).unwrap();
+        assert_eq!(statistic.good.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.corrupt.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.removed.load(Ordering::Relaxed), 1);
+        assert!(!corrupt.exists());
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::fs::{File, FileTimes, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::debug;
+
+use crate::utils::hash_stream;
+
+const SUFFIX: &str = ".blob";
+
+// How file content was transferred.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CopyMethod {
+    // Copy-on-write clone, no data is copied
+    Reflink,
+    // Filesystem doesn't support reflinks, data is copied
+    Copy,
+}
+
+// Content-addressed storage for uncompressed whole files.
+// Files are cloned in and out of store, which is a metadata-only operation on COW filesystems.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    #[must_use]
+    pub fn new(dir: PathBuf) -> Self {
+        FileStore { dir }
+    }
+
+    // Store file and return its content hash.
+    pub fn store(&self, source: &Path) -> crate::Result<String> {
+        let hash = hash_stream(&mut File::open(source)?)?;
+        let path = self.blob_path(&hash);
+        if !path.exists() {
+            let parent = path.parent().unwrap();
+            fs::create_dir_all(parent)?;
+            let temp = tempfile::Builder::new().make_in(parent, |temp| {
+                reflink_or_copy(source, temp).map(|method| {
+                    debug!("Store {} to cache: {method:?}", source.display());
+                })
+            })?;
+            // Other process may store the same file concurrently.
+            if let Err(e) = temp.persist(&path) {
+                if !path.exists() {
+                    return Err(e.error.into());
+                }
+            }
+        }
+        Ok(hash)
+    }
+
+    // Clone stored file to target path.
+    pub fn restore(&self, hash: &str, target: &Path) -> crate::Result<CopyMethod> {
+        let path = self.blob_path(hash);
+        // Touch file to keep it from eviction. Changing times requires write access on Windows,
+        // but content is never written: it would break sharing with restored copies.
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_times(FileTimes::new().set_accessed(SystemTime::now()))?;
+        let method = reflink_or_copy(&path, target)?;
+        debug!("Restore {} from cache: {method:?}", target.display());
+        Ok(method)
+    }
+
+    // Size of stored file.
+    pub fn size(&self, hash: &str) -> crate::Result<u64> {
+        Ok(fs::metadata(self.blob_path(hash))?.len())
+    }
+
+    #[must_use]
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blob_path(hash).is_file()
+    }
+
+    #[must_use]
+    pub fn blob_path(&self, hash: &str) -> PathBuf {
+        self.dir
+            .join(&hash[0..2])
+            .join(hash[2..].to_string() + SUFFIX)
+    }
+}
+
+// Clone file if filesystem supports it, with fallback to regular copy.
+fn reflink_or_copy(source: &Path, target: &Path) -> std::io::Result<CopyMethod> {
+    // Target must not exist for reflink to succeed
+    drop(fs::remove_file(target));
+    Ok(match reflink_copy::reflink_or_copy(source, target)? {
+        None => CopyMethod::Reflink,
+        Some(_) => CopyMethod::Copy,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::io::filestore::{CopyMethod, FileStore};
+
+    #[test]
+    fn test_store_restore() {
+        let temp = tempfile::tempdir().unwrap();
+        let store = FileStore::new(temp.path().join("files"));
+
+        let source = temp.path().join("source.obj");
+        let data: Vec<u8> = (0..256 * 1024).map(|_| rand::random::<u8>()).collect();
+        fs::write(&source, &data).unwrap();
+        let hash = store.store(&source).unwrap();
+        assert!(store.contains(&hash));
+        // Same content is stored once
+        assert_eq!(store.store(&source).unwrap(), hash);
+
+        let target = temp.path().join("target.obj");
+        fs::write(&target, b"stale").unwrap();
+        let method = store.restore(&hash, &target).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), data);
+
+        // Reflink must be used whenever filesystem supports it
+        let supported = reflink_copy::reflink(&source, temp.path().join("probe.obj")).is_ok();
+        let expected = if supported {
+            CopyMethod::Reflink
+        } else {
+            CopyMethod::Copy
+        };
+        assert_eq!(method, expected);
+
+        // Restored file is independent from cached one
+        fs::write(&target, b"modified").unwrap();
+        let again = temp.path().join("again.obj");
+        store.restore(&hash, &again).unwrap();
+        assert_eq!(fs::read(&again).unwrap(), data);
+    }
+}
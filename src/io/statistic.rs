@@ -1,7 +1,24 @@
+use std::cell::Cell;
 use std::cmp::max;
+use std::collections::{BTreeMap, BTreeSet};
 
 use std::fmt;
+use std::io::Write;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+// How many slowest tasks are remembered.
+const SLOWEST_TASKS: usize = 10;
+// Version of serialized statistic format.
+const SNAPSHOT_VERSION: u32 = 1;
+
+thread_local! {
+    // Cache hits of current thread, tells whether task run by it was restored from cache.
+    static THREAD_HITS: Cell<usize> = const { Cell::new(0) };
+}
 
 #[derive(Default)]
 pub struct Statistic {
@@ -10,6 +27,37 @@ pub struct Statistic {
     pub miss_count: AtomicUsize,
     pub miss_bytes: AtomicUsize,
     pub remote_count: AtomicUsize,
+    // Cache operations retried after transient filesystem errors.
+    pub retry_count: AtomicUsize,
+    pub workers: Mutex<Vec<WorkerStatistic>>,
+    // Slowest completed tasks, slowest first.
+    pub slowest: Mutex<Vec<(String, Duration)>>,
+    // Duration of every completed task by title.
+    pub durations: Mutex<BTreeMap<String, Duration>>,
+    // Titles of compilation tasks that weren't restored from cache.
+    pub uncached: Mutex<Vec<String>>,
+    // Compilation tasks by compiler family.
+    pub compilers: Mutex<BTreeMap<&'static str, CompilerStatistic>>,
+    // Commands which aren't compilations, like linking, run as is.
+    pub direct_count: AtomicUsize,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompilerStatistic {
+    // Completed task count
+    pub tasks: usize,
+    // Tasks restored from cache
+    pub hits: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct WorkerStatistic {
+    // Completed task count
+    pub tasks: usize,
+    // Time spent executing tasks
+    pub busy: Duration,
+    // Time spent waiting for tasks
+    pub idle: Duration,
 }
 
 impl fmt::Display for Statistic {
@@ -19,14 +67,16 @@ impl fmt::Display for Statistic {
         let miss_count = self.miss_count.load(Ordering::Relaxed);
         let miss_bytes = self.miss_bytes.load(Ordering::Relaxed);
         let remote_count = self.remote_count.load(Ordering::Relaxed);
+        let retry_count = self.retry_count.load(Ordering::Relaxed);
         let total_count = hit_count + miss_count;
         write!(
             f,
-            "Cache statistic: hit {} of {} ({} %), remote {}, read {}, write {}, total {}",
+            "Cache statistic: hit {} of {} ({} %), remote {}, retried {}, read {}, write {}, total {}",
             hit_count,
             total_count,
             hit_count * 100 / max(total_count, 1),
             remote_count,
+            retry_count,
             hit_bytes,
             miss_bytes,
             hit_bytes + miss_bytes,
@@ -41,6 +91,7 @@ impl Statistic {
     }
 
     pub fn add_hit(&self, bytes: usize) {
+        THREAD_HITS.set(THREAD_HITS.get() + 1);
         self.hit_count.fetch_add(1, Ordering::Release);
         self.hit_bytes.fetch_add(bytes, Ordering::Release);
     }
@@ -50,7 +101,339 @@ impl Statistic {
         self.miss_bytes.fetch_add(bytes, Ordering::Release);
     }
 
+    #[must_use]
+    pub fn thread_hits() -> usize {
+        THREAD_HITS.get()
+    }
+
+    pub fn add_uncached(&self, title: &str) {
+        self.uncached.lock().unwrap().push(title.to_string());
+    }
+
+    pub fn add_compiler_task(&self, compiler: &'static str, hit: bool) {
+        let mut compilers = self.compilers.lock().unwrap();
+        let item = compilers.entry(compiler).or_default();
+        item.tasks += 1;
+        item.hits += usize::from(hit);
+    }
+
+    pub fn inc_direct(&self) {
+        self.direct_count.fetch_add(1, Ordering::Release);
+    }
+
     pub fn inc_remote(&self) {
         self.remote_count.fetch_add(1, Ordering::Release);
     }
+
+    pub fn inc_retry(&self) {
+        self.retry_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn add_worker(&self, worker: usize, stat: WorkerStatistic) {
+        let mut workers = self.workers.lock().unwrap();
+        if workers.len() <= worker {
+            workers.resize(worker + 1, WorkerStatistic::default());
+        }
+        let item = &mut workers[worker];
+        item.tasks += stat.tasks;
+        item.busy += stat.busy;
+        item.idle += stat.idle;
+    }
+
+    pub fn add_task(&self, title: &str, duration: Duration) {
+        self.durations
+            .lock()
+            .unwrap()
+            .insert(title.to_string(), duration);
+        let mut slowest = self.slowest.lock().unwrap();
+        if slowest.len() == SLOWEST_TASKS && slowest[SLOWEST_TASKS - 1].1 >= duration {
+            return;
+        }
+        let position = slowest.partition_point(|(_, d)| *d >= duration);
+        slowest.insert(position, (title.to_string(), duration));
+        slowest.truncate(SLOWEST_TASKS);
+    }
+
+    // Capture statistic of the build that took `elapsed` time.
+    #[must_use]
+    pub fn snapshot(&self, elapsed: Duration) -> StatisticSnapshot {
+        StatisticSnapshot {
+            version: SNAPSHOT_VERSION,
+            hit_count: self.hit_count.load(Ordering::Relaxed),
+            miss_count: self.miss_count.load(Ordering::Relaxed),
+            remote_count: self.remote_count.load(Ordering::Relaxed),
+            hit_bytes: self.hit_bytes.load(Ordering::Relaxed),
+            miss_bytes: self.miss_bytes.load(Ordering::Relaxed),
+            elapsed: elapsed.as_secs_f64(),
+            workers: self
+                .workers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|worker| WorkerSnapshot {
+                    tasks: worker.tasks,
+                    busy: worker.busy.as_secs_f64(),
+                    idle: worker.idle.as_secs_f64(),
+                })
+                .collect(),
+            slowest: self
+                .slowest
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(title, duration)| TaskSnapshot {
+                    title: title.clone(),
+                    duration: duration.as_secs_f64(),
+                })
+                .collect(),
+            durations: self
+                .durations
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(title, duration)| (title.clone(), duration.as_secs_f64()))
+                .collect(),
+        }
+    }
+
+    pub fn print_workers(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let workers = self.workers.lock().unwrap();
+        if workers.is_empty() {
+            return Ok(());
+        }
+        writeln!(out, "Worker utilization:")?;
+        let mut busy_total = Duration::ZERO;
+        let mut idle_total = Duration::ZERO;
+        for (index, worker) in workers.iter().enumerate() {
+            writeln!(
+                out,
+                "  #{index}: tasks {}, busy {:.1}s, idle {:.1}s ({} %)",
+                worker.tasks,
+                worker.busy.as_secs_f64(),
+                worker.idle.as_secs_f64(),
+                utilization(worker.busy, worker.idle),
+            )?;
+            busy_total += worker.busy;
+            idle_total += worker.idle;
+        }
+        writeln!(
+            out,
+            "  total: busy {:.1}s, idle {:.1}s ({} %)",
+            busy_total.as_secs_f64(),
+            idle_total.as_secs_f64(),
+            utilization(busy_total, idle_total),
+        )
+    }
+
+    pub fn print_compilers(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let compilers = self.compilers.lock().unwrap();
+        let direct = self.direct_count.load(Ordering::Relaxed);
+        if compilers.is_empty() && direct == 0 {
+            return Ok(());
+        }
+        writeln!(out, "Backend usage:")?;
+        for (compiler, stat) in compilers.iter() {
+            writeln!(
+                out,
+                "  {compiler}: {} tasks, {} hits",
+                stat.tasks, stat.hits
+            )?;
+        }
+        writeln!(out, "  other commands: {direct} tasks")
+    }
+}
+
+fn utilization(busy: Duration, idle: Duration) -> u128 {
+    busy.as_millis() * 100 / max(busy.as_millis() + idle.as_millis(), 1)
+}
+
+// Serializable build statistic for comparing builds. Times are in seconds.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StatisticSnapshot {
+    pub version: u32,
+    pub hit_count: usize,
+    pub miss_count: usize,
+    pub remote_count: usize,
+    pub hit_bytes: usize,
+    pub miss_bytes: usize,
+    // Build wall time
+    pub elapsed: f64,
+    pub workers: Vec<WorkerSnapshot>,
+    // Slowest tasks, slowest first
+    pub slowest: Vec<TaskSnapshot>,
+    // Duration of every task by title, missing in files written by older versions
+    #[serde(default)]
+    pub durations: BTreeMap<String, f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WorkerSnapshot {
+    pub tasks: usize,
+    pub busy: f64,
+    pub idle: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TaskSnapshot {
+    pub title: String,
+    pub duration: f64,
+}
+
+impl StatisticSnapshot {
+    #[must_use]
+    pub fn hit_rate(&self) -> f64 {
+        percent(
+            self.hit_count as f64,
+            (self.hit_count + self.miss_count) as f64,
+        )
+    }
+
+    #[must_use]
+    pub fn utilization(&self) -> f64 {
+        let busy: f64 = self.workers.iter().map(|worker| worker.busy).sum();
+        let idle: f64 = self.workers.iter().map(|worker| worker.idle).sum();
+        percent(busy, busy + idle)
+    }
+
+    // Print changes since `old` build.
+    pub fn print_diff(&self, old: &StatisticSnapshot, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(out, "Statistic comparison:")?;
+        writeln!(
+            out,
+            "  cache hit rate: {:.0} % -> {:.0} % ({:+.0} %)",
+            old.hit_rate(),
+            self.hit_rate(),
+            self.hit_rate() - old.hit_rate()
+        )?;
+        writeln!(
+            out,
+            "  total time: {:.1}s -> {:.1}s ({:+.1}s, {:+.0} %)",
+            old.elapsed,
+            self.elapsed,
+            self.elapsed - old.elapsed,
+            percent(self.elapsed - old.elapsed, old.elapsed)
+        )?;
+        writeln!(
+            out,
+            "  utilization: {:.0} % -> {:.0} % ({:+.0} %)",
+            old.utilization(),
+            self.utilization(),
+            self.utilization() - old.utilization()
+        )?;
+        for index in 0..max(old.workers.len(), self.workers.len()) {
+            let format = |worker: Option<&WorkerSnapshot>| {
+                worker.map_or_else(
+                    || "-".to_string(),
+                    |w| format!("{:.0} %", percent(w.busy, w.busy + w.idle)),
+                )
+            };
+            writeln!(
+                out,
+                "    #{index}: {} -> {}",
+                format(old.workers.get(index)),
+                format(self.workers.get(index))
+            )?;
+        }
+        writeln!(out, "  slowest tasks:")?;
+        let mut shown = BTreeSet::new();
+        for task in self.slowest.iter().chain(old.slowest.iter()) {
+            if !shown.insert(task.title.as_str()) {
+                continue;
+            }
+            let find = |snapshot: &StatisticSnapshot| {
+                snapshot
+                    .slowest
+                    .iter()
+                    .find(|t| t.title == task.title)
+                    .map(|t| t.duration)
+            };
+            let format = |duration: Option<f64>| {
+                duration.map_or_else(|| "-".to_string(), |d| format!("{d:.1}s"))
+            };
+            let (before, after) = (find(old), find(self));
+            write!(
+                out,
+                "    {}: {} -> {}",
+                task.title,
+                format(before),
+                format(after)
+            )?;
+            if let (Some(before), Some(after)) = (before, after) {
+                write!(out, " ({:+.1}s)", after - before)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+fn percent(value: f64, total: f64) -> f64 {
+    if total > 0.0 {
+        value * 100.0 / total
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::io::statistic::{Statistic, StatisticSnapshot};
+
+    #[test]
+    fn test_slowest_tasks() {
+        let statistic = Statistic::new();
+        for i in 0..20 {
+            statistic.add_task(&format!("task {i}"), Duration::from_millis(i * 7 % 20));
+        }
+        let slowest = statistic.slowest.lock().unwrap();
+        assert_eq!(slowest.len(), 10);
+        assert_eq!(
+            slowest[0],
+            ("task 17".to_string(), Duration::from_millis(19))
+        );
+        assert!(slowest.windows(2).all(|w| w[0].1 >= w[1].1));
+        drop(slowest);
+        // Snapshot keeps duration of every task
+        let snapshot = statistic.snapshot(Duration::ZERO);
+        assert_eq!(snapshot.slowest.len(), 10);
+        assert_eq!(snapshot.durations.len(), 20);
+        assert_eq!(snapshot.durations["task 1"], 0.007);
+    }
+
+    #[test]
+    fn test_print_diff() {
+        let old: StatisticSnapshot = serde_json::from_str(
+            r#"{"version":1,"hit_count":2,"miss_count":8,"remote_count":0,"hit_bytes":0,"miss_bytes":0,
+                "elapsed":100.0,
+                "workers":[{"tasks":5,"busy":50.0,"idle":50.0},{"tasks":5,"busy":50.0,"idle":50.0}],
+                "slowest":[{"title":"big.cpp","duration":30.0},{"title":"old.cpp","duration":20.0}]}"#,
+        )
+        .unwrap();
+        let new: StatisticSnapshot = serde_json::from_str(
+            r#"{"version":1,"hit_count":8,"miss_count":2,"remote_count":0,"hit_bytes":0,"miss_bytes":0,
+                "elapsed":75.0,
+                "workers":[{"tasks":5,"busy":60.0,"idle":15.0},{"tasks":5,"busy":45.0,"idle":30.0}],
+                "slowest":[{"title":"new.cpp","duration":25.0},{"title":"big.cpp","duration":10.0}]}"#,
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        new.print_diff(&old, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"Statistic comparison:
+  cache hit rate: 20 % -> 80 % (+60 %)
+  total time: 100.0s -> 75.0s (-25.0s, -25 %)
+  utilization: 50 % -> 70 % (+20 %)
+    #0: 50 % -> 80 %
+    #1: 50 % -> 60 %
+  slowest tasks:
+    new.cpp: - -> 25.0s
+    big.cpp: 30.0s -> 10.0s (-20.0s)
+    old.cpp: 20.0s -> -
+"#
+        );
+    }
 }
@@ -4,6 +4,8 @@ use std::path::{Path, PathBuf};
 
 use uuid::Uuid;
 
+use crate::cmd::native::long_path;
+
 pub struct TempFile {
     path: Option<PathBuf>,
     disarmed: bool,
@@ -14,7 +16,7 @@ impl TempFile {
     #[must_use]
     pub fn new_in(path: &Path, suffix: &str) -> Self {
         let random_name = Uuid::new_v4().to_string() + suffix;
-        TempFile::wrap(&path.join(random_name))
+        TempFile::wrap(&long_path(&path.join(random_name)))
     }
 
     /// Wrap path to a temporary file. The file will be automatically
@@ -8,6 +8,7 @@ use crate::io::filecache::CacheError;
 use crate::vs::postprocess::PostprocessError;
 
 pub mod cache;
+pub mod checkpoint;
 
 pub mod cluster {
     pub mod builder;
@@ -17,14 +18,18 @@ pub mod cluster {
 
 pub mod compiler;
 pub mod config;
+pub mod dependencies;
+pub mod history;
 pub mod lazy;
 pub mod utils;
 pub mod version;
 
 pub mod io {
     pub mod binary;
+    pub mod chunkstore;
     pub mod counter;
     pub mod filecache;
+    pub mod filestore;
     pub mod memcache;
     pub mod memstream;
     pub mod statistic;
@@ -33,6 +38,7 @@ pub mod io {
 
 pub mod xg {
     pub mod parser;
+    pub mod source;
 }
 
 pub mod vs {
@@ -50,13 +56,30 @@ pub mod cmd {
     pub mod native;
 }
 
+pub mod reproduce;
+pub mod signal;
 pub mod simple;
+#[cfg(all(test, unix))]
+mod test_utils;
+pub mod trace;
 pub mod worker;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
     Bincode(#[from] bincode::Error),
+    #[error("Build failed: {failed} of {total} tasks failed, {skipped} skipped")]
+    BuildFailed {
+        failed: usize,
+        skipped: usize,
+        total: usize,
+    },
+    #[error("Build timed out after {limit:?}: {completed} of {total} tasks completed")]
+    BuildTimedOut {
+        limit: std::time::Duration,
+        completed: usize,
+        total: usize,
+    },
     #[error(transparent)]
     Cache(#[from] CacheError),
     #[error("Found cycles in build graph")]
@@ -78,13 +101,29 @@ pub enum Error {
     Generic(String),
     #[error(transparent)]
     IO(std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Compiled object {path} is malformed: {reason}")]
+    MalformedObject { path: PathBuf, reason: &'static str },
+    #[error("Compilation of {input} succeeded, but declared {role} file {output} doesn't exist")]
+    MissingOutput {
+        input: PathBuf,
+        role: &'static str,
+        output: PathBuf,
+    },
     #[error("Build task files not found")]
     NoTaskFiles,
+    #[error("{}", .0.join("\n"))]
+    OutputDirNotWritable(Vec<String>),
+    #[error("{}", .0.join("\n"))]
+    OutputCollision(Vec<String>),
     #[error("Failed to compile {path}: {error}")]
     Compilation {
         path: PathBuf,
         error: Box<crate::Error>,
     },
+    #[error("No compiler claimed command: {0}")]
+    CompilerNotClaimed(PathBuf),
     #[error("Failed to postprocess {path}: {error}")]
     Postprocess {
         path: PathBuf,
@@ -98,6 +137,8 @@ pub enum Error {
     Reqwest(#[from] reqwest::Error),
     #[error("Toolchain not found: {0}")]
     ToolchainNotFound(PathBuf),
+    #[error("Toolset {version} required for {path} is not installed")]
+    ToolsetNotFound { version: String, path: PathBuf },
 }
 
 impl From<std::io::Error> for Error {
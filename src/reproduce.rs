@@ -0,0 +1,186 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::compiler::{CompilationTask, OutputInfo};
+
+// Environment variables that affect compilation result and are safe to share.
+const ENV_WHITELIST: &[&str] = &[
+    "CPATH",
+    "C_INCLUDE_PATH",
+    "CPLUS_INCLUDE_PATH",
+    "INCLUDE",
+    "LIBPATH",
+    "PATH",
+    "SystemDrive",
+    "SystemRoot",
+];
+
+// Collects everything needed to replay failed compilations into tar archive.
+pub struct Reproducer {
+    path: PathBuf,
+    archive: Mutex<Option<tar::Builder<File>>>,
+    count: Mutex<usize>,
+}
+
+impl Reproducer {
+    #[must_use]
+    pub fn new(path: PathBuf) -> Self {
+        Reproducer {
+            path,
+            archive: Mutex::new(None),
+            count: Mutex::new(0),
+        }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Add failed compilation to archive, which is created on first failure.
+    pub fn add(
+        &self,
+        toolchain: Option<String>,
+        task: &CompilationTask,
+        args: &[OsString],
+        preprocessed: &[u8],
+        output: &OutputInfo,
+    ) -> crate::Result<()> {
+        let index = {
+            let mut count = self.count.lock().unwrap();
+            *count += 1;
+            *count
+        };
+        let name = task
+            .input_source
+            .file_name()
+            .map_or_else(|| "stdin".to_string(), |v| v.to_string_lossy().into_owned());
+        let dir = format!("{index:03}-{name}");
+
+        let mut command = vec![task.shared.command.program.to_string_lossy().into_owned()];
+        command.extend(args.iter().map(|arg| arg.to_string_lossy().into_owned()));
+        command.push("preprocessed.i".to_string());
+        let command = shlex::try_join(command.iter().map(String::as_str))? + "\n";
+
+        let env: String = ENV_WHITELIST
+            .iter()
+            .filter_map(|name| {
+                let value = task.shared.command.env.get(*name)?;
+                Some(format!("{name}={value}\n"))
+            })
+            .collect();
+
+        let mut log = output.stdout.clone();
+        log.extend_from_slice(&output.stderr);
+
+        let files: [(&str, &[u8]); 5] = [
+            ("preprocessed.i", preprocessed),
+            ("command.txt", command.as_bytes()),
+            ("env.txt", env.as_bytes()),
+            (
+                "toolchain.txt",
+                toolchain.as_deref().unwrap_or("unknown").as_bytes(),
+            ),
+            ("output.txt", &log),
+        ];
+
+        let mtime = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |v| v.as_secs());
+        let mut archive = self.archive.lock().unwrap();
+        if archive.is_none() {
+            *archive = Some(tar::Builder::new(File::create(&self.path)?));
+        }
+        let builder = archive.as_mut().unwrap();
+        for (file, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            builder.append_data(&mut header, format!("{dir}/{file}"), data)?;
+        }
+        Ok(())
+    }
+
+    // Complete archive. Returns number of bundled failures.
+    pub fn finish(&self) -> crate::Result<usize> {
+        if let Some(builder) = self.archive.lock().unwrap().take() {
+            builder.into_inner()?;
+        }
+        Ok(*self.count.lock().unwrap())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::clang::compiler::ClangCompiler;
+    use crate::compiler::{CommandArgs, Compiler, TaskOptions};
+    use crate::config::CacheMode;
+    use crate::reproduce::Reproducer;
+    use crate::test_utils::Fixture;
+
+    #[test]
+    fn test_reproduce_failed() {
+        // Fake compiler: preprocessing copies source, compilation fails.
+        let mut fixture = Fixture::clang(
+            r#"if [ -n "$preprocess" ]; then cat "$in"; exit 0; fi
+echo "sample.cpp:1:1: error: unknown type name 'broken'" >&2
+exit 1
+"#,
+        );
+        let source = fixture.path().join("sample.cpp");
+        std::fs::write(&source, "broken main();\n").unwrap();
+
+        fixture.config.cache_mode = CacheMode::None;
+        let mut state = fixture.state();
+        let bundle = fixture.path().join("bundle.tar");
+        state.reproduce = Some(Reproducer::new(bundle.clone()));
+
+        let args = ["-c", "sample.cpp", "-O2", "-o", "sample.o"].map(String::from);
+        let tasks = ClangCompiler::default()
+            .create_tasks(
+                fixture.command(),
+                CommandArgs::Regular(args.to_vec()),
+                &TaskOptions::default(),
+            )
+            .unwrap();
+        let output = tasks[0]
+            .toolchain
+            .compile_task(&state, &tasks[0].task)
+            .unwrap();
+        assert!(!output.success());
+        assert_eq!(state.reproduce.as_ref().unwrap().finish().unwrap(), 1);
+
+        let mut archive = tar::Archive::new(std::fs::File::open(&bundle).unwrap());
+        let mut files: Vec<(PathBuf, String)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut content = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+                (entry.path().unwrap().into_owned(), content)
+            })
+            .collect();
+        files.sort();
+        let names: Vec<&str> = files.iter().map(|(p, _)| p.to_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            [
+                "001-sample.cpp/command.txt",
+                "001-sample.cpp/env.txt",
+                "001-sample.cpp/output.txt",
+                "001-sample.cpp/preprocessed.i",
+                "001-sample.cpp/toolchain.txt",
+            ]
+        );
+        assert!(files[0].1.ends_with(" -x c++ -O2 preprocessed.i\n"));
+        assert!(files[2].1.contains("error: unknown type name"));
+        assert_eq!(files[3].1, "broken main();\n");
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::Write;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::io::statistic::Statistic;
+
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+// Abort flag set by termination request, null outside of `interruptible`.
+static INTERRUPTED: AtomicPtr<AtomicBool> = AtomicPtr::new(ptr::null_mut());
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALED.store(true, Ordering::Release);
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn on_signal(
+    ctrl_type: winapi::shared::minwindef::DWORD,
+) -> winapi::shared::minwindef::BOOL {
+    if ctrl_type == winapi::um::wincon::CTRL_BREAK_EVENT {
+        SIGNALED.store(true, Ordering::Release);
+        winapi::shared::minwindef::TRUE
+    } else {
+        winapi::shared::minwindef::FALSE
+    }
+}
+
+fn interrupt() {
+    let abort = INTERRUPTED.load(Ordering::Acquire);
+    if let Some(abort) = unsafe { abort.as_ref() } {
+        abort.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    interrupt();
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn on_interrupt(
+    ctrl_type: winapi::shared::minwindef::DWORD,
+) -> winapi::shared::minwindef::BOOL {
+    use winapi::um::wincon::{CTRL_CLOSE_EVENT, CTRL_C_EVENT};
+    if ctrl_type == CTRL_C_EVENT || ctrl_type == CTRL_CLOSE_EVENT {
+        interrupt();
+        winapi::shared::minwindef::TRUE
+    } else {
+        winapi::shared::minwindef::FALSE
+    }
+}
+
+// Install statistic dump request handler: SIGUSR1 on Unix, Ctrl+Break on Windows.
+#[cfg(unix)]
+pub fn install() -> crate::Result<()> {
+    let handler = on_signal as extern "C" fn(libc::c_int);
+    if unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn install() -> crate::Result<()> {
+    if unsafe { winapi::um::consoleapi::SetConsoleCtrlHandler(Some(on_signal), 1) } == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// Run function with termination request (SIGINT/SIGTERM, Ctrl+C on Windows) setting `abort`
+// flag instead of killing process. Default handling is restored afterwards.
+#[cfg(unix)]
+pub fn interruptible<T>(abort: &AtomicBool, func: impl FnOnce() -> T) -> T {
+    INTERRUPTED.store(ptr::from_ref(abort).cast_mut(), Ordering::Release);
+    let handler = on_interrupt as extern "C" fn(libc::c_int);
+    let previous = [libc::SIGINT, libc::SIGTERM].map(|signal| {
+        (signal, unsafe {
+            libc::signal(signal, handler as libc::sighandler_t)
+        })
+    });
+    let result = func();
+    for (signal, previous) in previous {
+        if previous != libc::SIG_ERR {
+            unsafe { libc::signal(signal, previous) };
+        }
+    }
+    INTERRUPTED.store(ptr::null_mut(), Ordering::Release);
+    result
+}
+
+#[cfg(windows)]
+pub fn interruptible<T>(abort: &AtomicBool, func: impl FnOnce() -> T) -> T {
+    use winapi::um::consoleapi::SetConsoleCtrlHandler;
+
+    INTERRUPTED.store(ptr::from_ref(abort).cast_mut(), Ordering::Release);
+    let installed = unsafe { SetConsoleCtrlHandler(Some(on_interrupt), 1) } != 0;
+    let result = func();
+    if installed {
+        unsafe { SetConsoleCtrlHandler(Some(on_interrupt), 0) };
+    }
+    INTERRUPTED.store(ptr::null_mut(), Ordering::Release);
+    result
+}
+
+// Run function and print statistic snapshot on every dump request until it completes.
+pub fn watch_statistic<T>(
+    statistic: &Statistic,
+    out: &mut (impl Write + Send),
+    func: impl FnOnce() -> T,
+) -> T {
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| loop {
+            let finished = done.load(Ordering::Acquire);
+            if SIGNALED.swap(false, Ordering::AcqRel) {
+                _ = writeln!(out, "{statistic}");
+            }
+            if finished {
+                break;
+            }
+            thread::sleep(POLL_INTERVAL);
+        });
+        let result = func();
+        done.store(true, Ordering::Release);
+        result
+    })
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::io::statistic::Statistic;
+
+    #[test]
+    fn test_dump_on_signal() {
+        super::install().unwrap();
+
+        let statistic = Statistic::new();
+        statistic.add_miss(42);
+
+        let mut out: Vec<u8> = Vec::new();
+        let value = super::watch_statistic(&statistic, &mut out, || {
+            assert_eq!(unsafe { libc::raise(libc::SIGUSR1) }, 0);
+            5
+        });
+        assert_eq!(value, 5);
+
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Cache statistic: hit 0 of 1"), "{text}");
+    }
+
+    // The only test raising termination signal: concurrent one could restore default handler.
+    #[test]
+    fn test_interruptible() {
+        use std::process::Command;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::{Duration, Instant};
+
+        use crate::compiler::SharedState;
+        use crate::config::Config;
+
+        let abort = AtomicBool::new(false);
+        super::interruptible(&abort, || {
+            assert_eq!(unsafe { libc::raise(libc::SIGTERM) }, 0);
+        });
+        assert!(abort.load(Ordering::Acquire));
+
+        // Running compiler is killed on interrupt
+        let state = SharedState::new(&Config::default()).unwrap();
+        let start = Instant::now();
+        let output = super::interruptible(state.cancel_flag(), || {
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    std::thread::sleep(Duration::from_millis(200));
+                    assert_eq!(unsafe { libc::raise(libc::SIGTERM) }, 0);
+                });
+                state.output(Command::new("sleep").arg("30"))
+            })
+        })
+        .unwrap();
+        assert!(!output.status.success());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(state.is_cancelled());
+    }
+}
@@ -7,11 +7,13 @@ use log::error;
 use petgraph::Graph;
 
 use crate::clang::compiler::ClangCompiler;
-use crate::cluster::client::RemoteCompiler;
-use crate::compiler::{CommandArgs, CommandInfo, Compiler, CompilerGroup, SharedState};
+use crate::cluster::client::{print_probes, probe_network, RemoteCompiler};
+use crate::compiler::{
+    CommandArgs, CommandInfo, Compiler, CompilerGroup, SharedState, TaskOptions,
+};
 use crate::config::Config;
 use crate::vs::compiler::VsCompiler;
-use crate::worker::execute_graph;
+use crate::worker::{check_output_collisions, check_output_dirs, execute_graph};
 use crate::worker::{BuildAction, BuildGraph, BuildResult, BuildTask};
 
 #[must_use]
@@ -47,6 +49,18 @@ where
             return 503;
         }
     };
+    if let [arg] = &env::args().skip(1).collect::<Vec<_>>()[..] {
+        if arg == "/probe-network" || arg == "--probe-network" {
+            return match probe(&config, &state, exec, &compiler) {
+                Ok(true) => 0,
+                Ok(false) => 1,
+                Err(e) => {
+                    error!("FATAL ERROR: {e}");
+                    1
+                }
+            };
+        }
+    }
     match compile(&config, &state, exec, compiler) {
         Ok(_) => 0,
         Err(e) => {
@@ -73,7 +87,7 @@ where
         command_info,
         CommandArgs::Regular(args),
         exec,
-        config.run_second_cpp,
+        &TaskOptions::new(config),
     );
 
     let mut build_graph: BuildGraph = Graph::new();
@@ -83,11 +97,33 @@ where
             action,
         }));
     }
+    check_output_collisions(&build_graph)?;
+    check_output_dirs(&build_graph)?;
     let result = execute_graph(state, build_graph, config.process_limit, print_task_result);
     writeln!(stdout(), "{}", state.statistic)?;
     result
 }
 
+// Check coordinator and every builder, returns whether all builders work.
+fn probe<C: Compiler>(
+    config: &Config,
+    state: &SharedState,
+    exec: &str,
+    compiler: &C,
+) -> crate::Result<bool> {
+    let coordinator = config
+        .coordinator
+        .as_ref()
+        .ok_or_else(|| crate::Error::Generic("Coordinator is not configured".to_string()))?;
+    let command = CommandInfo::simple(PathBuf::from(exec));
+    let toolchain = compiler
+        .resolve_toolchain(&command)
+        .ok_or_else(|| crate::Error::ToolchainNotFound(command.program.clone()))?;
+    let probes = probe_network(state, coordinator, &command, toolchain.as_ref())?;
+    print_probes(&probes, &mut stdout())?;
+    Ok(probes.iter().all(|probe| probe.result.is_ok()))
+}
+
 fn print_task_result(result: &BuildResult) -> crate::Result<()> {
     result.result.print_output()?;
     Ok(())
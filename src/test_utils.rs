@@ -0,0 +1,150 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+use crate::compiler::{CommandInfo, SharedState};
+use crate::config::Config;
+
+// Script prologue parsing clang arguments: `preprocess` is set by `-E`, `out` follows `-o`
+// and `in` is the last input (`-` for stdin).
+pub const CLANG_ARGS: &str = r#"for arg in "$@"; do
+    case "$prev" in
+        -o) out="$arg" ;;
+        -x) ;;
+        *) case "$arg" in
+            -E) preprocess=1 ;;
+            -) in="$arg" ;;
+            -*) ;;
+            *) in="$arg" ;;
+        esac ;;
+    esac
+    prev="$arg"
+done
+"#;
+
+// Script prologue parsing cl.exe arguments: `obj` follows `/Fo` and `map` follows `/Fm`.
+pub const CL_ARGS: &str = r#"for arg in "$@"; do
+    case "$arg" in
+        /Fo*) obj="${arg#/Fo}" ;;
+        /Fm*) map="${arg#/Fm}" ;;
+    esac
+done
+"#;
+
+// Fake clang body: preprocessing copies source, compilation writes `object`.
+pub const COPY_SOURCE: &str = r#"if [ -n "$preprocess" ]; then cat "$in"; exit 0; fi
+echo object > "$out"
+"#;
+
+// Write executable shell script `name` to `dir`.
+pub fn fake_compiler(dir: &Path, name: &str, script: &str) -> PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, format!("#!/bin/sh\n{script}")).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+// Fake clang running `body` after `CLANG_ARGS`.
+pub fn fake_clang(dir: &Path, body: &str) -> PathBuf {
+    fake_compiler(dir, "clang", &format!("{CLANG_ARGS}{body}"))
+}
+
+// Temporary directory with fake compiler, configuration caches into its `cache` subdirectory.
+pub struct Fixture {
+    pub temp: TempDir,
+    pub compiler: PathBuf,
+    pub config: Config,
+}
+
+impl Fixture {
+    // Fake compiler `name` running `script`.
+    pub fn new(name: &str, script: &str) -> Self {
+        let temp = tempfile::tempdir().unwrap();
+        let compiler = fake_compiler(temp.path(), name, script);
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        Fixture {
+            temp,
+            compiler,
+            config,
+        }
+    }
+
+    // Fake clang running `body` after `CLANG_ARGS`.
+    pub fn clang(body: &str) -> Self {
+        Fixture::new("clang", &format!("{CLANG_ARGS}{body}"))
+    }
+
+    // Fake cl.exe running `body` after `CL_ARGS`.
+    pub fn cl(body: &str) -> Self {
+        Fixture::new("cl.exe", &format!("{CL_ARGS}{body}"))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.temp.path()
+    }
+
+    pub fn state(&self) -> SharedState {
+        SharedState::new(&self.config).unwrap()
+    }
+
+    // Command running fake compiler in temporary directory.
+    pub fn command(&self) -> CommandInfo {
+        CommandInfo {
+            current_dir: Some(self.path().to_path_buf()),
+            ..CommandInfo::simple(self.compiler.clone())
+        }
+    }
+}
+
+// Minimal HTTP server answering every request by handler of path and body.
+pub fn serve_http<F>(handler: F) -> String
+where
+    F: Fn(&str, &[u8]) -> Vec<u8> + Send + 'static,
+{
+    serve_http_status(move |path, body| (200, handler(path, body)))
+}
+
+// Minimal HTTP server answering every request with status and body returned by handler.
+pub fn serve_http_status<F>(handler: F) -> String
+where
+    F: Fn(&str, &[u8]) -> (u16, Vec<u8>) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = BufReader::new(stream.unwrap());
+            let mut path = String::new();
+            let mut length = 0;
+            let mut line = String::new();
+            while stream.read_line(&mut line).unwrap() > 2 {
+                if path.is_empty() {
+                    path = line.split(' ').nth(1).unwrap_or_default().to_string();
+                } else if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).unwrap();
+            let (status, response) = handler(&path, &body);
+            let mut stream = stream.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                response.len()
+            )
+            .unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    });
+    endpoint
+}
@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Serialize;
+
+// Records task execution intervals in Chrome trace format (chrome://tracing, Perfetto).
+pub struct Tracer {
+    path: PathBuf,
+    start: Instant,
+    events: Mutex<Vec<TraceEvent>>,
+}
+
+#[derive(Serialize)]
+struct Trace<'a> {
+    #[serde(rename = "traceEvents")]
+    trace_events: &'a [TraceEvent],
+    #[serde(rename = "displayTimeUnit")]
+    display_time_unit: &'static str,
+}
+
+// Complete ("X") event, timestamps are in microseconds since build start.
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    pid: u32,
+    tid: usize,
+    ts: u64,
+    dur: u64,
+}
+
+impl Tracer {
+    #[must_use]
+    pub fn new(path: PathBuf) -> Self {
+        Tracer {
+            path,
+            start: Instant::now(),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Record task executed by worker between `begin` and `end`.
+    pub fn add(
+        &self,
+        worker: usize,
+        title: &str,
+        backend: &'static str,
+        begin: Instant,
+        end: Instant,
+    ) {
+        let ts = begin.saturating_duration_since(self.start);
+        let dur = end.saturating_duration_since(begin);
+        self.events.lock().unwrap().push(TraceEvent {
+            name: title.to_string(),
+            cat: backend,
+            ph: "X",
+            pid: std::process::id(),
+            tid: worker,
+            ts: u64::try_from(ts.as_micros()).unwrap_or(u64::MAX),
+            dur: u64::try_from(dur.as_micros()).unwrap_or(u64::MAX),
+        });
+    }
+
+    // Write trace file. Returns number of recorded tasks.
+    pub fn finish(&self) -> crate::Result<usize> {
+        let mut events = self.events.lock().unwrap();
+        events.sort_by_key(|event| (event.ts, event.tid));
+        let trace = Trace {
+            trace_events: &events,
+            display_time_unit: "ms",
+        };
+        serde_json::to_writer(BufWriter::new(File::create(&self.path)?), &trace)?;
+        Ok(events.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::compiler::SharedState;
+    use crate::config::Config;
+    use crate::trace::Tracer;
+    use crate::worker::{execute_graph, BuildAction, BuildGraph, BuildTask};
+
+    #[test]
+    fn test_trace_events() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("trace.json");
+        let mut state = SharedState::new(&Config::default()).unwrap();
+        state.trace = Some(Tracer::new(path.clone()));
+
+        let mut graph = BuildGraph::new();
+        let first = graph.add_node(Arc::new(BuildTask {
+            title: "task 1".to_string(),
+            action: BuildAction::Empty,
+        }));
+        let second = graph.add_node(Arc::new(BuildTask {
+            title: "task 2".to_string(),
+            action: BuildAction::Empty,
+        }));
+        graph.add_edge(second, first, ());
+        execute_graph(&state, graph, 2, |_| Ok(())).unwrap();
+        assert_eq!(state.trace.as_ref().unwrap().finish().unwrap(), 2);
+
+        let trace: serde_json::Value =
+            serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        for (event, title) in events.iter().zip(["task 1", "task 2"]) {
+            assert_eq!(event["name"], title);
+            assert_eq!(event["ph"], "X");
+            assert_eq!(event["cat"], "none");
+            assert_eq!(event["pid"], std::process::id());
+            assert!(event["tid"].as_u64().unwrap() < 2);
+            assert!(event["ts"].is_u64());
+            assert!(event["dur"].is_u64());
+        }
+        // Dependent task starts after its dependency ends.
+        let end = events[0]["ts"].as_u64().unwrap() + events[0]["dur"].as_u64().unwrap();
+        assert!(events[1]["ts"].as_u64().unwrap() >= end);
+    }
+}
@@ -3,7 +3,11 @@ use std::ffi::{OsStr, OsString};
 use std::io;
 use std::io::{Error, Read};
 use std::path::PathBuf;
-use std::time::Instant;
+use std::process::{Child, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{env, fs};
 
 use crate::cmd;
@@ -130,6 +134,259 @@ pub fn find_param<T, R, F: Fn(&T) -> Option<R>>(args: &[T], filter: F) -> ParamV
     }
 }
 
+// Remove ANSI escape sequences (colors, cursor movement, etc) from terminal output.
+#[must_use]
+pub fn strip_ansi(data: &[u8]) -> Vec<u8> {
+    const ESC: u8 = 0x1B;
+    const BEL: u8 = 0x07;
+
+    let mut result = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != ESC {
+            result.push(data[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        match data.get(i) {
+            // CSI: parameters and intermediate bytes up to final byte
+            Some(b'[') => {
+                i += 1;
+                while i < data.len() && !(0x40..=0x7E).contains(&data[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            // OSC: up to BEL or ST (ESC \)
+            Some(b']') => {
+                i += 1;
+                while i < data.len() && data[i] != BEL && data[i] != ESC {
+                    i += 1;
+                }
+                if data.get(i) == Some(&ESC) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            Some(_) => i += 1,
+            None => {}
+        }
+    }
+    result
+}
+
+// OS errors caused by momentary process creation resource shortage.
+#[cfg(windows)]
+const TRANSIENT_SPAWN_ERRORS: &[i32] = &[
+    4,    // ERROR_TOO_MANY_OPEN_FILES
+    8,    // ERROR_NOT_ENOUGH_MEMORY
+    14,   // ERROR_OUTOFMEMORY
+    1450, // ERROR_NO_SYSTEM_RESOURCES
+    1455, // ERROR_COMMITMENT_LIMIT
+];
+#[cfg(unix)]
+const TRANSIENT_SPAWN_ERRORS: &[i32] = &[libc::EAGAIN, libc::ENOMEM, libc::EMFILE, libc::ENFILE];
+
+const SPAWN_RETRIES: u32 = 4;
+const SPAWN_BACKOFF: Duration = Duration::from_millis(50);
+
+#[must_use]
+pub fn is_transient_spawn_error(e: &Error) -> bool {
+    e.raw_os_error()
+        .is_some_and(|code| TRANSIENT_SPAWN_ERRORS.contains(&code))
+}
+
+// OS errors of network filesystems that usually go away on retry.
+#[cfg(windows)]
+const TRANSIENT_IO_ERRORS: &[i32] = &[
+    32,  // ERROR_SHARING_VIOLATION
+    33,  // ERROR_LOCK_VIOLATION
+    54,  // ERROR_NETWORK_BUSY
+    59,  // ERROR_UNEXP_NET_ERR
+    64,  // ERROR_NETNAME_DELETED
+    121, // ERROR_SEM_TIMEOUT
+];
+#[cfg(unix)]
+const TRANSIENT_IO_ERRORS: &[i32] = &[
+    libc::EAGAIN,
+    libc::EBUSY,
+    libc::EINTR,
+    libc::ESTALE,
+    libc::ETIMEDOUT,
+];
+
+#[must_use]
+pub fn is_transient_io_error(e: &Error) -> bool {
+    e.raw_os_error()
+        .is_some_and(|code| TRANSIENT_IO_ERRORS.contains(&code))
+}
+
+// Run process spawning function, retrying with exponential backoff on transient OS errors.
+pub fn retry_spawn<T>(mut spawn: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = SPAWN_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match spawn() {
+            Err(e) if attempt < SPAWN_RETRIES && is_transient_spawn_error(&e) => {
+                log::warn!("Process spawn failed ({e}), retrying in {delay:?}");
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// How often running process is checked for cancellation.
+const CANCEL_POLL: Duration = Duration::from_millis(20);
+
+// Wait for process completion like `Child::wait_with_output`, killing it once `cancelled` is set.
+pub fn wait_cancellable(mut child: Child, cancelled: &AtomicBool) -> io::Result<Output> {
+    drop(child.stdin.take());
+    let read = |pipe: Option<Box<dyn Read + Send>>| -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buffer)?;
+        }
+        Ok(buffer)
+    };
+    let stdout = child
+        .stdout
+        .take()
+        .map(|v| Box::new(v) as Box<dyn Read + Send>);
+    let stderr = child
+        .stderr
+        .take()
+        .map(|v| Box::new(v) as Box<dyn Read + Send>);
+    thread::scope(|scope| {
+        let stdout = scope.spawn(|| read(stdout));
+        let stderr = scope.spawn(|| read(stderr));
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if cancelled.load(Ordering::Acquire) {
+                child.kill()?;
+                break child.wait()?;
+            }
+            thread::sleep(CANCEL_POLL);
+        };
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap()?,
+            stderr: stderr.join().unwrap()?,
+        })
+    })
+}
+
+// In-process counting semaphore.
+pub struct Limiter {
+    limit: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+pub struct LimiterGuard<'a>(&'a Limiter);
+
+impl Limiter {
+    #[must_use]
+    pub fn new(limit: usize) -> Self {
+        Limiter {
+            limit: limit.max(1),
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn access(&self) -> LimiterGuard<'_> {
+        let mut used = self.used.lock().unwrap();
+        while *used >= self.limit {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += 1;
+        LimiterGuard(self)
+    }
+}
+
+impl Drop for LimiterGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.used.lock().unwrap() -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+// Convert glob to case-insensitive regex matching whole path: `**` matches any path, `*` and `?`
+// don't cross directory separators. Both `/` and `\` separate directories.
+pub fn glob_to_regex(glob: &str) -> Result<regex::Regex, regex::Error> {
+    let mut pattern = String::from("(?i)^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str(r"[^/\\]*"),
+            '?' => pattern.push_str(r"[^/\\]"),
+            '/' | '\\' => pattern.push_str(r"[/\\]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    regex::Regex::new(&pattern)
+}
+
+#[test]
+fn test_glob_to_regex() {
+    let glob = glob_to_regex("**/ThirdParty/*.obj").unwrap();
+    assert!(glob.is_match("/src/ThirdParty/zlib.obj"));
+    assert!(glob.is_match(r"C:\Src\thirdparty\zlib.obj"));
+    assert!(!glob.is_match("/src/ThirdParty/zlib/inflate.obj"));
+    assert!(!glob.is_match("/src/ThirdParty/zlib.obj.tmp"));
+    let glob = glob_to_regex("*.pch").unwrap();
+    assert!(glob.is_match("Core.pch"));
+    assert!(!glob.is_match("/build/Core.pch"));
+}
+
+#[test]
+fn test_strip_ansi() {
+    assert_eq!(
+        strip_ansi(b"\x1B[1m\x1B[31merror:\x1B[0m unknown type\n"),
+        b"error: unknown type\n"
+    );
+    assert_eq!(
+        strip_ansi(b"\x1B]8;;file:///a.c\x07a.c\x1B]8;;\x1B\\"),
+        b"a.c"
+    );
+    assert_eq!(strip_ansi(b"plain text"), b"plain text");
+    assert_eq!(strip_ansi(b"truncated\x1B[1"), b"truncated");
+}
+
+#[test]
+fn test_retry_spawn_transient() {
+    let mut calls = 0;
+    let result = retry_spawn(|| {
+        calls += 1;
+        if calls < 3 {
+            Err(Error::from_raw_os_error(TRANSIENT_SPAWN_ERRORS[0]))
+        } else {
+            Ok(calls)
+        }
+    });
+    assert_eq!(result.unwrap(), 3);
+
+    // Permanent errors are not retried
+    let mut calls = 0;
+    let result: io::Result<()> = retry_spawn(|| {
+        calls += 1;
+        Err(Error::from(io::ErrorKind::NotFound))
+    });
+    assert!(result.is_err());
+    assert_eq!(calls, 1);
+}
+
 #[test]
 fn test_hash_stream() {
     use std::io::Cursor;
@@ -2,30 +2,45 @@ use crate::cmd;
 use crate::compiler::CompileInput::{Preprocessed, Source};
 use crate::compiler::{
     Arg, CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OsCommandArgs,
-    OutputInfo, PCHUsage, ParamForm, PreprocessResult, Scope, SharedState, Toolchain,
-    ToolchainHolder,
+    OutputInfo, PCHUsage, ParamForm, PreprocessResult, Scope, SharedState, SourceInput,
+    TaskOptions, Toolchain, ToolchainHolder,
 };
 use crate::io::memstream::MemStream;
 use crate::io::tempfile::TempFile;
 use crate::lazy::Lazy;
 use crate::utils::OsStrExt;
 use crate::vs::postprocess;
-use cmd::native::quote;
+use cmd::native::{long_path, quote};
 use regex::bytes::{NoExpand, Regex};
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::Cursor;
+use std::io::{Cursor, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 use std::sync::{Arc, OnceLock};
 use std::{env, fs};
 
+// Disables crash report dialogs and uploads.
+const ERROR_REPORT_NONE: &str = "/errorReport:none";
+
 #[derive(Default)]
 pub struct VsCompiler {
     toolchains: ToolchainHolder,
+    // Visual Studio installation directories.
+    installations: OnceLock<Vec<PathBuf>>,
+}
+
+impl VsCompiler {
+    #[must_use]
+    pub fn with_installations(installations: Vec<PathBuf>) -> Self {
+        VsCompiler {
+            toolchains: ToolchainHolder::new(),
+            installations: OnceLock::from(installations),
+        }
+    }
 }
 
-struct VsToolchain {
+pub(crate) struct VsToolchain {
     path: PathBuf,
     identifier: Lazy<Option<String>>,
 }
@@ -50,6 +65,13 @@ impl Compiler for VsCompiler {
             .resolve(&executable, |path| Arc::new(VsToolchain::new(path)))
     }
 
+    fn find_toolset(&self, command: &CommandInfo, version: &str) -> Option<PathBuf> {
+        self.resolve_toolchain(command)?;
+        let executable = command.find_executable()?;
+        let installations = self.installations.get_or_init(vs_installations);
+        find_msvc_toolset(installations, version, &msvc_host_target(&executable))
+    }
+
     #[cfg(unix)]
     fn discover_toolchains(&self) -> Vec<Arc<dyn Toolchain>> {
         Vec::new()
@@ -103,20 +125,118 @@ impl Compiler for VsCompiler {
     }
 }
 
+// Path of cl.exe relative to MSVC toolset directory, so pinned toolset uses the same host and target.
+fn msvc_host_target(executable: &Path) -> PathBuf {
+    executable
+        .ancestors()
+        .find(|dir| {
+            dir.parent()
+                .and_then(Path::file_name)
+                .is_some_and(|name| name.eq_ignore_ascii_case("MSVC"))
+        })
+        .and_then(|toolset| executable.strip_prefix(toolset).ok())
+        .map_or_else(
+            || Path::new("bin/Hostx64/x64").join(executable.file_name().unwrap_or_default()),
+            Path::to_path_buf,
+        )
+}
+
+// Find latest installed MSVC toolset with version matching requested prefix (for example, 14.29).
+fn find_msvc_toolset(
+    installations: &[PathBuf],
+    version: &str,
+    host_target: &Path,
+) -> Option<PathBuf> {
+    let parse = |name: &str| -> Vec<u64> {
+        name.split('.')
+            .map(|part| part.parse().unwrap_or_default())
+            .collect()
+    };
+    installations
+        .iter()
+        .filter_map(|installation| fs::read_dir(installation.join("VC/Tools/MSVC")).ok())
+        .flat_map(|entries| entries.filter_map(Result::ok))
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if name != version && !name.starts_with(&format!("{version}.")) {
+                return None;
+            }
+            let executable = entry.path().join(host_target);
+            executable.is_file().then(|| (parse(&name), executable))
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, executable)| executable)
+}
+
+#[cfg(unix)]
+fn vs_installations() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+#[cfg(windows)]
+fn vs_installations() -> Vec<PathBuf> {
+    let Some(program_files) = env::var_os("ProgramFiles(x86)") else {
+        return Vec::new();
+    };
+    let vswhere =
+        PathBuf::from(program_files).join("Microsoft Visual Studio/Installer/vswhere.exe");
+    match Command::new(vswhere)
+        .args([
+            "-all",
+            "-products",
+            "*",
+            "-utf8",
+            "-property",
+            "installationPath",
+        ])
+        .output()
+    {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn plain_preprocessed(
+    stdout: Vec<u8>,
+    normalize_newlines: bool,
+) -> crate::Result<PreprocessResult> {
+    if !normalize_newlines {
+        return Ok(PreprocessResult::Success(CompilerOutput::Vec(stdout)));
+    }
+    let mut writer = postprocess::NormalizeNewlines::new(
+        Vec::with_capacity(stdout.len()),
+        postprocess::can_skip_bom(&stdout),
+    );
+    writer.write_all(&stdout)?;
+    Ok(PreprocessResult::Success(CompilerOutput::Vec(
+        writer.finish()?,
+    )))
+}
+
 fn run_postprocess(
     output: Output,
     path: &Path,
     marker: &Option<OsString>,
     keep_headers: bool,
+    normalize_newlines: bool,
 ) -> crate::Result<PreprocessResult> {
     let mut content = MemStream::new();
-    postprocess::filter_preprocessed(
-        &mut Cursor::new(output.stdout),
-        &mut content,
-        marker,
-        keep_headers,
-    )
-    .map_err(|e| crate::Error::postprocess(path, e))?;
+    let mut reader = Cursor::new(output.stdout);
+    if normalize_newlines {
+        let skip_bom = postprocess::can_skip_bom(reader.get_ref());
+        let mut writer = postprocess::NormalizeNewlines::new(&mut content, skip_bom);
+        postprocess::filter_preprocessed(&mut reader, &mut writer, marker, keep_headers)
+            .map_err(|e| crate::Error::postprocess(path, e))?;
+        writer.finish()?;
+    } else {
+        postprocess::filter_preprocessed(&mut reader, &mut content, marker, keep_headers)
+            .map_err(|e| crate::Error::postprocess(path, e))?;
+    }
     Ok(PreprocessResult::Success(CompilerOutput::MemSteam(content)))
 }
 fn collect_args(
@@ -176,18 +296,31 @@ fn collect_args(
     Ok(())
 }
 
+// Extension of preprocessed source file by task language: `.ii` for C++, `.i` for C.
+fn preprocessed_suffix(language: &str) -> &'static str {
+    if language == "P" {
+        ".ii"
+    } else {
+        ".i"
+    }
+}
+
 impl Toolchain for VsToolchain {
     fn identifier(&self) -> Option<String> {
         self.identifier.get(|| vs_identifier(&self.path))
     }
 
+    fn compiler(&self) -> &'static str {
+        "VsCompiler"
+    }
+
     fn create_tasks(
         &self,
         command: CommandInfo,
         args: &[String],
-        run_second_cpp: bool,
+        options: &TaskOptions,
     ) -> crate::Result<Vec<CompilationTask>> {
-        super::prepare::create_tasks(command, args, run_second_cpp)
+        super::prepare::create_tasks(command, args, options)
     }
 
     fn run_preprocess(
@@ -200,42 +333,41 @@ impl Toolchain for VsToolchain {
             OsString::from("/T".to_string()).concat(&task.language),
             OsString::from("/E"),
             OsString::from("/we4002"), // C4002: too many actual parameters for macro 'identifier'
-            OsString::from("/Fo").concat(quote(&task.output_object)?), // /Fo option also set output path for #import directive
-            quote(&task.input_source)?,
+            OsString::from(ERROR_REPORT_NONE),
+            OsString::from("/Fo").concat(quote(long_path(&task.output_object).as_os_str())?), // /Fo option also set output path for #import directive
+            quote(long_path(&task.input_source).as_os_str())?,
         ];
-        collect_args(
-            &task.shared.args,
-            Scope::Preprocessor,
-            false,
-            false,
-            &mut args,
-        )?;
+        args.extend(self.phase_args(task, Scope::Preprocessor)?);
 
         let mut command = task.shared.command.to_command();
         let response_file =
             state.do_response_file(OsCommandArgs::Raw(args.join(" ".as_ref())), &mut command)?;
-        let output = state.wrap_slow(|| -> crate::Result<Output> {
-            let output = command.output()?;
+        let output = state.wrap_preprocess(|| -> crate::Result<Output> {
+            let output = state.output(&mut command)?;
             drop(response_file);
             Ok(output)
         })?;
 
         if output.status.success() {
             if task.shared.run_second_cpp {
-                Ok(PreprocessResult::Success(CompilerOutput::Vec(
-                    output.stdout,
-                )))
+                plain_preprocessed(output.stdout, state.normalize_newlines)
             } else {
                 match &task.shared.pch_usage {
-                    PCHUsage::None => Ok(PreprocessResult::Success(CompilerOutput::Vec(
-                        output.stdout,
-                    ))),
-                    PCHUsage::In(v) => {
-                        run_postprocess(output, &task.input_source, &v.marker, false)
-                    }
-                    PCHUsage::Out(v) => {
-                        run_postprocess(output, &task.input_source, &v.marker, true)
-                    }
+                    PCHUsage::None => plain_preprocessed(output.stdout, state.normalize_newlines),
+                    PCHUsage::In(v) => run_postprocess(
+                        output,
+                        &task.input_source,
+                        &v.marker,
+                        false,
+                        state.normalize_newlines,
+                    ),
+                    PCHUsage::Out(v) => run_postprocess(
+                        output,
+                        &task.input_source,
+                        &v.marker,
+                        true,
+                        state.normalize_newlines,
+                    ),
                 }
             }
         } else {
@@ -257,14 +389,44 @@ impl Toolchain for VsToolchain {
             OsString::from("/nologo"),
             OsString::from("/T".to_string() + &task.language),
         ];
+        args.extend(self.phase_args(task, Scope::Compiler)?);
+        let mut step = CompileStep::new(task, preprocessed, args);
+        // Never show crash report dialogs, they hang unattended builds.
+        step.extra_args.push(OsString::from(ERROR_REPORT_NONE));
+        // Map file location doesn't change its content, so it stays out of cache key.
+        if let Some(map) = task.map_file()? {
+            step.extra_args
+                .push(OsString::from("/Fm").concat(quote(long_path(&map).as_os_str())?));
+        }
+        // Full paths in diagnostics don't change object file: `__FILE__` is already expanded
+        // in preprocessed source, which is part of cache key.
+        let full_paths = OsString::from("/FC");
+        step.args.retain(|arg| *arg != full_paths);
+        if task
+            .shared
+            .args
+            .iter()
+            .any(|arg| matches!(arg, Arg::Flag { name, .. } if name == "FC"))
+        {
+            step.extra_args.push(full_paths);
+        }
+        Ok(step)
+    }
+
+    fn phase_args(&self, task: &CompilationTask, phase: Scope) -> crate::Result<Vec<OsString>> {
+        let (run_second_cpp, output_precompiled) = match phase {
+            Scope::Preprocessor => (false, false),
+            _ => (task.shared.run_second_cpp, task.shared.pch_usage.is_out()),
+        };
+        let mut args = Vec::new();
         collect_args(
             &task.shared.args,
-            Scope::Compiler,
-            task.shared.run_second_cpp,
-            task.shared.pch_usage.is_out(),
+            phase,
+            run_second_cpp,
+            output_precompiled,
             &mut args,
         )?;
-        Ok(CompileStep::new(task, preprocessed, args))
+        Ok(args)
     }
 
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
@@ -273,14 +435,28 @@ impl Toolchain for VsToolchain {
             None => {
                 let output_temp = tempfile::Builder::new()
                     .suffix(".o")
-                    .tempfile_in(state.temp_dir.path())?;
+                    .tempfile_in(long_path(state.temp_dir.path()))?;
                 (output_temp.path().to_path_buf(), Some(output_temp))
             }
         };
 
         let mut args = task.args.clone();
+        if let Source(SourceInput {
+            origin: Some(origin),
+            ..
+        }) = &task.input
+        {
+            // Copy of source resolves quoted includes from original source directory first.
+            if let Some(dir) = origin.parent() {
+                args.insert(
+                    0,
+                    OsString::from("/I").concat(quote(long_path(dir).as_os_str())?),
+                );
+            }
+        }
+        args.extend(task.extra_args.iter().cloned());
         args.push(OsString::from("/c"));
-        args.push(OsString::from("/Fo").concat(quote(output_path)?));
+        args.push(OsString::from("/Fo").concat(quote(long_path(&output_path).as_os_str())?));
 
         match &task.pch_usage {
             PCHUsage::None => {}
@@ -290,17 +466,28 @@ impl Toolchain for VsToolchain {
                 } else {
                     args.push(OsString::from("/Yu"));
                 }
-                args.push(OsString::from("/Fp").concat(quote(&v.path)?));
+                args.push(OsString::from("/Fp").concat(quote(long_path(&v.path).as_os_str())?));
             }
             PCHUsage::Out(v) => {
-                args.push(OsString::from("/Fp").concat(quote(&v.path)?));
+                args.push(OsString::from("/Fp").concat(quote(long_path(&v.path).as_os_str())?));
             }
         }
 
         let (input_path, temp_input, current_dir_override) = match &task.input {
             Preprocessed(preprocessed) => {
-                let input_temp = TempFile::new_in(state.temp_dir.path(), ".i");
-                preprocessed.copy(&mut File::create(input_temp.path())?)?;
+                let input_temp =
+                    TempFile::new_in(state.temp_dir.path(), preprocessed_suffix(&task.language));
+                let mut file = File::create(input_temp.path())?;
+                // Restore BOM dropped by newline normalization.
+                if state.normalize_newlines && !preprocessed.is_ascii() {
+                    let content = preprocessed.to_vec()?;
+                    if postprocess::needs_bom(&content) {
+                        file.write_all(postprocess::BOM)?;
+                    }
+                    file.write_all(&content)?;
+                } else {
+                    preprocessed.copy(&mut file)?;
+                }
                 (input_temp.path().to_path_buf(), Some(input_temp), None)
             }
             Source(source) => {
@@ -311,7 +498,7 @@ impl Toolchain for VsToolchain {
                 }
             }
         };
-        args.push(quote(&input_path)?);
+        args.push(quote(long_path(&input_path).as_os_str())?);
 
         // Run compiler.
 
@@ -341,7 +528,7 @@ impl Toolchain for VsToolchain {
 
             let response_file = state
                 .do_response_file(OsCommandArgs::Raw(args.join(" ".as_ref())), &mut command)?;
-            let output = command.output()?;
+            let output = state.output(&mut command)?;
             drop(temp_input);
             drop(response_file);
             Ok(output)
@@ -517,6 +704,42 @@ fn is_eol(c: u8) -> bool {
 #[cfg(test)]
 mod test {
     use std::io::Write;
+    use std::path::PathBuf;
+
+    use crate::compiler::{
+        CommandInfo, CompilationTask, CompileStep, CompilerOutput, SharedState, TaskOptions,
+        Toolchain,
+    };
+    use crate::config::Config;
+    #[cfg(unix)]
+    use crate::test_utils::{fake_compiler, Fixture};
+    use crate::vs::compiler::VsToolchain;
+
+    // Tasks of cl.exe command run in `dir`.
+    fn cl_tasks(dir: &str, args: &[&str], options: &TaskOptions) -> Vec<CompilationTask> {
+        let command = CommandInfo {
+            current_dir: Some(PathBuf::from(dir)),
+            ..CommandInfo::simple(PathBuf::from("cl.exe"))
+        };
+        let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+        VsToolchain::new(PathBuf::from("cl.exe"))
+            .create_tasks(command, &args, options)
+            .unwrap()
+    }
+
+    // Cache key and compile step of the first task of cl.exe command run in `dir`.
+    fn prepare_step(
+        state: &SharedState,
+        options: &TaskOptions,
+        dir: &str,
+        args: &[&str],
+        preprocessed: CompilerOutput,
+    ) -> (String, CompileStep) {
+        let tasks = cl_tasks(dir, args, options);
+        VsToolchain::new(PathBuf::from("cl.exe"))
+            .prepare_compile_step(state, &tasks[0], preprocessed)
+            .unwrap()
+    }
 
     fn check_prepare_output(original: &str, expected: &str, line: &str, success: bool) {
         let mut stream: Vec<u8> = Vec::new();
@@ -571,4 +794,464 @@ foo.c(43) : warning C4433: foo bar
             false,
         );
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pinned_toolset() {
+        use std::sync::Arc;
+
+        use regex::Regex;
+
+        use crate::compiler::{CommandArgs, Compiler};
+        use crate::vs::compiler::VsCompiler;
+
+        let temp = tempfile::tempdir().unwrap();
+        let installation = temp.path().join("VS");
+        let install_cl = |version: &str| -> PathBuf {
+            let dir = installation
+                .join("VC/Tools/MSVC")
+                .join(version)
+                .join("bin/Hostx64/x64");
+            std::fs::create_dir_all(&dir).unwrap();
+            fake_compiler(&dir, "cl", "").canonicalize().unwrap()
+        };
+        let old_cl = install_cl("14.29.30133");
+        let new_cl = install_cl("14.38.33130");
+
+        let compiler = VsCompiler::with_installations(vec![installation]);
+        let options = TaskOptions {
+            toolsets: vec![(Regex::new("legacy").unwrap(), "14.29".to_string())],
+            ..TaskOptions::default()
+        };
+        let create = |source: &str| {
+            compiler.create_tasks(
+                CommandInfo::simple(new_cl.clone()),
+                CommandArgs::Regular(vec!["/c".to_string(), source.to_string()]),
+                &options,
+            )
+        };
+
+        let default = create("modern.cpp").unwrap();
+        let pinned = create("legacy.cpp").unwrap();
+        assert_eq!(default[0].task.shared.command.program, new_cl);
+        assert_eq!(pinned[0].task.shared.command.program, old_cl);
+        assert!(!Arc::ptr_eq(&default[0].toolchain, &pinned[0].toolchain));
+        assert!(Arc::ptr_eq(
+            &pinned[0].toolchain,
+            &compiler
+                .resolve_toolchain(&CommandInfo::simple(old_cl))
+                .unwrap()
+        ));
+
+        let options = TaskOptions {
+            toolsets: vec![(Regex::new("legacy").unwrap(), "14.1".to_string())],
+            ..TaskOptions::default()
+        };
+        assert!(matches!(
+            compiler.create_tasks(
+                CommandInfo::simple(new_cl),
+                CommandArgs::Regular(vec!["/c".to_string(), "legacy.cpp".to_string()]),
+                &options,
+            ),
+            Err(crate::Error::ToolsetNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mp_stripped() {
+        let state = SharedState::new(&Config::default()).unwrap();
+        let compile_step = |flags: &[&str]| {
+            let args = [flags, &["/c", "/O2", "a.cpp", "b.cpp"]].concat();
+            assert_eq!(
+                cl_tasks("/project", &args, &TaskOptions::default()).len(),
+                2
+            );
+            let preprocessed = CompilerOutput::Vec(b"int a;\n".to_vec());
+            prepare_step(
+                &state,
+                &TaskOptions::default(),
+                "/project",
+                &args,
+                preprocessed,
+            )
+        };
+
+        let (hash, step) = compile_step(&["/MP8"]);
+        assert_eq!(step.args, ["/nologo", "/TP", "/O2"]);
+        assert_eq!(compile_step(&["/MP"]).0, hash);
+        assert_eq!(compile_step(&[]).0, hash);
+    }
+
+    #[test]
+    fn test_fc_and_error_report() {
+        let state = SharedState::new(&Config::default()).unwrap();
+        let compile_step = |flags: &[&str]| {
+            let args = [flags, &["/c", "/O2", "a.cpp"]].concat();
+            let preprocessed = CompilerOutput::Vec(b"int a;\n".to_vec());
+            prepare_step(
+                &state,
+                &TaskOptions::default(),
+                "/project",
+                &args,
+                preprocessed,
+            )
+        };
+
+        let (hash, step) = compile_step(&[]);
+        assert_eq!(step.extra_args, ["/errorReport:none"]);
+
+        let (fc_hash, step) = compile_step(&["/FC", "/errorReport:prompt"]);
+        assert_eq!(fc_hash, hash);
+        assert_eq!(step.args, ["/nologo", "/TP", "/O2"]);
+        assert_eq!(step.extra_args, ["/errorReport:none", "/FC"]);
+
+        // Compiling preprocessed source
+        let config = Config {
+            run_second_cpp: false,
+            ..Config::default()
+        };
+        let tasks = cl_tasks(
+            "/project",
+            &["/c", "/FC", "a.cpp"],
+            &TaskOptions::new(&config),
+        );
+        let step = VsToolchain::new(PathBuf::from("cl.exe"))
+            .create_compile_step(&tasks[0], CompilerOutput::Vec(Vec::new()))
+            .unwrap();
+        assert_eq!(step.args, ["/nologo", "/TP"]);
+        assert_eq!(step.extra_args, ["/errorReport:none", "/FC"]);
+    }
+
+    #[test]
+    fn test_cache_isolation() {
+        let hash = |state: &SharedState, dir: &str| {
+            let args = ["/c", "/O2", "a.cpp", "/Fo/tmp/a.obj"];
+            let preprocessed = CompilerOutput::Vec(b"int a;\n".to_vec());
+            prepare_step(state, &TaskOptions::default(), dir, &args, preprocessed).0
+        };
+
+        let shared = SharedState::new(&Config::default()).unwrap();
+        assert_eq!(hash(&shared, "/first"), hash(&shared, "/second"));
+
+        let isolated = SharedState::new(&Config {
+            cache_isolation: true,
+            ..Config::default()
+        })
+        .unwrap();
+        assert_ne!(hash(&isolated, "/first"), hash(&isolated, "/second"));
+        assert_eq!(hash(&isolated, "/first"), hash(&isolated, "/first"));
+        assert_ne!(hash(&isolated, "/first"), hash(&shared, "/first"));
+
+        let project = SharedState::new(&Config {
+            cache_isolation: true,
+            cache_project: Some("game".to_string()),
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(hash(&project, "/first"), hash(&project, "/second"));
+        assert_ne!(hash(&project, "/first"), hash(&shared, "/first"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compiler_wrapper() {
+        use crate::compiler::{CommandArgs, Compiler};
+        use crate::config::WrapperPolicy;
+        use crate::vs::compiler::VsCompiler;
+
+        let temp = tempfile::tempdir().unwrap();
+        let cl = fake_compiler(temp.path(), "cl.exe", "");
+
+        let compiler = VsCompiler::default();
+        let create_tasks = |wrapper: &str, options: &TaskOptions| {
+            let args = [cl.to_str().unwrap(), "/c", "a.cpp", "/Foa.obj"].map(String::from);
+            compiler
+                .create_tasks(
+                    CommandInfo::simple(PathBuf::from(wrapper)),
+                    CommandArgs::Regular(args.to_vec()),
+                    options,
+                )
+                .unwrap()
+        };
+        for wrapper in ["ccache", "/usr/bin/sccache", "distcc.exe"] {
+            let tasks = create_tasks(wrapper, &TaskOptions::default());
+            assert_eq!(tasks.len(), 1);
+            assert_eq!(tasks[0].toolchain.backend(), "local");
+            assert_eq!(tasks[0].task.shared.command.program, cl);
+            assert!(tasks[0].task.input_source.ends_with("a.cpp"));
+        }
+
+        let bypass = TaskOptions {
+            wrapper_policy: WrapperPolicy::Bypass,
+            ..TaskOptions::default()
+        };
+        assert!(create_tasks("ccache", &bypass).is_empty());
+        // Not a wrapper
+        assert!(compiler
+            .create_tasks(
+                CommandInfo::simple(PathBuf::from("ccache-stats")),
+                CommandArgs::Regular(vec![cl.to_str().unwrap().to_string()]),
+                &TaskOptions::default(),
+            )
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_map_file_cached() {
+        use std::sync::atomic::Ordering;
+
+        // Fake compiler: writes object and map files.
+        let fixture = Fixture::cl(
+            r#"echo object > "$obj"
+echo map > "$map"
+"#,
+        );
+        let temp = fixture.path();
+        std::fs::create_dir(temp.join("maps")).unwrap();
+        let toolchain = VsToolchain::new(fixture.compiler.clone());
+        let state = fixture.state();
+        let map_file = |flag: &str| {
+            toolchain
+                .create_tasks(
+                    fixture.command(),
+                    &["/c", "a.cpp", flag].map(String::from),
+                    &TaskOptions::new(&fixture.config),
+                )
+                .unwrap()[0]
+                .map_file()
+                .unwrap()
+        };
+        assert_eq!(map_file("/O2"), None);
+        assert_eq!(map_file("/Fm"), Some(temp.join("a.map")));
+        assert_eq!(map_file("/Fmb.map"), Some(temp.join("b.map")));
+        assert_eq!(map_file("/Fmmaps"), Some(temp.join("maps/a.map")));
+        assert_eq!(map_file("/Fmnew/"), Some(temp.join("new/a.map")));
+
+        let tasks = toolchain
+            .create_tasks(
+                fixture.command(),
+                &["/c", "a.cpp", "/Foa.obj", "/Fmmaps/"].map(String::from),
+                &TaskOptions::new(&fixture.config),
+            )
+            .unwrap();
+        let compile = || {
+            let preprocessed = CompilerOutput::Vec(b"int a;\n".to_vec());
+            assert!(toolchain
+                .run_compile_cached(&state, &tasks[0], preprocessed)
+                .unwrap()
+                .success());
+        };
+        compile();
+        let map = temp.join("maps/a.map");
+        assert_eq!(std::fs::read_to_string(&map).unwrap(), "map\n");
+
+        // Map file is restored from cache with object
+        std::fs::remove_file(&map).unwrap();
+        std::fs::remove_file(temp.join("a.obj")).unwrap();
+        compile();
+        assert_eq!(state.statistic.hit_count.load(Ordering::Relaxed), 1);
+        assert_eq!(std::fs::read_to_string(&map).unwrap(), "map\n");
+        assert!(temp.join("a.obj").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_normalize_newlines_key() {
+        use std::sync::Arc;
+
+        use crate::compiler::{CommandEnv, PreprocessResult};
+
+        // Fake preprocessor: BOM and line endings depend on environment.
+        let fixture = Fixture::new(
+            "cl.exe",
+            r#"case "$STYLE" in
+    crlf) printf '#line 1 "a.cpp"\r\nint a;\r\n' ;;
+    bom) printf '\357\273\277#line 1 "a.cpp"\nint a;\n' ;;
+    *) printf '#line 1 "a.cpp"\nint a;\n' ;;
+esac
+"#,
+        );
+        std::fs::write(fixture.path().join("a.cpp"), "int a;\n").unwrap();
+        let toolchain = VsToolchain::new(fixture.compiler.clone());
+        let key = |normalize_newlines: bool, style: &str| {
+            let config = Config {
+                normalize_newlines,
+                ..fixture.config.clone()
+            };
+            let state = SharedState::new(&config).unwrap();
+            let mut env = CommandEnv::new();
+            env.insert("STYLE", style);
+            let command = CommandInfo {
+                env: Arc::new(env),
+                ..fixture.command()
+            };
+            let task = toolchain
+                .create_tasks(
+                    command,
+                    &["/c", "a.cpp"].map(String::from),
+                    &TaskOptions::new(&config),
+                )
+                .unwrap()
+                .remove(0);
+            let PreprocessResult::Success(preprocessed) =
+                toolchain.run_preprocess(&state, &task).unwrap()
+            else {
+                panic!("preprocessing failed");
+            };
+            toolchain
+                .prepare_compile_step(&state, &task, preprocessed)
+                .unwrap()
+                .0
+        };
+        assert_ne!(key(false, "crlf"), key(false, "lf"));
+        assert_ne!(key(false, "bom"), key(false, "lf"));
+        assert_eq!(key(true, "crlf"), key(true, "lf"));
+        assert_eq!(key(true, "bom"), key(true, "lf"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preprocessed_suffix() {
+        // Fake compiler: object file lists arguments.
+        let mut fixture = Fixture::cl(
+            r#"printf '%s\n' "$@" > "$obj"
+"#,
+        );
+        let toolchain = VsToolchain::new(fixture.compiler.clone());
+        fixture.config = Config {
+            run_second_cpp: false,
+            ..fixture.config
+        };
+        let state = fixture.state();
+        let compile = |source: &str| {
+            let task = toolchain
+                .create_tasks(
+                    fixture.command(),
+                    &["/c", source].map(String::from),
+                    &TaskOptions::new(&fixture.config),
+                )
+                .unwrap()
+                .remove(0);
+            let step = toolchain
+                .create_compile_step(&task, CompilerOutput::Vec(b"int a;\n".to_vec()))
+                .unwrap();
+            assert!(toolchain.run_compile(&state, step).unwrap().success());
+            std::fs::read_to_string(&task.output_object).unwrap()
+        };
+
+        let args = compile("a.cpp");
+        assert!(args.lines().any(|arg| arg == "/TP"), "{args}");
+        assert!(args.trim_end().ends_with(".ii"), "{args}");
+        let args = compile("b.c");
+        assert!(args.lines().any(|arg| arg == "/TC"), "{args}");
+        assert!(args.trim_end().ends_with(".i"), "{args}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_bom() {
+        // Fake compiler: object file is copy of input.
+        let mut fixture = Fixture::cl(
+            r#"for arg in "$@"; do input="$arg"; done
+cat "$input" > "$obj"
+"#,
+        );
+        let toolchain = VsToolchain::new(fixture.compiler.clone());
+        fixture.config = Config {
+            normalize_newlines: true,
+            run_second_cpp: false,
+            ..fixture.config
+        };
+        let state = fixture.state();
+        let compile = |content: &[u8]| {
+            let task = toolchain
+                .create_tasks(
+                    fixture.command(),
+                    &["/c", "a.cpp"].map(String::from),
+                    &TaskOptions::new(&fixture.config),
+                )
+                .unwrap()
+                .remove(0);
+            let preprocessed = CompilerOutput::Vec(content.to_vec()).compress().unwrap();
+            let step = toolchain.create_compile_step(&task, preprocessed).unwrap();
+            assert!(toolchain.run_compile(&state, step).unwrap().success());
+            std::fs::read(&task.output_object).unwrap()
+        };
+
+        assert_eq!(compile(b"int a;\n"), b"int a;\n");
+        assert_eq!(compile(b"int \xC3\xA4;\n"), b"\xEF\xBB\xBFint \xC3\xA4;\n");
+        // Content in ANSI code page never had BOM
+        assert_eq!(compile(b"int \xE4;\n"), b"int \xE4;\n");
+    }
+
+    #[test]
+    fn test_compressed_preprocessed() {
+        let source: Vec<u8> = (0..1000)
+            .flat_map(|i| format!("int a{i} = {i};\n").into_bytes())
+            .collect();
+        let compressed = CompilerOutput::Vec(source.clone()).compress().unwrap();
+        assert!(
+            matches!(&compressed, CompilerOutput::Compressed(v) if v.data.len() < source.len())
+        );
+        assert_eq!(compressed.len(), source.len());
+        assert_eq!(compressed.to_vec().unwrap(), source);
+        let mut copied = Vec::new();
+        assert_eq!(compressed.copy(&mut copied).unwrap(), source.len());
+        assert_eq!(copied, source);
+        assert!(!compressed.is_trivial());
+        let trivial = CompilerOutput::Vec(b"#line 1 \"a.cpp\"\n\n".to_vec());
+        assert!(trivial.compress().unwrap().is_trivial());
+
+        // Cache key is calculated from original content
+        let state = SharedState::new(&Config::default()).unwrap();
+        let hash = |preprocessed: CompilerOutput| {
+            let args = ["/c", "a.cpp", "/Fo/tmp/a.obj"];
+            prepare_step(
+                &state,
+                &TaskOptions::default(),
+                "/project",
+                &args,
+                preprocessed,
+            )
+            .0
+        };
+        assert_eq!(hash(CompilerOutput::Vec(source)), hash(compressed));
+    }
+
+    #[test]
+    fn test_trivial_preprocessed() {
+        use crate::compiler::CompileInput::Preprocessed;
+
+        // Source entirely wrapped by `#if 0`
+        let disabled = b"#line 1 \"a.cpp\"\r\n\r\n  \r\n#pragma warning(pop)\r\n";
+        assert!(CompilerOutput::Vec(disabled.to_vec()).is_trivial());
+        assert!(CompilerOutput::Vec(Vec::new()).is_trivial());
+        assert!(!CompilerOutput::Vec(b"#line 1 \"a.cpp\"\n int a;\n".to_vec()).is_trivial());
+
+        let config = Config {
+            run_second_cpp: false,
+            ..Config::default()
+        };
+        let state = SharedState::new(&config).unwrap();
+        let options = TaskOptions::new(&config);
+        let compile_in = |dir: &str, source: &str, preprocessed: &[u8]| {
+            let preprocessed = CompilerOutput::Vec(preprocessed.to_vec());
+            prepare_step(&state, &options, dir, &["/c", "/O2", source], preprocessed)
+        };
+        let compile_step =
+            |source: &str, preprocessed: &[u8]| compile_in("/project", source, preprocessed);
+
+        let (hash, step) = compile_step("a.cpp", disabled);
+        // Trivial source is still compiled as usual
+        assert!(matches!(step.input, Preprocessed(ref v) if v.len() == disabled.len()));
+        assert_eq!(step.args, ["/nologo", "/TP", "/O2"]);
+        assert_eq!(compile_step("a.cpp", disabled).0, hash);
+        assert_ne!(compile_step("b.cpp", disabled).0, hash);
+        // The same relative source of another project
+        assert_ne!(compile_in("/other", "a.cpp", disabled).0, hash);
+
+        let code = b"int a;\n";
+        assert_eq!(compile_step("a.cpp", code).0, compile_step("b.cpp", code).0);
+    }
 }
@@ -29,6 +29,86 @@ pub fn filter_preprocessed(
     writer: &mut impl Write,
     marker: &Option<OsString>,
     keep_headers: bool,
+) -> crate::Result<()> {
+    scan_preprocessed(reader, writer, marker, keep_headers)
+}
+
+pub const BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+// Leading BOM is dropped if the rest is UTF-8, so content hashes the same with and without it.
+// Compilation writes it back before non-ASCII UTF-8 content, see `needs_bom`.
+pub fn can_skip_bom(data: &[u8]) -> bool {
+    matches!(data.strip_prefix(BOM), Some(rest) if std::str::from_utf8(rest).is_ok())
+}
+
+// Without BOM cl.exe reads UTF-8 content in ANSI code page, so non-ASCII characters change meaning.
+pub fn needs_bom(data: &[u8]) -> bool {
+    !data.is_ascii() && !data.starts_with(BOM) && std::str::from_utf8(data).is_ok()
+}
+
+// Writer converting CRLF line endings to LF. Other whitespace is kept as is: it is significant in
+// raw string literals and before line continuations.
+pub struct NormalizeNewlines<W: Write> {
+    inner: W,
+    // Not yet skipped part of leading BOM.
+    bom: &'static [u8],
+    // Carriage return at the end of previous write.
+    cr: bool,
+}
+
+impl<W: Write> NormalizeNewlines<W> {
+    pub fn new(inner: W, skip_bom: bool) -> Self {
+        NormalizeNewlines {
+            inner,
+            bom: if skip_bom { BOM } else { &[] },
+            cr: false,
+        }
+    }
+
+    // Write carriage return left at the end of stream.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.cr {
+            self.inner.write_all(b"\r")?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for NormalizeNewlines<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut data = buf;
+        while let (Some(a), Some(b)) = (self.bom.first(), data.first()) {
+            if a != b {
+                self.bom = &[];
+                break;
+            }
+            self.bom = &self.bom[1..];
+            data = &data[1..];
+        }
+        let mut result = Vec::with_capacity(data.len() + 1);
+        for &c in data {
+            if self.cr && c != b'\n' {
+                result.push(b'\r');
+            }
+            self.cr = c == b'\r';
+            if !self.cr {
+                result.push(c);
+            }
+        }
+        self.inner.write_all(&result)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+fn scan_preprocessed(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    marker: &Option<OsString>,
+    keep_headers: bool,
 ) -> crate::Result<()> {
     let mut state = ScannerState {
         buf_data: [0; BUF_SIZE],
@@ -474,6 +554,9 @@ unsafe fn delta(beg: *const u8, end: *const u8) -> usize {
 mod test {
     use std::ffi::OsString;
     use std::io::{Cursor, Write};
+    use std::slice;
+
+    use super::{can_skip_bom, NormalizeNewlines};
 
     fn check_filter_pass(
         original: &str,
@@ -508,6 +591,53 @@ mod test {
         check_filter_pass(original, expected, &marker, keep_headers, "\r\n");
     }
 
+    #[test]
+    fn test_normalize_newlines() {
+        let normalize = |data: &str| {
+            let mut writer = NormalizeNewlines::new(Vec::new(), can_skip_bom(data.as_bytes()));
+            super::filter_preprocessed(
+                &mut Cursor::new(data.as_bytes().to_vec()),
+                &mut writer,
+                &Some(OsString::from("sample.h")),
+                true,
+            )
+            .unwrap();
+            String::from_utf8(writer.finish().unwrap()).unwrap()
+        };
+        let expected = "#line 1 \"sample.cpp\"\nint a;  \n\t\n#pragma hdrstop\n\tint b;\nint c; ";
+        assert_eq!(normalize(&format!("\u{FEFF}{expected}")), expected);
+        // BOM is dropped before any UTF-8 content
+        assert_eq!(
+            normalize("\u{FEFF}#pragma hdrstop\r\nint \u{e4};\r\n"),
+            "#pragma hdrstop\nint \u{e4};\n"
+        );
+        assert!(can_skip_bom(b"\xEF\xBB\xBFint \xC3\xA4;"));
+        assert!(!can_skip_bom(b"\xEF\xBB\xBFint \xE4;"));
+        assert!(!can_skip_bom(b"int a;"));
+        assert_eq!(
+            normalize(
+                "#line 1 \"sample.cpp\"\r\nint a;  \r\n\t\r\n#pragma hdrstop\r\n\tint b;\r\nint c; "
+            ),
+            expected
+        );
+        assert_eq!(normalize(expected), expected);
+        // Whitespace inside raw string literal and before line continuation is significant
+        let literal = "#pragma hdrstop\nconst char* s = R\"(a  \n\tb \\ \n)\";\n";
+        assert_eq!(normalize(&literal.replace('\n', "\r\n")), literal);
+        // Lone carriage return is not a line ending
+        assert_eq!(
+            normalize("#pragma hdrstop\nint\ra;\r"),
+            "#pragma hdrstop\nint\ra;\r"
+        );
+
+        // BOM and line endings split between writes
+        let mut writer = NormalizeNewlines::new(Vec::new(), true);
+        for c in format!("\u{FEFF}{}", literal.replace('\n', "\r\n")).as_bytes() {
+            writer.write_all(slice::from_ref(c)).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), literal.as_bytes());
+    }
+
     #[test]
     fn test_filter_precompiled_keep() {
         check_filter(
@@ -1,21 +1,52 @@
 use std::ffi::OsString;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Once};
+
+use log::warn;
 
 use crate::compiler::{
     Arg, CommandInfo, CompilationArgs, CompilationTask, InputKind, OutputKind, PCHArgs, PCHUsage,
-    ParamForm, Scope,
+    ParamForm, Scope, TaskOptions,
 };
+use crate::config::IncrementalPolicy;
 use crate::utils::{expand_response_files, find_param, ParamValue};
 
 pub fn create_tasks(
     command: CommandInfo,
     args: &[String],
-    run_second_cpp: bool,
+    options: &TaskOptions,
 ) -> crate::Result<Vec<CompilationTask>> {
-    let expanded_args = expand_response_files(&command.current_dir, args)?;
+    let args = TaskOptions::with_args_file(options.vs_args_file.as_deref(), args);
+    let expanded_args = expand_response_files(&command.current_dir, &args)?;
 
-    let parsed_args = parse_arguments(expanded_args.iter())?;
+    let mut parsed_args = parse_arguments(expanded_args.iter())?;
+    let warnings = strip_incremental(&mut parsed_args);
+    if !warnings.is_empty() {
+        match options.incremental_policy {
+            IncrementalPolicy::Strip => {
+                static WARNING: Once = Once::new();
+                WARNING.call_once(|| {
+                    for warning in &warnings {
+                        warn!("{warning}");
+                    }
+                });
+            }
+            // Compiler state files can't be cached, run as is.
+            IncrementalPolicy::Bypass => return Ok(Vec::new()),
+        }
+    }
+    // Explicit language makes every input a source file.
+    if !parsed_args
+        .iter()
+        .any(|arg| matches!(arg, Arg::Param { name, .. } if name == "T"))
+    {
+        options.classify_inputs(&mut parsed_args);
+    }
+    let command = if options.explicit_env_paths {
+        explicit_env_paths(command, &mut parsed_args)
+    } else {
+        command
+    };
     // Source file name.
     let mut input_sources = Vec::<PathBuf>::new();
     for input in parsed_args.iter().filter_map(|arg| match arg {
@@ -60,6 +91,14 @@ pub fn create_tasks(
     });
     let pch_usage: PCHUsage = match &pch_param {
         ParamValue::None => crate::Result::<PCHUsage>::Ok(PCHUsage::None),
+        // Without both there is nothing to name precompiled header after.
+        ParamValue::Single((false, path)) if path.is_empty() && precompiled_file.is_none() => {
+            return Err(crate::Error::from(
+                "Precompiled header creation (/Yc) requires through header (/Yc<header>) \
+                 or precompiled header file (/Fp<file>)"
+                    .to_string(),
+            ));
+        }
         ParamValue::Single((input, path)) => {
             let precompiled_path = match precompiled_file {
                 Some(v) => v,
@@ -102,6 +141,8 @@ pub fn create_tasks(
             _ => None,
         }
     });
+    // Directory form (`/Fo<dir>\`) may point to directory that doesn't exist yet.
+    let output_dir = matches!(&output_param, ParamValue::Single(v) if v.to_string_lossy().ends_with(['/', '\\']));
     let output_object: Option<PathBuf> = match output_param {
         ParamValue::None => None,
         ParamValue::Single(v) => Some(command.absolutize(&v)?),
@@ -126,12 +167,27 @@ pub fn create_tasks(
             )));
         }
     };
+    // Forced using assemblies. Only files that can be found are hashed, others are resolved
+    // by compiler via search paths and only their names get into the cache key.
+    let mut referenced_files = Vec::new();
+    for arg in &parsed_args {
+        if let Arg::Param { name, value, .. } = arg {
+            if name == "FU" {
+                let path = command.absolutize(Path::new(value))?;
+                if path.is_file() {
+                    referenced_files.push(path);
+                }
+            }
+        }
+    }
     let shared = Arc::new(CompilationArgs {
         args: parsed_args,
         pch_usage,
         command,
         deps_file: None,
-        run_second_cpp,
+        referenced_files,
+        run_second_cpp: options.run_second_cpp,
+        color_diagnostics: false,
     });
     input_sources
         .into_iter()
@@ -148,13 +204,66 @@ pub fn create_tasks(
             Ok(CompilationTask {
                 shared: shared.clone(),
                 language,
-                output_object: get_output_object(&input_source, &output_object)?,
+                output_object: get_output_object(
+                    &shared.command,
+                    &input_source,
+                    &output_object,
+                    output_dir,
+                )?,
                 input_source,
             })
         })
         .collect()
 }
 
+// Remove minimal rebuild flag, its state file makes compiler output depend on previous builds.
+// Returns warnings about removed flags, empty if there were none.
+fn strip_incremental(args: &mut Vec<Arg>) -> Vec<&'static str> {
+    let count = args.len();
+    args.retain(|arg| !matches!(arg, Arg::Flag { name, .. } if name == "Gm"));
+    if args.len() == count {
+        return Vec::new();
+    }
+    vec!["Ignoring /Gm: minimal rebuild is superseded by octobuild cache"]
+}
+
+// Environment variables with search paths and corresponding compiler parameters.
+// LIB is not listed because it is used by linker only.
+const ENV_PATHS: &[(&str, &str, Scope)] = &[
+    ("INCLUDE", "I", Scope::Preprocessor),
+    ("LIBPATH", "AI", Scope::Shared),
+];
+
+// Move search paths from environment variables to explicit arguments, so they become part of
+// cache key instead of invisible environment state.
+fn explicit_env_paths(command: CommandInfo, args: &mut Vec<Arg>) -> CommandInfo {
+    let mut env = command.env.as_ref().clone();
+    for (var, flag, scope) in ENV_PATHS {
+        let Some(paths) = env.remove(*var) else {
+            continue;
+        };
+        for path in paths.split(';').filter(|path| !path.is_empty()) {
+            let exists = args.iter().any(|arg| match arg {
+                Arg::Param { name, value, .. } => name == flag && value == path,
+                _ => false,
+            });
+            if !exists {
+                args.push(Arg::param_ext(
+                    *scope,
+                    "/",
+                    *flag,
+                    path,
+                    ParamForm::Separate,
+                ));
+            }
+        }
+    }
+    CommandInfo {
+        env: Arc::new(env),
+        ..command
+    }
+}
+
 fn detect_language(path: &Path) -> Option<String> {
     let ext = path.extension()?.to_str()?;
     if ext.eq_ignore_ascii_case("cpp") || ext.eq_ignore_ascii_case("cc") {
@@ -167,17 +276,17 @@ fn detect_language(path: &Path) -> Option<String> {
 }
 
 fn get_output_object(
+    command: &CommandInfo,
     input_source: &Path,
     output_object: &Option<PathBuf>,
+    output_dir: bool,
 ) -> crate::Result<PathBuf> {
     let result = output_object.as_ref().map_or_else(
-        || {
-            assert!(input_source.is_absolute());
-            Ok(input_source.with_extension("obj"))
-        },
+        // Without `/Fo` object is written to working directory.
+        || command.default_output(input_source, "obj"),
         |path| {
             assert!(path.is_absolute());
-            if path.is_dir() {
+            if output_dir || path.is_dir() {
                 input_source
                     .file_name()
                     .map(|name| path.join(name).with_extension("obj"))
@@ -255,8 +364,10 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
 
                     "bigobj" | "FS" => Ok(Arg::flag(Scope::Compiler, "/", flag)),
 
-                    "FC"
-                    | "d2vzeroupper"
+                    // Changes `__FILE__`, compiler gets it separately to not affect cache key
+                    "FC" => Ok(Arg::flag(Scope::Preprocessor, "/", flag)),
+
+                    "d2vzeroupper"
                     | "d2vzeroupper-"
                     | "d2ExtendedWarningInfo"
                     | "d2ssa-cfg-question-"
@@ -280,7 +391,8 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
                     s if s.starts_with('Z') => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("d2Zi+") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("std:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
-                    s if s.starts_with("MP") => Ok(Arg::flag(Scope::Compiler, "/", flag)),
+                    // Each task compiles single source, parallelism is up to octobuild
+                    s if s.starts_with("MP") => Ok(Arg::flag(Scope::Ignore, "/", flag)),
                     s if s.starts_with("fsanitize=") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("MD") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("MT") => Ok(Arg::flag(Scope::Shared, "/", flag)),
@@ -290,7 +402,8 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
                         Ok(Arg::flag(Scope::Compiler, "/", flag))
                     }
                     s if s.starts_with("arch:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
-                    s if s.starts_with("errorReport:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
+                    // Crash reporting is always disabled by octobuild
+                    s if s.starts_with("errorReport:") => Ok(Arg::flag(Scope::Ignore, "/", flag)),
                     s if s.starts_with("source-charset:") => {
                         Ok(Arg::flag(Scope::Shared, "/", flag))
                     }
@@ -301,6 +414,7 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
                     s if s.starts_with("favor:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("Fo") => Ok(Arg::output(OutputKind::Object, "Fo", &s[2..])),
                     s if s.starts_with("Fp") => Ok(Arg::input(InputKind::Precompiled, &s[2..])),
+                    s if s.starts_with("Fm") => Ok(Arg::output(OutputKind::Map, "Fm", &s[2..])),
                     s if s.starts_with("Yc") => Ok(Arg::output(OutputKind::Marker, "Yc", &s[2..])),
                     s if s.starts_with("Yu") => Ok(Arg::input(InputKind::Marker, &s[2..])),
                     s if s.starts_with("Yl") => Ok(Arg::flag(Scope::Shared, "/", flag)),
@@ -312,6 +426,7 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
                         ParamForm::Smushed,
                     )),
                     s if s.starts_with("analyze") => Ok(Arg::flag(Scope::Shared, "/", flag)),
+                    s if s.starts_with("clr") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     _ => Err(arg.as_ref().to_string()),
                 },
             }
@@ -324,32 +439,76 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
     })
 }
 
-fn is_spaceable_param(flag: &str) -> Option<(&str, Scope)> {
-    for prefix in ["D"] {
-        if flag.starts_with(prefix) {
-            return Some((prefix, Scope::Shared));
-        }
-    }
+// Flags whose value may be separated by a space, matched by prefix in order.
+const SPACEABLE_PARAMS: &[(&str, Scope)] = &[
+    ("AI", Scope::Shared),
+    ("D", Scope::Shared),
+    ("FU", Scope::Shared),
+    ("external:I", Scope::Preprocessor),
+    ("I", Scope::Preprocessor),
+    ("sourceDependencies", Scope::Preprocessor),
+    ("experimental:log", Scope::Preprocessor),
+    ("W", Scope::Compiler),
+    ("wd", Scope::Compiler),
+    ("we", Scope::Compiler),
+    ("wo", Scope::Compiler),
+    ("w", Scope::Compiler),
+];
 
-    for prefix in ["external:I", "I", "sourceDependencies", "experimental:log"] {
-        if flag.starts_with(prefix) {
-            return Some((prefix, Scope::Preprocessor));
+fn is_spaceable_param(flag: &str) -> Option<(&str, Scope)> {
+    static CHECK: Once = Once::new();
+    CHECK.call_once(|| {
+        if let Err(e) = check_flag_table(SPACEABLE_PARAMS) {
+            panic!("Inconsistent cl.exe flag table: {e}");
         }
-    }
+    });
+    SPACEABLE_PARAMS
+        .iter()
+        .find(|(prefix, _)| flag.starts_with(prefix))
+        .map(|(prefix, scope)| (*prefix, *scope))
+}
 
-    for prefix in ["W", "wd", "we", "wo", "w"] {
-        if flag.starts_with(prefix) {
-            return Some((prefix, Scope::Compiler));
+// Every flag must have exactly one scope and must not be hidden by an earlier prefix.
+fn check_flag_table(table: &[(&str, Scope)]) -> Result<(), String> {
+    for (index, (flag, scope)) in table.iter().enumerate() {
+        for (prev_flag, prev_scope) in &table[..index] {
+            if flag == prev_flag {
+                return Err(format!(
+                    "flag /{flag} is classified twice: {prev_scope:?} and {scope:?}"
+                ));
+            }
+            if flag.starts_with(prev_flag) {
+                return Err(format!(
+                    "flag /{flag} ({scope:?}) is shadowed by /{prev_flag} ({prev_scope:?})"
+                ));
+            }
         }
     }
-
-    None
+    Ok(())
 }
 
 fn has_param_prefix(arg: &str) -> bool {
     arg.starts_with('/') || arg.starts_with('-')
 }
 
+#[test]
+fn test_flag_table_consistency() {
+    assert_eq!(check_flag_table(SPACEABLE_PARAMS), Ok(()));
+
+    let mut table = SPACEABLE_PARAMS.to_vec();
+    table.push(("D", Scope::Compiler));
+    assert_eq!(
+        check_flag_table(&table),
+        Err("flag /D is classified twice: Shared and Compiler".to_string())
+    );
+
+    let table = [("I", Scope::Preprocessor), ("Ifoo", Scope::Compiler)];
+    assert_eq!(
+        check_flag_table(&table),
+        Err("flag /Ifoo (Compiler) is shadowed by /I (Preprocessor)".to_string())
+    );
+}
+
 #[test]
 fn test_parse_argument() {
     let args: Vec<String> =
@@ -380,3 +539,240 @@ fn test_parse_argument() {
         ]
     )
 }
+
+#[test]
+fn test_parse_argument_clr() {
+    let args: Vec<String> = "/clr:netcore /FUSystem.dll /FU Mscorlib.dll sample.cpp"
+        .split(' ')
+        .map(|x| x.to_string())
+        .collect();
+    let parsed = parse_arguments(args.iter()).unwrap();
+    assert_eq!(
+        parsed,
+        [
+            Arg::flag(Scope::Shared, "/", "clr:netcore"),
+            Arg::param_ext(Scope::Shared, "/", "FU", "System.dll", ParamForm::Smushed),
+            Arg::param_ext(
+                Scope::Shared,
+                "/",
+                "FU",
+                "Mscorlib.dll",
+                ParamForm::Separate
+            ),
+            Arg::input(InputKind::Source, "sample.cpp")
+        ]
+    );
+    // Must survive into both preprocessing and compilation.
+    for arg in &parsed[..3] {
+        let scope = match arg {
+            Arg::Flag { scope, .. } | Arg::Param { scope, .. } => *scope,
+            _ => unreachable!(),
+        };
+        assert!(scope.matches(Scope::Preprocessor, false, false));
+        assert!(scope.matches(Scope::Compiler, false, false));
+    }
+}
+
+#[test]
+fn test_explicit_env_paths() {
+    let env = [
+        ("INCLUDE", "C:\\inc\\a;C:\\inc\\b;;C:\\inc\\a"),
+        ("LIBPATH", "C:\\ref"),
+        ("PATH", "C:\\bin"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    let command = CommandInfo {
+        program: PathBuf::from("cl.exe"),
+        current_dir: None,
+        env: Arc::new(env),
+    };
+    let args: Vec<String> = ["/c", "/I", "C:\\inc\\b", "sample.cpp"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let options = TaskOptions {
+        explicit_env_paths: true,
+        ..TaskOptions::default()
+    };
+    let tasks = create_tasks(command, &args, &options).unwrap();
+    let shared = &tasks[0].shared;
+    assert_eq!(
+        shared.args[1..],
+        [
+            Arg::param_ext(
+                Scope::Preprocessor,
+                "/",
+                "I",
+                "C:\\inc\\b",
+                ParamForm::Separate
+            ),
+            Arg::input(InputKind::Source, "sample.cpp"),
+            Arg::param_ext(
+                Scope::Preprocessor,
+                "/",
+                "I",
+                "C:\\inc\\a",
+                ParamForm::Separate
+            ),
+            Arg::param_ext(Scope::Shared, "/", "AI", "C:\\ref", ParamForm::Separate),
+        ]
+    );
+    assert_eq!(shared.command.env.get("INCLUDE"), None);
+    assert_eq!(shared.command.env.get("LIBPATH"), None);
+    assert_eq!(shared.command.env.get("PATH"), Some("C:\\bin"));
+    let child = shared.command.to_command();
+    assert!(child.get_envs().all(|(name, _)| name != "INCLUDE"));
+}
+
+#[test]
+fn test_passthrough_inputs() {
+    let args: Vec<String> = ["/c", "sample.cpp", "sample.obj", "other.lib", "app.res"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let tasks = create_tasks(
+        CommandInfo::simple(PathBuf::from("cl.exe")),
+        &args,
+        &TaskOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].input_source.file_name().unwrap(), "sample.cpp");
+    assert_eq!(
+        tasks[0].shared.args[2..],
+        [
+            Arg::input(InputKind::Passthrough, "sample.obj"),
+            Arg::input(InputKind::Passthrough, "other.lib"),
+            Arg::input(InputKind::Passthrough, "app.res"),
+        ]
+    );
+}
+
+#[test]
+fn test_default_output() {
+    use crate::compiler::{CompileStep, CompilerOutput};
+
+    let temp = tempfile::tempdir().unwrap();
+    let mut command = CommandInfo::simple(PathBuf::from("cl.exe"));
+    command.current_dir = Some(temp.path().join("build"));
+    let args = ["/c", "../src/sample.cpp"].map(String::from);
+    let tasks = create_tasks(command, &args, &TaskOptions::default()).unwrap();
+    let output = temp.path().join("build").join("sample.obj");
+    assert_eq!(tasks[0].output_object, output);
+    assert_eq!(
+        tasks[0].input_source,
+        temp.path().join("src").join("sample.cpp")
+    );
+    // Object is declared as task output, so it gets cached
+    let step = CompileStep::new(&tasks[0], CompilerOutput::Vec(Vec::new()), Vec::new());
+    assert_eq!(step.output_object, Some(output));
+}
+
+#[test]
+fn test_args_report() {
+    let args: Vec<String> = ["/c", "/nologo", "/Ox", "/Iinclude", "/bigobj", "sample.cpp"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let tasks = create_tasks(
+        CommandInfo::simple(PathBuf::from("cl.exe")),
+        &args,
+        &TaskOptions {
+            run_second_cpp: false,
+            ..TaskOptions::default()
+        },
+    )
+    .unwrap();
+    let toolchain = super::compiler::VsToolchain::new(PathBuf::from("cl.exe"));
+    let report = tasks[0].args_report(&toolchain).unwrap();
+    assert_eq!(report.preprocess, ["/Ox", "/Iinclude"]);
+    assert_eq!(report.compile, ["/Ox", "/bigobj"]);
+    assert_eq!(
+        report.dropped,
+        [
+            ("/c".to_string(), "set by octobuild for each phase"),
+            ("/nologo".to_string(), "set by octobuild for each phase"),
+            (
+                "sample.cpp".to_string(),
+                "source file is passed by octobuild"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_incremental_policy() {
+    let args: Vec<String> = ["/c", "/Gm", "/Gy", "sample.cpp"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let mut parsed = parse_arguments(args.iter()).unwrap();
+    assert_eq!(
+        strip_incremental(&mut parsed),
+        ["Ignoring /Gm: minimal rebuild is superseded by octobuild cache"]
+    );
+    assert!(strip_incremental(&mut parsed).is_empty());
+
+    let command = CommandInfo::simple(PathBuf::from("cl.exe"));
+    let tasks = create_tasks(command.clone(), &args, &TaskOptions::default()).unwrap();
+    assert_eq!(
+        tasks[0].shared.args[..2],
+        [
+            Arg::flag(Scope::Ignore, "/", "c"),
+            Arg::flag(Scope::Shared, "/", "Gy"),
+        ]
+    );
+    let options = TaskOptions {
+        incremental_policy: IncrementalPolicy::Bypass,
+        ..TaskOptions::default()
+    };
+    assert!(create_tasks(command, &args, &options).unwrap().is_empty());
+}
+
+#[test]
+fn test_args_classified() {
+    let args = ["/c", "/Iinclude", "/Ox", "/Fosample.obj", "sample.cpp"].map(String::from);
+    let tasks = create_tasks(
+        CommandInfo::simple(PathBuf::from("cl.exe")),
+        &args,
+        &TaskOptions::default(),
+    )
+    .unwrap();
+    let toolchain = super::compiler::VsToolchain::new(PathBuf::from("cl.exe"));
+    let report = tasks[0].args_report(&toolchain).unwrap();
+    assert_eq!(
+        report.classified,
+        [
+            "flag   ignore       /c",
+            "param  preprocessor /Iinclude",
+            "flag   shared       /Ox",
+            "output object       Fosample.obj",
+            "input  source       sample.cpp",
+        ]
+    );
+    assert!(report
+        .to_string()
+        .starts_with("  flag   ignore       /c\n  param  preprocessor /Iinclude\n"));
+}
+
+#[test]
+fn test_create_pch_without_header() {
+    let create = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        create_tasks(
+            CommandInfo::simple(PathBuf::from("cl.exe")),
+            &args,
+            &TaskOptions::default(),
+        )
+    };
+    let error = create(&["/c", "/Yc", "stdafx.cpp"]).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Error: Precompiled header creation (/Yc) requires through header (/Yc<header>) \
+         or precompiled header file (/Fp<file>)"
+    );
+    assert!(create(&["/c", "/Yc", "/Fpstdafx.pch", "stdafx.cpp"]).is_ok());
+    assert!(create(&["/c", "/Ycstdafx.h", "stdafx.cpp"]).is_ok());
+}
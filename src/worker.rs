@@ -1,17 +1,27 @@
-use log::error;
+use log::{error, warn};
 use std::borrow::Cow;
-use std::cmp::{max, min};
+use std::cmp::{max, min, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use petgraph::algo::toposort;
 use petgraph::graph::NodeIndex;
+use petgraph::visit::{Dfs, IntoNodeIdentifiers, Reversed};
 use petgraph::{EdgeDirection, Graph};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
+use crate::cache::FileHasher;
+use crate::cmd;
 use crate::compiler::{
-    BuildTaskResult, CommandArgs, CommandInfo, CompilationTask, Compiler, OutputInfo, SharedState,
-    Toolchain,
+    Arg, BuildTaskResult, CommandArgs, CommandInfo, CompilationTask, Compiler, CompilerOutput,
+    Hasher, OutputInfo, OutputKind, PCHUsage, SharedState, TaskOptions, Toolchain,
 };
+use crate::config::{SchedulePolicy, UnclaimedPolicy};
+use crate::io::statistic::{Statistic, WorkerStatistic};
 
 pub type BuildGraph = Graph<Arc<BuildTask>, ()>;
 
@@ -21,22 +31,172 @@ pub struct BuildTask {
 }
 
 impl BuildTask {
+    // Identity of task command, stable between builds.
+    #[must_use]
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.hash_str(&self.title);
+        hasher.hash_str(self.action.backend());
+        match &self.action {
+            BuildAction::Empty => {}
+            BuildAction::Exec(command, args) | BuildAction::Unclaimed(command, args) => {
+                hasher.hash_os_string(command.program.as_os_str());
+                hasher.hash_str(&format!("{:?}", command.current_dir));
+                hasher.hash_str(&format!("{args:?}"));
+            }
+            BuildAction::Rejected(args, reason) => {
+                hasher.hash_str(&format!("{args:?}"));
+                hasher.hash_str(reason);
+            }
+            BuildAction::Compilation(_, task) => {
+                hasher.hash_os_string(task.shared.command.program.as_os_str());
+                hasher.hash_str(&format!("{:?}", task.shared.command.current_dir));
+                hasher.hash_str(&format!("{:?}", task.shared.args));
+                hasher.hash_os_string(task.input_source.as_os_str());
+                hasher.hash_os_string(task.output_object.as_os_str());
+            }
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    // Content of task input files and command environment, tells apart runs of the same command
+    // with different inputs. Arguments of plain commands are inputs if they name existing files.
+    #[must_use]
+    pub fn input_hash(&self, state: &SharedState) -> String {
+        let (command, files) = match &self.action {
+            BuildAction::Empty | BuildAction::Rejected(..) => (None, Vec::new()),
+            BuildAction::Exec(command, args) | BuildAction::Unclaimed(command, args) => {
+                let args = match args {
+                    CommandArgs::Regular(v) => v.clone(),
+                    CommandArgs::Raw(v) => cmd::native::parse(v).unwrap_or_default(),
+                };
+                let files: Vec<PathBuf> = args
+                    .iter()
+                    .filter_map(|arg| command.absolutize(Path::new(arg)).ok())
+                    .filter(|path| path.is_file())
+                    .collect();
+                (Some(command), files)
+            }
+            BuildAction::Compilation(_, task) => {
+                let command = &task.shared.command;
+                let files: Vec<PathBuf> = command
+                    .absolutize(&task.input_source)
+                    .into_iter()
+                    .chain(task.shared.pch_usage.get_in_abs().cloned())
+                    .chain(task.shared.referenced_files.iter().cloned())
+                    .collect();
+                (Some(command), files)
+            }
+        };
+        let mut hasher = Sha256::new();
+        let mut env: Vec<_> = command.iter().flat_map(|v| v.env.iter()).collect();
+        env.sort_unstable();
+        hasher.hash_u64(env.len() as u64);
+        for (name, value) in env {
+            hasher.hash_str(name);
+            hasher.hash_str(value);
+        }
+        hasher.hash_u64(files.len() as u64);
+        for file in files {
+            hasher.hash_os_string(file.as_os_str());
+            // Missing input is hashed as empty one.
+            hasher.hash_str(
+                &state
+                    .cache
+                    .file_hash(&file)
+                    .map_or(String::new(), |v| v.hash),
+            );
+        }
+        hex::encode(hasher.finalize())
+    }
+
     fn execute(&self, state: &SharedState) -> BuildTaskResult {
         let start_time = Instant::now();
+        let checkpoint = state.checkpoint.as_ref();
+        // Inputs are hashed before task can change them.
+        let inputs =
+            (checkpoint.is_some() || state.history.is_some()).then(|| self.input_hash(state));
+        if let (Some(checkpoint), Some(inputs)) = (checkpoint, &inputs) {
+            if checkpoint.is_completed(self, inputs) {
+                return BuildTaskResult {
+                    output: Ok(OutputInfo {
+                        status: Some(0),
+                        stderr: Vec::new(),
+                        stdout: Vec::new(),
+                    }),
+                    duration: Instant::now().duration_since(start_time),
+                };
+            }
+        }
         let output = match &self.action {
+            // Queued tasks of cancelled build are not started.
+            _ if state.is_cancelled() => Err(crate::Error::from("Build cancelled".to_string())),
             BuildAction::Empty => Ok(OutputInfo {
                 status: Some(0),
                 stderr: Vec::new(),
                 stdout: Vec::new(),
             }),
+            // Only cacheable tasks are preprocessed, others can't be run partially.
+            BuildAction::Exec(..) | BuildAction::Unclaimed(..)
+                if state.preprocess_only.is_some() =>
+            {
+                Ok(OutputInfo {
+                    status: Some(0),
+                    stdout: format!("Skipped in preprocess-only mode: {}\n", self.title)
+                        .into_bytes(),
+                    stderr: Vec::new(),
+                })
+            }
             BuildAction::Exec(command_info, args) => state.wrap_slow(|| {
+                state.statistic.inc_direct();
                 let mut command = command_info.to_command();
                 args.append_to(&mut command)?;
-                let output = command.output()?;
+                let output = state.output(&mut command)?;
                 Ok(OutputInfo::new(output))
             }),
-            BuildAction::Compilation(toolchain, task) => toolchain.compile_task(state, task),
+            BuildAction::Compilation(toolchain, task) => {
+                let hits = Statistic::thread_hits();
+                let output = toolchain.compile_task(state, task);
+                let hit = Statistic::thread_hits() != hits;
+                if !hit {
+                    state.statistic.add_uncached(&self.title);
+                }
+                state.statistic.add_compiler_task(toolchain.compiler(), hit);
+                output
+            }
+            BuildAction::Unclaimed(command_info, _) => Err(crate::Error::CompilerNotClaimed(
+                command_info.program.clone(),
+            )),
+            BuildAction::Rejected(_, reason) => Err(crate::Error::Generic(reason.clone())),
         };
+        if let (Some(dump), Ok(output)) = (&state.dependencies, &output) {
+            match &self.action {
+                BuildAction::Compilation(_, task) if output.success() && !dump.contains(task) => {
+                    dump.add_unknown(&self.title);
+                }
+                BuildAction::Exec(..) if output.success() => dump.add_unknown(&self.title),
+                _ => {}
+            }
+        }
+        if let (Some(checkpoint), Some(inputs), Ok(output)) = (checkpoint, &inputs, &output) {
+            if output.success() {
+                if let Err(e) = checkpoint.add(self, inputs) {
+                    warn!(
+                        "Can't update checkpoint {}: {e}",
+                        checkpoint.path().display()
+                    );
+                }
+            }
+        }
+        if let (Some(history), Some(inputs)) = (&state.history, &inputs) {
+            // Cancelled tasks and group nodes tell nothing about flakiness.
+            if !matches!(self.action, BuildAction::Empty) && !state.is_cancelled() {
+                let success = matches!(&output, Ok(output) if output.success());
+                if let Err(e) = history.add(self, inputs, success) {
+                    warn!("Can't update history {}: {e}", history.path().display());
+                }
+            }
+        }
         BuildTaskResult {
             output,
             duration: Instant::now().duration_since(start_time),
@@ -48,6 +208,10 @@ pub enum BuildAction {
     Empty,
     Exec(CommandInfo, CommandArgs),
     Compilation(Arc<dyn Toolchain>, CompilationTask),
+    // Command not recognized by any compiler, rejected by policy.
+    Unclaimed(CommandInfo, CommandArgs),
+    // Command can't be run with required toolset.
+    Rejected(CommandArgs, String),
 }
 
 pub struct BuildResult<'a> {
@@ -96,42 +260,96 @@ impl<'a> BuildResult<'a> {
     }
 }
 
+// Task which would fall back to direct execution, failed with `--require-backend`.
+fn require_backend(
+    title: &str,
+    command: &CommandInfo,
+    args: CommandArgs,
+    reason: String,
+) -> BuildAction {
+    BuildAction::Rejected(
+        args,
+        format!(
+            "Task {title} requires compiler backend, but would run directly ({}): {reason}",
+            command.program.display()
+        ),
+    )
+}
+
 impl BuildAction {
-    pub fn create_tasks<C: Compiler>(
+    pub fn create_tasks<C: Compiler + ?Sized>(
         compiler: &C,
         command: CommandInfo,
         args: CommandArgs,
         title: &str,
-        run_second_cpp: bool,
+        options: &TaskOptions,
     ) -> Vec<BuildAction> {
-        let actions: Vec<BuildAction> = compiler
-            .create_tasks(command.clone(), args.clone(), run_second_cpp)
-            .map(|tasks| {
-                tasks
+        let actions: Vec<BuildAction> =
+            match compiler.create_tasks(command.clone(), args.clone(), options) {
+                Ok(tasks) => tasks
                     .into_iter()
                     .map(|task| BuildAction::Compilation(task.toolchain, task.task))
-                    .collect()
-            })
-            .unwrap_or_else(|e| {
-                match e {
-                    crate::Error::ToolchainNotFound(_) => {}
-                    e => {
-                        error!("Cannot cache task {title}: {e}");
+                    .collect(),
+                Err(crate::Error::ToolchainNotFound(program)) => match options.unclaimed_policy {
+                    UnclaimedPolicy::Passthrough => Vec::new(),
+                    UnclaimedPolicy::Warn => {
+                        warn!(
+                            "No compiler claimed task {title}, running uncached: {}",
+                            program.display()
+                        );
+                        Vec::new()
                     }
+                    UnclaimedPolicy::Error => return vec![BuildAction::Unclaimed(command, args)],
+                },
+                // Running with default toolset would silently produce wrong result.
+                Err(e @ crate::Error::ToolsetNotFound { .. }) => {
+                    return vec![BuildAction::Rejected(args, e.to_string())]
                 }
-                Vec::new()
-            });
+                Err(e) if options.require_backend => {
+                    return vec![require_backend(title, &command, args, e.to_string())];
+                }
+                Err(e) => {
+                    error!("Cannot cache task {title}: {e}");
+                    Vec::new()
+                }
+            };
         if actions.is_empty() {
             return vec![BuildAction::Exec(command, args)];
         }
         actions
     }
 
+    // Files produced by action.
+    #[must_use]
+    pub fn outputs(&self) -> Vec<&Path> {
+        match &self {
+            BuildAction::Empty
+            | BuildAction::Exec(..)
+            | BuildAction::Unclaimed(..)
+            | BuildAction::Rejected(..) => Vec::new(),
+            BuildAction::Compilation(_, task) => vec![task.output_object.as_path()],
+        }
+    }
+
+    // Where action runs.
+    #[must_use]
+    pub fn backend(&self) -> &'static str {
+        match &self {
+            BuildAction::Empty => "none",
+            BuildAction::Exec(..) => "exec",
+            BuildAction::Compilation(toolchain, _) => toolchain.backend(),
+            BuildAction::Unclaimed(..) => "unclaimed",
+            BuildAction::Rejected(..) => "rejected",
+        }
+    }
+
     #[must_use]
     pub fn title(&self) -> Cow<str> {
         match &self {
             BuildAction::Empty => Cow::Borrowed(""),
-            BuildAction::Exec(_, args) => Cow::Owned(format!("{args:?}")),
+            BuildAction::Exec(_, args)
+            | BuildAction::Unclaimed(_, args)
+            | BuildAction::Rejected(args, _) => Cow::Owned(format!("{args:?}")),
             BuildAction::Compilation(_, task) => {
                 Cow::Borrowed(task.input_source.to_str().unwrap_or("<stdin>"))
             }
@@ -168,9 +386,418 @@ pub fn validate_graph<N, E>(graph: Graph<N, E>) -> crate::Result<Graph<N, E>> {
     Err(crate::Error::CyclesInBuildGraph)
 }
 
+#[derive(Serialize)]
+pub struct PlannedTask {
+    // Position in scheduling order.
+    pub order: usize,
+    pub title: String,
+    pub backend: &'static str,
+    // Orders of tasks that must complete first.
+    pub depends: Vec<usize>,
+    pub inputs: Vec<PathBuf>,
+    pub outputs: Vec<PathBuf>,
+    // Cache key components besides preprocessed source, for cacheable tasks only.
+    pub cache_key: Option<CacheKeyInfo>,
+}
+
+#[derive(Serialize)]
+pub struct CacheKeyInfo {
+    pub toolchain: Option<String>,
+    pub args: Vec<String>,
+    // Files hashed into cache key.
+    pub files: Vec<PathBuf>,
+}
+
+// Describe how graph would be executed, in scheduling order.
+pub fn explain_graph(graph: &BuildGraph) -> crate::Result<Vec<PlannedTask>> {
+    // Edges point from task to its dependency.
+    let mut order = toposort(graph, None).map_err(|_| crate::Error::CyclesInBuildGraph)?;
+    order.reverse();
+    let mut position = vec![0; graph.node_count()];
+    for (i, index) in order.iter().enumerate() {
+        position[index.index()] = i;
+    }
+    order
+        .into_iter()
+        .enumerate()
+        .map(|(i, index)| {
+            let task = &graph[index];
+            let mut depends: Vec<usize> = graph
+                .neighbors_directed(index, EdgeDirection::Outgoing)
+                .map(|dep| position[dep.index()])
+                .collect();
+            depends.sort_unstable();
+            let mut planned = PlannedTask {
+                order: i,
+                title: task.title.clone(),
+                backend: task.action.backend(),
+                depends,
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+                cache_key: None,
+            };
+            if let BuildAction::Compilation(toolchain, task) = &task.action {
+                let files: Vec<PathBuf> = (task.shared.pch_usage.get_in_abs().into_iter())
+                    .chain(task.shared.referenced_files.iter())
+                    .cloned()
+                    .collect();
+                planned.inputs.push(task.input_source.clone());
+                planned.inputs.extend(files.iter().cloned());
+                planned.outputs.push(task.output_object.clone());
+                planned
+                    .outputs
+                    .extend(task.shared.pch_usage.get_out_abs().cloned());
+                planned.outputs.extend(task.map_file()?);
+                planned.outputs.extend(task.module_file()?);
+                // Arguments don't depend on preprocessed content.
+                let step = toolchain.create_compile_step(task, CompilerOutput::Vec(Vec::new()))?;
+                planned.cache_key = Some(CacheKeyInfo {
+                    toolchain: toolchain.identifier(),
+                    args: step
+                        .args
+                        .iter()
+                        .map(|arg| arg.to_string_lossy().into_owned())
+                        .collect(),
+                    files,
+                });
+            }
+            Ok(planned)
+        })
+        .collect()
+}
+
+// Check that all task output directories are writable before running anything.
+pub fn check_output_dirs(graph: &BuildGraph) -> crate::Result<()> {
+    check_writable(graph.raw_nodes().iter().flat_map(|node| {
+        let task = &node.weight;
+        task.action
+            .outputs()
+            .into_iter()
+            .map(|output| (task.title.as_str(), output))
+    }))
+}
+
+// Check that no output file is produced by several tasks, for example when sources with the same
+// name in different directories are compiled to the same `/Fo<dir>\`.
+pub fn check_output_collisions(graph: &BuildGraph) -> crate::Result<()> {
+    let mut producers: HashMap<String, Vec<&str>> = HashMap::new();
+    let mut order: Vec<(&Path, String)> = Vec::new();
+    for node in graph.raw_nodes() {
+        let task = &node.weight;
+        for output in task.action.outputs() {
+            let key = output_key(output);
+            let titles = producers.entry(key.clone()).or_default();
+            if titles.is_empty() {
+                order.push((output, key));
+            }
+            titles.push(task.title.as_str());
+        }
+    }
+    let errors: Vec<String> = order
+        .into_iter()
+        .filter_map(|(output, key)| {
+            let titles = &producers[&key];
+            (titles.len() > 1).then(|| {
+                format!(
+                    "output file {} is produced by several tasks: {}",
+                    output.display(),
+                    titles.join(", ")
+                )
+            })
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(crate::Error::OutputCollision(errors))
+    }
+}
+
+// Windows file names are case-insensitive
+fn output_key(output: &Path) -> String {
+    if cfg!(windows) {
+        output.to_string_lossy().to_lowercase()
+    } else {
+        output.to_string_lossy().into_owned()
+    }
+}
+
+// Output files of graphs loaded one by one, checks that graph doesn't produce output file of
+// itself or of graphs added before.
+#[derive(Default)]
+pub struct OutputProducers {
+    titles: HashMap<String, String>,
+}
+
+impl OutputProducers {
+    pub fn add(&mut self, graph: &BuildGraph) -> crate::Result<()> {
+        check_output_collisions(graph)?;
+        let mut errors: Vec<String> = Vec::new();
+        for node in graph.raw_nodes() {
+            let task = &node.weight;
+            for output in task.action.outputs() {
+                if let Some(title) = self.titles.get(&output_key(output)) {
+                    errors.push(format!(
+                        "output file {} is produced by several tasks: {title}, {}",
+                        output.display(),
+                        task.title
+                    ));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(crate::Error::OutputCollision(errors));
+        }
+        for node in graph.raw_nodes() {
+            for output in node.weight.action.outputs() {
+                self.titles
+                    .insert(output_key(output), node.weight.title.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_writable<'a, I: Iterator<Item = (&'a str, &'a Path)>>(outputs: I) -> crate::Result<()> {
+    let mut checked: HashSet<&Path> = HashSet::new();
+    let mut errors: Vec<String> = Vec::new();
+    for (title, output) in outputs {
+        let dir = match output.parent() {
+            Some(v) if v.is_dir() => v,
+            // Directory will be created by the build itself
+            _ => continue,
+        };
+        if !checked.insert(dir) {
+            continue;
+        }
+        if tempfile::tempfile_in(dir).is_err() {
+            errors.push(format!(
+                "output directory not writable: {} (task: {title})",
+                dir.display()
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(crate::Error::OutputDirNotWritable(errors))
+    }
+}
+
+// Move outputs under `from` prefix to `to` root: objects, precompiled headers (with tasks using
+// them), map, module and dependency files. Returns directories of remapped outputs, which are not
+// created here, so explaining build doesn't touch filesystem.
+// Cache keys don't depend on output paths, so remapped tasks share cache entries with original ones.
+pub fn remap_outputs(
+    graph: &mut BuildGraph,
+    remap: &[(PathBuf, PathBuf)],
+) -> crate::Result<BTreeSet<PathBuf>> {
+    let remapped = |path: &Path| {
+        remap.iter().find_map(|(from, to)| {
+            let relative = path.strip_prefix(from).ok()?;
+            Some(to.join(relative))
+        })
+    };
+    let mut dirs = BTreeSet::new();
+    let mut add = |path: PathBuf| {
+        if let Some(dir) = path.parent() {
+            dirs.insert(dir.to_path_buf());
+        }
+        path
+    };
+    for node in graph.node_weights_mut() {
+        let BuildAction::Compilation(toolchain, task) = &node.action else {
+            continue;
+        };
+        let mut shared = (*task.shared).clone();
+        let mut changed = false;
+        let pch_out = shared.pch_usage.is_out();
+        if let PCHUsage::In(pch) | PCHUsage::Out(pch) = &mut shared.pch_usage {
+            if let Some(path) = remapped(&pch.path_abs) {
+                pch.path.clone_from(&path);
+                pch.path_abs = if pch_out { add(path) } else { path };
+                changed = true;
+            }
+        }
+        if let Some(path) = shared.deps_file.as_deref().and_then(remapped) {
+            for arg in &mut shared.args {
+                if let Arg::Param { name, value, .. } = arg {
+                    if name == "MF" {
+                        *value = path.to_string_lossy().into_owned();
+                    }
+                }
+            }
+            shared.deps_file = Some(add(path));
+            changed = true;
+        }
+        // Without explicit file name these are placed relative to source or object.
+        for (kind, original) in [
+            (OutputKind::Map, task.map_file()?),
+            (OutputKind::Module, task.module_file()?),
+        ] {
+            let Some(path) = original.as_deref().and_then(remapped) else {
+                continue;
+            };
+            let arg = shared
+                .args
+                .iter_mut()
+                .rev()
+                .find(|arg| matches!(arg, Arg::Output { kind: found, .. } if *found == kind));
+            if let Some(Arg::Output { name, file, .. }) = arg {
+                // Module next to object follows remapped object.
+                if kind == OutputKind::Module && file.is_empty() && !name.ends_with('=') {
+                    continue;
+                }
+                *file = add(path).to_string_lossy().into_owned();
+                changed = true;
+            }
+        }
+        let output_object = match remapped(&task.output_object) {
+            Some(path) => {
+                changed = true;
+                add(path)
+            }
+            None => task.output_object.clone(),
+        };
+        if !changed {
+            continue;
+        }
+        let action = BuildAction::Compilation(
+            toolchain.clone(),
+            CompilationTask {
+                shared: Arc::new(shared),
+                output_object,
+                ..task.clone()
+            },
+        );
+        *node = Arc::new(BuildTask {
+            title: node.title.clone(),
+            action,
+        });
+    }
+    Ok(dirs)
+}
+
+// Append independent graph, returns index of its first node in combined graph.
+pub fn append_graph(graph: &mut BuildGraph, other: &BuildGraph) -> usize {
+    let offset = graph.node_count();
+    for node in other.raw_nodes() {
+        graph.add_node(node.weight.clone());
+    }
+    for edge in other.raw_edges() {
+        graph.add_edge(
+            NodeIndex::new(offset + edge.source().index()),
+            NodeIndex::new(offset + edge.target().index()),
+            (),
+        );
+    }
+    offset
+}
+
+// Ready tasks waiting for free worker, sent in order of priority given by policy.
+struct Dispatcher<'a> {
+    tx_task: &'a crossbeam_channel::Sender<TaskMessage>,
+    policy: SchedulePolicy,
+    durations: &'a HashMap<String, Duration>,
+    workers: usize,
+    // Priority, discovery order and task
+    ready: BinaryHeap<(u64, Reverse<usize>, NodeIndex)>,
+    discovered: usize,
+    // Priority of every graph task
+    priorities: Vec<u64>,
+    // Tasks sent to workers and not completed yet
+    pending: usize,
+}
+
+impl<'a> Dispatcher<'a> {
+    fn new(
+        state: &'a SharedState,
+        tx_task: &'a crossbeam_channel::Sender<TaskMessage>,
+        workers: usize,
+    ) -> Self {
+        Dispatcher {
+            tx_task,
+            policy: state.schedule_policy,
+            durations: &state.task_durations,
+            workers,
+            ready: BinaryHeap::new(),
+            discovered: 0,
+            priorities: Vec::new(),
+            pending: 0,
+        }
+    }
+
+    // Calculate priorities of tasks of appended graph.
+    fn add_graph(&mut self, graph: &BuildGraph) {
+        match self.policy {
+            SchedulePolicy::Discovery => self
+                .priorities
+                .resize(self.priorities.len() + graph.node_count(), 0),
+            SchedulePolicy::Descendants => self.priorities.extend(descendant_counts(graph)),
+            SchedulePolicy::Duration => self.priorities.extend(graph.node_weights().map(|task| {
+                self.durations
+                    .get(&task.title)
+                    .map_or(0, |duration| duration.as_millis() as u64)
+            })),
+        }
+    }
+
+    fn push(&mut self, index: NodeIndex) {
+        self.ready.push((
+            self.priorities[index.index()],
+            Reverse(self.discovered),
+            index,
+        ));
+        self.discovered += 1;
+    }
+
+    // Send ready tasks while there are free workers.
+    fn dispatch(&mut self, graph: &BuildGraph) -> crate::Result<()> {
+        while self.pending < self.workers {
+            let Some((_, _, index)) = self.ready.pop() else {
+                break;
+            };
+            self.tx_task
+                .send(TaskMessage {
+                    index,
+                    task: graph.node_weight(index).unwrap().clone(),
+                })
+                .map_err(crate::Error::send_error)?;
+            self.pending += 1;
+        }
+        Ok(())
+    }
+}
+
+// Count of distinct tasks depending on every task directly or transitively. Every edge goes from
+// dependent task to its dependency, so dependents are visited on reversed graph. Visited map is
+// shared and cleared between tasks, so memory stays linear for large graphs.
+fn descendant_counts(graph: &BuildGraph) -> Vec<u64> {
+    let graph = Reversed(graph);
+    let mut dfs = Dfs::empty(graph);
+    graph
+        .node_identifiers()
+        .map(|index| {
+            dfs.reset(graph);
+            dfs.move_to(index);
+            // Task itself is visited first and isn't its own descendant
+            let mut count = 0u64;
+            dfs.next(graph);
+            while dfs.next(graph).is_some() {
+                count += 1;
+            }
+            count
+        })
+        .collect()
+}
+
+// Graphs are appended to `graph` as they are received, and their ready tasks start immediately.
+// With `keep_going` tasks depending on failed ones are skipped and the rest are run.
 fn execute_until_failed<F>(
-    graph: &BuildGraph,
-    tx_task: &crossbeam_channel::Sender<TaskMessage>,
+    graph: &mut BuildGraph,
+    rx_graph: &crossbeam_channel::Receiver<crate::Result<BuildGraph>>,
+    state: &SharedState,
+    dispatcher: &mut Dispatcher,
     rx_result: &crossbeam_channel::Receiver<ResultMessage>,
     count: &mut usize,
     update_progress: F,
@@ -178,44 +805,150 @@ fn execute_until_failed<F>(
 where
     F: Fn(&BuildResult) -> crate::Result<()>,
 {
-    let mut completed: Vec<bool> = vec![false; graph.node_count()];
-    for index in graph.externals(EdgeDirection::Outgoing) {
-        tx_task
-            .send(TaskMessage {
-                index,
-                task: graph.node_weight(index).unwrap().clone(),
-            })
-            .map_err(crate::Error::send_error)?;
-    }
-
-    for message in rx_result {
-        assert!(!completed[message.index.index()]);
+    let mut completed: Vec<bool> = Vec::new();
+    let mut rx_graph = rx_graph.clone();
+    let mut loading = true;
+    let mut failed: usize = 0;
+    let keep_going = state.keep_going;
+    let timeout = match state.max_wall_time {
+        Some(limit) => crossbeam_channel::after(limit),
+        None => crossbeam_channel::never(),
+    };
+    loop {
+        crossbeam_channel::select! {
+            recv(rx_graph) -> message => match message {
+                Ok(next) => {
+                    let next = next?;
+                    let offset = append_graph(graph, &next);
+                    completed.resize(graph.node_count(), false);
+                    dispatcher.add_graph(&next);
+                    for index in next.externals(EdgeDirection::Outgoing) {
+                        dispatcher.push(NodeIndex::new(offset + index.index()));
+                    }
+                }
+                // All graphs are loaded
+                Err(_) => {
+                    rx_graph = crossbeam_channel::never();
+                    loading = false;
+                }
+            },
+            recv(rx_result) -> message => {
+                let Ok(message) = message else {
+                    return Err(crate::Error::from(
+                        "Unexpected end of result pipe".to_string(),
+                    ));
+                };
+                assert!(!completed[message.index.index()]);
+                dispatcher.pending -= 1;
 
-        update_progress(&BuildResult::new(&message, count, graph.node_count()))?;
-        let output = message.result.output?;
-        if !output.success() {
-            return Err(crate::Error::from("Build failed".to_string()));
+                update_progress(&BuildResult::new(&message, count, graph.node_count()))?;
+                let success = match message.result.output {
+                    Ok(output) => output.success(),
+                    Err(_) if keep_going => false,
+                    Err(e) => return Err(e),
+                };
+                if success {
+                    completed[message.index.index()] = true;
+                    for source in graph.neighbors_directed(message.index, EdgeDirection::Incoming) {
+                        if is_ready(graph, &completed, source) {
+                            dispatcher.push(source);
+                        }
+                    }
+                } else if keep_going {
+                    failed += 1;
+                } else {
+                    return Err(crate::Error::from("Build failed".to_string()));
+                }
+            },
+            recv(timeout) -> _ => {
+                // Kill running processes, completed count is updated while they are drained.
+                // Failed tasks are not counted as completed.
+                state.cancel();
+                return Err(crate::Error::BuildTimedOut {
+                    limit: state.max_wall_time.unwrap_or_default(),
+                    completed: completed.iter().filter(|v| **v).count(),
+                    total: graph.node_count(),
+                });
+            },
         }
-        completed[message.index.index()] = true;
+        dispatcher.dispatch(graph)?;
 
-        for source in graph.neighbors_directed(message.index, EdgeDirection::Incoming) {
-            if is_ready(graph, &completed, source) {
-                tx_task
-                    .send(TaskMessage {
-                        index: source,
-                        task: graph.node_weight(source).unwrap().clone(),
-                    })
-                    .map_err(crate::Error::send_error)?;
-            }
+        if loading || dispatcher.pending > 0 {
+            continue;
         }
-
-        if *count == completed.len() {
+        if failed == 0 && *count == completed.len() {
             return Ok(());
         }
+        return Err(crate::Error::BuildFailed {
+            failed,
+            skipped: completed.len() - *count,
+            total: completed.len(),
+        });
+    }
+}
+
+// Accept build with failed tasks if at least `min_success` percent of all tasks succeeded.
+pub fn check_min_success(
+    result: crate::Result<()>,
+    min_success: f64,
+    out: &mut impl Write,
+) -> crate::Result<()> {
+    let Err(crate::Error::BuildFailed {
+        failed,
+        skipped,
+        total,
+    }) = &result
+    else {
+        return result;
+    };
+    let succeeded = total - failed - skipped;
+    let ratio = succeeded as f64 * 100.0 / *total as f64;
+    writeln!(
+        out,
+        "Succeeded {succeeded} of {total} tasks ({ratio:.1}%), required {min_success}%"
+    )?;
+    if ratio >= min_success {
+        Ok(())
+    } else {
+        result
+    }
+}
+
+// Compilation tasks required to be restored from cache.
+pub enum ExpectCached {
+    All,
+    Titles(HashSet<String>),
+}
+
+// Fail build if any expected task wasn't restored from cache, listing such tasks.
+pub fn check_expect_cached(
+    statistic: &Statistic,
+    expected: &ExpectCached,
+    out: &mut impl Write,
+) -> crate::Result<()> {
+    let mut missed: Vec<String> = statistic
+        .uncached
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|title| match expected {
+            ExpectCached::All => true,
+            ExpectCached::Titles(titles) => titles.contains(*title),
+        })
+        .cloned()
+        .collect();
+    if missed.is_empty() {
+        return Ok(());
+    }
+    missed.sort();
+    writeln!(out, "Tasks expected to be cached ({}):", missed.len())?;
+    for title in &missed {
+        writeln!(out, "  {title}")?;
     }
-    Err(crate::Error::from(
-        "Unexpected end of result pipe".to_string(),
-    ))
+    Err(crate::Error::Generic(format!(
+        "{} tasks expected to be cached were compiled",
+        missed.len()
+    )))
 }
 
 fn is_ready<N, E>(graph: &Graph<N, E>, completed: &[bool], source: NodeIndex) -> bool {
@@ -240,20 +973,77 @@ where
     if graph.node_count() == 0 {
         return Ok(());
     }
+    let num_cpus = max(1, min(process_limit, graph.node_count()));
+    execute_stream(state, std::iter::once(Ok(graph)), num_cpus, update_progress)
+}
 
+// Execute independent graphs while they are loaded: ready tasks of every graph start without
+// waiting for following graphs. Graphs must be already validated. Loading stops on first error.
+pub fn execute_graphs<I, F>(
+    state: &SharedState,
+    graphs: I,
+    process_limit: usize,
+    update_progress: F,
+) -> crate::Result<()>
+where
+    I: Iterator<Item = crate::Result<BuildGraph>> + Send,
+    F: Fn(&BuildResult) -> crate::Result<()>,
+{
+    execute_stream(state, graphs, max(1, process_limit), update_progress)
+}
+
+fn execute_stream<I, F>(
+    state: &SharedState,
+    graphs: I,
+    num_cpus: usize,
+    update_progress: F,
+) -> crate::Result<()>
+where
+    I: Iterator<Item = crate::Result<BuildGraph>> + Send,
+    F: Fn(&BuildResult) -> crate::Result<()>,
+{
     let (tx_result, rx_result) = crossbeam_channel::unbounded::<ResultMessage>();
     let (tx_task, rx_task) = crossbeam_channel::unbounded::<TaskMessage>();
-    let num_cpus = max(1, min(process_limit, graph.node_count()));
+    let (tx_graph, rx_graph) = crossbeam_channel::unbounded::<crate::Result<BuildGraph>>();
     std::thread::scope(|scope| {
+        scope.spawn(move || {
+            for graph in graphs {
+                let failed = graph.is_err();
+                // Stop loading if build is already finished
+                if tx_graph.send(graph).is_err() || failed {
+                    break;
+                }
+            }
+        });
         for worker_id in 0..num_cpus {
             let local_rx_task = rx_task.clone();
             let local_tx_result = tx_result.clone();
             scope.spawn(move || {
+                let mut stat = WorkerStatistic::default();
+                let mut idle_since = Instant::now();
                 while let Ok(message) = local_rx_task.recv() {
+                    let busy_since = Instant::now();
+                    stat.idle += busy_since.duration_since(idle_since);
+                    let result = message.task.execute(state);
+                    state
+                        .statistic
+                        .add_task(&message.task.title, result.duration);
+                    idle_since = Instant::now();
+                    if let Some(tracer) = &state.trace {
+                        tracer.add(
+                            worker_id,
+                            &message.task.title,
+                            message.task.action.backend(),
+                            busy_since,
+                            idle_since,
+                        );
+                    }
+                    stat.busy += idle_since.duration_since(busy_since);
+                    stat.tasks += 1;
                     match local_tx_result.send(ResultMessage {
                         index: message.index,
                         worker: worker_id,
-                        result: message.task.execute(state),
+                        result,
                         task: message.task,
                     }) {
                         Ok(_) => {}
@@ -262,31 +1052,142 @@ where
                         }
                     }
                 }
+                stat.idle += Instant::now().duration_since(idle_since);
+                state.statistic.add_worker(worker_id, stat);
             });
         }
         drop(tx_result);
         // Run all tasks.
+        let mut graph = BuildGraph::new();
         let mut count: usize = 0;
-        let result =
-            execute_until_failed(&graph, &tx_task, &rx_result, &mut count, &update_progress);
-        // Cleanup task queue.
+        let result = execute_until_failed(
+            &mut graph,
+            &rx_graph,
+            state,
+            &mut Dispatcher::new(state, &tx_task, num_cpus),
+            &rx_result,
+            &mut count,
+            &update_progress,
+        );
+        // Cleanup task and graph queues.
         drop(tx_task);
         drop(rx_task);
+        drop(rx_graph);
         // Wait for in progress task completion.
+        let mut succeeded = 0;
         for message in rx_result {
+            if matches!(&message.result.output, Ok(output) if output.success()) {
+                succeeded += 1;
+            }
             update_progress(&BuildResult::new(&message, &mut count, graph.node_count()))?;
         }
-        result
+        match result {
+            Err(crate::Error::BuildTimedOut {
+                limit,
+                completed,
+                total,
+            }) => Err(crate::Error::BuildTimedOut {
+                limit,
+                completed: completed + succeeded,
+                total,
+            }),
+            result => result,
+        }
     })
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashSet;
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
-    use crate::compiler::SharedState;
-    use crate::config::Config;
-    use crate::worker::{execute_graph, BuildAction, BuildGraph, BuildTask};
+    use crate::compiler::{
+        CommandArgs, CommandInfo, CompilationArgs, CompilationTask, CompileStep, CompilerGroup,
+        CompilerOutput, OutputInfo, PCHUsage, PreprocessResult, Scope, SharedState, TaskOptions,
+        Toolchain,
+    };
+    use crate::config::{CacheMode, Config, SchedulePolicy, UnclaimedPolicy};
+    use crate::worker::{
+        check_expect_cached, check_min_success, check_output_collisions, check_writable,
+        execute_graph, execute_graphs, explain_graph, remap_outputs, BuildAction, BuildGraph,
+        BuildTask, ExpectCached, OutputProducers,
+    };
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_writable_readonly() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        // Root ignores directory permissions
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+
+        let temp = tempfile::tempdir().unwrap();
+        let output = temp.path().join("sample.obj");
+        fs::set_permissions(temp.path(), fs::Permissions::from_mode(0o555)).unwrap();
+        let result = check_writable(vec![("task 1", output.as_path())].into_iter());
+        fs::set_permissions(temp.path(), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let message = result.unwrap_err().to_string();
+        assert_eq!(
+            message,
+            format!(
+                "output directory not writable: {} (task: task 1)",
+                temp.path().display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_output_collisions() {
+        let temp = tempfile::tempdir().unwrap();
+        let command = CommandInfo {
+            current_dir: Some(temp.path().to_path_buf()),
+            ..CommandInfo::simple(PathBuf::from("cl.exe"))
+        };
+        let toolchain: Arc<dyn Toolchain> = Arc::new(SleepToolchain::default());
+        let mut graph = BuildGraph::new();
+        for (title, source) in [
+            ("first", "a/foo.cpp"),
+            ("second", "b/foo.cpp"),
+            ("third", "b/bar.cpp"),
+        ] {
+            let args = ["/c", "/Foout/", source].map(str::to_string);
+            let task =
+                crate::vs::prepare::create_tasks(command.clone(), &args, &TaskOptions::default())
+                    .unwrap()
+                    .remove(0);
+            graph.add_node(Arc::new(BuildTask {
+                title: title.to_string(),
+                action: BuildAction::Compilation(toolchain.clone(), task),
+            }));
+        }
+
+        let expected = format!(
+            "output file {} is produced by several tasks: first, second",
+            temp.path().join("out").join("foo.obj").display()
+        );
+        let message = check_output_collisions(&graph).unwrap_err().to_string();
+        assert_eq!(message, expected);
+
+        // Tasks of different graphs loaded one by one
+        let single = |index: usize| {
+            let mut single = BuildGraph::new();
+            single.add_node(graph.raw_nodes()[index].weight.clone());
+            single
+        };
+        let mut producers = OutputProducers::default();
+        producers.add(&single(0)).unwrap();
+        producers.add(&single(2)).unwrap();
+        let message = producers.add(&single(1)).unwrap_err().to_string();
+        assert_eq!(message, expected);
+    }
 
     #[test]
     fn test_execute_graph_empty() {
@@ -320,6 +1221,825 @@ mod test {
         assert_eq!(actual, vec!["task 1".to_string()]);
     }
 
+    #[test]
+    fn test_keep_going_min_success() {
+        let mut state = SharedState::new(&Config::default()).unwrap();
+        state.keep_going = true;
+
+        // 8 of 10 tasks succeed, dependent of failed task is skipped
+        let mut graph = BuildGraph::new();
+        let mut add = |title: &str, action: BuildAction| {
+            graph.add_node(Arc::new(BuildTask {
+                title: title.to_string(),
+                action,
+            }))
+        };
+        let failed = add(
+            "failed",
+            BuildAction::Rejected(CommandArgs::Regular(Vec::new()), "broken".to_string()),
+        );
+        let skipped = add("skipped", BuildAction::Empty);
+        for i in 0..8 {
+            add(&format!("task {i}"), BuildAction::Empty);
+        }
+        graph.add_edge(skipped, failed, ());
+
+        let executed = Mutex::new(Vec::new());
+        let result = execute_graph(&state, graph, 4, |r| {
+            executed.lock().unwrap().push(r.task.title.clone());
+            Ok(())
+        });
+        assert!(matches!(
+            result,
+            Err(crate::Error::BuildFailed {
+                failed: 1,
+                skipped: 1,
+                total: 10
+            })
+        ));
+        assert_eq!(executed.lock().unwrap().len(), 9);
+
+        let mut out = Vec::new();
+        assert!(check_min_success(result, 75.0, &mut out).is_ok());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Succeeded 8 of 10 tasks (80.0%), required 75%\n"
+        );
+        let result = Err(crate::Error::BuildFailed {
+            failed: 1,
+            skipped: 1,
+            total: 10,
+        });
+        assert!(check_min_success(result, 90.0, &mut Vec::new()).is_err());
+        assert!(check_min_success(Ok(()), 90.0, &mut Vec::new()).is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_max_wall_time() {
+        let mut state = SharedState::new(&Config::default()).unwrap();
+        state.max_wall_time = Some(Duration::from_millis(300));
+        state.keep_going = true;
+
+        let mut graph = BuildGraph::new();
+        let mut add = |title: &str, action: BuildAction| {
+            graph.add_node(Arc::new(BuildTask {
+                title: title.to_string(),
+                action,
+            }))
+        };
+        let sleep = || {
+            BuildAction::Exec(
+                CommandInfo::simple(PathBuf::from("sleep")),
+                CommandArgs::Regular(vec!["30".to_string()]),
+            )
+        };
+        add("fast", BuildAction::Empty);
+        add(
+            "broken",
+            BuildAction::Exec(
+                CommandInfo::simple(PathBuf::from("false")),
+                CommandArgs::Regular(Vec::new()),
+            ),
+        );
+        let slow = add("slow 1", sleep());
+        add("slow 2", sleep());
+        let queued = add("queued", BuildAction::Empty);
+        graph.add_edge(queued, slow, ());
+
+        let start = std::time::Instant::now();
+        let executed = Mutex::new(Vec::new());
+        let result = execute_graph(&state, graph, 4, |r| {
+            executed.lock().unwrap().push(r.task.title.clone());
+            Ok(())
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(matches!(
+            result,
+            Err(crate::Error::BuildTimedOut {
+                completed: 1,
+                total: 5,
+                ..
+            })
+        ));
+        assert!(state.is_cancelled());
+        // Killed tasks are reported, dependent ones are never started
+        let mut executed = executed.into_inner().unwrap();
+        executed.sort();
+        assert_eq!(executed, vec!["broken", "fast", "slow 1", "slow 2"]);
+    }
+
+    #[test]
+    fn test_schedule_descendants() {
+        let run = |policy: SchedulePolicy| {
+            let mut state = SharedState::new(&Config::default()).unwrap();
+            state.schedule_policy = policy;
+            let mut graph = BuildGraph::new();
+            let mut add = |title: &str| {
+                graph.add_node(Arc::new(BuildTask {
+                    title: title.to_string(),
+                    action: BuildAction::Empty,
+                }))
+            };
+            add("leaf");
+            let shared = add("shared");
+            let link = add("link");
+            let package = add("package");
+            add("test");
+            graph.add_edge(link, shared, ());
+            graph.add_edge(package, link, ());
+
+            let executed = Mutex::new(Vec::new());
+            execute_graph(&state, graph, 1, |r| {
+                executed.lock().unwrap().push(r.task.title.clone());
+                Ok(())
+            })
+            .unwrap();
+            executed.into_inner().unwrap()
+        };
+        assert_eq!(
+            run(SchedulePolicy::Discovery),
+            ["leaf", "shared", "test", "link", "package"]
+        );
+        // Task unblocking more work goes first
+        assert_eq!(
+            run(SchedulePolicy::Descendants),
+            ["shared", "link", "leaf", "test", "package"]
+        );
+    }
+
+    #[test]
+    fn test_descendant_counts_diamond() {
+        use super::descendant_counts;
+
+        let mut graph = BuildGraph::new();
+        let mut add = |title: &str| {
+            graph.add_node(Arc::new(BuildTask {
+                title: title.to_string(),
+                action: BuildAction::Empty,
+            }))
+        };
+        let base = add("base");
+        let left = add("left");
+        let right = add("right");
+        let top = add("top");
+        let package = add("package");
+        // Both `left` and `right` depend on `base`, `top` depends on both of them
+        graph.add_edge(left, base, ());
+        graph.add_edge(right, base, ());
+        graph.add_edge(top, left, ());
+        graph.add_edge(top, right, ());
+        graph.add_edge(package, top, ());
+        // `top` and `package` are reachable from `base` through two paths, but counted once
+        assert_eq!(descendant_counts(&graph), [4, 2, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_schedule_duration() {
+        let mut state = SharedState::new(&Config::default()).unwrap();
+        state.schedule_policy = SchedulePolicy::Duration;
+        state.task_durations = [("slow", 3), ("fast", 1), ("medium", 2)]
+            .into_iter()
+            .map(|(title, secs)| (title.to_string(), Duration::from_secs(secs)))
+            .collect();
+        let mut graph = BuildGraph::new();
+        for title in ["new", "fast", "medium", "slow"] {
+            graph.add_node(Arc::new(BuildTask {
+                title: title.to_string(),
+                action: BuildAction::Empty,
+            }));
+        }
+        let executed = Mutex::new(Vec::new());
+        execute_graph(&state, graph, 1, |r| {
+            executed.lock().unwrap().push(r.task.title.clone());
+            Ok(())
+        })
+        .unwrap();
+        // Tasks without known duration go last
+        assert_eq!(
+            executed.into_inner().unwrap(),
+            ["slow", "medium", "fast", "new"]
+        );
+    }
+
+    #[test]
+    fn test_execute_graphs_streaming() {
+        let state = SharedState::new(&Config::default()).unwrap();
+        let started = AtomicUsize::new(0);
+        let graph = |file: usize| {
+            // Task depends on previous task of the same file
+            let mut graph = BuildGraph::new();
+            let mut prev = None;
+            for i in 0..10 {
+                let node = graph.add_node(Arc::new(BuildTask {
+                    title: format!("file {file} task {i}"),
+                    action: BuildAction::Empty,
+                }));
+                if let Some(prev) = prev {
+                    graph.add_edge(node, prev, ());
+                }
+                prev = Some(node);
+            }
+            graph
+        };
+        // Last file is "parsed" only after tasks of first files have run
+        let early = Mutex::new(0);
+        let graphs = (0..5).map(|file| {
+            if file == 4 {
+                let deadline = std::time::Instant::now() + Duration::from_secs(10);
+                while started.load(Ordering::Relaxed) == 0 && std::time::Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                *early.lock().unwrap() = started.load(Ordering::Relaxed);
+            }
+            Ok(graph(file))
+        });
+        let titles = Mutex::new(Vec::new());
+        execute_graphs(&state, graphs, 4, |r| {
+            started.fetch_add(1, Ordering::Relaxed);
+            titles.lock().unwrap().push(r.task.title.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert!(*early.lock().unwrap() > 0);
+        let titles = titles.into_inner().unwrap();
+        assert_eq!(titles.len(), 50);
+        // Dependencies are kept within every file
+        for file in 0..5 {
+            let order: Vec<&String> = titles
+                .iter()
+                .filter(|title| title.starts_with(&format!("file {file} ")))
+                .collect();
+            assert_eq!(order[0], &format!("file {file} task 0"));
+            assert_eq!(order[9], &format!("file {file} task 9"));
+        }
+
+        // Loading error stops the build
+        let graphs = [Ok(graph(0)), Err(crate::Error::from("broken"))].into_iter();
+        assert!(execute_graphs(&state, graphs, 4, |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_execute_graph_worker_statistic() {
+        let state = SharedState::new(&Config::default()).unwrap();
+
+        let mut graph = BuildGraph::new();
+        for i in 0..10 {
+            graph.add_node(Arc::new(BuildTask {
+                title: format!("task {i}"),
+                action: BuildAction::Empty,
+            }));
+        }
+
+        execute_graph(&state, graph, 3, |_| Ok(())).unwrap();
+
+        let workers = state.statistic.workers.lock().unwrap();
+        assert_eq!(workers.len(), 3);
+        assert_eq!(workers.iter().map(|w| w.tasks).sum::<usize>(), 10);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compiler_statistic() {
+        use crate::clang::compiler::ClangCompiler;
+        use crate::compiler::Compiler;
+        use crate::io::statistic::CompilerStatistic;
+        use crate::test_utils::{Fixture, COPY_SOURCE};
+
+        // Fake compiler: preprocessing copies source, compilation writes object.
+        let fixture = Fixture::clang(COPY_SOURCE);
+        std::fs::write(fixture.path().join("a.cpp"), "int a;\n").unwrap();
+        let state = fixture.state();
+        let sleep_toolchain: Arc<dyn Toolchain> = Arc::new(SleepToolchain::default());
+
+        // The same source is compiled twice, the second one is restored from cache
+        let mut graph = BuildGraph::new();
+        let mut previous = None;
+        for (title, output) in [("clang 1", "a.o"), ("clang 2", "a.o"), ("sleep", "b.o")] {
+            let args = ["-c", "a.cpp", "-o", output].map(String::from);
+            let task = ClangCompiler::default()
+                .create_tasks(
+                    fixture.command(),
+                    CommandArgs::Regular(args.to_vec()),
+                    &TaskOptions::new(&fixture.config),
+                )
+                .unwrap()
+                .remove(0);
+            let toolchain = if title == "sleep" {
+                sleep_toolchain.clone()
+            } else {
+                task.toolchain
+            };
+            let node = graph.add_node(Arc::new(BuildTask {
+                title: title.to_string(),
+                action: BuildAction::Compilation(toolchain, task.task),
+            }));
+            if let Some(previous) = previous {
+                graph.add_edge(node, previous, ());
+            }
+            previous = Some(node);
+        }
+        graph.add_node(Arc::new(BuildTask {
+            title: "link".to_string(),
+            action: BuildAction::Exec(
+                CommandInfo::simple(PathBuf::from("true")),
+                CommandArgs::Regular(Vec::new()),
+            ),
+        }));
+        execute_graph(&state, graph, 2, |_| Ok(())).unwrap();
+
+        let compilers = state.statistic.compilers.lock().unwrap().clone();
+        assert_eq!(
+            compilers.into_iter().collect::<Vec<_>>(),
+            [
+                ("ClangCompiler", CompilerStatistic { tasks: 2, hits: 1 }),
+                ("other", CompilerStatistic { tasks: 1, hits: 0 }),
+            ]
+        );
+        let mut out = Vec::new();
+        state.statistic.print_compilers(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Backend usage:\n  ClangCompiler: 2 tasks, 1 hits\n  \
+             other: 1 tasks, 0 hits\n  other commands: 1 tasks\n"
+        );
+    }
+
+    #[derive(Default)]
+    struct Concurrency {
+        current: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    impl Concurrency {
+        fn run(&self) {
+            let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(20));
+            self.current.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Backend that sleeps in each phase and tracks phase concurrency.
+    #[derive(Default)]
+    struct SleepToolchain {
+        preprocess: Concurrency,
+        compile: Concurrency,
+    }
+
+    impl Toolchain for SleepToolchain {
+        fn identifier(&self) -> Option<String> {
+            None
+        }
+
+        fn create_tasks(
+            &self,
+            _: CommandInfo,
+            _: &[String],
+            _: &TaskOptions,
+        ) -> crate::Result<Vec<CompilationTask>> {
+            Ok(Vec::new())
+        }
+
+        fn run_preprocess(
+            &self,
+            state: &SharedState,
+            task: &CompilationTask,
+        ) -> crate::Result<PreprocessResult> {
+            state.wrap_preprocess(|| self.preprocess.run());
+            Ok(PreprocessResult::Success(CompilerOutput::Vec(
+                task.input_source.to_string_lossy().as_bytes().to_vec(),
+            )))
+        }
+
+        fn create_compile_step(
+            &self,
+            task: &CompilationTask,
+            preprocessed: CompilerOutput,
+        ) -> crate::Result<CompileStep> {
+            Ok(CompileStep::new(task, preprocessed, Vec::new()))
+        }
+
+        fn phase_args(&self, _: &CompilationTask, _: Scope) -> crate::Result<Vec<OsString>> {
+            Ok(Vec::new())
+        }
+
+        fn run_compile(&self, state: &SharedState, _: CompileStep) -> crate::Result<OutputInfo> {
+            state.wrap_slow(|| self.compile.run());
+            Ok(OutputInfo {
+                status: Some(0),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_execute_graph_stage_limits() {
+        let config = Config {
+            cache_mode: CacheMode::None,
+            process_limit: 2,
+            preprocess_limit: 6,
+            compile_limit: 2,
+            ..Config::default()
+        };
+        let state = SharedState::new(&config).unwrap();
+        let toolchain = Arc::new(SleepToolchain::default());
+
+        let mut graph = BuildGraph::new();
+        for i in 0..32 {
+            let task = CompilationTask {
+                shared: Arc::new(CompilationArgs {
+                    command: CommandInfo::simple(PathBuf::from("sleep")),
+                    args: Vec::new(),
+                    pch_usage: PCHUsage::None,
+                    deps_file: None,
+                    referenced_files: Vec::new(),
+                    run_second_cpp: false,
+                    color_diagnostics: false,
+                }),
+                language: "C".to_string(),
+                input_source: state.temp_dir.path().join(format!("{i}.c")),
+                output_object: state.temp_dir.path().join(format!("{i}.o")),
+            };
+            graph.add_node(Arc::new(BuildTask {
+                title: format!("task {i}"),
+                action: BuildAction::Compilation(toolchain.clone(), task),
+            }));
+        }
+
+        execute_graph(&state, graph, config.worker_limit(), |_| Ok(())).unwrap();
+
+        // Preprocessing is not limited by process limit
+        let preprocess = toolchain.preprocess.max.load(Ordering::SeqCst);
+        let compile = toolchain.compile.max.load(Ordering::SeqCst);
+        assert!((3..=6).contains(&preprocess), "preprocess: {preprocess}");
+        assert!((1..=2).contains(&compile), "compile: {compile}");
+    }
+
+    #[test]
+    fn test_preprocess_only() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache_mode: CacheMode::None,
+            ..Config::default()
+        };
+        let mut state = SharedState::new(&config).unwrap();
+        let dir = temp.path().join("preprocessed");
+        state.preprocess_only = Some(dir.clone());
+        let toolchain = Arc::new(SleepToolchain::default());
+
+        let mut graph = BuildGraph::new();
+        let mut sources = Vec::new();
+        for i in 0..2 {
+            let source = temp.path().join(format!("{i}.c"));
+            sources.push(source.to_string_lossy().into_owned());
+            let task = CompilationTask {
+                shared: Arc::new(CompilationArgs {
+                    command: CommandInfo::simple(PathBuf::from("sleep")),
+                    args: Vec::new(),
+                    pch_usage: PCHUsage::None,
+                    deps_file: None,
+                    referenced_files: Vec::new(),
+                    run_second_cpp: false,
+                    color_diagnostics: false,
+                }),
+                language: "C".to_string(),
+                input_source: source,
+                output_object: temp.path().join(format!("{i}.o")),
+            };
+            graph.add_node(Arc::new(BuildTask {
+                title: format!("task {i}"),
+                action: BuildAction::Compilation(toolchain.clone(), task),
+            }));
+        }
+        graph.add_node(Arc::new(BuildTask {
+            title: "link".to_string(),
+            action: BuildAction::Exec(
+                CommandInfo::simple(PathBuf::from("false")),
+                CommandArgs::Regular(Vec::new()),
+            ),
+        }));
+
+        execute_graph(&state, graph, 2, |_| Ok(())).unwrap();
+
+        let mut contents: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                assert_eq!(path.extension().unwrap(), "i");
+                std::fs::read_to_string(path).unwrap()
+            })
+            .collect();
+        contents.sort();
+        assert_eq!(contents, sources);
+        assert_eq!(toolchain.compile.max.load(Ordering::SeqCst), 0);
+        assert!(!temp.path().join("0.o").exists());
+    }
+
+    #[test]
+    fn test_unclaimed_policy() {
+        let create = |policy: UnclaimedPolicy| {
+            let options = TaskOptions {
+                unclaimed_policy: policy,
+                ..TaskOptions::default()
+            };
+            BuildAction::create_tasks(
+                &CompilerGroup::new(),
+                CommandInfo::simple(PathBuf::from("custom-tool")),
+                CommandArgs::Regular(vec!["--generate".to_string()]),
+                "custom step",
+                &options,
+            )
+        };
+
+        let actions = create(UnclaimedPolicy::Passthrough);
+        assert!(matches!(actions[..], [BuildAction::Exec(..)]));
+
+        let actions = create(UnclaimedPolicy::Warn);
+        assert!(matches!(actions[..], [BuildAction::Exec(..)]));
+
+        let actions = create(UnclaimedPolicy::Error);
+        assert!(matches!(actions[..], [BuildAction::Unclaimed(..)]));
+        let mut graph = BuildGraph::new();
+        graph.add_node(Arc::new(BuildTask {
+            title: "custom step".to_string(),
+            action: actions.into_iter().next().unwrap(),
+        }));
+        let state = SharedState::new(&Config::default()).unwrap();
+        let error = execute_graph(&state, graph, 1, |_| Ok(())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No compiler claimed command: custom-tool"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_require_backend() {
+        use std::path::Path;
+
+        use crate::clang::compiler::ClangCompiler;
+        use crate::test_utils::fake_compiler;
+
+        let temp = tempfile::tempdir().unwrap();
+        let clang = fake_compiler(temp.path(), "clang", "");
+        let compiler = CompilerGroup::new().add::<ClangCompiler>();
+        let options = TaskOptions {
+            require_backend: true,
+            ..TaskOptions::default()
+        };
+        let create_tasks = |program: &Path, args: &[&str]| {
+            BuildAction::create_tasks(
+                &compiler,
+                CommandInfo::simple(program.to_path_buf()),
+                CommandArgs::Regular(args.iter().map(ToString::to_string).collect()),
+                "compile a.cpp",
+                &options,
+            )
+        };
+
+        // Unclaimed programs are left to unclaimed policy
+        let actions = create_tasks(Path::new("custom-cc"), &["-c", "a.cpp"]);
+        assert!(matches!(actions[..], [BuildAction::Exec(..)]));
+
+        // Claimed compiler command which backend can't handle
+        let actions = create_tasks(&clang, &["-c"]);
+        assert!(matches!(actions[..], [BuildAction::Rejected(..)]));
+        let mut graph = BuildGraph::new();
+        graph.add_node(Arc::new(BuildTask {
+            title: "compile a.cpp".to_string(),
+            action: actions.into_iter().next().unwrap(),
+        }));
+        let state = SharedState::new(&Config::default()).unwrap();
+        let error = execute_graph(&state, graph, 1, |_| Ok(())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Error: Task compile a.cpp requires compiler backend, but would run directly \
+                 ({}): Error: Can't find source file path.",
+                clang.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_explain_graph() {
+        let temp = tempfile::tempdir().unwrap();
+        let command = CommandInfo {
+            current_dir: Some(temp.path().to_path_buf()),
+            ..CommandInfo::simple(PathBuf::from("cl.exe"))
+        };
+        let args = ["/c", "/O2", "/MP", "sample.cpp"].map(str::to_string);
+        let task =
+            crate::vs::prepare::create_tasks(command.clone(), &args, &TaskOptions::default())
+                .unwrap()
+                .remove(0);
+        let toolchain: Arc<dyn Toolchain> = Arc::new(SleepToolchain::default());
+
+        let mut graph = BuildGraph::new();
+        let link = graph.add_node(Arc::new(BuildTask {
+            title: "link".to_string(),
+            action: BuildAction::Exec(command.clone(), CommandArgs::Regular(Vec::new())),
+        }));
+        let compile = graph.add_node(Arc::new(BuildTask {
+            title: "compile".to_string(),
+            action: BuildAction::Compilation(toolchain, task),
+        }));
+        let custom = graph.add_node(Arc::new(BuildTask {
+            title: "custom".to_string(),
+            action: BuildAction::Unclaimed(command, CommandArgs::Regular(Vec::new())),
+        }));
+        graph.add_edge(link, compile, ());
+        graph.add_edge(link, custom, ());
+
+        let plan = serde_json::to_value(explain_graph(&graph).unwrap()).unwrap();
+        let backends: Vec<(&str, &str)> = plan
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|task| {
+                (
+                    task["title"].as_str().unwrap(),
+                    task["backend"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(backends.len(), 3);
+        assert!(backends.contains(&("compile", "local")));
+        assert!(backends.contains(&("custom", "unclaimed")));
+        // Dependencies are scheduled first
+        assert_eq!(backends[2], ("link", "exec"));
+        assert_eq!(plan[2]["depends"], serde_json::json!([0, 1]));
+        assert_eq!(plan[2]["cache_key"], serde_json::Value::Null);
+
+        let compile = &plan[backends
+            .iter()
+            .position(|(title, _)| *title == "compile")
+            .unwrap()];
+        assert_eq!(
+            compile["outputs"],
+            serde_json::json!([temp.path().join("sample.obj")])
+        );
+        assert_eq!(
+            compile["inputs"],
+            serde_json::json!([temp.path().join("sample.cpp")])
+        );
+        assert_eq!(compile["cache_key"]["args"], serde_json::json!([]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remap_outputs() {
+        use crate::clang::compiler::ClangCompiler;
+        use crate::compiler::Compiler;
+        use crate::test_utils::{Fixture, COPY_SOURCE};
+
+        // Fake compiler: preprocessing copies source, compilation writes object.
+        let fixture = Fixture::clang(COPY_SOURCE);
+        let temp = fixture.path();
+        std::fs::write(temp.join("a.cpp"), "int main();\n").unwrap();
+        std::fs::create_dir(temp.join("out")).unwrap();
+
+        let state = fixture.state();
+        let args = ["-c", "a.cpp", "-o", "out/a.o"].map(String::from);
+        let task = ClangCompiler::default()
+            .create_tasks(
+                fixture.command(),
+                CommandArgs::Regular(args.to_vec()),
+                &TaskOptions::new(&fixture.config),
+            )
+            .unwrap()
+            .remove(0);
+        let build = |remap: &[(PathBuf, PathBuf)]| {
+            let mut graph = BuildGraph::new();
+            graph.add_node(Arc::new(BuildTask {
+                title: "compile".to_string(),
+                action: BuildAction::Compilation(task.toolchain.clone(), task.task.clone()),
+            }));
+            for dir in remap_outputs(&mut graph, remap).unwrap() {
+                std::fs::create_dir_all(dir).unwrap();
+            }
+            execute_graph(&state, graph, 1, |_| Ok(())).unwrap();
+        };
+
+        build(&[]);
+        assert!(temp.join("out/a.o").exists());
+        assert_eq!(state.statistic.miss_count.load(Ordering::Relaxed), 1);
+
+        // Remapped output lands under new root and reuses cache entry.
+        std::fs::remove_file(temp.join("out/a.o")).unwrap();
+        build(&[(temp.join("out"), temp.join("ci/objects"))]);
+        assert!(!temp.join("out/a.o").exists());
+        assert_eq!(
+            std::fs::read_to_string(temp.join("ci/objects/a.o")).unwrap(),
+            "object\n"
+        );
+        assert_eq!(state.statistic.miss_count.load(Ordering::Relaxed), 1);
+        assert_eq!(state.statistic.hit_count.load(Ordering::Relaxed), 1);
+
+        // Every output is remapped, directories are left to caller.
+        let args = [
+            "-c",
+            "a.cpp",
+            "-o",
+            "out/b.o",
+            "-MD",
+            "-MF",
+            "out/deps/b.d",
+            "-fmodule-output=out/modules/b.pcm",
+        ]
+        .map(String::from);
+        let task = ClangCompiler::default()
+            .create_tasks(
+                fixture.command(),
+                CommandArgs::Regular(args.to_vec()),
+                &TaskOptions::new(&fixture.config),
+            )
+            .unwrap()
+            .remove(0);
+        let mut graph = BuildGraph::new();
+        graph.add_node(Arc::new(BuildTask {
+            title: "compile".to_string(),
+            action: BuildAction::Compilation(task.toolchain, task.task),
+        }));
+        let ci = temp.join("ci");
+        let dirs = remap_outputs(&mut graph, &[(temp.join("out"), ci.clone())]).unwrap();
+        assert_eq!(
+            dirs.into_iter().collect::<Vec<_>>(),
+            [ci.clone(), ci.join("deps"), ci.join("modules")]
+        );
+        assert!(!ci.join("deps").exists());
+        let BuildAction::Compilation(_, task) = &graph.raw_nodes()[0].weight.action else {
+            panic!("compilation task expected");
+        };
+        assert_eq!(task.output_object, ci.join("b.o"));
+        assert_eq!(task.shared.deps_file, Some(ci.join("deps/b.d")));
+        assert_eq!(task.module_file().unwrap(), Some(ci.join("modules/b.pcm")));
+        let args: Vec<String> = task.shared.args.iter().map(ToString::to_string).collect();
+        assert!(
+            args.contains(&format!("-MF {}", ci.join("deps/b.d").display())),
+            "{args:?}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expect_cached() {
+        use crate::clang::compiler::ClangCompiler;
+        use crate::compiler::Compiler;
+        use crate::test_utils::{Fixture, COPY_SOURCE};
+
+        // Fake compiler: preprocessing copies source, compilation writes object.
+        let fixture = Fixture::clang(COPY_SOURCE);
+        std::fs::write(fixture.path().join("a.cpp"), "int a;\n").unwrap();
+        std::fs::write(fixture.path().join("b.cpp"), "int b;\n").unwrap();
+
+        let build = || {
+            let state = fixture.state();
+            let mut graph = BuildGraph::new();
+            graph.add_node(Arc::new(BuildTask {
+                title: "link".to_string(),
+                action: BuildAction::Empty,
+            }));
+            for name in ["a", "b"] {
+                let args =
+                    ["-c", &format!("{name}.cpp"), "-o", &format!("{name}.o")].map(String::from);
+                let task = ClangCompiler::default()
+                    .create_tasks(
+                        fixture.command(),
+                        CommandArgs::Regular(args.to_vec()),
+                        &TaskOptions::new(&fixture.config),
+                    )
+                    .unwrap()
+                    .remove(0);
+                graph.add_node(Arc::new(BuildTask {
+                    title: format!("compile {name}"),
+                    action: BuildAction::Compilation(task.toolchain, task.task),
+                }));
+            }
+            execute_graph(&state, graph, 2, |_| Ok(())).unwrap();
+            state
+        };
+
+        let state = build();
+        assert!(
+            check_expect_cached(&state.statistic, &ExpectCached::All, &mut Vec::new()).is_err()
+        );
+
+        // Changed source misses cache, other task and non-compilation tasks are fine.
+        std::fs::write(fixture.path().join("b.cpp"), "int b = 1;\n").unwrap();
+        let state = build();
+        let mut out = Vec::new();
+        assert!(check_expect_cached(&state.statistic, &ExpectCached::All, &mut out).is_err());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Tasks expected to be cached (1):\n  compile b\n"
+        );
+        let expected = ExpectCached::Titles(HashSet::from(["compile a".to_string()]));
+        assert!(check_expect_cached(&state.statistic, &expected, &mut Vec::new()).is_ok());
+    }
+
     // Test for #19 issue (https://github.com/octobuild/octobuild/issues/19)
     #[test]
     fn test_execute_graph_no_hang() {
@@ -66,11 +66,20 @@ struct XgTool {
 }
 
 pub fn parse<R: Read>(graph: &mut XgGraph, reader: R) -> Result<(), Error> {
+    parse_with_env(graph, reader, &env::vars().collect())
+}
+
+// Parse task file, using `inherited` as base environment of every task.
+pub fn parse_with_env<R: Read>(
+    graph: &mut XgGraph,
+    reader: R,
+    inherited: &CommandEnv,
+) -> Result<(), Error> {
     let mut parser = EventReader::new(reader);
     loop {
         if let XmlEvent::StartElement { name, .. } = next_xml_event(&mut parser)? {
             return match &name.local_name[..] {
-                "BuildSet" => parse_build_set(graph, &mut parser),
+                "BuildSet" => parse_build_set(graph, &mut parser, inherited),
                 _ => Err(Error::new(
                     ErrorKind::InvalidInput,
                     XgParseError::InvalidStreamFormat,
@@ -80,7 +89,11 @@ pub fn parse<R: Read>(graph: &mut XgGraph, reader: R) -> Result<(), Error> {
     }
 }
 
-fn parse_build_set<R: Read>(graph: &mut XgGraph, events: &mut EventReader<R>) -> Result<(), Error> {
+fn parse_build_set<R: Read>(
+    graph: &mut XgGraph,
+    events: &mut EventReader<R>,
+    inherited: &CommandEnv,
+) -> Result<(), Error> {
     let mut envs: HashMap<String, XgEnvironment> = HashMap::new();
     let mut projects: Vec<XgProject> = Vec::new();
     loop {
@@ -89,7 +102,7 @@ fn parse_build_set<R: Read>(graph: &mut XgGraph, events: &mut EventReader<R>) ->
                 name, attributes, ..
             } => match &name.local_name[..] {
                 "Environments" => {
-                    parse_environments(events, &mut envs)?;
+                    parse_environments(events, &mut envs, inherited)?;
                 }
                 "Project" => {
                     let mut attrs = map_attributes(attributes);
@@ -114,6 +127,7 @@ fn parse_build_set<R: Read>(graph: &mut XgGraph, events: &mut EventReader<R>) ->
 fn parse_environments<R: Read>(
     events: &mut EventReader<R>,
     envs: &mut HashMap<String, XgEnvironment>,
+    inherited: &CommandEnv,
 ) -> Result<(), Error> {
     loop {
         match next_xml_event(events)? {
@@ -123,7 +137,7 @@ fn parse_environments<R: Read>(
                 "Environment" => {
                     let mut attrs = map_attributes(attributes);
                     let name = take_attr(&mut attrs, "Name")?;
-                    envs.insert(name, parse_environment(events)?);
+                    envs.insert(name, parse_environment(events, inherited)?);
                 }
                 _ => {
                     parse_skip(events, ())?;
@@ -137,8 +151,11 @@ fn parse_environments<R: Read>(
     }
 }
 
-fn parse_environment<R: Read>(events: &mut EventReader<R>) -> Result<XgEnvironment, Error> {
-    let mut variables = env::vars().collect();
+fn parse_environment<R: Read>(
+    events: &mut EventReader<R>,
+    inherited: &CommandEnv,
+) -> Result<XgEnvironment, Error> {
+    let mut variables = inherited.clone();
     let mut tools = HashMap::new();
     loop {
         match next_xml_event(events)? {
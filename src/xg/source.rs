@@ -0,0 +1,307 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use petgraph::graph::NodeIndex;
+use petgraph::{EdgeDirection, Graph};
+
+use crate::compiler::{CommandArgs, CommandEnv, Compiler, TaskOptions};
+use crate::worker::{validate_graph, BuildAction, BuildGraph, BuildTask};
+use crate::xg::parser::{parse_with_env, XgGraph, XgNode};
+
+// Everything needed to turn commands of task file into build tasks.
+pub struct GraphContext<'a> {
+    // Base environment of every task.
+    pub inherited: &'a CommandEnv,
+    pub compiler: &'a dyn Compiler,
+    pub options: &'a TaskOptions,
+}
+
+// Parser of task file format into graph of build tasks.
+pub trait GraphSource: Send + Sync {
+    // Format name for explicit selection.
+    fn name(&self) -> &'static str;
+    // Task file extensions of this format (lowercase, without dot).
+    fn extensions(&self) -> &[&'static str];
+    // Parse task file, edges go from task to tasks it depends on.
+    fn parse(&self, reader: &mut dyn Read, context: &GraphContext) -> crate::Result<BuildGraph>;
+}
+
+// IncrediBuild XGE XML task files.
+pub struct XgSource;
+
+impl GraphSource for XgSource {
+    fn name(&self) -> &'static str {
+        "xge"
+    }
+
+    fn extensions(&self) -> &[&'static str] {
+        &["xml"]
+    }
+
+    fn parse(&self, reader: &mut dyn Read, context: &GraphContext) -> crate::Result<BuildGraph> {
+        let mut graph: XgGraph = Graph::new();
+        parse_with_env(&mut graph, reader, context.inherited)?;
+        prepare_graph(context.compiler, validate_graph(graph)?, context.options)
+    }
+}
+
+// Registered task file formats, the first one is used for unknown extensions.
+pub struct GraphSources(Vec<Box<dyn GraphSource>>);
+
+impl Default for GraphSources {
+    fn default() -> Self {
+        GraphSources(vec![Box::new(XgSource)])
+    }
+}
+
+impl GraphSources {
+    #[must_use]
+    pub fn new() -> Self {
+        GraphSources::default()
+    }
+
+    // Register format, it takes precedence over earlier ones with the same extensions.
+    #[must_use]
+    pub fn register<S: 'static + GraphSource>(mut self, source: S) -> Self {
+        self.0.push(Box::new(source));
+        self
+    }
+
+    // Select format by name if given, otherwise by task file extension.
+    pub fn select(&self, path: &Path, format: Option<&str>) -> crate::Result<&dyn GraphSource> {
+        if let Some(format) = format {
+            return self
+                .0
+                .iter()
+                .find(|source| source.name().eq_ignore_ascii_case(format))
+                .map(AsRef::as_ref)
+                .ok_or_else(|| {
+                    crate::Error::Generic(format!("Unknown task file format: {format}"))
+                });
+        }
+        let extension = path
+            .extension()
+            .map(|v| v.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        Ok(self
+            .0
+            .iter()
+            .rev()
+            .find(|source| source.extensions().contains(&extension.as_str()))
+            .unwrap_or(&self.0[0])
+            .as_ref())
+    }
+
+    // Load task file with format selected like by `select`.
+    pub fn load(
+        &self,
+        path: &Path,
+        format: Option<&str>,
+        context: &GraphContext,
+    ) -> crate::Result<BuildGraph> {
+        let source = self.select(path, format)?;
+        source.parse(&mut BufReader::new(File::open(path)?), context)
+    }
+}
+
+fn prepare_graph(
+    compiler: &dyn Compiler,
+    graph: XgGraph,
+    options: &TaskOptions,
+) -> crate::Result<BuildGraph> {
+    let mut remap: Vec<NodeIndex> = Vec::with_capacity(graph.node_count());
+    let mut depends: Vec<NodeIndex> = Vec::with_capacity(graph.node_count());
+
+    let mut result: BuildGraph = Graph::new();
+    for raw_node in graph.raw_nodes() {
+        let node: &XgNode = &raw_node.weight;
+        // Variables are resolved from task environment, so `--isolate-env` applies to them too
+        let raw_args: String = expand_arg(&node.raw_args, &|name: &str| {
+            node.command.env.get(name).map(str::to_string)
+        });
+        let command = node.command.clone();
+
+        let actions = BuildAction::create_tasks(
+            compiler,
+            command.clone(),
+            CommandArgs::Raw(raw_args),
+            &node.title,
+            options,
+        );
+        let node_index = NodeIndex::new(remap.len());
+        if actions.len() == 1 {
+            depends.push(node_index);
+            remap.push(result.add_node(Arc::new(BuildTask {
+                title: node.title.clone(),
+                action: actions.into_iter().next().unwrap(),
+            })));
+        } else {
+            // Add group node for tracking end of all task actions
+            let group_node = result.add_node(Arc::new(BuildTask {
+                title: node.title.clone(),
+                action: BuildAction::Empty,
+            }));
+            depends.push(NodeIndex::end());
+            // Add task actions
+            let mut index = 1;
+            let total = actions.len();
+            for action in actions {
+                let action_node = result.add_node(Arc::new(BuildTask {
+                    title: format!("{} ({index}/{total})", node.title),
+                    action,
+                }));
+                depends.push(node_index);
+                result.add_edge(group_node, action_node, ());
+                index += 1;
+            }
+            remap.push(group_node);
+        }
+    }
+
+    assert_eq!(remap.len(), graph.node_count());
+    assert_eq!(depends.len(), result.node_count());
+    for i in 0..depends.len() {
+        let node_a = NodeIndex::new(i);
+        for neighbor in graph.neighbors_directed(*depends.get(i).unwrap(), EdgeDirection::Outgoing)
+        {
+            let node_b = remap.get(neighbor.index()).unwrap();
+            result.add_edge(node_a, *node_b, ());
+        }
+    }
+    validate_graph(result)
+}
+
+fn expand_arg<F: Fn(&str) -> Option<String>>(arg: &str, resolver: &F) -> String {
+    let mut result = String::new();
+    let mut suffix = arg;
+    loop {
+        match suffix.find("$(") {
+            Some(begin) => match suffix[begin..].find(')') {
+                Some(end) => {
+                    let name = &suffix[begin + 2..begin + end];
+                    match resolver(name) {
+                        Some(ref value) => {
+                            result += &suffix[..begin];
+                            result += value;
+                        }
+                        None => {
+                            result += &suffix[..=begin + end];
+                        }
+                    }
+                    suffix = &suffix[begin + end + 1..];
+                }
+                None => {
+                    result += suffix;
+                    break;
+                }
+            },
+            None => {
+                result += suffix;
+                break;
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::path::Path;
+
+    use crate::compiler::{CommandArgs, CommandEnv, CompilerGroup, TaskOptions};
+    use crate::worker::{BuildAction, BuildGraph};
+    use crate::xg::source::{expand_arg, GraphContext, GraphSource, GraphSources, XgSource};
+
+    struct Dummy(&'static str);
+
+    impl GraphSource for Dummy {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn extensions(&self) -> &[&'static str] {
+            &["json", "xml"]
+        }
+
+        fn parse(&self, _: &mut dyn Read, _: &GraphContext) -> crate::Result<BuildGraph> {
+            Ok(BuildGraph::new())
+        }
+    }
+
+    #[test]
+    fn test_select_source() {
+        let sources = GraphSources::new()
+            .register(Dummy("first"))
+            .register(Dummy("second"));
+        let select = |path: &str, format: Option<&str>| {
+            sources
+                .select(Path::new(path), format)
+                .map(|source| source.name())
+        };
+        assert_eq!(select("build.JSON", None).unwrap(), "second");
+        assert_eq!(select("build.xml", None).unwrap(), "second");
+        assert_eq!(select("build.txt", None).unwrap(), "xge");
+        assert_eq!(select("build.json", Some("XGE")).unwrap(), "xge");
+        assert_eq!(select("build.json", Some("first")).unwrap(), "first");
+        assert!(select("build.xml", Some("unknown")).is_err());
+    }
+
+    #[test]
+    fn test_parse_vars() {
+        assert_eq!(
+            expand_arg(
+                "A$(test)$(inner)$(none)B",
+                &|name: &str| -> Option<String> {
+                    match name {
+                        "test" => Some("foo".to_string()),
+                        "inner" => Some("$(bar)".to_string()),
+                        "none" => None,
+                        _ => {
+                            unreachable!("Unexpected value: {}", name);
+                        }
+                    }
+                },
+            ),
+            "Afoo$(bar)$(none)B"
+        );
+    }
+
+    #[test]
+    fn test_expand_task_env() {
+        let xml = r#"<BuildSet FormatVersion="1">
+  <Environments>
+    <Environment Name="Default">
+      <Variables>
+        <Variable Name="OCTOBUILD_EXPAND_DECLARED" Value="declared" />
+      </Variables>
+      <Tools>
+        <Tool Name="Tool0" Path="/usr/bin/cc"
+          Params="$(OCTOBUILD_EXPAND_DECLARED) $(OCTOBUILD_EXPAND_INHERITED) $(CARGO_PKG_NAME)" />
+      </Tools>
+    </Environment>
+  </Environments>
+  <Project Name="Default" Env="Default">
+    <Task Name="Action0" Tool="Tool0" WorkingDir="/tmp" />
+  </Project>
+</BuildSet>"#;
+        // Variables of octobuild process aren't expanded unless they're inherited by tasks
+        let inherited: CommandEnv = [("OCTOBUILD_EXPAND_INHERITED", "inherited")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let context = GraphContext {
+            inherited: &inherited,
+            compiler: &CompilerGroup::new(),
+            options: &TaskOptions::default(),
+        };
+        let graph = XgSource.parse(&mut xml.as_bytes(), &context).unwrap();
+        let BuildAction::Exec(_, CommandArgs::Raw(args)) = &graph.raw_nodes()[0].weight.action
+        else {
+            panic!("Unexpected task action");
+        };
+        assert_eq!(args, "declared inherited $(CARGO_PKG_NAME)");
+    }
+}
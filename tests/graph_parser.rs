@@ -2,6 +2,7 @@ use std::fs::File;
 use std::io::BufReader;
 use std::path::PathBuf;
 
+use octobuild::compiler::CommandEnv;
 use petgraph::Graph;
 
 #[test]
@@ -13,3 +14,47 @@ fn test_parse_smoke() {
     let reader = BufReader::new(File::open(f).unwrap());
     octobuild::xg::parser::parse(&mut Graph::new(), reader).unwrap();
 }
+
+#[test]
+fn test_parse_isolated_env() {
+    let xml = r#"<BuildSet FormatVersion="1">
+  <Environments>
+    <Environment Name="Default">
+      <Variables>
+        <Variable Name="OCTOBUILD_ISOLATED_DECLARED" Value="declared" />
+      </Variables>
+      <Tools>
+        <Tool Name="Tool0" Params="-c a.cpp" Path="/usr/bin/cc" />
+      </Tools>
+    </Environment>
+  </Environments>
+  <Project Name="Default" Env="Default">
+    <Task Name="Action0" Tool="Tool0" WorkingDir="/tmp" />
+  </Project>
+</BuildSet>"#;
+
+    // Environment is built explicitly, the process one is shared by tests running in parallel
+    let base: CommandEnv = [
+        ("OCTOBUILD_ISOLATED_INHERITED", "inherited"),
+        ("OCTOBUILD_ISOLATED_ALLOWED", "allowed"),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect();
+    let inherited = base.isolate(&["OCTOBUILD_ISOLATED_ALLOWED".to_string()]);
+    let mut graph = Graph::new();
+    octobuild::xg::parser::parse_with_env(&mut graph, xml.as_bytes(), &inherited).unwrap();
+    let env = &graph.raw_nodes()[0].weight.command.env;
+    assert_eq!(env.get("OCTOBUILD_ISOLATED_DECLARED"), Some("declared"));
+    assert_eq!(env.get("OCTOBUILD_ISOLATED_ALLOWED"), Some("allowed"));
+    assert_eq!(env.get("OCTOBUILD_ISOLATED_INHERITED"), None);
+
+    let mut graph = Graph::new();
+    octobuild::xg::parser::parse(&mut graph, xml.as_bytes()).unwrap();
+    let env = &graph.raw_nodes()[0].weight.command.env;
+    // Without explicit environment tasks inherit the process one
+    assert_eq!(
+        env.get("CARGO_PKG_NAME"),
+        std::env::var("CARGO_PKG_NAME").ok().as_deref()
+    );
+}